@@ -0,0 +1,196 @@
+// Converts BDF fonts into the packed `.font` files next to this one, which
+// `src/font.rs` includes. Only the packed files are kept in the repository;
+// to change one, convert its BDF source again:
+//
+//     rustc --edition 2024 -O fonts/convert.rs -o target/convert-font
+//     target/convert-font 6x13.bdf fonts/6x13.font
+
+use std::fs;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [bdf, font] = args.as_slice() else {
+        eprintln!("Usage: convert-font BDF FONT");
+        return ExitCode::from(2);
+    };
+    // Some fonts carry Latin-1 in their comments.
+    let source = match fs::read(bdf) {
+        Ok(source) => String::from_utf8_lossy(&source).into_owned(),
+        Err(e) => {
+            eprintln!("Cannot read {bdf}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let packed = match convert(&source) {
+        Ok(packed) => packed,
+        Err(e) => {
+            eprintln!("{bdf}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = fs::write(font, packed) {
+        eprintln!("Cannot write {font}: {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+struct Glyph {
+    codepoint: u32,
+    advance: u8,
+    /// Rows of pixels, top to bottom, each as bits from the left.
+    rows: Vec<Vec<bool>>,
+    /// Position of the bitmap's bottom left corner relative to the origin,
+    /// y pointing up.
+    x: i32,
+    y: i32,
+}
+
+impl Glyph {
+    /// Drop blank rows and columns around the pixels.
+    fn trim(&mut self) {
+        while self.rows.last().is_some_and(|row| !row.contains(&true)) {
+            self.rows.pop();
+            self.y += 1;
+        }
+        while self.rows.first().is_some_and(|row| !row.contains(&true)) {
+            self.rows.remove(0);
+        }
+        while !self.rows.is_empty() && self.rows.iter().all(|row| !row[0]) {
+            for row in &mut self.rows {
+                row.remove(0);
+            }
+            self.x += 1;
+        }
+        while !self.rows.is_empty() && self.rows.iter().all(|row| !row[row.len() - 1]) {
+            for row in &mut self.rows {
+                row.pop();
+            }
+        }
+    }
+
+    fn width(&self) -> usize {
+        self.rows.first().map_or(0, Vec::len)
+    }
+}
+
+fn number<T: std::str::FromStr>(field: Option<&str>, what: &str) -> Result<T, String> {
+    field
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("bad {what}"))
+}
+
+/// Convert one BDF font.
+///
+/// The packed format starts with an 8 byte header: the magic `BMF1`, the line
+/// height and the ascent in pixels, and the number of glyphs as a
+/// little-endian `u16`. A table of 8 byte entries follows, sorted by code
+/// point: the code point and the offset of the glyph from the start of the
+/// font, both little-endian `u32`. Each glyph is its advance, width and
+/// height, the `i8` offsets of its bitmap's left edge from the pen position
+/// and of its top row above the baseline, then the bitmap, rows padded to
+/// whole bytes with the leftmost pixel in the high bit.
+fn convert(bdf: &str) -> Result<Vec<u8>, String> {
+    let mut ascent = None;
+    let mut descent = None;
+    let mut glyphs = Vec::new();
+    let mut lines = bdf.lines();
+    while let Some(line) = lines.next() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("FONT_ASCENT") => ascent = Some(number::<u8>(fields.next(), "FONT_ASCENT")?),
+            Some("FONT_DESCENT") => descent = Some(number::<u8>(fields.next(), "FONT_DESCENT")?),
+            Some("STARTCHAR") => {
+                let mut codepoint = None;
+                let mut advance = 0;
+                let (mut width, mut height, mut x, mut y) = (0, 0, 0, 0);
+                let mut rows = Vec::new();
+                while let Some(line) = lines.next() {
+                    let mut fields = line.split_whitespace();
+                    match fields.next() {
+                        Some("ENCODING") => {
+                            codepoint = number::<i64>(fields.next(), "ENCODING")?.try_into().ok()
+                        }
+                        Some("DWIDTH") => advance = number(fields.next(), "DWIDTH")?,
+                        Some("BBX") => {
+                            width = number(fields.next(), "BBX")?;
+                            height = number(fields.next(), "BBX")?;
+                            x = number(fields.next(), "BBX")?;
+                            y = number(fields.next(), "BBX")?;
+                        }
+                        Some("BITMAP") => {
+                            for line in lines.by_ref().take(height) {
+                                let digits = line
+                                    .trim()
+                                    .chars()
+                                    .map(|c| c.to_digit(16))
+                                    .collect::<Option<Vec<_>>>()
+                                    .ok_or("bad BITMAP")?;
+                                let bit = |i: usize| {
+                                    digits
+                                        .get(i / 4)
+                                        .is_some_and(|digit| digit >> (3 - i % 4) & 1 == 1)
+                                };
+                                rows.push((0..width).map(bit).collect());
+                            }
+                        }
+                        Some("ENDCHAR") => break,
+                        _ => {}
+                    }
+                }
+                // Glyphs without a code point only exist for other encodings.
+                if let Some(codepoint) = codepoint {
+                    let mut glyph = Glyph {
+                        codepoint,
+                        advance,
+                        rows,
+                        x,
+                        y,
+                    };
+                    glyph.trim();
+                    glyphs.push(glyph);
+                }
+            }
+            _ => {}
+        }
+    }
+    let ascent = ascent.ok_or("no FONT_ASCENT")?;
+    let descent = descent.ok_or("no FONT_DESCENT")?;
+    glyphs.sort_by_key(|glyph| glyph.codepoint);
+    glyphs.dedup_by_key(|glyph| glyph.codepoint);
+
+    let count = u16::try_from(glyphs.len()).map_err(|_| "too many glyphs")?;
+    let mut table = Vec::new();
+    let mut bitmaps = Vec::new();
+    let data_start = 8 + glyphs.len() * 8;
+    for glyph in &glyphs {
+        let offset = u32::try_from(data_start + bitmaps.len()).map_err(|_| "font too large")?;
+        table.extend_from_slice(&glyph.codepoint.to_le_bytes());
+        table.extend_from_slice(&offset.to_le_bytes());
+        let top = glyph.y + glyph.rows.len() as i32 - 1;
+        bitmaps.extend_from_slice(&[
+            glyph.advance,
+            u8::try_from(glyph.width()).map_err(|_| "glyph too wide")?,
+            u8::try_from(glyph.rows.len()).map_err(|_| "glyph too high")?,
+            i8::try_from(glyph.x).map_err(|_| "glyph offset too large")? as u8,
+            i8::try_from(top).map_err(|_| "glyph offset too large")? as u8,
+        ]);
+        for row in &glyph.rows {
+            for byte in row.chunks(8) {
+                bitmaps.push(
+                    byte.iter()
+                        .enumerate()
+                        .fold(0, |acc, (i, &on)| acc | (on as u8) << (7 - i)),
+                );
+            }
+        }
+    }
+    let mut packed = Vec::with_capacity(data_start + bitmaps.len());
+    packed.extend_from_slice(b"BMF1");
+    packed.extend_from_slice(&[ascent + descent, ascent]);
+    packed.extend_from_slice(&count.to_le_bytes());
+    packed.extend_from_slice(&table);
+    packed.extend_from_slice(&bitmaps);
+    Ok(packed)
+}
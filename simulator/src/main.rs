@@ -0,0 +1,486 @@
+// The firmware's drawing code, built for the host. Parts of it only the
+// device uses.
+#![allow(dead_code)]
+
+#[path = "../../src/api.rs"]
+mod api;
+#[path = "../../src/datetime.rs"]
+mod datetime;
+#[path = "../../src/dither.rs"]
+mod dither;
+#[path = "../../src/epaper.rs"]
+mod epaper;
+#[path = "../../src/font.rs"]
+mod font;
+#[path = "../../src/layout.rs"]
+mod layout;
+#[path = "../../src/overlay.rs"]
+mod overlay;
+#[path = "../../src/panel.rs"]
+mod panel;
+#[path = "../../src/placement.rs"]
+mod placement;
+#[path = "../../src/policy.rs"]
+mod policy;
+#[path = "../../src/qr.rs"]
+mod qr;
+#[path = "../../src/refresh.rs"]
+mod refresh;
+#[path = "../../src/render.rs"]
+mod render;
+#[path = "../../src/request.rs"]
+mod request;
+#[path = "../../src/screens.rs"]
+mod screens;
+#[path = "../../src/screenshot.rs"]
+mod screenshot;
+#[path = "../../src/settings.rs"]
+mod settings;
+#[path = "../../src/shell.rs"]
+mod shell;
+#[path = "../../src/store.rs"]
+mod store;
+#[path = "../../src/timebase.rs"]
+mod timebase;
+#[path = "../../src/uc8179.rs"]
+mod uc8179;
+
+mod output;
+mod unwired;
+
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
+
+use embedded_graphics::pixelcolor::{BinaryColor, Gray2};
+use embedded_graphics::prelude::{DrawTarget, DrawTargetExt, Size};
+use log::{debug, error, info, warn};
+use tinyqoi::Qoi;
+
+use epaper::{GrayFrame, PAPER, TriColor, TriColorFrame};
+use panel::{ColorDepth, Frame, Panel};
+use render::Canvas;
+use unwired::Unwired;
+
+const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Largest response the device can take; see `pipeline::SLOT_SIZE`.
+const MAX_RESPONSE: u64 = 56 << 10;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(45);
+
+type SimulatedPanel = panel::Selected<Unwired, Unwired, Unwired, Unwired, Unwired>;
+type Framebuffer = <SimulatedPanel as Panel>::Framebuffer;
+
+const PANEL_SIZE: Size = Size::new(SimulatedPanel::WIDTH, SimulatedPanel::HEIGHT);
+
+const USAGE: &str = "\
+Usage: atrmnl-sim --server URL --token ID [OPTIONS]
+
+Fetches what the device would show from the server and writes the panel's
+framebuffer to a file, or the diagnostic screen if that fails.
+
+Options:
+  --server URL        Server address, as `trmnl.address` in build_cfg.toml
+  --token ID          Device ID, as `trmnl.device_id` in build_cfg.toml
+  --output PATH       File to write; PNG if it ends in .png, PBM/PGM
+                      otherwise [default: frame.png]
+  --orientation DEG   How the panel is mounted: 0, 90, 180 or 270
+  --scale             Scale small images up to fit the panel
+  --status-bar POS    Draw the status bar at top, bottom, top-left,
+                      top-right, bottom-left or bottom-right
+  --large-bar         Use the large status bar
+  --invert-on-dark    Draw the status bar light on dark images
+  --battery PERCENT   Battery charge for the status bar
+  --rssi DBM          Signal strength for the status bar
+  --stale             Mark the image as stale
+  --notice TEXT       One-line notice drawn over the image";
+
+#[derive(Debug)]
+enum Error {
+    /// No usable response; with the status code if the server sent one.
+    Http(Option<u16>),
+    Decode,
+    Image,
+    Layout,
+}
+
+impl From<ureq::Error> for Error {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::StatusCode(code) => Self::Http(Some(code)),
+            e => {
+                debug!("Discarding http error details: {e}");
+                Self::Http(None)
+            }
+        }
+    }
+}
+
+impl From<serde_json_core::de::Error> for Error {
+    fn from(e: serde_json_core::de::Error) -> Self {
+        debug!("Discarding decode error details: {e:?}");
+        Self::Decode
+    }
+}
+
+impl From<tinyqoi::Error> for Error {
+    fn from(e: tinyqoi::Error) -> Self {
+        debug!("Discarding image error details: {e:?}");
+        Self::Image
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Http(Some(code)) => write!(f, "http request has status code of: {code}"),
+            Error::Http(None) => write!(f, "http request failed"),
+            Error::Decode => write!(f, "failed to decode response"),
+            Error::Image => write!(f, "failed to decode image"),
+            Error::Layout => write!(f, "failed to parse layout"),
+        }
+    }
+}
+
+impl Error {
+    /// The diagnostic the device shows after this error fetching `url`.
+    fn diagnostic(&self, url: &str) -> screens::Diagnostic {
+        let url = heapless::String::try_from(url).unwrap_or_default();
+        match self {
+            Error::Image => screens::Diagnostic::ImageDecode { url },
+            Error::Layout => screens::Diagnostic::LayoutDecode { url },
+            Error::Http(status) => screens::Diagnostic::ServerUnreachable {
+                url,
+                status: *status,
+            },
+            Error::Decode => screens::Diagnostic::ServerUnreachable { url, status: None },
+        }
+    }
+}
+
+struct Options {
+    server: String,
+    token: String,
+    output: PathBuf,
+    layout: render::Layout,
+    notice: String,
+    status: overlay::DeviceStatus,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut server = None;
+        let mut token = None;
+        let mut output = PathBuf::from("frame.png");
+        let mut display = render::DisplaySettings::default();
+        let mut bar = overlay::BarSettings::default();
+        let mut notice = String::new();
+        let mut status = overlay::DeviceStatus::default();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+            match arg.as_str() {
+                "--server" => server = Some(value()?),
+                "--token" => token = Some(value()?),
+                "--output" => output = value()?.into(),
+                "--orientation" => display
+                    .set("orientation", &value()?)
+                    .map_err(|e| format!("{arg} {e}"))?,
+                "--scale" => display.scale_images = true,
+                "--status-bar" => bar
+                    .set("position", &value()?)
+                    .map_err(|e| format!("{arg} {e}"))?,
+                "--large-bar" => bar.size = overlay::BarSize::Large,
+                "--invert-on-dark" => bar.invert_on_dark = true,
+                "--battery" => status.battery = Some(number(&arg, value()?)?),
+                "--rssi" => status.rssi = Some(number(&arg, value()?)?),
+                "--stale" => status.stale = true,
+                "--notice" => notice = value()?,
+                // Empty for asking for the usage.
+                "--help" | "-h" => return Err(String::new()),
+                _ => return Err(format!("unknown argument: {arg}")),
+            }
+        }
+        Ok(Self {
+            server: server.ok_or("--server is required")?,
+            token: token.ok_or("--token is required")?,
+            output,
+            layout: render::Layout::new(PANEL_SIZE, &display, &bar),
+            notice,
+            status,
+        })
+    }
+}
+
+fn number<T: std::str::FromStr>(arg: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{arg} needs a number, not {value}"))
+}
+
+/// What the device would get from the server.
+struct Fetched {
+    api: api::ApiResponse,
+    payload: Payload,
+    /// UTC seconds from the `Date` header of the `/api/display` response.
+    server_time: Option<u64>,
+}
+
+/// Ask `url` what to show, as `http::Client::fetch_api_display` does.
+/// Also returns the UTC seconds from the `Date` header.
+fn fetch_api_display(
+    agent: &ureq::Agent,
+    url: &str,
+    token: &str,
+) -> Result<(api::ApiResponse, Option<u64>), Error> {
+    let mut resp = agent
+        .get(url)
+        .header("Access-Token", token)
+        .header("FW-Version", FIRMWARE_VERSION)
+        .header("Width", PANEL_SIZE.width.to_string())
+        .header("Height", PANEL_SIZE.height.to_string())
+        .call()?;
+    let server_time = resp
+        .headers()
+        .get("date")
+        .and_then(|value| datetime::parse_http_date(value.as_bytes()));
+    let body = resp
+        .body_mut()
+        .with_config()
+        .limit(MAX_RESPONSE)
+        .read_to_vec()?;
+    let (api, _) = serde_json_core::from_slice(&body)?;
+    Ok((api, server_time))
+}
+
+/// Download the QOI image at `url` and check its header.
+fn fetch_image(agent: &ureq::Agent, url: &str) -> Result<Vec<u8>, Error> {
+    let mut resp = agent.get(url).header("Accept", "image/qoi").call()?;
+    let image = resp
+        .body_mut()
+        .with_config()
+        .limit(MAX_RESPONSE)
+        .read_to_vec()?;
+    Qoi::new(&image)?;
+    Ok(image)
+}
+
+/// What the image or layout URL returned, as `pipeline::Payload`.
+enum Payload {
+    Image(Vec<u8>),
+    Layout(Vec<u8>),
+}
+
+/// Download the layout document at `url`, parse it and log what is wrong
+/// with its elements.
+fn fetch_layout(agent: &ureq::Agent, url: &str) -> Result<Vec<u8>, Error> {
+    let mut resp = agent.get(url).header("Accept", "application/json").call()?;
+    let json = resp
+        .body_mut()
+        .with_config()
+        .limit(MAX_RESPONSE)
+        .read_to_vec()?;
+    let errors = layout::parse(&json)
+        .map_err(|e| {
+            debug!("Failed to parse layout: {e:?}");
+            Error::Layout
+        })?
+        .errors()
+        .collect::<Vec<_>>();
+    for (index, error) in errors {
+        warn!("Layout element {index} left out: {error}");
+    }
+    Ok(json)
+}
+
+/// Does what the fetch task does. On error, returns the URL that failed.
+fn fetch(agent: &ureq::Agent, options: &Options) -> Result<Fetched, (String, Error)> {
+    info!("Fetching data for screen.");
+    let url = format!("{}/api/display", options.server);
+    let (api, server_time) = fetch_api_display(agent, &url, &options.token).map_err(|e| {
+        error!("Failed to fetch from /api/display: {e}");
+        (url.clone(), e)
+    })?;
+    let payload = match &api.layout_url {
+        Some(url) => {
+            info!("Got response. Continue to fetch layout from: {url}");
+            fetch_layout(agent, url).map(Payload::Layout)
+        }
+        None => {
+            info!(
+                "Got response. Continue to fetch image from: {}",
+                api.image_url
+            );
+            fetch_image(agent, &api.image_url).map(Payload::Image)
+        }
+    };
+    let url = api.layout_url.as_ref().unwrap_or(&api.image_url);
+    let payload = payload.map_err(|e| {
+        error!("Failed to fetch {url}: {e}");
+        (url.to_string(), e)
+    })?;
+    Ok(Fetched {
+        api,
+        payload,
+        server_time,
+    })
+}
+
+/// The device's two framebuffers, drawn into as the display task draws
+/// into the screen.
+struct Frames {
+    buffers: [Framebuffer; 2],
+    /// What the buffers hold: black and white in the first one, or gray
+    /// levels or inks over both.
+    depth: ColorDepth,
+}
+
+impl Frames {
+    fn new() -> Self {
+        let mut frames = Self {
+            buffers: [Framebuffer::default(), Framebuffer::default()],
+            depth: ColorDepth::BlackWhite,
+        };
+        // Safe to unwrap: drawing into a framebuffer cannot fail.
+        frames.buffers[0].clear(PAPER.into()).unwrap();
+        frames
+    }
+
+    fn screenshot(&self) -> screenshot::Screenshot<'_> {
+        let planes = [self.buffers[0].bytes(), self.buffers[1].bytes()];
+        match self.depth {
+            ColorDepth::Gray4 => screenshot::Screenshot::gray(planes, PANEL_SIZE),
+            ColorDepth::TriColor => screenshot::Screenshot::tricolor(planes, PANEL_SIZE),
+            ColorDepth::BlackWhite => {
+                // Whether paper is stored as 1 or 0 depends on the panel's colour type.
+                let mut blank = Framebuffer::default();
+                // Safe to unwrap: as above.
+                blank.clear(PAPER.into()).unwrap();
+                let paper = blank.bytes()[0] & 0x80 != 0;
+                screenshot::Screenshot::mono(planes[0], paper, PANEL_SIZE)
+            }
+        }
+    }
+}
+
+impl render::Canvas for Frames {
+    fn mono(&mut self) -> impl DrawTarget<Color = BinaryColor, Error = Infallible> {
+        self.depth = ColorDepth::BlackWhite;
+        // Safe to unwrap here and below: drawing into a framebuffer cannot fail.
+        self.buffers[0].clear(PAPER.into()).unwrap();
+        self.buffers[0].color_converted()
+    }
+
+    fn gray(&mut self) -> Option<impl DrawTarget<Color = Gray2, Error = Infallible>> {
+        if SimulatedPanel::COLOR_DEPTH != ColorDepth::Gray4 {
+            return None;
+        }
+        self.depth = ColorDepth::Gray4;
+        let [draw, shown] = &mut self.buffers;
+        let mut frame = GrayFrame::new([draw.bytes_mut(), shown.bytes_mut()], PANEL_SIZE);
+        frame.clear(PAPER.into()).unwrap();
+        Some(frame)
+    }
+
+    fn tricolor(&mut self) -> Option<impl DrawTarget<Color = TriColor, Error = Infallible>> {
+        if SimulatedPanel::COLOR_DEPTH != ColorDepth::TriColor {
+            return None;
+        }
+        self.depth = ColorDepth::TriColor;
+        let [draw, shown] = &mut self.buffers;
+        let mut frame = TriColorFrame::new([draw.bytes_mut(), shown.bytes_mut()], PANEL_SIZE);
+        frame.clear(TriColor::White).unwrap();
+        Some(frame)
+    }
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) if e.is_empty() => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    info!(
+        "Simulating a {} panel ({}).",
+        SimulatedPanel::NAME,
+        SimulatedPanel::COLOR_DEPTH
+    );
+
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(REQUEST_TIMEOUT))
+        .build()
+        .into();
+    // The device keeps its two framebuffers; gray images take up both.
+    let mut frames = Frames::new();
+    match fetch(&agent, &options) {
+        Ok(fetched) => {
+            let utc = fetched.server_time.unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs())
+            });
+            let offset = fetched.api.utc_offset.unwrap_or(0);
+            options.status.updated =
+                Some(datetime::DateTime::from_unix(utc as i64 + offset as i64));
+            let api = &fetched.api;
+            // Safe to unwrap: fetching checked the image header, or parsed
+            // the document.
+            match &fetched.payload {
+                Payload::Image(image) => options.layout.render(
+                    &mut frames,
+                    &Qoi::new(image).unwrap(),
+                    api.render_mode,
+                    api.dither,
+                    &options.notice,
+                    &options.status,
+                ),
+                Payload::Layout(json) => options.layout.render_document(
+                    &mut frames,
+                    &layout::parse(json).unwrap(),
+                    api.dither,
+                    &options.notice,
+                    &options.status,
+                ),
+            };
+        }
+        Err((url, e)) => {
+            let diagnostic = e.diagnostic(&url);
+            info!("Showing diagnostic: {diagnostic}");
+            let device = screens::DeviceInfo {
+                mac: [0; 6],
+                ip: None,
+            };
+            // Safe to unwrap: drawing into a framebuffer cannot fail.
+            diagnostic
+                .draw(
+                    &mut options.layout.oriented().apply(&mut frames.mono()),
+                    &device,
+                )
+                .unwrap();
+        }
+    }
+
+    let shot = frames.screenshot();
+    // The device reports the same hash with its next request.
+    info!("Framebuffer hash: {:08x}", shot.hash());
+    let written = output::write(&options.output, &shot);
+    match written {
+        Ok(()) => {
+            info!("Wrote {}.", options.output.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("Could not write {}: {e}", options.output.display());
+            ExitCode::FAILURE
+        }
+    }
+}
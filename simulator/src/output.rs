@@ -0,0 +1,51 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write as _};
+use std::path::Path;
+
+use crate::screenshot::{Format, Screenshot};
+
+/// Write `shot` as PNG if `path` ends in `.png` and as the netpbm image the
+/// device uploads otherwise.
+pub fn write(path: &Path, shot: &Screenshot<'_>) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    if is_png(path) {
+        let mut pixels = Vec::new();
+        shot.encode_pixels(|piece| pixels.extend_from_slice(piece));
+        let (color, depth) = match shot.format() {
+            Format::Pbm => {
+                // PNG has white as 1, PBM has black as 1.
+                pixels.iter_mut().for_each(|byte| *byte = !*byte);
+                (png::ColorType::Grayscale, png::BitDepth::One)
+            }
+            Format::Pgm => {
+                // Spread the four levels over the whole range.
+                pixels.iter_mut().for_each(|level| *level *= 0x55);
+                (png::ColorType::Grayscale, png::BitDepth::Eight)
+            }
+            Format::Ppm => {
+                pixels.iter_mut().for_each(|channel| *channel *= 0xFF);
+                (png::ColorType::Rgb, png::BitDepth::Eight)
+            }
+        };
+        let mut encoder = png::Encoder::new(&mut out, shot.size().width, shot.size().height);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        writer.finish()?;
+    } else {
+        let mut written = Ok(());
+        shot.encode(|piece| {
+            if written.is_ok() {
+                written = out.write_all(piece);
+            }
+        });
+        written?;
+    }
+    out.flush()
+}
+
+fn is_png(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+}
@@ -0,0 +1,85 @@
+use core::convert::Infallible;
+
+use embedded_hal::{delay, digital, spi};
+
+/// Stands in for the SPI bus, pins and delay of a panel that is not there.
+///
+/// The simulator never talks to a panel; it only needs the panel types to
+/// name their framebuffer and size, and the drivers want these filled in.
+pub struct Unwired;
+
+impl digital::ErrorType for Unwired {
+    type Error = Infallible;
+}
+
+impl digital::OutputPin for Unwired {
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl digital::InputPin for Unwired {
+    fn is_high(&mut self) -> Result<bool, Infallible> {
+        Ok(true)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Infallible> {
+        Ok(false)
+    }
+}
+
+impl embedded_hal_async::digital::Wait for Unwired {
+    async fn wait_for_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl spi::ErrorType for Unwired {
+    type Error = Infallible;
+}
+
+impl spi::SpiDevice for Unwired {
+    fn transaction(
+        &mut self,
+        _operations: &mut [spi::Operation<'_, u8>],
+    ) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl embedded_hal_async::spi::SpiDevice for Unwired {
+    async fn transaction(
+        &mut self,
+        _operations: &mut [spi::Operation<'_, u8>],
+    ) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl delay::DelayNs for Unwired {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+impl embedded_hal_async::delay::DelayNs for Unwired {
+    async fn delay_ns(&mut self, _ns: u32) {}
+}
@@ -0,0 +1,94 @@
+use serde::Deserialize;
+
+use crate::dither;
+
+/// Body of a successful `/api/display` response.
+#[derive(Deserialize)]
+pub struct ApiResponse {
+    pub image_url: heapless::String<128>,
+    /// Where to fetch a layout document to draw instead of the image, see
+    /// [`crate::layout`].
+    pub layout_url: Option<heapless::String<128>>,
+    pub refresh_rate: u64,
+    /// Offset of the device's local time from UTC, in seconds.
+    pub utc_offset: Option<i32>,
+    #[serde(default)]
+    pub render_mode: RenderMode,
+    /// How to dither black and white images.
+    #[serde(default)]
+    pub dither: dither::Method,
+    #[serde(default)]
+    pub special_function: SpecialFunction,
+    /// Timeouts and retry intervals to use instead of the configured ones.
+    #[serde(default)]
+    pub policy: PolicyOverrides,
+}
+
+/// The `policy` object of a response, in seconds. Fields left out keep the
+/// configured value.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PolicyOverrides {
+    pub min_retry_secs: Option<u32>,
+    pub max_retry_secs: Option<u32>,
+    pub wifi_timeout_secs: Option<u32>,
+    pub http_timeout_secs: Option<u32>,
+    pub boot_retry_secs: Option<u32>,
+}
+
+/// Something the server asks the device to do besides showing the image.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpecialFunction {
+    #[default]
+    None,
+    /// Run the ghosting clean cycle before showing the image.
+    DeepClean,
+    /// Upload what the panel shows to `/api/screenshot` once it is refreshed.
+    Screenshot,
+    /// Anything this firmware does not implement.
+    #[serde(other)]
+    Unsupported,
+}
+
+/// How the server wants the image drawn.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderMode {
+    /// Black and white.
+    #[default]
+    Mono,
+    /// Four gray levels, on panels that can show them.
+    Gray4,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> ApiResponse {
+        let (response, _) = serde_json_core::from_str::<ApiResponse>(json).unwrap();
+        response
+    }
+
+    #[test]
+    fn policy_is_optional() {
+        let response = parse(r#"{"image_url":"http://x/a.qoi","refresh_rate":900}"#);
+        assert_eq!(response.policy, PolicyOverrides::default());
+
+        let response = parse(
+            r#"{"image_url":"http://x/a.qoi","refresh_rate":900,
+                "policy":{"min_retry_secs":60,"boot_retry_secs":10}}"#,
+        );
+        assert_eq!(
+            response.policy,
+            PolicyOverrides {
+                min_retry_secs: Some(60),
+                boot_retry_secs: Some(10),
+                ..PolicyOverrides::default()
+            }
+        );
+
+        let response = parse(r#"{"image_url":"http://x/a.qoi","refresh_rate":900,"policy":{}}"#);
+        assert_eq!(response.policy, PolicyOverrides::default());
+    }
+}
@@ -0,0 +1,91 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+#[cfg(feature = "battery")]
+use esp_hal::Blocking;
+#[cfg(feature = "battery")]
+use esp_hal::analog::adc::{Adc, AdcCalCurve, AdcConfig, AdcPin, Attenuation};
+#[cfg(feature = "battery")]
+use esp_hal::peripherals::ADC1;
+
+/// The battery is measured through a divider of two equal resistors.
+#[cfg(feature = "battery")]
+const DIVIDER: u32 = 2;
+/// Readings below this mean that no battery is connected and the pin floats.
+#[cfg(feature = "battery")]
+const NO_BATTERY_MV: u32 = 2_500;
+/// Below this the battery should be charged soon.
+pub const LOW_BATTERY_MV: u32 = 3_400;
+#[cfg(feature = "battery")]
+const SAMPLES: u32 = 8;
+
+/// Last measured voltage in millivolts, zero while unknown or without a
+/// battery.
+static LAST_MV: AtomicU32 = AtomicU32::new(0);
+
+/// The voltage [`Battery::millivolts`] measured last.
+pub fn last_millivolts() -> Option<u32> {
+    let mv = LAST_MV.load(Ordering::Relaxed);
+    (mv != 0).then_some(mv)
+}
+
+#[cfg(feature = "battery")]
+type BatteryPin = AdcPin<crate::board::BatteryPin, ADC1<'static>, AdcCalCurve<ADC1<'static>>>;
+
+/// Measures the battery, on boards with the `battery` feature.
+#[cfg(feature = "battery")]
+pub struct Battery {
+    adc: Adc<'static, ADC1<'static>, Blocking>,
+    pin: BatteryPin,
+}
+
+#[cfg(feature = "battery")]
+impl Battery {
+    pub fn new(adc: ADC1<'static>, pin: crate::board::BatteryPin) -> Self {
+        let mut config = AdcConfig::new();
+        let pin = config.enable_pin_with_cal(pin, Attenuation::_11dB);
+        Self {
+            adc: Adc::new(adc, config),
+            pin,
+        }
+    }
+
+    /// Battery voltage in millivolts, or `None` when running without one.
+    pub fn millivolts(&mut self) -> Option<u32> {
+        let mut sum = 0;
+        for _ in 0..SAMPLES {
+            sum += loop {
+                if let Ok(mv) = self.adc.read_oneshot(&mut self.pin) {
+                    break mv as u32;
+                }
+            };
+        }
+        let mv = sum / SAMPLES * DIVIDER;
+        let mv = (mv >= NO_BATTERY_MV).then_some(mv);
+        LAST_MV.store(mv.unwrap_or(0), Ordering::Relaxed);
+        mv
+    }
+}
+
+/// Rough state of charge of a single LiPo cell.
+pub fn percent(millivolts: u32) -> u8 {
+    // (millivolts, percent), linear in between.
+    const CURVE: [(u32, u32); 6] = [
+        (3_300, 0),
+        (3_600, 10),
+        (3_700, 30),
+        (3_850, 60),
+        (4_000, 85),
+        (4_200, 100),
+    ];
+    let (first, last) = (CURVE[0], CURVE[CURVE.len() - 1]);
+    if millivolts <= first.0 {
+        return 0;
+    }
+    if millivolts >= last.0 {
+        return 100;
+    }
+    let upper = CURVE.iter().position(|&(mv, _)| mv >= millivolts).unwrap();
+    let (lo_mv, lo_pct) = CURVE[upper - 1];
+    let (hi_mv, hi_pct) = CURVE[upper];
+    (lo_pct + (millivolts - lo_mv) * (hi_pct - lo_pct) / (hi_mv - lo_mv)) as u8
+}
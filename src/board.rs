@@ -0,0 +1,102 @@
+use core::convert::Infallible;
+
+use embedded_hal::digital::{ErrorType, OutputPin};
+use esp_hal::gpio::{AnyPin, Output};
+
+/// The pins of a board, taken with [`take_pins`]. Peripherals a board does not
+/// have are left out by its `board-*` feature not enabling theirs.
+pub struct Pins {
+    pub sck: AnyPin<'static>,
+    pub mosi: AnyPin<'static>,
+    pub cs: AnyPin<'static>,
+    pub dc: AnyPin<'static>,
+    pub rst: AnyPin<'static>,
+    pub busy: AnyPin<'static>,
+    /// Switches the panel's supply, on boards that can.
+    pub panel_power: Option<AnyPin<'static>>,
+    /// Low while pressed. Held down for `SETUP_BUTTON_HOLD` while the firmware
+    /// runs, it opens the Wi-Fi setup. Held down at reset, GPIO9 starts the
+    /// ROM's download mode instead of the firmware.
+    pub button: AnyPin<'static>,
+    /// Data line of the WS2812B status LED.
+    #[cfg(feature = "status-led")]
+    pub status_led: AnyPin<'static>,
+    #[cfg(feature = "battery")]
+    pub battery: BatteryPin,
+}
+
+/// The ESP32-C6 DevKit wired as in the README.
+#[cfg(feature = "board-devkit")]
+macro_rules! take_pins {
+    ($peripherals:ident) => {
+        $crate::board::Pins {
+            sck: esp_hal::gpio::Pin::degrade($peripherals.GPIO19),
+            mosi: esp_hal::gpio::Pin::degrade($peripherals.GPIO20),
+            cs: esp_hal::gpio::Pin::degrade($peripherals.GPIO18),
+            dc: esp_hal::gpio::Pin::degrade($peripherals.GPIO21),
+            rst: esp_hal::gpio::Pin::degrade($peripherals.GPIO22),
+            busy: esp_hal::gpio::Pin::degrade($peripherals.GPIO23),
+            panel_power: Some(esp_hal::gpio::Pin::degrade($peripherals.GPIO10)),
+            // The DevKit's BOOT button.
+            button: esp_hal::gpio::Pin::degrade($peripherals.GPIO9),
+            status_led: esp_hal::gpio::Pin::degrade($peripherals.GPIO8),
+            battery: $peripherals.GPIO0,
+        }
+    };
+}
+#[cfg(feature = "board-devkit")]
+pub type BatteryPin = esp_hal::peripherals::GPIO0<'static>;
+
+/// A Seeed Studio XIAO ESP32-C6 on the Seeed ePaper driver board for XIAO.
+/// Its user LED is a plain one and it measures no battery.
+#[cfg(feature = "board-xiao-c6")]
+macro_rules! take_pins {
+    ($peripherals:ident) => {
+        $crate::board::Pins {
+            // D8, D10, D1, D3, D0 and D2.
+            sck: esp_hal::gpio::Pin::degrade($peripherals.GPIO19),
+            mosi: esp_hal::gpio::Pin::degrade($peripherals.GPIO18),
+            cs: esp_hal::gpio::Pin::degrade($peripherals.GPIO1),
+            dc: esp_hal::gpio::Pin::degrade($peripherals.GPIO21),
+            rst: esp_hal::gpio::Pin::degrade($peripherals.GPIO0),
+            busy: esp_hal::gpio::Pin::degrade($peripherals.GPIO2),
+            panel_power: None,
+            // The XIAO's BOOT button.
+            button: esp_hal::gpio::Pin::degrade($peripherals.GPIO9),
+        }
+    };
+}
+
+#[cfg(not(any(feature = "board-devkit", feature = "board-xiao-c6")))]
+compile_error!("select the board with exactly one of the `board-*` features");
+#[cfg(all(feature = "board-devkit", feature = "board-xiao-c6"))]
+compile_error!("select only one of the `board-*` features");
+
+/// Take the pins of the board this firmware is built for, picked with one of
+/// the `board-*` cargo features, out of the `Peripherals` that
+/// `esp_hal::init` returned, leaving the rest.
+pub(crate) use take_pins;
+
+/// The panel's power switch, or nothing on boards where the panel is always
+/// powered.
+pub struct PanelPower(pub Option<Output<'static>>);
+
+impl ErrorType for PanelPower {
+    type Error = Infallible;
+}
+
+impl OutputPin for PanelPower {
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        if let Some(pin) = &mut self.0 {
+            pin.set_low();
+        }
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        if let Some(pin) = &mut self.0 {
+            pin.set_high();
+        }
+        Ok(())
+    }
+}
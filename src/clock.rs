@@ -0,0 +1,210 @@
+use core::cell::RefCell;
+
+use embassy_executor::Spawner;
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Timer};
+use esp_hal::rng::Rng;
+use log::{debug, info, warn};
+
+use crate::datetime::DateTime;
+use crate::timebase::Timebase;
+use crate::{rtc, sntp};
+
+const NTP_SERVER: &str = "pool.ntp.org";
+const RESYNC_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// An HTTP `Date` header is only trusted while SNTP has not succeeded for this long.
+const HTTP_FALLBACK_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Sntp,
+    Http,
+}
+
+const PERSISTED_MAGIC: u32 = 0x434c_4b31; // "CLK1"
+
+/// Clock state kept in RTC memory so that it survives deep sleep and resets.
+/// The last word is a checksum over the others.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PERSISTED: [u32; 9] = [0; 9];
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    timebase: Option<(Timebase, Source)>,
+    utc_offset_secs: i32,
+}
+
+impl State {
+    const EMPTY: Self = Self {
+        timebase: None,
+        utc_offset_secs: 0,
+    };
+
+    fn to_words(self) -> [u32; 9] {
+        let mut words = [0; 9];
+        words[0] = PERSISTED_MAGIC;
+        if let Some((timebase, source)) = self.timebase {
+            words[1] = match source {
+                Source::Sntp => 1,
+                Source::Http => 2,
+            };
+            words[2] = timebase.utc_us as u32;
+            words[3] = (timebase.utc_us >> 32) as u32;
+            words[4] = timebase.local_us as u32;
+            words[5] = (timebase.local_us >> 32) as u32;
+            words[6] = timebase.drift_ppm as u32;
+        }
+        words[7] = self.utc_offset_secs as u32;
+        words[8] = rtc::checksum(&words[..8]);
+        words
+    }
+
+    fn from_words(words: &[u32; 9]) -> Option<Self> {
+        if words[0] != PERSISTED_MAGIC || words[8] != rtc::checksum(&words[..8]) {
+            return None;
+        }
+        let source = match words[1] {
+            0 => None,
+            1 => Some(Source::Sntp),
+            2 => Some(Source::Http),
+            _ => return None,
+        };
+        Some(Self {
+            timebase: source.map(|source| {
+                let timebase = Timebase {
+                    utc_us: words[2] as u64 | (words[3] as u64) << 32,
+                    local_us: words[4] as u64 | (words[5] as u64) << 32,
+                    drift_ppm: words[6] as i32,
+                };
+                (timebase, source)
+            }),
+            utc_offset_secs: words[7] as i32,
+        })
+    }
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> =
+    Mutex::new(RefCell::new(State::EMPTY));
+
+fn update(f: impl FnOnce(&mut State)) {
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        f(&mut state);
+        // SAFETY: only ever accessed while holding the `STATE` lock.
+        unsafe { (&raw mut PERSISTED).write_volatile(state.to_words()) };
+    });
+}
+
+/// Restore the clock from RTC memory. Call once at boot, after [`rtc::init`].
+pub fn init() {
+    // SAFETY: nothing else touches `PERSISTED` before `init` returns.
+    let words = unsafe { (&raw const PERSISTED).read_volatile() };
+    let Some(mut restored) = State::from_words(&words) else {
+        info!("No valid clock state in RTC memory");
+        update(|state| *state = State::EMPTY);
+        return;
+    };
+    if let Some((timebase, _)) = restored.timebase
+        && timebase.utc_at(rtc::micros()).is_none()
+    {
+        info!("RTC counter was reset; discarding the stored time");
+        restored.timebase = None;
+    }
+    update(|state| *state = restored);
+    if let Some(now) = now() {
+        info!("Clock restored: {now}");
+    }
+}
+
+/// Current UTC in seconds since the unix epoch, if the clock was ever synced.
+pub fn now_utc() -> Option<u64> {
+    let (timebase, _) = STATE.lock(|state| state.borrow().timebase)?;
+    Some(timebase.utc_at(rtc::micros())? / 1_000_000)
+}
+
+/// Current local time, if the clock was ever synced.
+pub fn now() -> Option<DateTime> {
+    let utc = now_utc()?;
+    Some(DateTime::from_unix(utc as i64 + utc_offset() as i64))
+}
+
+/// Time since the last successful sync and where it came from.
+pub fn sync_age() -> Option<(Duration, Source)> {
+    let (timebase, source) = STATE.lock(|state| state.borrow().timebase)?;
+    let age_us = rtc::micros().checked_sub(timebase.local_us)?;
+    Some((Duration::from_micros(age_us), source))
+}
+
+pub fn utc_offset() -> i32 {
+    STATE.lock(|state| state.borrow().utc_offset_secs)
+}
+
+pub fn set_utc_offset(secs: i32) {
+    if secs != utc_offset() {
+        info!("Setting UTC offset to {secs} seconds");
+        update(|state| state.utc_offset_secs = secs);
+    }
+}
+
+fn apply_sync(utc_us: u64, source: Source) {
+    let local_us = rtc::micros();
+    update(|state| {
+        let timebase = match state.timebase {
+            Some((mut timebase, previous)) => {
+                // Drift is only learned between two SNTP samples; the one second
+                // resolution of HTTP dates is too coarse for it.
+                let estimate_drift = source == Source::Sntp && previous == Source::Sntp;
+                timebase.resync(utc_us, local_us, estimate_drift);
+                timebase
+            }
+            None => Timebase::new(utc_us, local_us),
+        };
+        debug!("Clock drift estimate: {} ppm", timebase.drift_ppm);
+        state.timebase = Some((timebase, source));
+    });
+}
+
+/// Feed the `Date` header of an HTTP response into the clock.
+///
+/// Only used while SNTP has not succeeded recently.
+pub fn observe_http_date(utc_secs: u64) {
+    let trusted = matches!(
+        sync_age(),
+        Some((age, Source::Sntp)) if age < HTTP_FALLBACK_AGE
+    );
+    if !trusted {
+        debug!("Setting clock from HTTP date header");
+        apply_sync(utc_secs * 1_000_000, Source::Http);
+    }
+}
+
+pub fn start(spawner: &Spawner, stack: Stack<'static>) {
+    spawner.spawn(sync_runner(stack).unwrap());
+}
+
+#[embassy_executor::task]
+async fn sync_runner(stack: Stack<'static>) {
+    let rng = Rng::new();
+    loop {
+        if let Some((age, Source::Sntp)) = sync_age()
+            && age < RESYNC_INTERVAL
+        {
+            Timer::after(RESYNC_INTERVAL - age).await;
+        }
+        let nonce = (rng.random() as u64) << 32 | rng.random() as u64;
+        match sntp::query(stack, NTP_SERVER, nonce).await {
+            Ok(utc_us) => {
+                apply_sync(utc_us, Source::Sntp);
+                if let Some(now) = now() {
+                    info!("Clock synchronized: {now}");
+                }
+            }
+            Err(e) => {
+                warn!("SNTP sync failed: {e}");
+                Timer::after(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
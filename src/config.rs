@@ -0,0 +1,343 @@
+use core::cell::RefCell;
+use core::fmt::{Display, Write as _};
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embedded_config::prelude::embed_config_value;
+use esp_bootloader_esp_idf::partitions;
+use esp_hal::peripherals::FLASH;
+use esp_storage::FlashStorage;
+use log::{debug, info, warn};
+use static_cell::StaticCell;
+
+use crate::overlay::{self, BarSettings};
+use crate::policy::{self, Policy};
+use crate::render::{self, DisplaySettings};
+use crate::settings::{self, SettingError};
+use crate::store::{self, Store};
+
+/// Label of the flash partition holding the store, see `partitions.csv`.
+const PARTITION: &str = "config";
+
+/// Settings that can change without rebuilding the firmware. What
+/// `build_cfg.toml` has is the default for whatever the store does not.
+pub struct Config {
+    pub wifi_ssid: heapless::String<32>,
+    pub wifi_password: heapless::String<64>,
+    /// Base URL of the server, without a trailing slash.
+    pub server: heapless::String<96>,
+    /// Sent as the `Access-Token` header.
+    pub device_id: heapless::String<64>,
+    /// Timeouts and retry intervals, before the server's overrides. Only
+    /// ever stored, never built in.
+    pub policy: Policy,
+    /// Device state drawn over fetched images. Only ever stored, never built
+    /// in.
+    pub status_bar: BarSettings,
+    /// How the panel is mounted and driven. Only ever stored, never built in.
+    pub display: DisplaySettings,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnknownKey,
+    TooLong,
+    Policy(policy::Error),
+    /// A status bar or display setting with an invalid value.
+    Setting(SettingError),
+    /// The flash has no config partition, or it could not be read.
+    NoStore,
+    Store(store::Error),
+}
+
+impl From<store::Error> for Error {
+    fn from(e: store::Error) -> Self {
+        Self::Store(e)
+    }
+}
+
+impl From<policy::Error> for Error {
+    fn from(e: policy::Error) -> Self {
+        match e {
+            policy::Error::UnknownName => Self::UnknownKey,
+            e => Self::Policy(e),
+        }
+    }
+}
+
+impl From<SettingError> for Error {
+    fn from(e: SettingError) -> Self {
+        match e {
+            SettingError::UnknownName => Self::UnknownKey,
+            e => Self::Setting(e),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::UnknownKey => write!(f, "unknown config key"),
+            Error::TooLong => write!(f, "value too long"),
+            Error::Policy(e) => write!(f, "{e}"),
+            Error::Setting(e) => write!(f, "{e}"),
+            Error::NoStore => write!(f, "no config store"),
+            Error::Store(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// A setting as [`Config::get`] returns it.
+pub enum Value<'a> {
+    Text(&'a str),
+    Secs(u32),
+    Count(u32),
+}
+
+impl From<settings::Value> for Value<'_> {
+    fn from(value: settings::Value) -> Self {
+        match value {
+            settings::Value::Name(name) => Value::Text(name),
+            settings::Value::Count(count) => Value::Count(count),
+        }
+    }
+}
+
+impl Display for Value<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Value::Text(text) => f.write_str(text),
+            Value::Secs(secs) => write!(f, "{secs}"),
+            Value::Count(count) => write!(f, "{count}"),
+        }
+    }
+}
+
+/// Keys in the store, named as in `build_cfg.toml`. The [`policy`] ones
+/// follow as `policy.` and their name, the status bar ones as
+/// `overlay.status_bar.` and theirs, the [`render`] ones as `display.` and
+/// theirs.
+const KEYS: [&str; 4] = [
+    "wifi.ssid",
+    "wifi.password",
+    "trmnl.address",
+    "trmnl.device_id",
+];
+const STATUS_BAR: &str = "overlay.status_bar.";
+const DISPLAY: &str = "display.";
+
+impl Config {
+    /// The values built into the firmware.
+    fn defaults() -> Self {
+        let mut config = Self {
+            wifi_ssid: heapless::String::new(),
+            wifi_password: heapless::String::new(),
+            server: heapless::String::new(),
+            device_id: heapless::String::new(),
+            policy: Policy::default(),
+            status_bar: BarSettings::default(),
+            display: DisplaySettings::default(),
+        };
+        let defaults = [
+            embed_config_value!("wifi.ssid"),
+            embed_config_value!("wifi.password"),
+            embed_config_value!("trmnl.address"),
+            embed_config_value!("trmnl.device_id"),
+        ];
+        for (key, value) in KEYS.into_iter().zip(defaults) {
+            if let Err(e) = config.set(key, value) {
+                warn!("Built-in {key} is unusable: {e}");
+            }
+        }
+        config
+    }
+
+    /// Change the setting `key` to `value`, in memory only.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        fn assign<const N: usize>(
+            field: &mut heapless::String<N>,
+            value: &str,
+        ) -> Result<(), Error> {
+            *field = heapless::String::try_from(value).map_err(|_| Error::TooLong)?;
+            Ok(())
+        }
+        match key {
+            "wifi.ssid" => assign(&mut self.wifi_ssid, value),
+            "wifi.password" => assign(&mut self.wifi_password, value),
+            "trmnl.address" => assign(&mut self.server, value.trim_end_matches('/')),
+            "trmnl.device_id" => assign(&mut self.device_id, value),
+            _ => match (
+                key.strip_prefix("policy."),
+                key.strip_prefix(STATUS_BAR),
+                key.strip_prefix(DISPLAY),
+            ) {
+                (Some(name), _, _) => Ok(self.policy.set(name, value)?),
+                (_, Some(name), _) => Ok(self.status_bar.set(name, value)?),
+                (_, _, Some(name)) => Ok(self.display.set(name, value)?),
+                _ => Err(Error::UnknownKey),
+            },
+        }
+    }
+
+    /// The setting `key`.
+    pub fn get(&self, key: &str) -> Result<Value<'_>, Error> {
+        match key {
+            "wifi.ssid" => Ok(Value::Text(&self.wifi_ssid)),
+            "wifi.password" => Ok(Value::Text(&self.wifi_password)),
+            "trmnl.address" => Ok(Value::Text(&self.server)),
+            "trmnl.device_id" => Ok(Value::Text(&self.device_id)),
+            _ => match (
+                key.strip_prefix("policy."),
+                key.strip_prefix(STATUS_BAR),
+                key.strip_prefix(DISPLAY),
+            ) {
+                (Some(name), _, _) => Ok(Value::Secs(self.policy.get(name)?)),
+                (_, Some(name), _) => Ok(self.status_bar.get(name)?.into()),
+                (_, _, Some(name)) => Ok(self.display.get(name)?.into()),
+                _ => Err(Error::UnknownKey),
+            },
+        }
+    }
+
+    /// Override the defaults with what the store has.
+    fn load<F: embedded_storage::nor_flash::NorFlash>(&mut self, store: &mut Store<F>) {
+        let mut buf = [0; store::MAX_VALUE];
+        let mut prefixed_key = heapless::String::<32>::new();
+        let policy_keys = policy::NAMES.map(|name| ("policy.", name));
+        let status_bar_keys = overlay::SETTINGS.map(|name| (STATUS_BAR, name));
+        let display_keys = render::SETTINGS.map(|name| (DISPLAY, name));
+        let keys = policy_keys
+            .into_iter()
+            .chain(status_bar_keys)
+            .chain(display_keys);
+        for (prefix, name) in keys {
+            prefixed_key.clear();
+            // Safe to unwrap: the names are short.
+            write!(prefixed_key, "{prefix}{name}").unwrap();
+            self.load_key(store, &prefixed_key, &mut buf);
+        }
+        for key in KEYS {
+            self.load_key(store, key, &mut buf);
+        }
+    }
+
+    fn load_key<F: embedded_storage::nor_flash::NorFlash>(
+        &mut self,
+        store: &mut Store<F>,
+        key: &str,
+        buf: &mut [u8; store::MAX_VALUE],
+    ) {
+        let value = match store.get(key, buf) {
+            Ok(Some(value)) => value,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Cannot read {key} from the config store: {e}");
+                return;
+            }
+        };
+        let Ok(value) = core::str::from_utf8(value) else {
+            warn!("Stored {key} is not UTF-8, using the default");
+            return;
+        };
+        match self.set(key, value) {
+            Ok(()) => debug!("Using the stored {key}"),
+            Err(e) => warn!("Stored {key} is unusable, using the default: {e}"),
+        }
+    }
+
+    /// `path` on the server.
+    pub fn url(&self, path: &str) -> heapless::String<128> {
+        let mut url = heapless::String::new();
+        // Safe to unwrap: the server address leaves room for the API paths.
+        write!(url, "{}/{path}", self.server).unwrap();
+        url
+    }
+}
+
+type Flash = &'static mut FlashStorage<'static>;
+
+static FLASH_STORAGE: StaticCell<FlashStorage<'static>> = StaticCell::new();
+static CONFIG: StaticCell<Config> = StaticCell::new();
+/// Kept open for changing settings at runtime; they take effect on the next
+/// boot.
+static STORE: Mutex<CriticalSectionRawMutex, RefCell<Option<Store<Flash>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Load the configuration, from the store where it has values and from
+/// `build_cfg.toml` otherwise.
+pub fn init(flash: FLASH<'static>) -> &'static Config {
+    let config = CONFIG.init_with(Config::defaults);
+    match open(FLASH_STORAGE.init(FlashStorage::new(flash))) {
+        Ok(mut store) => {
+            config.load(&mut store);
+            STORE.lock(|cell| cell.replace(Some(store)));
+        }
+        Err(e) => warn!("Using the built-in config: {e}"),
+    }
+    config
+}
+
+fn open(flash: Flash) -> Result<Store<Flash>, Error> {
+    let mut table = [0; partitions::PARTITION_TABLE_MAX_LEN];
+    let partitions = partitions::read_partition_table(flash, &mut table).map_err(|e| {
+        debug!("Discarding partition table error details: {e:?}");
+        Error::NoStore
+    })?;
+    let partition = partitions
+        .iter()
+        .find(|partition| partition.label_as_str() == PARTITION)
+        .ok_or(Error::NoStore)?;
+    info!(
+        "Config partition at {:#x}, {} bytes",
+        partition.offset(),
+        partition.len()
+    );
+    Ok(Store::open(flash, partition.offset(), partition.len())?)
+}
+
+/// Store `value` for `key`, to be used from the next boot on.
+pub fn save(key: &str, value: &str) -> Result<(), Error> {
+    // Refuse what the config could not hold.
+    Config::defaults().set(key, value)?;
+    STORE.lock(|cell| {
+        let mut store = cell.borrow_mut();
+        let store = store.as_mut().ok_or(Error::NoStore)?;
+        Ok(store.set(key, value.as_bytes())?)
+    })
+}
+
+/// The value stored for `key`, if any, as it will be used from the next boot
+/// on. Values [`init`] would not use either count as not stored.
+pub fn stored<const N: usize>(key: &str) -> Result<Option<heapless::String<N>>, Error> {
+    Config::defaults().get(key)?;
+    let mut buf = [0; store::MAX_VALUE];
+    STORE.lock(|cell| {
+        let mut store = cell.borrow_mut();
+        let store = store.as_mut().ok_or(Error::NoStore)?;
+        let Some(value) = store.get(key, &mut buf)? else {
+            return Ok(None);
+        };
+        let value = core::str::from_utf8(value).ok();
+        Ok(value.and_then(|value| heapless::String::try_from(value).ok()))
+    })
+}
+
+/// Forget the stored value for `key`, going back to the built-in one from the
+/// next boot on.
+pub fn remove(key: &str) -> Result<(), Error> {
+    Config::defaults().get(key)?;
+    STORE.lock(|cell| {
+        let mut store = cell.borrow_mut();
+        let store = store.as_mut().ok_or(Error::NoStore)?;
+        Ok(store.remove(key)?)
+    })
+}
+
+/// Forget every stored setting, going back to the built-in ones from the next
+/// boot on.
+pub fn reset() -> Result<(), Error> {
+    STORE.lock(|cell| {
+        let mut store = cell.borrow_mut();
+        let store = store.as_mut().ok_or(Error::NoStore)?;
+        Ok(store.clear()?)
+    })
+}
@@ -0,0 +1,252 @@
+use core::cell::Cell;
+use core::fmt::{Display, Write};
+
+use embassy_executor::Spawner;
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Instant, Timer};
+use embedded_io_async::Read as _;
+use esp_hal::peripherals::USB_DEVICE;
+use esp_hal::usb_serial_jtag::UsbSerialJtag;
+use log::{debug, info, warn};
+
+use crate::config::{self, Config};
+use crate::pipeline::{self, Job};
+use crate::shell::{self, Setting};
+use crate::{battery, clock, logbuf, onscreen, safemode, wifi};
+
+const PROMPT: &str = "> ";
+
+/// Cuts the sleep of the fetch task short, for `refresh`.
+pub static REFRESH: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The network stack, once boot has finished and images are being fetched.
+static ONLINE: Mutex<CriticalSectionRawMutex, Cell<Option<Stack<'static>>>> =
+    Mutex::new(Cell::new(None));
+
+/// Serve the shell on the USB serial port, next to the log.
+pub fn start(spawner: &Spawner, usb: USB_DEVICE<'static>, config: &'static Config) {
+    spawner.spawn(console(usb, config).unwrap());
+}
+
+/// Let the commands that need the network and the fetch and display tasks use
+/// `stack`.
+pub fn online(stack: Stack<'static>) {
+    ONLINE.lock(|online| online.set(Some(stack)));
+}
+
+fn stack() -> Option<Stack<'static>> {
+    ONLINE.lock(Cell::get)
+}
+
+#[embassy_executor::task]
+async fn console(usb: USB_DEVICE<'static>, config: &'static Config) {
+    let (mut rx, _tx) = UsbSerialJtag::new(usb).into_async().split();
+    let mut device = Console { config };
+    let mut line = heapless::String::<{ shell::MAX_LINE }>::new();
+    let mut buf = [0; 64];
+    let mut after_cr = false;
+    info!("Serial console ready, type \"help\".");
+    loop {
+        let len = match rx.read(&mut buf).await {
+            Ok(len) => len,
+            Err(e) => {
+                debug!("Discarding serial read error details: {e:?}");
+                continue;
+            }
+        };
+        for &byte in &buf[..len] {
+            match byte {
+                // A CR LF pair ends one line, not two.
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    esp_println::println!();
+                    let mut out = Output::default();
+                    let _ = shell::execute(&line, &mut device, &mut out).await;
+                    out.flush();
+                    line.clear();
+                    esp_println::print!("{PROMPT}");
+                }
+                // Backspace and delete.
+                0x08 | 0x7f => {
+                    if line.pop().is_some() {
+                        esp_println::print!("\x08 \x08");
+                    }
+                }
+                b' '..=b'~' => {
+                    if line.push(byte as char).is_ok() {
+                        esp_println::print!("{}", byte as char);
+                    }
+                }
+                // Escape sequences and anything beyond ASCII.
+                _ => {}
+            }
+            after_cr = byte == b'\r';
+        }
+    }
+}
+
+/// Prints what the shell writes line by line, so that log lines from other
+/// tasks go between them rather than into them.
+#[derive(Default)]
+struct Output {
+    line: heapless::String<{ shell::MAX_LINE }>,
+}
+
+impl Output {
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            esp_println::println!("{}", self.line);
+            self.line.clear();
+        }
+    }
+}
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            if c == '\n' {
+                self.flush();
+            } else if self.line.push(c).is_err() {
+                self.flush();
+                // Safe to unwrap: the line is empty now.
+                self.line.push(c).unwrap();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Error {
+    /// Wi-Fi is not connected, or the fetch and display tasks are not running.
+    Offline,
+    ScanFailed,
+    Config(config::Error),
+}
+
+impl From<config::Error> for Error {
+    fn from(e: config::Error) -> Self {
+        Self::Config(e)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Offline => write!(f, "not online"),
+            Error::ScanFailed => write!(f, "no scan result"),
+            Error::Config(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// The device as the shell sees it.
+struct Console {
+    config: &'static Config,
+}
+
+impl shell::Device for Console {
+    type Error = Error;
+
+    fn status(&mut self) -> shell::Status {
+        shell::Status {
+            firmware: crate::FIRMWARE_VERSION,
+            uptime_secs: Instant::now().as_secs(),
+            mac: esp_hal::efuse::Efuse::mac_address(),
+            ssid: self.config.wifi_ssid.clone(),
+            ip: stack()
+                .and_then(|stack| stack.config_v4())
+                .map(|config| config.address.address()),
+            rssi: wifi::rssi(),
+            battery_mv: battery::last_millivolts(),
+            time: clock::now(),
+            failed_boots: safemode::failed_attempts(),
+            frame_hash: onscreen::frame_hash(),
+        }
+    }
+
+    fn refresh(&mut self) -> Result<(), Error> {
+        stack().ok_or(Error::Offline)?;
+        REFRESH.signal(());
+        Ok(())
+    }
+
+    async fn reboot(&mut self) {
+        warn!("Rebooting on request from the serial console.");
+        // Let the output go out.
+        Timer::after_millis(100).await;
+        esp_hal::system::software_reset()
+    }
+
+    fn config_get(&mut self, key: &str) -> Result<Setting, Error> {
+        let mut running = heapless::String::new();
+        // Every setting fits a shell value.
+        let _ = write!(running, "{}", self.config.get(key)?);
+        let stored = match config::stored(key) {
+            Ok(stored) => stored,
+            Err(config::Error::NoStore) => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Setting { running, stored })
+    }
+
+    fn config_set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        config::save(key, value)?;
+        info!("Stored {key} from the serial console.");
+        Ok(())
+    }
+
+    fn config_unset(&mut self, key: &str) -> Result<(), Error> {
+        config::remove(key)?;
+        info!("Unset {key} from the serial console.");
+        Ok(())
+    }
+
+    async fn wifi_scan(&mut self, found: &mut dyn FnMut(&str, i8, bool)) -> Result<(), Error> {
+        stack().ok_or(Error::Offline)?;
+        let networks = wifi::scan_connected().await.ok_or(Error::ScanFailed)?;
+        for network in &networks {
+            found(&network.ssid, network.rssi, network.open);
+        }
+        Ok(())
+    }
+
+    fn log_tail(&mut self, lines: usize, line: &mut dyn FnMut(&str)) {
+        logbuf::tail(lines, line);
+    }
+
+    async fn screen_test(&mut self) -> Result<(), Error> {
+        let stack = stack().ok_or(Error::Offline)?;
+        let ip = stack.config_v4().map(|config| config.address.address());
+        pipeline::submit(Job::TestPattern { ip }).await;
+        Ok(())
+    }
+
+    async fn screenshot(&mut self) -> Result<(), Error> {
+        stack().ok_or(Error::Offline)?;
+        pipeline::submit(Job::PrintScreenshot).await;
+        Ok(())
+    }
+
+    fn ota(&mut self, out: &mut dyn Write) -> core::fmt::Result {
+        writeln!(
+            out,
+            "firmware {}, in the factory app partition",
+            crate::FIRMWARE_VERSION
+        )?;
+        writeln!(
+            out,
+            "the partition table has no OTA slots; flash updates over USB:"
+        )?;
+        writeln!(out, "  cargo run --release")
+    }
+
+    fn factory_reset(&mut self) -> Result<(), Error> {
+        config::reset()?;
+        safemode::reset();
+        warn!("Settings erased from the serial console.");
+        Ok(())
+    }
+}
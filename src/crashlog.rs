@@ -0,0 +1,238 @@
+use core::fmt::{Display, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use log::warn;
+
+use crate::rtc;
+
+const MAX_FRAMES: usize = 16;
+const MESSAGE_LEN: usize = 128;
+const FILE_LEN: usize = 64;
+const RECORD_MAGIC: u32 = 0x5041_4e31; // "PAN1"
+
+/// Stack frames must lie in the HP SRAM of the ESP32-C6.
+const RAM_START: u32 = 0x4080_0000;
+const RAM_END: u32 = 0x4088_0000;
+
+/// A panic as stored in RTC memory.
+///
+/// Only `u32` and `[u8; 4n]` fields so that there is no padding and every bit
+/// pattern is valid; the checksum tells a real record from leftover garbage.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CrashRecord {
+    magic: u32,
+    uptime_ms: u32,
+    line: u32,
+    column: u32,
+    frame_count: u32,
+    frames: [u32; MAX_FRAMES],
+    message_len: u32,
+    message: [u8; MESSAGE_LEN],
+    file_len: u32,
+    file: [u8; FILE_LEN],
+    checksum: u32,
+}
+
+// SAFETY: see the type documentation.
+unsafe impl esp_hal::Persistable for CrashRecord {}
+
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut RECORD: CrashRecord = CrashRecord::EMPTY;
+
+impl CrashRecord {
+    const EMPTY: Self = Self {
+        magic: 0,
+        uptime_ms: 0,
+        line: 0,
+        column: 0,
+        frame_count: 0,
+        frames: [0; MAX_FRAMES],
+        message_len: 0,
+        message: [0; MESSAGE_LEN],
+        file_len: 0,
+        file: [0; FILE_LEN],
+        checksum: 0,
+    };
+
+    fn capture(info: &PanicInfo<'_>) -> Self {
+        let mut record = Self::EMPTY;
+        record.magic = RECORD_MAGIC;
+        record.uptime_ms = esp_hal::time::Instant::now()
+            .duration_since_epoch()
+            .as_millis() as u32;
+
+        let mut message = Truncating::new(&mut record.message);
+        let _ = write!(message, "{}", info.message());
+        record.message_len = message.len as u32;
+
+        if let Some(location) = info.location() {
+            // Keep the tail of long paths; it is the part that identifies the file.
+            let file = location.file().as_bytes();
+            let file = &file[file.len().saturating_sub(FILE_LEN)..];
+            record.file[..file.len()].copy_from_slice(file);
+            record.file_len = file.len() as u32;
+            record.line = location.line();
+            record.column = location.column();
+        }
+
+        record.frame_count = capture_backtrace(&mut record.frames) as u32;
+        record.checksum = record.compute_checksum();
+        record
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        const WORDS: usize = size_of::<CrashRecord>() / 4;
+        // SAFETY: `CrashRecord` is `repr(C)` without padding and its size is a
+        // multiple of four, so it can be viewed as a slice of words.
+        let words =
+            unsafe { core::slice::from_raw_parts(self as *const Self as *const u32, WORDS) };
+        rtc::checksum(&words[..WORDS - 1])
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == RECORD_MAGIC
+            && self.checksum == self.compute_checksum()
+            && self.message_len as usize <= MESSAGE_LEN
+            && self.file_len as usize <= FILE_LEN
+            && self.frame_count as usize <= MAX_FRAMES
+    }
+
+    pub fn message(&self) -> &str {
+        str_from(&self.message[..self.message_len as usize])
+    }
+
+    pub fn file(&self) -> &str {
+        str_from(&self.file[..self.file_len as usize])
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn frames(&self) -> &[u32] {
+        &self.frames[..self.frame_count as usize]
+    }
+}
+
+impl Display for CrashRecord {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "panic after {} ms at {}:{}:{}: {}; backtrace:",
+            self.uptime_ms,
+            self.file(),
+            self.line,
+            self.column,
+            self.message()
+        )?;
+        for frame in self.frames() {
+            write!(f, " {frame:#010x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Truncation may have split a multi-byte character; drop the broken tail.
+fn str_from(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+    }
+}
+
+/// A `fmt::Write` into a fixed buffer that silently drops what doesn't fit.
+struct Truncating<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Truncating<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Walks the frame pointer chain (the build forces frame pointers) and
+/// stores the return addresses. Returns the number of frames captured.
+fn capture_backtrace(frames: &mut [u32]) -> usize {
+    let mut fp: u32;
+    // SAFETY: reading s0 has no side effects.
+    unsafe { core::arch::asm!("mv {0}, s0", out(reg) fp) };
+
+    let mut count = 0;
+    while count < frames.len() && fp.is_multiple_of(4) && (RAM_START + 8..RAM_END).contains(&fp) {
+        // SAFETY: `fp` was checked to point into RAM. With frame pointers the
+        // return address is saved right below it and the caller's frame
+        // pointer below that.
+        let (ra, next) = unsafe {
+            let ptr = fp as *const u32;
+            (
+                ptr.offset(-1).read_volatile(),
+                ptr.offset(-2).read_volatile(),
+            )
+        };
+        if ra == 0 {
+            break;
+        }
+        // `ra` points after the call instruction; report the call itself.
+        frames[count] = ra.saturating_sub(4);
+        count += 1;
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    count
+}
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+#[panic_handler]
+fn panic(info: &PanicInfo<'_>) -> ! {
+    // A panic while recording the panic must not clobber the first record.
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        let record = CrashRecord::capture(info);
+        // SAFETY: nothing else runs once we are panicking.
+        unsafe { (&raw mut RECORD).write_volatile(record) };
+
+        esp_println::println!("\n====================== PANIC ======================");
+        esp_println::println!("{info}");
+        esp_println::println!("Backtrace:");
+        for frame in record.frames() {
+            esp_println::println!("{frame:#010x}");
+        }
+    }
+    esp_hal::system::software_reset()
+}
+
+/// The crash left behind by the previous run, if any. Stays available until
+/// [`clear`] is called.
+pub fn pending() -> Option<CrashRecord> {
+    // SAFETY: only written by the panic handler, after which we reset.
+    let record = unsafe { (&raw const RECORD).read_volatile() };
+    record.is_valid().then_some(record)
+}
+
+/// Forget the recorded crash once it has been reported.
+pub fn clear() {
+    // SAFETY: see `pending`.
+    unsafe { (&raw mut RECORD).write_volatile(CrashRecord::EMPTY) };
+}
+
+/// Log a crash left behind by the previous run. Call once at boot.
+pub fn init() {
+    if let Some(record) = pending() {
+        warn!("Recovered from a {record}");
+    }
+}
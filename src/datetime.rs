@@ -0,0 +1,171 @@
+use core::fmt::Display;
+
+/// A civil date and time, without any notion of time zones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    pub fn from_unix(secs: i64) -> Self {
+        let days = secs.div_euclid(86_400);
+        let rem = secs.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    pub fn to_unix(self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * 86_400
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Both conversions follow Howard Hinnant's `chrono`-compatible date algorithms.
+fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let year = year as i64 - if month <= 2 { 1 } else { 0 };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as i32, month, day)
+}
+
+/// Parses an RFC 9110 IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`
+/// into seconds since the unix epoch.
+pub fn parse_http_date(value: &[u8]) -> Option<u64> {
+    const DAYS: [&[u8]; 7] = [b"Mon", b"Tue", b"Wed", b"Thu", b"Fri", b"Sat", b"Sun"];
+    const MONTHS: [&[u8]; 12] = [
+        b"Jan", b"Feb", b"Mar", b"Apr", b"May", b"Jun", b"Jul", b"Aug", b"Sep", b"Oct", b"Nov",
+        b"Dec",
+    ];
+    fn number(digits: &[u8]) -> Option<u32> {
+        digits.iter().try_fold(0u32, |acc, &d| {
+            d.is_ascii_digit().then(|| acc * 10 + (d - b'0') as u32)
+        })
+    }
+
+    // "Sun, 06 Nov 1994 08:49:37 GMT" is fixed width.
+    if value.len() != 29 || &value[3..5] != b", " || &value[25..] != b" GMT" {
+        return None;
+    }
+    if !DAYS.contains(&&value[..3]) {
+        return None;
+    }
+    let day = number(&value[5..7])? as u8;
+    let month = MONTHS.iter().position(|m| *m == &value[8..11])? as u8 + 1;
+    let year = number(&value[12..16])? as i32;
+    let hour = number(&value[17..19])? as u8;
+    let minute = number(&value[20..22])? as u8;
+    let second = number(&value[23..25])? as u8;
+    let separators_ok =
+        [7, 11, 16].iter().all(|&i| value[i] == b' ') && value[19] == b':' && value[22] == b':';
+    if !separators_ok || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    // Days past the end of the month would roll over into the next one.
+    if civil_from_days(days_from_civil(year, month, day)) != (year, month, day) {
+        return None;
+    }
+    let secs = DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+    .to_unix();
+    u64::try_from(secs).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_imf_fixdate() {
+        assert_eq!(
+            parse_http_date(b"Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784111777)
+        );
+        assert_eq!(
+            parse_http_date(b"Thu, 29 Feb 2024 23:59:60 GMT"),
+            Some(1709251200)
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_names() {
+        assert_eq!(parse_http_date(b"Sux, 06 Nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date(b"sun, 06 Nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date(b"Sun, 06 Nox 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date(b"Sun, 06 nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date(b"Sun, 06 Nov 1994 08:49:37 UTC"), None);
+    }
+
+    #[test]
+    fn refuses_fields_out_of_range() {
+        for date in [
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 31 Nov 1994 08:49:37 GMT",
+            "Sun, 29 Feb 2023 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:49:37 GMT",
+            "Sun, 06 Nov 1994 08:60:37 GMT",
+            "Sun, 06 Nov 1994 08:49:61 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Sun, 0x Nov 1994 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(date.as_bytes()), None, "{date}");
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_dates() {
+        let date = b"Sun, 06 Nov 1994 08:49:37 GMT";
+        for len in 0..date.len() {
+            assert_eq!(parse_http_date(&date[..len]), None);
+        }
+        assert_eq!(parse_http_date(b"Sun, 6 Nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date(b"Sun, 06 Nov 1994 08:49:37 GMT "), None);
+        assert_eq!(parse_http_date(b"Sun, 06-Nov-1994 08:49:37 GMT"), None);
+    }
+}
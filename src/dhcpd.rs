@@ -0,0 +1,247 @@
+use core::net::Ipv4Addr;
+
+use embassy_net::{
+    IpEndpoint, Stack,
+    udp::{self, PacketMetadata, UdpSocket},
+};
+use log::{debug, info, warn};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+/// Room for the largest message a client sends without negotiating more.
+const PACKET_LEN: usize = 576;
+/// Replies are padded to the minimum BOOTP message size, which some clients
+/// insist on.
+const REPLY_LEN: usize = 300;
+const LEASE_SECS: u32 = 60 * 60;
+/// Clients served at once; the oldest lease goes to a new client after that.
+const MAX_LEASES: usize = 8;
+/// Host part of the first address handed out.
+const FIRST_HOST: u8 = 2;
+/// Where the options start, after the fixed fields and the magic cookie.
+const OPTIONS: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+/// Hand out addresses on the /24 network of `address`, naming `address` as
+/// router and DNS server. Only returns if the port cannot be bound.
+pub async fn serve(stack: Stack<'_>, address: Ipv4Addr) -> Result<(), udp::BindError> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buf = [0; 2 * PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buf = [0; 2 * REPLY_LEN];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    socket.bind(SERVER_PORT)?;
+
+    let mut leases = Leases::new(address);
+    let mut packet = [0; PACKET_LEN];
+    let mut reply = [0; REPLY_LEN];
+    loop {
+        let len = match socket.recv_from(&mut packet).await {
+            Ok((len, _)) => len,
+            Err(e) => {
+                warn!("DHCP receive failed: {e:?}");
+                continue;
+            }
+        };
+        let Some(request) = Request::parse(&packet[..len]) else {
+            debug!("Ignoring a malformed DHCP message");
+            continue;
+        };
+        let Some((kind, offered)) = leases.answer(&request) else {
+            continue;
+        };
+        request.reply(kind, offered, address, &mut reply);
+        // The client has no address yet to send to.
+        let broadcast = IpEndpoint::new(Ipv4Addr::BROADCAST.into(), CLIENT_PORT);
+        if let Err(e) = socket.send_to(&reply, broadcast).await {
+            warn!("DHCP reply failed: {e:?}");
+        }
+    }
+}
+
+/// The parts of a client message the server looks at.
+struct Request<'a> {
+    /// The fixed fields, echoed back in the reply.
+    header: &'a [u8],
+    mac: [u8; 6],
+    kind: u8,
+    /// What the client asks for, from the options or its current address.
+    requested: Option<Ipv4Addr>,
+    server: Option<Ipv4Addr>,
+}
+
+impl<'a> Request<'a> {
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        // A request from an Ethernet-like client with a 6 byte address.
+        if packet.len() < OPTIONS || packet[0] != 1 || packet[1] != 1 || packet[2] != 6 {
+            return None;
+        }
+        if packet[236..OPTIONS] != MAGIC_COOKIE {
+            return None;
+        }
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&packet[28..34]);
+        let client = ipv4(&packet[12..16]).filter(|ip| !ip.is_unspecified());
+        let mut request = Self {
+            header: &packet[..OPTIONS],
+            mac,
+            kind: 0,
+            requested: client,
+            server: None,
+        };
+
+        let mut options = &packet[OPTIONS..];
+        while let [code, rest @ ..] = options {
+            match *code {
+                OPTION_END => break,
+                OPTION_PAD => {
+                    options = rest;
+                    continue;
+                }
+                _ => {}
+            }
+            let (&len, rest) = rest.split_first()?;
+            let value = rest.get(..len as usize)?;
+            match *code {
+                OPTION_MESSAGE_TYPE => request.kind = *value.first()?,
+                OPTION_REQUESTED_IP => request.requested = ipv4(value),
+                OPTION_SERVER_ID => request.server = ipv4(value),
+                _ => {}
+            }
+            options = &rest[len as usize..];
+        }
+        Some(request)
+    }
+
+    /// Write the reply of type `kind` offering `offered` into `reply`.
+    fn reply(&self, kind: u8, offered: Ipv4Addr, server: Ipv4Addr, reply: &mut [u8; REPLY_LEN]) {
+        reply.fill(0);
+        reply[..OPTIONS].copy_from_slice(self.header);
+        reply[0] = 2;
+        // Hops, and the server name and boot file. Only an ACK keeps the
+        // address the client already has.
+        reply[3] = 0;
+        if kind != ACK {
+            reply[12..16].fill(0);
+        }
+        reply[44..236].fill(0);
+        if kind != NAK {
+            reply[16..20].copy_from_slice(&offered.octets());
+            reply[20..24].copy_from_slice(&server.octets());
+        }
+
+        let mut options = Options {
+            buf: &mut reply[OPTIONS..],
+            len: 0,
+        };
+        options.push(OPTION_MESSAGE_TYPE, &[kind]);
+        options.push(OPTION_SERVER_ID, &server.octets());
+        if kind != NAK {
+            options.push(OPTION_LEASE_TIME, &LEASE_SECS.to_be_bytes());
+            options.push(OPTION_SUBNET_MASK, &[255, 255, 255, 0]);
+            options.push(OPTION_ROUTER, &server.octets());
+            options.push(OPTION_DNS, &server.octets());
+        }
+        options.push(OPTION_END, &[]);
+    }
+}
+
+struct Options<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Options<'_> {
+    fn push(&mut self, code: u8, value: &[u8]) {
+        self.buf[self.len] = code;
+        self.len += 1;
+        if code == OPTION_END {
+            return;
+        }
+        self.buf[self.len] = value.len() as u8;
+        self.buf[self.len + 1..][..value.len()].copy_from_slice(value);
+        self.len += 1 + value.len();
+    }
+}
+
+fn ipv4(bytes: &[u8]) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = bytes.try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+/// Which client has which address, in a fixed pool after the server's own.
+struct Leases {
+    server: Ipv4Addr,
+    clients: [Option<[u8; 6]>; MAX_LEASES],
+    /// The lease to take from its client when the pool is used up.
+    evict: usize,
+}
+
+impl Leases {
+    fn new(server: Ipv4Addr) -> Self {
+        Self {
+            server,
+            clients: [None; MAX_LEASES],
+            evict: 0,
+        }
+    }
+
+    fn address(&self, index: usize) -> Ipv4Addr {
+        let [a, b, c, _] = self.server.octets();
+        Ipv4Addr::new(a, b, c, FIRST_HOST + index as u8)
+    }
+
+    /// The address leased to `mac`, handing out a new one if it has none.
+    fn lease(&mut self, mac: [u8; 6]) -> Ipv4Addr {
+        if let Some(index) = self.clients.iter().position(|&client| client == Some(mac)) {
+            return self.address(index);
+        }
+        let index = match self.clients.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                let index = self.evict;
+                self.evict = (self.evict + 1) % MAX_LEASES;
+                index
+            }
+        };
+        self.clients[index] = Some(mac);
+        let address = self.address(index);
+        info!("Leasing {address} to a new client");
+        address
+    }
+
+    /// The message type and address to answer `request` with, if any.
+    fn answer(&mut self, request: &Request<'_>) -> Option<(u8, Ipv4Addr)> {
+        match request.kind {
+            DISCOVER => Some((OFFER, self.lease(request.mac))),
+            REQUEST => {
+                // The client picked another server's offer.
+                if request.server.is_some_and(|id| id != self.server) {
+                    return None;
+                }
+                let leased = self.lease(request.mac);
+                match request.requested {
+                    Some(requested) if requested != leased => Some((NAK, leased)),
+                    _ => Some((ACK, leased)),
+                }
+            }
+            _ => None,
+        }
+    }
+}
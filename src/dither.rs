@@ -0,0 +1,548 @@
+use embedded_graphics::{
+    Pixel,
+    pixelcolor::{BinaryColor, Gray8, GrayColor, Rgb888},
+    prelude::*,
+    primitives::Rectangle,
+};
+use serde::Deserialize;
+
+use crate::epaper::TriColor;
+
+/// Widest row the error buffer covers. Pixels further right are thresholded.
+pub const MAX_WIDTH: usize = 800;
+
+/// How gray levels are turned into black and white, or colours into the inks
+/// of a tri-colour panel.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Method {
+    /// Hard threshold at 50 %, or the nearest ink.
+    None,
+    #[default]
+    FloydSteinberg,
+    Atkinson,
+    /// Ordered dithering with a 4×4 Bayer matrix.
+    Bayer,
+}
+
+/// Share of the error pushed to each neighbour, in units of `1 / divisor`.
+struct Kernel {
+    right: i16,
+    right2: i16,
+    below_left: i16,
+    below: i16,
+    below_right: i16,
+    divisor: i16,
+}
+
+impl Kernel {
+    const FLOYD_STEINBERG: Kernel = Kernel {
+        right: 7,
+        right2: 0,
+        below_left: 3,
+        below: 5,
+        below_right: 1,
+        divisor: 16,
+    };
+
+    /// Atkinson also pushes an eighth two rows down. With a single row of
+    /// error that share is dropped, like the quarter Atkinson always drops.
+    const ATKINSON: Kernel = Kernel {
+        right: 1,
+        right2: 1,
+        below_left: 1,
+        below: 1,
+        below_right: 1,
+        divisor: 8,
+    };
+}
+
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Inks of a tri-colour panel with the colour each stands for.
+const INKS: [(TriColor, [i16; 3]); 3] = [
+    (TriColor::White, [255, 255, 255]),
+    (TriColor::Black, [0, 0, 0]),
+    (TriColor::Red, [255, 0, 0]),
+];
+
+/// Values are clamped to this range, so that colours no ink comes close to
+/// do not pile up error without bound.
+const VALUE_RANGE: core::ops::RangeInclusive<i16> = -255..=510;
+
+/// Error diffusion state for pixels arriving in row-major order, with `N`
+/// channels per pixel.
+///
+/// `errors[x + 1]` holds the error carried into column `x`: for columns not
+/// yet reached in the current row it comes from the row above, for columns
+/// already passed it is destined for the row below.
+struct Diffusion<const N: usize> {
+    errors: [[i16; N]; MAX_WIDTH + 2],
+    /// Error for the next one and two pixels of the current row.
+    right: [i16; N],
+    right2: [i16; N],
+    /// Error for the row below, one column right of the last pixel. Its slot
+    /// in `errors` is still needed by the current row.
+    below_right: [i16; N],
+    row: i32,
+    row_start: i32,
+    next_x: i32,
+}
+
+fn add<const N: usize>(a: [i16; N], b: [i16; N]) -> [i16; N] {
+    core::array::from_fn(|i| a[i] + b[i])
+}
+
+impl<const N: usize> Diffusion<N> {
+    const fn new() -> Self {
+        Self {
+            errors: [[0; N]; MAX_WIDTH + 2],
+            right: [0; N],
+            right2: [0; N],
+            below_right: [0; N],
+            row: i32::MIN,
+            row_start: 0,
+            next_x: i32::MIN,
+        }
+    }
+
+    fn reset(&mut self, point: Point) {
+        self.errors.fill([0; N]);
+        self.right = [0; N];
+        self.right2 = [0; N];
+        self.below_right = [0; N];
+        self.row = point.y;
+        self.row_start = point.x;
+    }
+
+    /// Follow the pixel stream; anything but the next pixel or the start of
+    /// the next row begins a fresh diffusion.
+    fn advance(&mut self, point: Point) {
+        if point.y == self.row && point.x == self.next_x {
+            // Continuing the row.
+        } else if point.y == self.row + 1 && point.x == self.row_start {
+            self.end_row();
+            self.row = point.y;
+        } else {
+            self.reset(point);
+        }
+        self.next_x = point.x + 1;
+    }
+
+    fn end_row(&mut self) {
+        if let Some(slot) = self.slot(self.next_x) {
+            self.errors[slot] = add(self.errors[slot], self.below_right);
+        }
+        self.right = [0; N];
+        self.right2 = [0; N];
+        self.below_right = [0; N];
+    }
+
+    fn slot(&self, x: i32) -> Option<usize> {
+        usize::try_from(x + 1)
+            .ok()
+            .filter(|&slot| slot < self.errors.len())
+    }
+
+    /// Pick the colour for `value` with `quantize`, which also returns the
+    /// value of that colour, and spread the difference over the neighbours.
+    fn apply<C>(
+        &mut self,
+        kernel: &Kernel,
+        point: Point,
+        value: [i16; N],
+        quantize: impl Fn([i16; N]) -> (C, [i16; N]),
+    ) -> C {
+        self.advance(point);
+        let Some(slot) = self
+            .slot(point.x)
+            .filter(|slot| (1..=MAX_WIDTH).contains(slot))
+        else {
+            return quantize(value).0;
+        };
+        let value = add(add(value, self.errors[slot]), self.right)
+            .map(|channel| channel.clamp(*VALUE_RANGE.start(), *VALUE_RANGE.end()));
+        let (color, level) = quantize(value);
+        let share = |weight: i16| -> [i16; N] {
+            core::array::from_fn(|i| (value[i] - level[i]) * weight / kernel.divisor)
+        };
+
+        self.right = add(self.right2, share(kernel.right));
+        self.right2 = share(kernel.right2);
+        self.errors[slot - 1] = add(self.errors[slot - 1], share(kernel.below_left));
+        self.errors[slot] = add(self.below_right, share(kernel.below));
+        self.below_right = share(kernel.below_right);
+        color
+    }
+}
+
+fn threshold(value: i16) -> BinaryColor {
+    (value >= 128).into()
+}
+
+/// [`threshold`] for [`Diffusion::apply`].
+fn quantize_luma([value]: [i16; 1]) -> (BinaryColor, [i16; 1]) {
+    let color = threshold(value);
+    (color, [if color.is_on() { 255 } else { 0 }])
+}
+
+/// The ink closest to `value`, and the colour it stands for.
+fn nearest_ink(value: [i16; 3]) -> (TriColor, [i16; 3]) {
+    let distance = |ink: &[i16; 3]| -> i32 {
+        value
+            .iter()
+            .zip(ink)
+            .map(|(&a, &b)| (a as i32 - b as i32).pow(2))
+            .sum()
+    };
+    // Safe to unwrap: there are inks to pick from.
+    *INKS.iter().min_by_key(|(_, ink)| distance(ink)).unwrap()
+}
+
+/// How far the Bayer matrix moves the threshold at `point` below 50 %.
+fn bayer_offset(point: Point) -> i16 {
+    let threshold = BAYER_4X4[point.y.rem_euclid(4) as usize][point.x.rem_euclid(4) as usize];
+    128 - (threshold as i16 * 16 + 8)
+}
+
+fn bayer(point: Point, luma: u8) -> BinaryColor {
+    (luma as i16 + bayer_offset(point) > 128).into()
+}
+
+/// Like [`bayer`], for the inks of a tri-colour panel.
+fn bayer_ink(point: Point, value: [i16; 3]) -> TriColor {
+    let offset = bayer_offset(point);
+    nearest_ink(value.map(|channel| channel + offset)).0
+}
+
+/// Dithers gray pixels drawn into it onto a black and white target.
+///
+/// Error diffusion only keeps one row of error, so pixels have to arrive
+/// row by row, left to right, as images draw them. Out of order pixels start
+/// the diffusion over.
+pub struct Dither<'a, D> {
+    target: &'a mut D,
+    method: Method,
+    diffusion: Diffusion<1>,
+}
+
+impl<'a, D> Dither<'a, D>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    pub fn new(target: &'a mut D, method: Method) -> Self {
+        Self {
+            target,
+            method,
+            diffusion: Diffusion::new(),
+        }
+    }
+}
+
+/// Turn one pixel into black or white, updating the diffusion state.
+fn dither(method: Method, diffusion: &mut Diffusion<1>, point: Point, color: Gray8) -> BinaryColor {
+    let luma = color.luma();
+    let value = [luma as i16];
+    match method {
+        Method::None => threshold(luma as i16),
+        Method::FloydSteinberg => {
+            diffusion.apply(&Kernel::FLOYD_STEINBERG, point, value, quantize_luma)
+        }
+        Method::Atkinson => diffusion.apply(&Kernel::ATKINSON, point, value, quantize_luma),
+        Method::Bayer => bayer(point, luma),
+    }
+}
+
+impl<D> Dimensions for Dither<'_, D>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    fn bounding_box(&self) -> Rectangle {
+        self.target.bounding_box()
+    }
+}
+
+impl<D> DrawTarget for Dither<'_, D>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    type Color = Gray8;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let (method, diffusion) = (self.method, &mut self.diffusion);
+        self.target.draw_iter(
+            pixels
+                .into_iter()
+                .map(|Pixel(point, color)| Pixel(point, dither(method, diffusion, point, color))),
+        )
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        let (method, diffusion) = (self.method, &mut self.diffusion);
+        let colors = area
+            .points()
+            .zip(colors)
+            .map(|(point, color)| dither(method, diffusion, point, color));
+        self.target.fill_contiguous(area, colors)
+    }
+}
+
+/// Dithers colour pixels drawn into it onto the black, white and red inks of
+/// a tri-colour panel, with the same methods and ordering rules as [`Dither`].
+pub struct InkDither<'a, D> {
+    target: &'a mut D,
+    method: Method,
+    diffusion: Diffusion<3>,
+}
+
+impl<'a, D> InkDither<'a, D>
+where
+    D: DrawTarget<Color = TriColor>,
+{
+    pub fn new(target: &'a mut D, method: Method) -> Self {
+        Self {
+            target,
+            method,
+            diffusion: Diffusion::new(),
+        }
+    }
+}
+
+/// Turn one pixel into an ink, updating the diffusion state.
+fn dither_ink(
+    method: Method,
+    diffusion: &mut Diffusion<3>,
+    point: Point,
+    color: Rgb888,
+) -> TriColor {
+    let value = [color.r() as i16, color.g() as i16, color.b() as i16];
+    match method {
+        Method::None => nearest_ink(value).0,
+        Method::FloydSteinberg => {
+            diffusion.apply(&Kernel::FLOYD_STEINBERG, point, value, nearest_ink)
+        }
+        Method::Atkinson => diffusion.apply(&Kernel::ATKINSON, point, value, nearest_ink),
+        Method::Bayer => bayer_ink(point, value),
+    }
+}
+
+impl<D> Dimensions for InkDither<'_, D>
+where
+    D: DrawTarget<Color = TriColor>,
+{
+    fn bounding_box(&self) -> Rectangle {
+        self.target.bounding_box()
+    }
+}
+
+impl<D> DrawTarget for InkDither<'_, D>
+where
+    D: DrawTarget<Color = TriColor>,
+{
+    type Color = Rgb888;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let (method, diffusion) = (self.method, &mut self.diffusion);
+        self.target.draw_iter(
+            pixels.into_iter().map(|Pixel(point, color)| {
+                Pixel(point, dither_ink(method, diffusion, point, color))
+            }),
+        )
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        let (method, diffusion) = (self.method, &mut self.diffusion);
+        let colors = area
+            .points()
+            .zip(colors)
+            .map(|(point, color)| dither_ink(method, diffusion, point, color));
+        self.target.fill_contiguous(area, colors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use super::*;
+
+    const SIZE: Size = Size::new(64, 32);
+
+    /// Each method, and what it makes of [`gray`] and of [`color`].
+    const FIXTURES: [(Method, &str, &[u8], &[u8]); 4] = [
+        (
+            Method::None,
+            "none",
+            include_bytes!("../simulator/fixtures/dither/none.pbm"),
+            include_bytes!("../simulator/fixtures/dither/ink-none.ppm"),
+        ),
+        (
+            Method::FloydSteinberg,
+            "floyd-steinberg",
+            include_bytes!("../simulator/fixtures/dither/floyd-steinberg.pbm"),
+            include_bytes!("../simulator/fixtures/dither/ink-floyd-steinberg.ppm"),
+        ),
+        (
+            Method::Atkinson,
+            "atkinson",
+            include_bytes!("../simulator/fixtures/dither/atkinson.pbm"),
+            include_bytes!("../simulator/fixtures/dither/ink-atkinson.ppm"),
+        ),
+        (
+            Method::Bayer,
+            "bayer",
+            include_bytes!("../simulator/fixtures/dither/bayer.pbm"),
+            include_bytes!("../simulator/fixtures/dither/ink-bayer.ppm"),
+        ),
+    ];
+
+    /// The pixels drawn into it, row by row.
+    struct Canvas<C> {
+        pixels: Vec<C>,
+    }
+
+    impl<C: PixelColor> Canvas<C> {
+        fn new(blank: C) -> Self {
+            Self {
+                pixels: vec![blank; SIZE.width as usize * SIZE.height as usize],
+            }
+        }
+    }
+
+    impl<C> OriginDimensions for Canvas<C> {
+        fn size(&self) -> Size {
+            SIZE
+        }
+    }
+
+    impl<C: PixelColor> DrawTarget for Canvas<C> {
+        type Color = C;
+        type Error = Infallible;
+
+        fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Infallible>
+        where
+            I: IntoIterator<Item = Pixel<C>>,
+        {
+            for Pixel(point, color) in pixels {
+                self.pixels[point.y as usize * SIZE.width as usize + point.x as usize] = color;
+            }
+            Ok(())
+        }
+    }
+
+    /// Ramps from black to white, across in the top half and down in the
+    /// bottom half.
+    fn gray(point: Point) -> u8 {
+        let (x, y) = (point.x as u32, point.y as u32);
+        let half = SIZE.height / 2;
+        match y < half {
+            true => (x * 255 / (SIZE.width - 1)) as u8,
+            false => ((y - half) * 255 / (half - 1)) as u8,
+        }
+    }
+
+    /// Ramps through red and gray, which the inks mix into.
+    fn color(point: Point) -> Rgb888 {
+        let level = gray(point);
+        match point.x < SIZE.width as i32 / 2 {
+            true => Rgb888::new(255, level, level),
+            false => Rgb888::new(level, level / 2, level / 2),
+        }
+    }
+
+    fn pbm(canvas: &Canvas<BinaryColor>) -> Vec<u8> {
+        let mut pbm = format!("P4\n{} {}\n", SIZE.width, SIZE.height).into_bytes();
+        for row in canvas.pixels.chunks(SIZE.width as usize) {
+            for byte in row.chunks(8) {
+                // Black is 1.
+                let bits = byte.iter().map(|color| color.is_off() as u8);
+                pbm.push(bits.fold(0, |bits, bit| bits << 1 | bit));
+            }
+        }
+        pbm
+    }
+
+    fn ppm(canvas: &Canvas<TriColor>) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n1\n", SIZE.width, SIZE.height).into_bytes();
+        for color in &canvas.pixels {
+            ppm.extend_from_slice(match color {
+                TriColor::White => &[1, 1, 1],
+                TriColor::Black => &[0, 0, 0],
+                TriColor::Red => &[1, 0, 0],
+            });
+        }
+        ppm
+    }
+
+    /// Compare `actual` with the fixture `name`. On a mismatch the new image
+    /// is left in the temporary directory, to look at and copy over the
+    /// fixture if the change is meant.
+    fn check(name: &str, expected: &[u8], actual: &[u8]) {
+        if actual != expected {
+            let path = std::env::temp_dir().join(name);
+            std::fs::write(&path, actual).unwrap();
+            panic!(
+                "{name} differs from its fixture in simulator/fixtures/dither/, see {}",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn dithers_gradients_like_the_fixtures() {
+        let area = Rectangle::new(Point::zero(), SIZE);
+        for (method, name, expected, _) in FIXTURES {
+            let mut canvas = Canvas::new(BinaryColor::On);
+            let colors = area.points().map(|point| Gray8::new(gray(point)));
+            Dither::new(&mut canvas, method)
+                .fill_contiguous(&area, colors)
+                .unwrap();
+            check(&format!("{name}.pbm"), expected, &pbm(&canvas));
+        }
+    }
+
+    #[test]
+    fn dithers_colour_gradients_like_the_fixtures() {
+        let area = Rectangle::new(Point::zero(), SIZE);
+        for (method, name, _, expected) in FIXTURES {
+            let mut canvas = Canvas::new(TriColor::White);
+            let colors = area.points().map(color);
+            InkDither::new(&mut canvas, method)
+                .fill_contiguous(&area, colors)
+                .unwrap();
+            check(&format!("ink-{name}.ppm"), expected, &ppm(&canvas));
+        }
+    }
+
+    #[test]
+    fn draws_pixel_by_pixel_like_a_whole_area() {
+        let area = Rectangle::new(Point::zero(), SIZE);
+        let mut filled = Canvas::new(BinaryColor::On);
+        let colors = area.points().map(|point| Gray8::new(gray(point)));
+        Dither::new(&mut filled, Method::FloydSteinberg)
+            .fill_contiguous(&area, colors)
+            .unwrap();
+        let mut drawn = Canvas::new(BinaryColor::On);
+        let pixels = area
+            .points()
+            .map(|point| Pixel(point, Gray8::new(gray(point))));
+        Dither::new(&mut drawn, Method::FloydSteinberg)
+            .draw_iter(pixels)
+            .unwrap();
+        assert_eq!(pbm(&drawn), pbm(&filled));
+    }
+}
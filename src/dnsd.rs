@@ -0,0 +1,95 @@
+use core::net::Ipv4Addr;
+
+use embassy_net::{
+    Stack,
+    udp::{self, PacketMetadata, UdpSocket},
+};
+use log::{debug, warn};
+
+const PORT: u16 = 53;
+/// The largest message over UDP without extensions.
+const PACKET_LEN: usize = 512;
+const HEADER_LEN: usize = 12;
+/// Seconds a client may remember an answer.
+const TTL_SECS: u32 = 60;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// The answer names the question, by pointing at where it starts.
+const NAME_POINTER: [u8; 2] = [0xc0, HEADER_LEN as u8];
+
+/// Answer every address query with `address`, so that whatever a client looks
+/// up leads to the device. Only returns if the port cannot be bound.
+pub async fn serve(stack: Stack<'_>, address: Ipv4Addr) -> Result<(), udp::BindError> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buf = [0; 2 * PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buf = [0; 2 * PACKET_LEN];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    socket.bind(PORT)?;
+
+    let mut packet = [0; PACKET_LEN];
+    loop {
+        let (len, meta) = match socket.recv_from(&mut packet).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("DNS receive failed: {e:?}");
+                continue;
+            }
+        };
+        let Some(len) = answer(&mut packet, len, address) else {
+            debug!("Ignoring a DNS message that is not a simple query");
+            continue;
+        };
+        if let Err(e) = socket.send_to(&packet[..len], meta.endpoint).await {
+            warn!("DNS reply failed: {e:?}");
+        }
+    }
+}
+
+/// Turn the query of `len` bytes in `packet` into its response, returning the
+/// length of that. `None` if the message is anything but a single question.
+fn answer(packet: &mut [u8; PACKET_LEN], len: usize, address: Ipv4Addr) -> Option<usize> {
+    let query = packet.get(..len)?;
+    let header = query.get(..HEADER_LEN)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    // A query (QR = 0) with the standard opcode and exactly one question.
+    if flags & 0xf800 != 0 || header[4..6] != [0, 1] {
+        return None;
+    }
+
+    let mut end = HEADER_LEN;
+    loop {
+        let label = *query.get(end)? as usize;
+        end += 1;
+        match label {
+            0 => break,
+            1..=63 => end += label,
+            // Queries have no compressed names.
+            _ => return None,
+        }
+    }
+    let question = query.get(end..end + 4)?;
+    let kind = u16::from_be_bytes([question[0], question[1]]);
+    let class = u16::from_be_bytes([question[2], question[3]]);
+    end += 4;
+
+    let answers = matches!(kind, TYPE_A | TYPE_ANY) && class == CLASS_IN;
+    // A response, authoritative, echoing whether recursion was desired.
+    let flags = 0x8400 | (flags & 0x0100);
+    packet[2..4].copy_from_slice(&flags.to_be_bytes());
+    packet[6..8].copy_from_slice(&u16::from(answers).to_be_bytes());
+    packet[8..12].fill(0);
+    if !answers {
+        return Some(end);
+    }
+
+    let record = packet.get_mut(end..end + 16)?;
+    record[..2].copy_from_slice(&NAME_POINTER);
+    record[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+    record[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+    record[6..10].copy_from_slice(&TTL_SECS.to_be_bytes());
+    record[10..12].copy_from_slice(&4u16.to_be_bytes());
+    record[12..].copy_from_slice(&address.octets());
+    Some(end + 16)
+}
@@ -1,119 +1,472 @@
-use embedded_graphics::draw_target::DrawTarget;
-use embedded_graphics::pixelcolor::BinaryColor;
-use embedded_hal::{
-    delay::DelayNs,
-    digital::{InputPin, OutputPin},
-    spi::SpiBus,
-};
-use embedded_hal_bus::spi::ExclusiveDevice;
-use epd_waveshare::{
-    epd7in5_v2::{Display7in5, Epd7in5},
-    prelude::WaveshareDisplay,
-};
-use log::info;
-use static_cell::StaticCell;
+use core::convert::Infallible;
+use core::marker::PhantomData;
+
+use embedded_graphics::draw_target::{DrawTarget, DrawTargetExt};
+use embedded_graphics::pixelcolor::{BinaryColor, Gray2, GrayColor, PixelColor};
+use embedded_graphics::prelude::{OriginDimensions, Pixel, Size};
+use embedded_hal::digital::OutputPin;
+use log::{debug, info};
+
+use crate::panel::{ColorDepth, Frame, Panel};
+use crate::refresh::{self, Refresh};
+use crate::render;
+use crate::screenshot::Screenshot;
 
 #[derive(Debug)]
 pub enum Error {
     Pin,
+    Bus,
     InitScreen,
     WakeUp,
-    #[allow(dead_code)]
     Sleep,
     BecomingReady,
     UpdateScreen,
+    Unsupported,
 }
 
 impl core::fmt::Display for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Error::Pin => write!(f, "error setting cs/pwr pin"),
+            Error::Bus => write!(f, "error talking to the panel controller"),
             Error::InitScreen => write!(f, "could not initialize screen"),
             Error::WakeUp => write!(f, "error waking up screen"),
             Error::Sleep => write!(f, "error putting screen to sleep"),
             Error::BecomingReady => write!(f, "error waiting for screen to become ready"),
             Error::UpdateScreen => write!(f, "error updating the screen"),
+            Error::Unsupported => write!(f, "not supported by this panel"),
+        }
+    }
+}
+
+/// Colour of the blank panel.
+pub const PAPER: BinaryColor = BinaryColor::On;
+/// Colour drawn onto the blank panel.
+pub const INK: BinaryColor = BinaryColor::Off;
+
+/// The inks of a tri-colour panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriColor {
+    White,
+    Black,
+    Red,
+}
+
+impl PixelColor for TriColor {
+    type Raw = ();
+}
+
+impl From<BinaryColor> for TriColor {
+    fn from(color: BinaryColor) -> Self {
+        match color == PAPER {
+            true => TriColor::White,
+            false => TriColor::Black,
         }
     }
 }
 
-static FRAMEBUFFER: StaticCell<Display7in5> = StaticCell::new();
+/// Red is drawn as black on panels without red ink.
+impl From<TriColor> for BinaryColor {
+    fn from(color: TriColor) -> Self {
+        match color {
+            TriColor::White => PAPER,
+            TriColor::Black | TriColor::Red => INK,
+        }
+    }
+}
+
+/// A colour stored as one bit in each of the two planes of a [`PlaneFrame`].
+pub trait PlaneColor: PixelColor {
+    fn bits(self) -> [bool; 2];
+}
+
+/// White is `(1, 1)`, light gray `(1, 0)`, dark gray `(0, 1)` and black
+/// `(0, 0)`.
+impl PlaneColor for Gray2 {
+    fn bits(self) -> [bool; 2] {
+        match self.luma() {
+            3 => [true, true],
+            2 => [true, false],
+            1 => [false, true],
+            _ => [false, false],
+        }
+    }
+}
+
+/// The first plane is black and white, the second marks red: white is
+/// `(1, 0)`, black `(0, 0)` and red `(1, 1)`.
+impl PlaneColor for TriColor {
+    fn bits(self) -> [bool; 2] {
+        match self {
+            TriColor::White => [true, false],
+            TriColor::Black => [false, false],
+            TriColor::Red => [true, true],
+        }
+    }
+}
+
+/// A framebuffer with more than two colours, stored as two 1 bit per pixel
+/// planes the way the controller takes them. Each colour is a pair of bits,
+/// one from each plane.
+pub struct PlaneFrame<'a, C> {
+    planes: [&'a mut [u8]; 2],
+    size: Size,
+    _color: PhantomData<C>,
+}
+
+/// Four gray levels.
+pub type GrayFrame<'a> = PlaneFrame<'a, Gray2>;
+/// Black, white and red.
+pub type TriColorFrame<'a> = PlaneFrame<'a, TriColor>;
+
+impl<'a, C: PlaneColor> PlaneFrame<'a, C> {
+    /// A frame of `size` pixels over two planes of a 1 bit per pixel
+    /// framebuffer of that size each.
+    pub fn new(planes: [&'a mut [u8]; 2], size: Size) -> Self {
+        Self {
+            planes,
+            size,
+            _color: PhantomData,
+        }
+    }
+
+    fn set(&mut self, x: u32, y: u32, color: C) {
+        let index = (y * self.size.width.div_ceil(8) + x / 8) as usize;
+        let mask = 0x80 >> (x % 8);
+        for (plane, bit) in self.planes.iter_mut().zip(color.bits()) {
+            match bit {
+                true => plane[index] |= mask,
+                false => plane[index] &= !mask,
+            }
+        }
+    }
+}
+
+impl<C> OriginDimensions for PlaneFrame<'_, C> {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl<C: PlaneColor> DrawTarget for PlaneFrame<'_, C> {
+    type Color = C;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y))
+                && x < self.size.width
+                && y < self.size.height
+            {
+                self.set(x, y, color);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    BlackWhite,
+    Gray4,
+    TriColor,
+}
 
-pub struct Screen<SPI, CS, BUSY, DC, RST, PWR, DELAY> {
-    delay: DELAY,
+/// The two framebuffers a [`Screen`] needs.
+pub struct Framebuffers<F: 'static> {
+    /// Where the next image is drawn.
+    pub draw: &'static mut F,
+    /// What the panel shows, to find out what changed.
+    pub shown: &'static mut F,
+}
+
+pub struct Screen<P: Panel, PWR> {
+    delay: P::Delay,
     pwr_pin: PWR,
-    spi_device: ExclusiveDevice<SPI, CS, DELAY>,
-    device: Epd7in5<ExclusiveDevice<SPI, CS, DELAY>, BUSY, DC, RST, DELAY>,
-    buffer: &'static mut Display7in5,
+    spi_device: P::Spi,
+    device: P,
+    buffer: &'static mut P::Framebuffer,
+    shown: &'static mut P::Framebuffer,
+    /// Whether `shown` matches the panel; unknown after boot or a failed update.
+    shown_valid: bool,
+    partials: u32,
+    full_refresh_interval: u32,
+    mode: Mode,
+    /// The bit blank pixels have in a black and white framebuffer, which
+    /// depends on the panel's colour type.
+    paper: bool,
 }
 
-impl<SPI, CS, BUSY, DC, RST, PWR, DELAY> Screen<SPI, CS, BUSY, DC, RST, PWR, DELAY>
+impl<P, PWR> Screen<P, PWR>
 where
-    SPI: SpiBus,
-    CS: OutputPin,
-    BUSY: InputPin,
-    DC: OutputPin,
-    RST: OutputPin,
+    P: Panel,
     PWR: OutputPin,
-    DELAY: DelayNs + Clone,
 {
-    pub fn init(
-        spi_bus: SPI,
-        cs_pin: CS,
-        busy_pin: BUSY,
-        dc_pin: DC,
-        rst_pin: RST,
+    /// Bring up the panel. After `full_refresh_interval` partial refreshes
+    /// a full one clears the ghosting they leave.
+    pub async fn init(
+        mut spi_device: P::Spi,
+        busy_pin: P::Busy,
+        dc_pin: P::Dc,
+        rst_pin: P::Rst,
         pwr_pin: PWR,
-        mut delay: DELAY,
+        mut delay: P::Delay,
+        mut buffers: Framebuffers<P::Framebuffer>,
+        full_refresh_interval: u32,
     ) -> Result<Self, Error> {
-        let mut device =
-            ExclusiveDevice::new(spi_bus, cs_pin, delay.clone()).map_err(|_| Error::Pin)?;
-        let screen = Epd7in5::new(&mut device, busy_pin, dc_pin, rst_pin, &mut delay, None)
-            .map_err(|_| Error::InitScreen)?;
+        info!(
+            "Panel: {}, {}x{}, {}",
+            P::NAME,
+            P::WIDTH,
+            P::HEIGHT,
+            P::COLOR_DEPTH
+        );
+        let device = P::init(&mut spi_device, busy_pin, dc_pin, rst_pin, &mut delay).await?;
+        // Safe to unwrap: clearing just fills an in-memory buffer and cannot fail.
+        buffers.draw.clear(PAPER.into()).unwrap();
+        let paper = buffers.draw.bytes()[0] & 0x80 != 0;
         Ok(Self {
             delay,
             pwr_pin,
-            spi_device: device,
-            device: screen,
-            buffer: FRAMEBUFFER.init_with(Display7in5::default),
+            spi_device,
+            device,
+            buffer: buffers.draw,
+            shown: buffers.shown,
+            shown_valid: false,
+            partials: 0,
+            full_refresh_interval,
+            mode: Mode::BlackWhite,
+            paper,
         })
     }
 
+    fn plan(&self) -> Refresh {
+        if !self.shown_valid || self.mode != Mode::BlackWhite {
+            return Refresh::Full;
+        }
+        let full_every = match P::PARTIAL_REFRESH {
+            true => self.full_refresh_interval,
+            false => 0,
+        };
+        refresh::plan(
+            self.shown.bytes(),
+            self.buffer.bytes(),
+            Size::new(P::WIDTH, P::HEIGHT),
+            self.partials,
+            full_every,
+        )
+    }
+
     /// Wake the display, wait for it to become ready, and send the framebuffer content.
-    fn wake_and_display(&mut self) -> Result<(), Error> {
-        self.device
-            .wake_up(&mut self.spi_device, &mut self.delay)
-            .map_err(|_| Error::WakeUp)?;
+    async fn wake_and_display(&mut self, refresh: &Refresh) -> Result<(), Error> {
         info!("Waiting for display to awake...");
         self.device
-            .wait_until_idle(&mut self.spi_device, &mut self.delay)
-            .map_err(|_| Error::BecomingReady)?;
+            .wake(&mut self.spi_device, &mut self.delay)
+            .await?;
         info!("Display reporting ready... updating the content.");
-        self.device
-            .update_and_display_frame(&mut self.spi_device, self.buffer.buffer(), &mut self.delay)
-            .map_err(|_| Error::UpdateScreen)
+        match refresh {
+            Refresh::Unchanged => Ok(()),
+            Refresh::Full if self.mode == Mode::Gray4 => {
+                self.device
+                    .update_gray4(
+                        &mut self.spi_device,
+                        [self.buffer.bytes(), self.shown.bytes()],
+                        &mut self.delay,
+                    )
+                    .await
+            }
+            Refresh::Full if self.mode == Mode::TriColor => {
+                self.device
+                    .update_tricolor(
+                        &mut self.spi_device,
+                        [self.buffer.bytes(), self.shown.bytes()],
+                        &mut self.delay,
+                    )
+                    .await
+            }
+            Refresh::Full => {
+                self.device
+                    .update(&mut self.spi_device, self.buffer, &mut self.delay)
+                    .await
+            }
+            Refresh::Partial(areas) => {
+                for area in areas {
+                    debug!("Partial refresh of {area:?}");
+                    self.device
+                        .update_partial(
+                            &mut self.spi_device,
+                            self.buffer,
+                            self.shown,
+                            *area,
+                            &mut self.delay,
+                        )
+                        .await?;
+                }
+                Ok(())
+            }
+        }
     }
 
-    // Safe to unwrap: Display7in5::clear() just fills an in-memory buffer and cannot fail.
+    // Safe to unwrap: clearing just fills an in-memory buffer and cannot fail.
     pub fn clear(&mut self) {
-        self.buffer.clear(BinaryColor::On.into()).unwrap();
+        self.mode = Mode::BlackWhite;
+        self.buffer.clear(PAPER.into()).unwrap();
+    }
+
+    /// Switch the next update to four gray levels and return the blank
+    /// framebuffer to draw it into, or `None` if the panel cannot show gray.
+    ///
+    /// The planes take up both framebuffers, so the refresh after a grayscale
+    /// one is always full.
+    pub fn gray_display(&mut self) -> Option<GrayFrame<'_>> {
+        if P::COLOR_DEPTH != ColorDepth::Gray4 {
+            return None;
+        }
+        self.mode = Mode::Gray4;
+        self.shown_valid = false;
+        let mut frame = GrayFrame::new(
+            [self.buffer.bytes_mut(), self.shown.bytes_mut()],
+            Size::new(P::WIDTH, P::HEIGHT),
+        );
+        // Safe to unwrap: drawing into a GrayFrame cannot fail.
+        frame.clear(PAPER.into()).unwrap();
+        Some(frame)
     }
 
-    pub fn update(&mut self) -> Result<(), Error> {
+    /// Switch the next update to the panel's three inks and return the blank
+    /// framebuffer to draw it into, or `None` if the panel only has black.
+    ///
+    /// Like [`Screen::gray_display`], this takes up both framebuffers.
+    pub fn tricolor_display(&mut self) -> Option<TriColorFrame<'_>> {
+        if P::COLOR_DEPTH != ColorDepth::TriColor {
+            return None;
+        }
+        self.mode = Mode::TriColor;
+        self.shown_valid = false;
+        let mut frame = TriColorFrame::new(
+            [self.buffer.bytes_mut(), self.shown.bytes_mut()],
+            Size::new(P::WIDTH, P::HEIGHT),
+        );
+        // Safe to unwrap: drawing into a TriColorFrame cannot fail.
+        frame.clear(TriColor::White).unwrap();
+        Some(frame)
+    }
+
+    /// Bring the panel up to date with the framebuffer, refreshing only what
+    /// changed where the panel supports it.
+    pub async fn update(&mut self) -> Result<(), Error> {
+        let refresh = self.plan();
+        match &refresh {
+            Refresh::Unchanged => {
+                info!("Screen content unchanged, not refreshing.");
+                return Ok(());
+            }
+            Refresh::Full => info!("Full refresh ({:?}).", self.mode),
+            Refresh::Partial(areas) => info!("Partial refresh of {} areas.", areas.len()),
+        }
+
         self.pwr_pin.set_high().map_err(|_| Error::Pin)?;
 
-        let result = self.wake_and_display();
+        let result = self.wake_and_display(&refresh).await;
 
         // Always attempt to sleep the display and power down, even on error,
         // to avoid leaving the e-paper panel in an active power state.
         info!("Putting screen back to sleep.");
-        let _ = self.device.sleep(&mut self.spi_device, &mut self.delay);
+        let _ = self
+            .device
+            .sleep(&mut self.spi_device, &mut self.delay)
+            .await;
         let _ = self.pwr_pin.set_low();
 
+        self.shown_valid = result.is_ok() && self.mode == Mode::BlackWhite;
+        if self.shown_valid {
+            self.shown.bytes_mut().copy_from_slice(self.buffer.bytes());
+            self.partials = match refresh {
+                Refresh::Partial(_) => self.partials + 1,
+                _ => 0,
+            };
+        }
+        result
+    }
+
+    /// Drive every pixel to black, white and black again with full refreshes,
+    /// which clears the ghosting that builds up over many updates.
+    ///
+    /// This overwrites the framebuffer, so draw the next image afterwards. The
+    /// update that shows it is a full one.
+    pub async fn deep_clean(&mut self) -> Result<(), Error> {
+        info!("Deep cleaning the panel.");
+        self.mode = Mode::BlackWhite;
+        self.shown_valid = false;
+        self.pwr_pin.set_high().map_err(|_| Error::Pin)?;
+
+        let result = self.clean_cycle().await;
+
+        let _ = self
+            .device
+            .sleep(&mut self.spi_device, &mut self.delay)
+            .await;
+        let _ = self.pwr_pin.set_low();
+        if result.is_ok() {
+            self.partials = 0;
+        }
         result
     }
 
-    pub fn display(&mut self) -> &mut Display7in5 {
+    async fn clean_cycle(&mut self) -> Result<(), Error> {
+        self.device
+            .wake(&mut self.spi_device, &mut self.delay)
+            .await?;
+        for color in [INK, PAPER, INK] {
+            // Safe to unwrap: clearing just fills an in-memory buffer and cannot fail.
+            self.buffer.clear(color.into()).unwrap();
+            self.device
+                .update(&mut self.spi_device, self.buffer, &mut self.delay)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the panel shows what is in the framebuffer, so that drawing
+    /// over it and updating changes just that.
+    pub fn shows_framebuffer(&self) -> bool {
+        self.shown_valid
+    }
+
+    /// The framebuffer as an image. Right after a successful [`Screen::update`]
+    /// this is what the panel shows.
+    pub fn screenshot(&self) -> Screenshot<'_> {
+        let size = Size::new(P::WIDTH, P::HEIGHT);
+        match self.mode {
+            Mode::BlackWhite => Screenshot::mono(self.buffer.bytes(), self.paper, size),
+            Mode::Gray4 => Screenshot::gray([self.buffer.bytes(), self.shown.bytes()], size),
+            Mode::TriColor => Screenshot::tricolor([self.buffer.bytes(), self.shown.bytes()], size),
+        }
+    }
+
+    pub fn display(&mut self) -> &mut P::Framebuffer {
         self.buffer
     }
 }
+
+impl<P, PWR> render::Canvas for Screen<P, PWR>
+where
+    P: Panel,
+    PWR: OutputPin,
+{
+    fn mono(&mut self) -> impl DrawTarget<Color = BinaryColor, Error = Infallible> {
+        self.clear();
+        self.buffer.color_converted()
+    }
+
+    fn gray(&mut self) -> Option<impl DrawTarget<Color = Gray2, Error = Infallible>> {
+        self.gray_display()
+    }
+
+    fn tricolor(&mut self) -> Option<impl DrawTarget<Color = TriColor, Error = Infallible>> {
+        self.tricolor_display()
+    }
+}
@@ -0,0 +1,510 @@
+use embedded_graphics::{
+    Pixel,
+    prelude::*,
+    primitives::Rectangle,
+    text::{
+        Alignment, Baseline,
+        renderer::{CharacterStyle, TextMetrics, TextRenderer},
+    },
+};
+
+/// Header: magic, line height, ascent and the glyph count.
+const HEADER_LEN: usize = 8;
+/// Table entry: code point and offset of the glyph.
+const ENTRY_LEN: usize = 8;
+/// Glyph header: advance, width, height, left and top.
+const GLYPH_HEADER_LEN: usize = 5;
+
+/// Why bytes are not a font.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Not in the packed format, or a version this firmware does not read.
+    Format,
+    /// Shorter than its glyph table.
+    Truncated,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Format => write!(f, "not a packed font"),
+            Error::Truncated => write!(f, "font is truncated"),
+        }
+    }
+}
+
+/// A bitmap font in the packed format `fonts/convert.rs` writes from BDF.
+///
+/// Glyphs are looked up by code point, so a font can cover any script it
+/// has glyphs for. Characters it lacks are drawn as U+FFFD, or `?` if the
+/// font has no replacement character either.
+#[derive(Debug, Clone, Copy)]
+pub struct Font<'a> {
+    data: &'a [u8],
+    glyphs: usize,
+    line_height: u32,
+    ascent: u32,
+}
+
+macro_rules! builtin {
+    ($name:literal) => {
+        match Font::new(include_bytes!(concat!("../fonts/", $name, ".font"))) {
+            Ok(font) => font,
+            Err(_) => panic!(concat!("invalid built-in font ", $name)),
+        }
+    };
+}
+
+/// 6 pixels wide, 13 high.
+pub const SMALL: Font<'static> = builtin!("6x13");
+pub const SMALL_BOLD: Font<'static> = builtin!("6x13B");
+/// 9 pixels wide, 15 high.
+pub const MEDIUM: Font<'static> = builtin!("9x15");
+pub const MEDIUM_BOLD: Font<'static> = builtin!("9x15B");
+/// 10 pixels wide, 20 high.
+pub const LARGE: Font<'static> = builtin!("10x20");
+
+/// One character of a [`Font`].
+struct Glyph<'a> {
+    advance: u32,
+    width: u32,
+    height: u32,
+    /// Offset of the bitmap's left edge from the pen position.
+    left: i32,
+    /// Rows the bitmap's top row is above the baseline.
+    top: i32,
+    bitmap: &'a [u8],
+}
+
+impl Glyph<'_> {
+    /// The pixels that are set, with the pen at `origin` on the baseline.
+    fn pixels(&self, origin: Point) -> impl Iterator<Item = Point> + '_ {
+        let stride = self.width.div_ceil(8);
+        let top_left = origin + Point::new(self.left, -self.top);
+        (0..self.height).flat_map(move |y| {
+            (0..self.width).filter_map(move |x| {
+                let byte = self.bitmap[(y * stride + x / 8) as usize];
+                (byte & 0x80 >> (x % 8) != 0).then(|| top_left + Point::new(x as i32, y as i32))
+            })
+        })
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+impl<'a> Font<'a> {
+    /// Read the font in `data`. Glyphs are checked when they are looked up,
+    /// so a damaged glyph only loses that character.
+    pub const fn new(data: &'a [u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        if !matches!(data, [b'B', b'M', b'F', b'1', ..]) {
+            return Err(Error::Format);
+        }
+        let glyphs = u16::from_le_bytes([data[6], data[7]]) as usize;
+        if data.len() < HEADER_LEN + glyphs * ENTRY_LEN {
+            return Err(Error::Truncated);
+        }
+        Ok(Self {
+            data,
+            glyphs,
+            line_height: data[4] as u32,
+            ascent: data[5] as u32,
+        })
+    }
+
+    /// Distance between the tops of two lines.
+    pub const fn line_height(&self) -> u32 {
+        self.line_height
+    }
+
+    /// Rows from the top of a line to the baseline, the baseline included.
+    pub const fn ascent(&self) -> u32 {
+        self.ascent
+    }
+
+    fn codepoint(&self, index: usize) -> u32 {
+        // Safe to unwrap: `new` checked that the table is there.
+        read_u32(self.data, HEADER_LEN + index * ENTRY_LEN).unwrap()
+    }
+
+    fn lookup(&self, c: char) -> Option<Glyph<'a>> {
+        let (mut low, mut high) = (0, self.glyphs);
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.codepoint(middle).cmp(&(c as u32)) {
+                core::cmp::Ordering::Less => low = middle + 1,
+                core::cmp::Ordering::Greater => high = middle,
+                core::cmp::Ordering::Equal => return self.glyph_at(middle),
+            }
+        }
+        None
+    }
+
+    fn glyph_at(&self, index: usize) -> Option<Glyph<'a>> {
+        let offset = read_u32(self.data, HEADER_LEN + index * ENTRY_LEN + 4)? as usize;
+        let header = self.data.get(offset..offset + GLYPH_HEADER_LEN)?;
+        let (width, height) = (header[1] as u32, header[2] as u32);
+        let len = (width.div_ceil(8) * height) as usize;
+        let start = offset + GLYPH_HEADER_LEN;
+        Some(Glyph {
+            advance: header[0] as u32,
+            width,
+            height,
+            left: header[3] as i8 as i32,
+            top: header[4] as i8 as i32,
+            bitmap: self.data.get(start..start + len)?,
+        })
+    }
+
+    /// Whether the font has a glyph for `c`.
+    pub fn has_glyph(&self, c: char) -> bool {
+        self.lookup(c).is_some()
+    }
+
+    /// The glyph for `c`, or the one drawn in its place.
+    fn glyph(&self, c: char) -> Option<Glyph<'a>> {
+        self.lookup(c)
+            .or_else(|| self.lookup(char::REPLACEMENT_CHARACTER))
+            .or_else(|| self.lookup('?'))
+    }
+
+    /// How far the pen moves for `c`.
+    pub fn advance(&self, c: char) -> u32 {
+        self.glyph(c).map_or(0, |glyph| glyph.advance)
+    }
+
+    /// Width of `text` on one line.
+    pub fn width(&self, text: &str) -> u32 {
+        text.chars().map(|c| self.advance(c)).sum()
+    }
+
+    /// Break `text` into lines no wider than `width`: at line feeds, at the
+    /// last space that fits, or between characters for words, such as CJK
+    /// text, with no space to break at.
+    pub fn lines<'t>(&self, text: &'t str, width: u32) -> Lines<'t, 'a> {
+        Lines {
+            font: *self,
+            rest: Some(text).filter(|text| !text.is_empty()),
+            width,
+        }
+    }
+}
+
+/// The lines [`Font::lines`] breaks text into.
+pub struct Lines<'t, 'a> {
+    font: Font<'a>,
+    rest: Option<&'t str>,
+    width: u32,
+}
+
+impl<'t> Iterator for Lines<'t, '_> {
+    type Item = &'t str;
+
+    fn next(&mut self) -> Option<&'t str> {
+        let text = self.rest.take()?;
+        let mut used = 0;
+        // Where the last space was, to break there.
+        let mut space = None;
+        for (i, c) in text.char_indices() {
+            if c == '\n' {
+                self.rest = Some(&text[i + 1..]).filter(|rest| !rest.is_empty());
+                return Some(text[..i].trim_end_matches(' '));
+            }
+            if c == ' ' {
+                space = Some(i);
+            }
+            used += self.font.advance(c);
+            if used > self.width && i > 0 {
+                let end = match (c, space) {
+                    (' ', _) => i,
+                    (_, Some(space)) if space > 0 => space,
+                    // Keep at least one character on a line.
+                    _ => i,
+                };
+                let rest = text[end..].trim_start_matches(' ');
+                self.rest = Some(rest).filter(|rest| !rest.is_empty());
+                return Some(text[..end].trim_end_matches(' '));
+            }
+        }
+        Some(text.trim_end_matches(' '))
+    }
+}
+
+/// Draws text in a [`Font`], as `MonoTextStyle` does for `MonoFont`s.
+#[derive(Debug, Clone, Copy)]
+pub struct FontStyle<'a, C> {
+    pub font: Font<'a>,
+    pub text_color: Option<C>,
+    pub background_color: Option<C>,
+}
+
+impl<'a, C: PixelColor> FontStyle<'a, C> {
+    pub fn new(font: Font<'a>, text_color: C) -> Self {
+        Self {
+            font,
+            text_color: Some(text_color),
+            background_color: None,
+        }
+    }
+
+    /// Offset from `position` to the baseline.
+    fn baseline_offset(&self, baseline: Baseline) -> i32 {
+        let above = self.font.ascent as i32 - 1;
+        let below = self.font.line_height as i32 - self.font.ascent as i32;
+        match baseline {
+            Baseline::Top => above,
+            Baseline::Bottom => -below,
+            Baseline::Middle => above - (self.font.line_height as i32 - 1) / 2,
+            Baseline::Alphabetic => 0,
+        }
+    }
+
+    fn fill_background<D>(
+        &self,
+        x: i32,
+        baseline_y: i32,
+        width: u32,
+        target: &mut D,
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = C>,
+    {
+        match self.background_color {
+            Some(color) if width > 0 => {
+                let top = baseline_y - (self.font.ascent as i32 - 1);
+                target.fill_solid(
+                    &Rectangle::new(Point::new(x, top), Size::new(width, self.font.line_height)),
+                    color,
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Draw `text` wrapped to the width of `area`, each line aligned within
+    /// it, from the top of `area` down. Lines that would not fit `area` are
+    /// left out. Returns the height of the lines drawn.
+    pub fn draw_wrapped<D>(
+        &self,
+        text: &str,
+        area: Rectangle,
+        alignment: Alignment,
+        target: &mut D,
+    ) -> Result<u32, D::Error>
+    where
+        D: DrawTarget<Color = C>,
+    {
+        let mut height = 0;
+        for line in self.font.lines(text, area.size.width) {
+            if height + self.font.line_height > area.size.height {
+                break;
+            }
+            let spare = area.size.width.saturating_sub(self.font.width(line)) as i32;
+            let x = match alignment {
+                Alignment::Left => 0,
+                Alignment::Center => spare / 2,
+                Alignment::Right => spare,
+            };
+            let position = area.top_left + Point::new(x, height as i32);
+            self.draw_string(line, position, Baseline::Top, target)?;
+            height += self.font.line_height;
+        }
+        Ok(height)
+    }
+}
+
+impl<C: PixelColor> CharacterStyle for FontStyle<'_, C> {
+    type Color = C;
+
+    fn set_text_color(&mut self, text_color: Option<C>) {
+        self.text_color = text_color;
+    }
+
+    fn set_background_color(&mut self, background_color: Option<C>) {
+        self.background_color = background_color;
+    }
+}
+
+impl<C: PixelColor> TextRenderer for FontStyle<'_, C> {
+    type Color = C;
+
+    fn draw_string<D>(
+        &self,
+        text: &str,
+        position: Point,
+        baseline: Baseline,
+        target: &mut D,
+    ) -> Result<Point, D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        let y = position.y + self.baseline_offset(baseline);
+        let mut x = position.x;
+        for c in text.chars() {
+            let Some(glyph) = self.font.glyph(c) else {
+                continue;
+            };
+            self.fill_background(x, y, glyph.advance, target)?;
+            if let Some(color) = self.text_color {
+                target.draw_iter(
+                    glyph
+                        .pixels(Point::new(x, y))
+                        .map(|point| Pixel(point, color)),
+                )?;
+            }
+            x += glyph.advance as i32;
+        }
+        Ok(Point::new(x, position.y))
+    }
+
+    fn draw_whitespace<D>(
+        &self,
+        width: u32,
+        position: Point,
+        baseline: Baseline,
+        target: &mut D,
+    ) -> Result<Point, D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        self.fill_background(
+            position.x,
+            position.y + self.baseline_offset(baseline),
+            width,
+            target,
+        )?;
+        Ok(position + Point::new(width as i32, 0))
+    }
+
+    fn measure_string(&self, text: &str, position: Point, baseline: Baseline) -> TextMetrics {
+        let width = self.font.width(text);
+        let top = position.y + self.baseline_offset(baseline) - (self.font.ascent as i32 - 1);
+        TextMetrics {
+            bounding_box: Rectangle::new(
+                Point::new(position.x, top),
+                Size::new(width, self.font.line_height),
+            ),
+            next_position: position + Point::new(width as i32, 0),
+        }
+    }
+
+    fn line_height(&self) -> u32 {
+        self.font.line_height
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embedded_graphics::pixelcolor::BinaryColor;
+
+    use super::*;
+
+    fn lines(text: &str, width: u32) -> Vec<&str> {
+        SMALL.lines(text, width).collect()
+    }
+
+    #[test]
+    fn breaks_at_line_feeds_and_spaces() {
+        // SMALL is 6 pixels wide, so 30 pixels hold five characters.
+        assert_eq!(lines("hello world", 30), ["hello", "world"]);
+        assert_eq!(lines("ab cd ef", 30), ["ab cd", "ef"]);
+        assert_eq!(lines("ab cdefg", 30), ["ab", "cdefg"]);
+        assert_eq!(lines("ab   \n\ncd  ", 30), ["ab", "", "cd"]);
+        assert_eq!(lines("ab      cd", 30), ["ab", "cd"]);
+        assert!(lines("", 30).is_empty());
+    }
+
+    #[test]
+    fn breaks_words_between_characters() {
+        assert_eq!(lines("abcdefghijk", 30), ["abcde", "fghij", "k"]);
+        assert_eq!(lines("ab abcdefghij", 30), ["ab", "abcde", "fghij"]);
+        // Missing glyphs, drawn as U+FFFD, take its width.
+        assert_eq!(lines("日本語のテキスト", 18), ["日本語", "のテキ", "スト"]);
+        // Too narrow for any character, one goes on each line.
+        assert_eq!(lines("ab", 3), ["a", "b"]);
+    }
+
+    /// Records which rows and columns are drawn to.
+    #[derive(Default)]
+    struct Canvas {
+        points: Vec<Point>,
+    }
+
+    impl Canvas {
+        /// First and last column drawn to in `rows`.
+        fn span(&self, rows: core::ops::Range<i32>) -> Option<(i32, i32)> {
+            let mut columns = self
+                .points
+                .iter()
+                .filter(|point| rows.contains(&point.y))
+                .map(|point| point.x);
+            let first = columns.next()?;
+            Some(columns.fold((first, first), |(min, max), x| (min.min(x), max.max(x))))
+        }
+    }
+
+    impl OriginDimensions for Canvas {
+        fn size(&self) -> Size {
+            Size::new(64, 64)
+        }
+    }
+
+    impl DrawTarget for Canvas {
+        type Color = BinaryColor;
+        type Error = Infallible;
+
+        fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Infallible>
+        where
+            I: IntoIterator<Item = Pixel<BinaryColor>>,
+        {
+            self.points
+                .extend(pixels.into_iter().map(|Pixel(point, _)| point));
+            Ok(())
+        }
+    }
+
+    /// Draw two lines, 2 and 4 characters long, into a box 30 pixels wide at
+    /// x 10 and y 5, and return the columns each line covers.
+    fn draw(alignment: Alignment, height: u32) -> (u32, [Option<(i32, i32)>; 2]) {
+        // Only the background, which covers the whole advance of each glyph.
+        let style = FontStyle {
+            font: SMALL,
+            text_color: None,
+            background_color: Some(BinaryColor::On),
+        };
+        let mut canvas = Canvas::default();
+        let area = Rectangle::new(Point::new(10, 5), Size::new(30, height));
+        let drawn = style
+            .draw_wrapped("ab\nabcd", area, alignment, &mut canvas)
+            .unwrap();
+        (drawn, [canvas.span(5..18), canvas.span(18..31)])
+    }
+
+    #[test]
+    fn aligns_each_line() {
+        assert_eq!(
+            draw(Alignment::Left, 40),
+            (26, [Some((10, 21)), Some((10, 33))])
+        );
+        assert_eq!(
+            draw(Alignment::Center, 40),
+            (26, [Some((19, 30)), Some((13, 36))])
+        );
+        assert_eq!(
+            draw(Alignment::Right, 40),
+            (26, [Some((28, 39)), Some((16, 39))])
+        );
+    }
+
+    #[test]
+    fn leaves_out_lines_below_the_area() {
+        assert_eq!(draw(Alignment::Left, 25), (13, [Some((10, 21)), None]));
+        assert_eq!(draw(Alignment::Left, 12), (0, [None, None]));
+    }
+}
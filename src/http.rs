@@ -1,4 +1,5 @@
-use core::fmt::Display;
+use core::fmt::{Display, Write as _};
+use core::ops::Range;
 
 use embassy_net::{
     Stack,
@@ -8,19 +9,24 @@ use embassy_net::{
         client::{TcpClient, TcpClientState},
     },
 };
-use embassy_time::{Duration, WithTimeout};
-use embedded_config::prelude::embed_config_value;
+use embassy_time::WithTimeout;
 use esp_hal::rng::Rng;
 use log::{debug, error};
 use reqwless::{
     client::{HttpClient, TlsConfig, TlsVerify},
-    request::{Method, RequestBuilder},
+    request::{Method, RequestBody, RequestBuilder},
     response::StatusCode,
 };
-use serde::Deserialize;
 use static_cell::StaticCell;
 use tinyqoi::Qoi;
 
+use crate::api::ApiResponse;
+use crate::config::Config;
+use crate::datetime;
+use crate::layout;
+use crate::overrides;
+use crate::pipeline;
+
 #[derive(Debug)]
 pub enum Error {
     ConnectionReset,
@@ -28,7 +34,9 @@ pub enum Error {
     Http,
     StatusCode(StatusCode),
     Decode,
+    Encode,
     Image,
+    Layout,
 }
 
 impl From<tcp::Error> for Error {
@@ -65,6 +73,16 @@ impl From<tinyqoi::Error> for Error {
     }
 }
 
+impl Error {
+    /// The HTTP status code, if the server answered with an error.
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            Error::StatusCode(code) => Some(code.0),
+            _ => None,
+        }
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
@@ -73,21 +91,23 @@ impl Display for Error {
             Error::RequestTimedOut => write!(f, "endpoint took too long to respond"),
             Error::StatusCode(code) => write!(f, "http request has status code of: {code:?}"),
             Error::Decode => write!(f, "failed to decode response"),
+            Error::Encode => write!(f, "failed to encode request"),
             Error::Image => write!(f, "failed to decode image"),
+            Error::Layout => write!(f, "failed to parse layout"),
         }
     }
 }
 
-macro_rules! url {
-    ($path:expr) => {
-        concat!(embed_config_value!("trmnl.address"), "/", $path)
-    };
-}
+/// Path of the endpoint telling the device what to show.
+pub const DISPLAY_PATH: &str = "api/display";
 
-#[derive(Deserialize)]
-pub struct ApiResponse {
-    pub image_url: heapless::String<128>,
-    pub refresh_rate: u64,
+/// Device properties reported with every `/api/display` request.
+pub struct Telemetry {
+    pub width: u32,
+    pub height: u32,
+    /// [`Screenshot::hash`](crate::screenshot::Screenshot::hash) of what the
+    /// panel shows, once known.
+    pub frame_hash: Option<u32>,
 }
 
 static TCP_STATE: StaticCell<TcpClientState<1, 2048, 2048>> = StaticCell::new();
@@ -100,24 +120,30 @@ pub struct Client<'stack> {
     rx_buf: &'static mut [u8; 16 << 10],
     tx_buf: &'static mut [u8; 16 << 10],
     rng: Rng,
+    server_time: Option<u64>,
+    config: &'static Config,
 }
 
 impl<'stack> Client<'stack> {
-    pub fn new(stack: Stack<'stack>) -> Self {
+    pub fn new(stack: Stack<'stack>, config: &'static Config) -> Self {
         Self {
             stack,
             tcp_client_state: TCP_STATE.init(TcpClientState::new()),
             rx_buf: RX_BUF.init([0; 16 << 10]),
             tx_buf: TX_BUF.init([0; 16 << 10]),
             rng: Rng::new(),
+            server_time: None,
+            config,
         }
     }
 
-    async fn send_request_with_header<'buf>(
+    async fn send_request<'buf, B: RequestBody>(
         &mut self,
         buf: &'buf mut [u8],
+        method: Method,
         url: &str,
         headers: &[(&str, &str)],
+        body: B,
     ) -> Result<&'buf [u8], Error> {
         debug!("Sending http request to {url}");
 
@@ -136,13 +162,18 @@ impl<'stack> Client<'stack> {
         debug!("Creating request");
         debug!("Setting headers: {headers:?}");
         let mut req = client
-            .request(Method::GET, url)
+            .request(method, url)
             .await
             .inspect(|_| debug!("Request prepped."))
             .inspect_err(|e| error!("Producing request: {e:?}"))?
-            .headers(headers);
+            .headers(headers)
+            .body(body);
         debug!("Sending request");
-        let resp = match req.send(buf).with_timeout(Duration::from_secs(45)).await {
+        let resp = match req
+            .send(buf)
+            .with_timeout(overrides::policy().http_timeout())
+            .await
+        {
             Ok(Ok(resp)) => resp,
             Ok(Err(e)) => {
                 error!("http request failed with: {e:?}");
@@ -153,6 +184,10 @@ impl<'stack> Client<'stack> {
         if !resp.status.is_successful() {
             return Err(Error::StatusCode(resp.status));
         }
+        self.server_time = resp
+            .headers()
+            .find(|(name, _)| name.eq_ignore_ascii_case("date"))
+            .and_then(|(_, value)| datetime::parse_http_date(value));
         let buf = resp
             .body()
             .read_to_end()
@@ -162,24 +197,174 @@ impl<'stack> Client<'stack> {
         Ok(buf)
     }
 
-    pub async fn fetch_api_display(&mut self, buf: &mut [u8]) -> Result<ApiResponse, Error> {
+    /// UTC seconds from the `Date` header of the last successful response.
+    pub fn server_time(&self) -> Option<u64> {
+        self.server_time
+    }
+
+    pub async fn fetch_api_display(
+        &mut self,
+        buf: &mut [u8],
+        telemetry: &Telemetry,
+    ) -> Result<ApiResponse, Error> {
+        let width = decimal(telemetry.width);
+        let height = decimal(telemetry.height);
+        let frame_hash = telemetry.frame_hash.map(hex);
+        let config = self.config;
+        let mut headers = heapless::Vec::<_, 5>::new();
+        // Safe to unwrap: there is room for every header.
+        headers
+            .extend_from_slice(&[
+                ("Access-Token", config.device_id.as_str()),
+                ("FW-Version", crate::FIRMWARE_VERSION),
+                ("Width", width.as_str()),
+                ("Height", height.as_str()),
+            ])
+            .unwrap();
+        if let Some(hash) = &frame_hash {
+            headers.push(("Framebuffer-Hash", hash.as_str())).unwrap();
+        }
         let resp = self
-            .send_request_with_header(
-                buf,
-                url!("api/display"),
-                &[("Access-Token", embed_config_value!("trmnl.device_id"))],
-            )
+            .send_request(buf, Method::GET, &config.url(DISPLAY_PATH), &headers, ())
             .await
             .inspect_err(|e| debug!("Failed to fetch api response: {e:?}"))?;
         let (api, _) = serde_json_core::from_slice(resp)?;
         Ok(api)
     }
 
-    pub async fn fetch_image<'b>(&mut self, buf: &'b mut [u8], url: &str) -> Result<Qoi<'b>, Error> {
+    /// Download the QOI image at `url` into `buf` and check its header.
+    /// Returns where in `buf` the image ended up.
+    pub async fn fetch_image(&mut self, buf: &mut [u8], url: &str) -> Result<Range<usize>, Error> {
+        let base = buf.as_ptr() as usize;
         let resp = self
-            .send_request_with_header(buf, url, &[("Accept", "image/qoi")])
+            .send_request(buf, Method::GET, url, &[("Accept", "image/qoi")], ())
             .await
             .inspect_err(|e| debug!("Failed to fetch image: {e:?}"))?;
-        Ok(Qoi::new(resp).inspect_err(|e| debug!("Failed to decode image: {e:?}"))?)
+        Qoi::new(resp).inspect_err(|e| debug!("Failed to decode image: {e:?}"))?;
+        let start = resp.as_ptr() as usize - base;
+        Ok(start..start + resp.len())
+    }
+
+    /// Download the layout document at `url` into `buf` and parse it. Returns
+    /// where in `buf` it is.
+    pub async fn fetch_layout(&mut self, buf: &mut [u8], url: &str) -> Result<Range<usize>, Error> {
+        let base = buf.as_ptr() as usize;
+        let resp = self
+            .send_request(buf, Method::GET, url, &[("Accept", "application/json")], ())
+            .await
+            .inspect_err(|e| debug!("Failed to fetch layout: {e:?}"))?;
+        layout::parse(resp).map_err(|e| {
+            debug!("Failed to parse layout: {e:?}");
+            Error::Layout
+        })?;
+        let start = resp.as_ptr() as usize - base;
+        Ok(start..start + resp.len())
+    }
+
+    /// Upload a log message to `/api/log`.
+    pub async fn send_log(&mut self, buf: &mut [u8], message: &str) -> Result<(), Error> {
+        let mut body = heapless::String::<1024>::new();
+        write!(
+            body,
+            r#"{{"log":{{"logs_array":[{{"log_message":"{}","firmware_version":"{}"}}]}}}}"#,
+            JsonStr(message),
+            crate::FIRMWARE_VERSION,
+        )
+        .map_err(|_| Error::Encode)?;
+        let config = self.config;
+        self.send_request(
+            buf,
+            Method::POST,
+            &config.url("api/log"),
+            &[
+                ("Access-Token", config.device_id.as_str()),
+                ("FW-Version", crate::FIRMWARE_VERSION),
+                ("Content-Type", "application/json"),
+            ],
+            body.as_bytes(),
+        )
+        .await
+        .inspect_err(|e| debug!("Failed to send log: {e:?}"))?;
+        Ok(())
+    }
+
+    /// Upload the netpbm screenshot of `len` bytes and type `content_type`
+    /// that the display task streams to `/api/screenshot`. The response goes
+    /// into `buf`.
+    pub async fn send_screenshot(
+        &mut self,
+        buf: &mut [u8],
+        len: usize,
+        content_type: &str,
+    ) -> Result<(), Error> {
+        let config = self.config;
+        self.send_request(
+            buf,
+            Method::POST,
+            &config.url("api/screenshot"),
+            &[
+                ("Access-Token", config.device_id.as_str()),
+                ("FW-Version", crate::FIRMWARE_VERSION),
+                ("Content-Type", content_type),
+            ],
+            StreamedScreenshot { len },
+        )
+        .await
+        .inspect_err(|e| debug!("Failed to send screenshot: {e:?}"))?;
+        Ok(())
+    }
+}
+
+/// The screenshot [`pipeline::stream_screenshot`] sends, as a request body.
+struct StreamedScreenshot {
+    len: usize,
+}
+
+impl RequestBody for StreamedScreenshot {
+    fn len(&self) -> Option<usize> {
+        Some(self.len)
+    }
+
+    async fn write<W: embedded_io_async::Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        let mut piece = [0; 256];
+        let mut left = self.len;
+        while left > 0 {
+            let len = pipeline::read_screenshot(&mut piece[..left.min(piece.len())]).await;
+            writer.write_all(&piece[..len]).await?;
+            left -= len;
+        }
+        Ok(())
+    }
+}
+
+fn decimal(value: u32) -> heapless::String<10> {
+    let mut s = heapless::String::new();
+    // Safe to unwrap: a u32 has at most 10 digits.
+    write!(s, "{value}").unwrap();
+    s
+}
+
+fn hex(value: u32) -> heapless::String<8> {
+    let mut s = heapless::String::new();
+    // Safe to unwrap: a u32 has 8 hex digits.
+    write!(s, "{value:08x}").unwrap();
+    s
+}
+
+/// Formats a string as the inside of a JSON string literal.
+struct JsonStr<'a>(&'a str);
+
+impl Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,667 @@
+use core::fmt;
+
+use embedded_graphics::{
+    image::Image,
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Circle, Line, PrimitiveStyle, Rectangle, Triangle},
+    text::Alignment,
+};
+use serde::Deserialize;
+use serde_json_core::str::{EscapedStr, EscapedStringFragment};
+use tinyqoi::Qoi;
+
+use crate::dither;
+use crate::epaper::TriColor;
+use crate::font;
+
+/// Most elements a document can have. Documents with more fail to parse.
+pub const MAX_ELEMENTS: usize = 32;
+/// Longest text of a text element, in bytes once unescaped.
+pub const MAX_TEXT: usize = 256;
+/// Largest QOI bitmap an element can embed, in bytes once decoded.
+pub const MAX_BITMAP: usize = 4 << 10;
+/// Magic, width, height, channels and colour space.
+const QOI_HEADER: usize = 14;
+/// Icon size when an icon element does not give a width.
+const ICON_SIZE: u32 = 24;
+
+/// A screen described as elements to draw, for servers that would rather not
+/// send a whole image to change a number. Coordinates are pixels on the
+/// panel the way it is mounted, drawn in order onto paper.
+#[derive(Deserialize, Debug)]
+pub struct Document<'a> {
+    #[serde(borrow)]
+    pub elements: heapless::Vec<Element<'a>, MAX_ELEMENTS>,
+}
+
+/// Parse the document in `json`. Only its structure is checked here; see
+/// [`Document::errors`] for problems with single elements.
+pub fn parse(json: &[u8]) -> Result<Document<'_>, serde_json_core::de::Error> {
+    serde_json_core::from_slice(json).map(|(document, _)| document)
+}
+
+/// One element of a [`Document`]. Which fields it needs depends on its
+/// `type`; the others are ignored.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Element<'a> {
+    #[serde(rename = "type")]
+    pub kind: Option<Kind>,
+    /// Top left corner, or where lines start.
+    pub x: i32,
+    pub y: i32,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Where lines end.
+    pub x2: Option<i32>,
+    pub y2: Option<i32>,
+    pub color: Color,
+    /// Line width of lines, and of rectangles that are not filled.
+    pub stroke: Option<u32>,
+    /// Fill rectangles instead of outlining them.
+    pub fill: bool,
+    #[serde(borrow)]
+    pub text: Option<EscapedStr<'a>>,
+    pub font: FontWeight,
+    pub size: FontSize,
+    pub align: Align,
+    /// How full a progress bar is, in percent. Read wider than that so a
+    /// value out of range is reported rather than failing the document.
+    pub value: Option<i32>,
+    /// Which built-in icon to draw, see [`Icon`].
+    #[serde(borrow)]
+    pub icon: Option<&'a str>,
+    /// A QOI image, base64-encoded.
+    #[serde(borrow)]
+    pub qoi: Option<EscapedStr<'a>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// `text` in `font` and `size`, its top left corner at `x`, `y`,
+    /// wrapped to `width` and aligned within it. Without a `width` it wraps
+    /// at the edge of the panel; `height` limits how many lines are shown.
+    Text,
+    /// `width` by `height`, outlined or filled.
+    Rect,
+    /// From `x`, `y` to `x2`, `y2`.
+    Line,
+    /// `width` by `height`, filled to `value` percent.
+    Progress,
+    /// A built-in icon, `width` pixels square.
+    Icon,
+    /// An embedded `qoi` bitmap, dithered like fetched images.
+    Image,
+    /// Anything this firmware does not implement.
+    #[serde(other)]
+    Unsupported,
+}
+
+/// Colour of an element. Panels without red ink draw red as black.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Color {
+    #[default]
+    Black,
+    White,
+    Red,
+    /// Any other name, reported by [`Document::errors`].
+    #[serde(other)]
+    Unsupported,
+}
+
+impl From<Color> for TriColor {
+    fn from(color: Color) -> Self {
+        match color {
+            // Elements drawn in an unsupported colour are left out.
+            Color::Black | Color::Unsupported => TriColor::Black,
+            Color::White => TriColor::White,
+            Color::Red => TriColor::Red,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FontWeight {
+    #[default]
+    Regular,
+    /// Large text has no bold font and stays regular.
+    Bold,
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FontSize {
+    /// 13 pixels high.
+    Small,
+    /// 15 pixels high.
+    #[default]
+    Medium,
+    /// 20 pixels high.
+    Large,
+    #[serde(other)]
+    Unsupported,
+}
+
+fn builtin_font(weight: FontWeight, size: FontSize) -> Result<font::Font<'static>, ElementError> {
+    Ok(match (size, weight) {
+        (FontSize::Unsupported, _) => return Err(ElementError::Invalid("size")),
+        (_, FontWeight::Unsupported) => return Err(ElementError::Invalid("font")),
+        (FontSize::Small, FontWeight::Regular) => font::SMALL,
+        (FontSize::Small, FontWeight::Bold) => font::SMALL_BOLD,
+        (FontSize::Medium, FontWeight::Regular) => font::MEDIUM,
+        (FontSize::Medium, FontWeight::Bold) => font::MEDIUM_BOLD,
+        (FontSize::Large, _) => font::LARGE,
+    })
+}
+
+/// Where the lines of a text element go within its width.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+    #[serde(other)]
+    Unsupported,
+}
+
+impl TryFrom<Align> for Alignment {
+    type Error = ElementError;
+
+    fn try_from(align: Align) -> Result<Self, ElementError> {
+        Ok(match align {
+            Align::Left => Alignment::Left,
+            Align::Center => Alignment::Center,
+            Align::Right => Alignment::Right,
+            Align::Unsupported => return Err(ElementError::Invalid("align")),
+        })
+    }
+}
+
+/// Icons an element can refer to by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Icon {
+    Check,
+    Cross,
+    ArrowUp,
+    ArrowRight,
+    ArrowDown,
+    ArrowLeft,
+    Warning,
+    Info,
+    Dot,
+}
+
+impl Icon {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "check" => Icon::Check,
+            "cross" => Icon::Cross,
+            "arrow-up" => Icon::ArrowUp,
+            "arrow-right" => Icon::ArrowRight,
+            "arrow-down" => Icon::ArrowDown,
+            "arrow-left" => Icon::ArrowLeft,
+            "warning" => Icon::Warning,
+            "info" => Icon::Info,
+            "dot" => Icon::Dot,
+            _ => return None,
+        })
+    }
+
+    /// Draw the icon `size` pixels square with its top left corner at `at`.
+    /// Icons are drawn on a 16 by 16 grid scaled to `size`.
+    fn draw<D>(self, target: &mut D, at: Point, size: u32, color: TriColor) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = TriColor>,
+    {
+        let turns = match self {
+            Icon::ArrowRight => 1,
+            Icon::ArrowDown => 2,
+            Icon::ArrowLeft => 3,
+            _ => 0,
+        };
+        // Turn the grid clockwise around its center before scaling it.
+        let grid = |x: i32, y: i32| -> Point {
+            let (x, y) = (0..turns).fold((x, y), |(x, y), _| (16 - y, x));
+            at + Point::new(x * size as i32 / 16, y * size as i32 / 16)
+        };
+        let stroke = PrimitiveStyle::with_stroke(color, (size / 8).max(1));
+        let fill = PrimitiveStyle::with_fill(color);
+        match self {
+            Icon::Check => {
+                Line::new(grid(2, 9), grid(6, 13))
+                    .into_styled(stroke)
+                    .draw(target)?;
+                Line::new(grid(6, 13), grid(14, 3))
+                    .into_styled(stroke)
+                    .draw(target)
+            }
+            Icon::Cross => {
+                Line::new(grid(3, 3), grid(13, 13))
+                    .into_styled(stroke)
+                    .draw(target)?;
+                Line::new(grid(13, 3), grid(3, 13))
+                    .into_styled(stroke)
+                    .draw(target)
+            }
+            Icon::ArrowUp | Icon::ArrowRight | Icon::ArrowDown | Icon::ArrowLeft => {
+                Triangle::new(grid(8, 1), grid(1, 8), grid(15, 8))
+                    .into_styled(fill)
+                    .draw(target)?;
+                Line::new(grid(8, 8), grid(8, 15))
+                    .into_styled(PrimitiveStyle::with_stroke(color, (size / 4).max(1)))
+                    .draw(target)
+            }
+            Icon::Warning => {
+                Triangle::new(grid(8, 1), grid(1, 15), grid(15, 15))
+                    .into_styled(stroke)
+                    .draw(target)?;
+                Line::new(grid(8, 6), grid(8, 10))
+                    .into_styled(stroke)
+                    .draw(target)?;
+                Circle::with_center(grid(8, 13), (size / 8).max(1))
+                    .into_styled(fill)
+                    .draw(target)
+            }
+            Icon::Info => {
+                Circle::new(at, size).into_styled(stroke).draw(target)?;
+                Circle::with_center(grid(8, 4), (size / 8).max(1))
+                    .into_styled(fill)
+                    .draw(target)?;
+                Line::new(grid(8, 7), grid(8, 12))
+                    .into_styled(stroke)
+                    .draw(target)
+            }
+            Icon::Dot => Circle::new(at, size).into_styled(fill).draw(target),
+        }
+    }
+}
+
+/// Why an element is left out when drawing its document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementError {
+    MissingType,
+    UnsupportedType,
+    /// A field its type needs is not there.
+    Missing(&'static str),
+    UnknownIcon,
+    /// A field with a name or number its type does not have.
+    Invalid(&'static str),
+    /// Text with an invalid escape, or longer than [`MAX_TEXT`].
+    Text,
+    /// Not starting with a QOI header. Bitmaps that are broken further on
+    /// or larger than [`MAX_BITMAP`] are only found out, and left out, when
+    /// drawn.
+    Bitmap,
+}
+
+impl fmt::Display for ElementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElementError::MissingType => write!(f, "no type"),
+            ElementError::UnsupportedType => write!(f, "unsupported type"),
+            ElementError::Missing(field) => write!(f, "missing {field}"),
+            ElementError::UnknownIcon => write!(f, "unknown icon"),
+            ElementError::Invalid(field) => write!(f, "invalid {field}"),
+            ElementError::Text => write!(f, "invalid or too long text"),
+            ElementError::Bitmap => write!(f, "invalid or too large bitmap"),
+        }
+    }
+}
+
+/// An element with what its type needs checked.
+enum Shape<'a> {
+    Text(EscapedStr<'a>, font::Font<'static>, Alignment),
+    Rect(Rectangle),
+    Line(Line),
+    Progress(Rectangle, u8),
+    Icon(Icon, u32),
+    Image(EscapedStr<'a>),
+}
+
+/// Call `f` with each character of `text`, unescaped, until it returns `None`.
+fn unescape(text: EscapedStr<'_>, mut f: impl FnMut(char) -> Option<()>) -> Option<()> {
+    for fragment in text.fragments() {
+        match fragment.ok()? {
+            EscapedStringFragment::NotEscaped(chars) => chars.chars().try_for_each(&mut f)?,
+            EscapedStringFragment::Escaped(c) => f(c)?,
+        }
+    }
+    Some(())
+}
+
+/// Unescape `text`, which has to fit [`MAX_TEXT`].
+fn unescape_text(text: EscapedStr<'_>) -> Result<heapless::String<MAX_TEXT>, ElementError> {
+    let mut unescaped = heapless::String::new();
+    unescape(text, |c| unescaped.push(c).ok()).ok_or(ElementError::Text)?;
+    Ok(unescaped)
+}
+
+/// Decode the base64 in `text`, passing each byte to `f` until it returns
+/// `None`.
+fn decode_base64(text: EscapedStr<'_>, mut f: impl FnMut(u8) -> Option<()>) -> Option<()> {
+    let (mut bits, mut pending) = (0u32, 0u32);
+    unescape(text, |c| {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' => 62,
+            '/' => 63,
+            '=' => return Some(()),
+            _ => return None,
+        };
+        bits = (bits << 6 | value) & 0xFFFF;
+        pending += 6;
+        if pending >= 8 {
+            pending -= 8;
+            f((bits >> pending) as u8)?;
+        }
+        Some(())
+    })
+}
+
+/// Check that the embedded bitmap `qoi` starts with a QOI header, without
+/// decoding the rest of it.
+fn check_bitmap(qoi: EscapedStr<'_>) -> Result<(), ElementError> {
+    let mut header = heapless::Vec::<u8, QOI_HEADER>::new();
+    // Stops once the header is full.
+    let _ = decode_base64(qoi, |byte| header.push(byte).ok());
+    let valid = header.len() == QOI_HEADER
+        && header.starts_with(b"qoif")
+        && matches!(header[12], 3 | 4)
+        && header[13] <= 1;
+    valid.then_some(()).ok_or(ElementError::Bitmap)
+}
+
+/// Decode the embedded bitmap `qoi` into `buf`.
+fn decode_bitmap<'b>(
+    qoi: EscapedStr<'_>,
+    buf: &'b mut [u8; MAX_BITMAP],
+) -> Result<Qoi<'b>, ElementError> {
+    let mut len = 0;
+    decode_base64(qoi, |byte| {
+        *buf.get_mut(len)? = byte;
+        len += 1;
+        Some(())
+    })
+    .ok_or(ElementError::Bitmap)?;
+    Qoi::new(&buf[..len]).map_err(|_| ElementError::Bitmap)
+}
+
+impl<'a> Element<'a> {
+    fn shape(&self) -> Result<Shape<'a>, ElementError> {
+        let at = Point::new(self.x, self.y);
+        let size = || -> Result<Size, ElementError> {
+            Ok(Size::new(
+                self.width.ok_or(ElementError::Missing("width"))?,
+                self.height.ok_or(ElementError::Missing("height"))?,
+            ))
+        };
+        let kind = self.kind.ok_or(ElementError::MissingType)?;
+        // Embedded bitmaps bring their own colours.
+        if self.color == Color::Unsupported && kind != Kind::Image {
+            return Err(ElementError::Invalid("color"));
+        }
+        Ok(match kind {
+            Kind::Text => {
+                let text = self.text.ok_or(ElementError::Missing("text"))?;
+                unescape_text(text)?;
+                Shape::Text(
+                    text,
+                    builtin_font(self.font, self.size)?,
+                    self.align.try_into()?,
+                )
+            }
+            Kind::Rect => Shape::Rect(Rectangle::new(at, size()?)),
+            Kind::Line => Shape::Line(Line::new(
+                at,
+                Point::new(
+                    self.x2.ok_or(ElementError::Missing("x2"))?,
+                    self.y2.ok_or(ElementError::Missing("y2"))?,
+                ),
+            )),
+            Kind::Progress => Shape::Progress(
+                Rectangle::new(at, size()?),
+                match self.value.ok_or(ElementError::Missing("value"))? {
+                    value @ 0..=100 => value as u8,
+                    _ => return Err(ElementError::Invalid("value")),
+                },
+            ),
+            Kind::Icon => Shape::Icon(
+                Icon::from_name(self.icon.ok_or(ElementError::Missing("icon"))?)
+                    .ok_or(ElementError::UnknownIcon)?,
+                self.width.unwrap_or(ICON_SIZE),
+            ),
+            Kind::Image => {
+                let qoi = self.qoi.ok_or(ElementError::Missing("qoi"))?;
+                check_bitmap(qoi)?;
+                Shape::Image(qoi)
+            }
+            Kind::Unsupported => return Err(ElementError::UnsupportedType),
+        })
+    }
+
+    /// Draw the element as checked by [`Self::shape`].
+    fn draw<D>(
+        &self,
+        shape: Shape<'_>,
+        target: &mut D,
+        dither: dither::Method,
+        red: bool,
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = TriColor>,
+    {
+        let at = Point::new(self.x, self.y);
+        let color = self.color.into();
+        let stroke = PrimitiveStyle::with_stroke(color, self.stroke.unwrap_or(1));
+        match shape {
+            Shape::Text(text, font, align) => {
+                // Safe to unwrap: the shape was checked by unescaping it.
+                let text = unescape_text(text).unwrap();
+                // Up to the edges of the panel unless the element says otherwise.
+                let bounds = target.bounding_box();
+                let right = bounds.top_left.x + bounds.size.width as i32;
+                let bottom = bounds.top_left.y + bounds.size.height as i32;
+                let size = Size::new(
+                    self.width.unwrap_or((right - at.x).max(0) as u32),
+                    self.height.unwrap_or((bottom - at.y).max(0) as u32),
+                );
+                font::FontStyle::new(font, color).draw_wrapped(
+                    &text,
+                    Rectangle::new(at, size),
+                    align,
+                    target,
+                )?;
+            }
+            Shape::Rect(area) => {
+                let style = match self.fill {
+                    true => PrimitiveStyle::with_fill(color),
+                    false => stroke,
+                };
+                area.into_styled(style).draw(target)?;
+            }
+            Shape::Line(line) => line.into_styled(stroke).draw(target)?,
+            Shape::Progress(area, percent) => {
+                area.into_styled(PrimitiveStyle::with_stroke(color, 1))
+                    .draw(target)?;
+                let inner = area.offset(-2);
+                let level = inner.size.width * percent as u32 / 100;
+                Rectangle::new(inner.top_left, Size::new(level, inner.size.height))
+                    .into_styled(PrimitiveStyle::with_fill(color))
+                    .draw(target)?;
+            }
+            Shape::Icon(icon, size) => icon.draw(target, at, size, color)?,
+            Shape::Image(qoi) => {
+                let mut buf = [0; MAX_BITMAP];
+                // Only its header was checked; a bitmap broken past it is left
+                // out like any other element that does not check out.
+                let Ok(img) = decode_bitmap(qoi, &mut buf) else {
+                    return Ok(());
+                };
+                let img = Image::new(&img, at);
+                match red {
+                    true => img.draw(&mut dither::InkDither::new(target, dither))?,
+                    false => img.draw(
+                        &mut dither::Dither::new(
+                            &mut target.color_converted::<BinaryColor>(),
+                            dither,
+                        )
+                        .color_converted(),
+                    )?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Document<'_> {
+    /// What is wrong with which element, by index. Those elements are left
+    /// out when drawing.
+    pub fn errors(&self) -> impl Iterator<Item = (usize, ElementError)> + '_ {
+        self.elements
+            .iter()
+            .enumerate()
+            .filter_map(|(index, element)| element.shape().err().map(|error| (index, error)))
+    }
+
+    /// Draw the elements in order. `red` tells whether `target` shows red;
+    /// without it, embedded bitmaps are dithered to black and white.
+    pub fn draw<D>(&self, target: &mut D, dither: dither::Method, red: bool) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = TriColor>,
+    {
+        for element in &self.elements {
+            if let Ok(shape) = element.shape() {
+                element.draw(shape, target, dither, red)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use super::*;
+
+    /// Remembers what was drawn where, on a 32 by 16 panel.
+    #[derive(Default)]
+    struct Canvas {
+        pixels: [[Option<TriColor>; 32]; 16],
+    }
+
+    impl OriginDimensions for Canvas {
+        fn size(&self) -> Size {
+            Size::new(32, 16)
+        }
+    }
+
+    impl DrawTarget for Canvas {
+        type Color = TriColor;
+        type Error = Infallible;
+
+        fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Infallible>
+        where
+            I: IntoIterator<Item = Pixel<TriColor>>,
+        {
+            for Pixel(point, color) in pixels {
+                if let Some(pixel) = self
+                    .pixels
+                    .get_mut(point.y as usize)
+                    .and_then(|row| row.get_mut(point.x as usize))
+                {
+                    *pixel = Some(color);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reports_only_the_bad_elements() {
+        let document = parse(
+            br#"{"elements": [
+                {"type": "text", "x": 1, "y": 1, "text": "Hello"},
+                {"type": "icon", "x": 1, "y": 1, "icon": "sun"},
+                {"type": "rect", "x": 1, "y": 1, "width": 4, "height": 4},
+                {"type": "rect", "x": 1, "y": 1, "width": 4},
+                {"type": "image", "x": 1, "y": 1, "qoi": "cW9pZgAAAAIAAAABAwD+AAAA/v///wAAAAAAAAAB"},
+                {"type": "image", "x": 1, "y": 1, "qoi": "cW9pZgAAAAIAAAABBQA="},
+                {"type": "circle", "x": 1, "y": 1},
+                {"x": 1, "y": 1},
+                {"type": "rect", "x": 1, "y": 1, "width": 4, "height": 4, "color": "blue"},
+                {"type": "text", "x": 1, "y": 1, "text": "Hello", "align": "middle"},
+                {"type": "text", "x": 1, "y": 1, "text": "Hello", "font": "italic"},
+                {"type": "text", "x": 1, "y": 1, "text": "Hello", "size": "huge"},
+                {"type": "progress", "x": 1, "y": 1, "width": 4, "height": 4, "value": 150},
+                {"type": "progress", "x": 1, "y": 1, "width": 4, "height": 4, "value": -1},
+                {"type": "progress", "x": 1, "y": 1, "width": 4, "height": 4, "value": 100},
+                {"type": "image", "x": 1, "y": 1, "qoi": "cW9pZgAAAAIAAAABAwD+AAAA/v///wAAAAAAAAAB", "color": "blue"}
+            ]}"#,
+        )
+        .unwrap();
+        let errors: Vec<_> = document.errors().collect();
+        assert_eq!(
+            errors,
+            [
+                (1, ElementError::UnknownIcon),
+                (3, ElementError::Missing("height")),
+                (5, ElementError::Bitmap),
+                (6, ElementError::UnsupportedType),
+                (7, ElementError::MissingType),
+                (8, ElementError::Invalid("color")),
+                (9, ElementError::Invalid("align")),
+                (10, ElementError::Invalid("font")),
+                (11, ElementError::Invalid("size")),
+                (12, ElementError::Invalid("value")),
+                (13, ElementError::Invalid("value")),
+            ]
+        );
+    }
+
+    #[test]
+    fn draws_the_good_elements_around_a_bad_one() {
+        let document = parse(
+            br#"{"elements": [
+                {"type": "rect", "x": 0, "y": 0, "width": 2, "height": 2, "fill": true},
+                {"type": "image", "x": 10, "y": 10, "qoi": "cW9pZgAAAAIAAAABAwD+AAAA*"},
+                {"type": "icon", "x": 10, "y": 10, "icon": "sun"},
+                {"type": "image", "x": 20, "y": 10, "qoi": "cW9pZgAAAAIAAAABAwD+AAAA\/v\/\/\/wAAAAAAAAAB"},
+                {"type": "rect", "x": 4, "y": 0, "width": 2, "height": 2, "fill": true, "color": "red"}
+            ]}"#,
+        )
+        .unwrap();
+        // The broken bitmap has a good header, so only the icon is reported.
+        let errors: Vec<_> = document.errors().collect();
+        assert_eq!(errors, [(2, ElementError::UnknownIcon)]);
+
+        let mut canvas = Canvas::default();
+        document
+            .draw(&mut canvas, dither::Method::None, true)
+            .unwrap();
+        let drawn = |x: usize, y: usize| canvas.pixels[y][x];
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert_eq!(drawn(x, y), Some(TriColor::Black));
+            assert_eq!(drawn(x + 4, y), Some(TriColor::Red));
+        }
+        assert!(drawn(20, 10).is_some() && drawn(21, 10).is_some());
+        let count = canvas
+            .pixels
+            .iter()
+            .flatten()
+            .filter(|pixel| pixel.is_some())
+            .count();
+        assert_eq!(count, 4 + 4 + 2);
+    }
+}
@@ -0,0 +1,141 @@
+use core::cell::RefCell;
+use core::fmt::Write as _;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Bytes of recent log lines kept for `log tail`.
+pub const CAPACITY: usize = 4096;
+/// Longer lines are cut when kept; they are printed whole.
+const MAX_LINE: usize = 160;
+/// Filter as for the `esp_println` logger, e.g. `info` or `info,esp_radio=warn`.
+const FILTER: &str = match option_env!("ESP_LOG") {
+    Some(filter) => filter,
+    None => "info",
+};
+
+/// The latest lines, oldest first, each ending in a newline. Old lines are
+/// dropped whole to make room.
+static LINES: Mutex<CriticalSectionRawMutex, RefCell<heapless::Deque<u8, CAPACITY>>> =
+    Mutex::new(RefCell::new(heapless::Deque::new()));
+
+/// Prints like the `esp_println` logger, and keeps what it printed.
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+/// Install the logger. Call once, first thing at boot.
+pub fn init() {
+    // The most verbose level any directive asks for, so that `log` filters
+    // out the rest before it gets formatted.
+    let max = FILTER
+        .split(',')
+        .filter_map(|directive| directive.rsplit('=').next()?.trim().parse().ok())
+        .max()
+        .unwrap_or(LevelFilter::Info);
+    // Safe to unwrap: nothing else installs a logger.
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(max);
+}
+
+/// The level `FILTER` sets for `target`; the longest matching module wins.
+fn level_for(target: &str) -> LevelFilter {
+    let mut level = LevelFilter::Info;
+    let mut matched: Option<usize> = None;
+    for directive in FILTER.split(',') {
+        let (module, filter) = directive.split_once('=').unwrap_or(("", directive));
+        let Ok(filter) = filter.trim().parse() else {
+            continue;
+        };
+        let module = module.trim();
+        if target.starts_with(module) && matched.is_none_or(|len| module.len() >= len) {
+            level = filter;
+            matched = Some(module.len());
+        }
+    }
+    level
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let color = match record.level() {
+            Level::Error => "\x1b[31m",
+            Level::Warn => "\x1b[33m",
+            Level::Info => "\x1b[32m",
+            Level::Debug => "\x1b[34m",
+            Level::Trace => "\x1b[35m",
+        };
+        esp_println::println!("{color}{} - {}\x1b[0m", record.level(), record.args());
+
+        let mut line = heapless::String::<MAX_LINE>::new();
+        // Overlong lines are cut at a character boundary.
+        let _ = write!(
+            Truncating(&mut line),
+            "{} - {}",
+            record.level(),
+            record.args()
+        );
+        keep(&line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Writes what fits and drops the rest without failing, so that the start of
+/// a long message is kept.
+struct Truncating<'a, const N: usize>(&'a mut heapless::String<N>);
+
+impl<const N: usize> core::fmt::Write for Truncating<'_, N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn keep(line: &str) {
+    LINES.lock(|lines| {
+        let mut lines = lines.borrow_mut();
+        let needed = line.len() + 1;
+        while lines.capacity() - lines.len() < needed {
+            // Drop the oldest line.
+            while let Some(byte) = lines.pop_front() {
+                if byte == b'\n' {
+                    break;
+                }
+            }
+        }
+        for &byte in line.as_bytes().iter().chain(b"\n") {
+            // Safe to unwrap: room was made above.
+            lines.push_back(byte).unwrap();
+        }
+    });
+}
+
+/// Call `f` with each of the latest `count` lines, oldest first.
+pub fn tail(count: usize, mut f: impl FnMut(&str)) {
+    // Copied out so that `f` can take its time without holding the lock.
+    let mut copy = heapless::Vec::<u8, CAPACITY>::new();
+    LINES.lock(|lines| {
+        let lines = lines.borrow();
+        let (front, back) = lines.as_slices();
+        // Safe to unwrap: the copy is as large as the buffer.
+        copy.extend_from_slice(front).unwrap();
+        copy.extend_from_slice(back).unwrap();
+    });
+    let text = core::str::from_utf8(&copy).unwrap_or_default();
+    let total = text.lines().count();
+    for line in text.lines().skip(total.saturating_sub(count)) {
+        f(line);
+    }
+}
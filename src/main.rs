@@ -4,58 +4,125 @@
 #![warn(tail_expr_drop_order)]
 #![warn(clippy::large_futures)]
 
+mod api;
+mod battery;
+mod board;
+mod clock;
+mod config;
+mod console;
+mod crashlog;
+mod datetime;
+mod dhcpd;
+mod dither;
+mod dnsd;
 mod epaper;
+mod font;
 mod http;
+mod layout;
+mod logbuf;
+mod maintenance;
+mod onscreen;
+mod overlay;
+mod overrides;
+mod panel;
+mod pipeline;
+mod placement;
+mod policy;
+mod portal;
+mod qr;
+mod refresh;
+mod render;
+mod request;
+mod rtc;
+mod safemode;
+mod screens;
+mod screenshot;
+mod settings;
+mod setup;
+mod shell;
+mod sntp;
 mod status;
+mod store;
+mod timebase;
+mod uc8179;
+mod watchdog;
 mod wifi;
 
+use core::fmt::Write as _;
+use core::net::Ipv4Addr;
+use core::ops::Range;
+
 use embassy_net::Stack;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::signal::Signal;
-use embedded_config::prelude::*;
-use embedded_graphics::prelude::{DrawTargetExt, Point};
+use embedded_graphics::prelude::{DrawTargetExt, Size};
+use embedded_hal_bus::spi::ExclusiveDevice;
 
 
+use esp_hal::dma::{DmaRxBuf, DmaTxBuf};
+#[cfg(feature = "status-led")]
+use esp_hal::gpio::AnyPin;
 use esp_hal::gpio::InputConfig;
 use esp_hal::gpio::OutputConfig;
-use esp_hal::gpio::{AnyPin, Input, Level, Output, Pin as _, Pull};
+use esp_hal::gpio::{Input, Level, Output, Pull};
 use esp_hal::interrupt::software::SoftwareInterruptControl;
-use esp_hal::peripherals::{self, TIMG0};
+use esp_hal::peripherals::{self, TIMG0, TIMG1};
+#[cfg(feature = "status-led")]
 use esp_hal::rmt::Rmt;
-use esp_hal::spi::master::Spi;
-use esp_hal::timer::timg::TimerGroup;
-use esp_hal::{Blocking, rom};
+use esp_hal::spi::master::{Spi, SpiDmaBus};
+use esp_hal::timer::timg::{TimerGroup, Wdt};
 use esp_hal::{clock::CpuClock, rng::Rng};
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
 use embassy_executor::Spawner;
-use embassy_time::{Delay, Duration, Timer, WithTimeout};
+use embassy_time::{Delay, Duration, Instant, Timer, WithTimeout};
+
+use static_cell::StaticCell;
+use tinyqoi::Qoi;
 
-use esp_backtrace as _;
-use static_cell::ConstStaticCell;
+use panel::Panel;
 
 extern crate alloc;
 
 esp_bootloader_esp_idf::esp_app_desc!();
 
-const WIFI_SSID: &str = embed_config_value!("wifi.ssid");
-const WIFI_PWD: &str = embed_config_value!("wifi.password");
+const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-const MIN_RETRY_SECS: u64 = 15;
-const MAX_RETRY_SECS: u64 = 300;
+const LOW_BATTERY_SLEEP: Duration = Duration::from_secs(60 * 60);
+/// How late the next image may be before the status bar marks the shown one
+/// as stale.
+const STALE_AFTER: Duration = Duration::from_secs(5 * 60);
+/// Refreshes after which the panel is deep cleaned to clear ghosting.
+const DEEP_CLEAN_REFRESHES: u32 = 500;
+/// Deep clean at least this often, however few refreshes there were.
+const DEEP_CLEAN_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Bytes the panel SPI moves per DMA transfer; longer writes are split.
+const SPI_DMA_BUFFER: usize = 4092;
+/// Deep sleep after the setup access point timed out unused.
+const SETUP_RETRY_SLEEP: Duration = Duration::from_secs(60);
+/// How long the board's button is held down to open the setup access point.
+const SETUP_BUTTON_HOLD: Duration = Duration::from_secs(3);
 
 static STATUS_LED: Signal<CriticalSectionRawMutex, status::Status> = Signal::new();
 
-type ConcreteScreen = epaper::Screen<
-    Spi<'static, Blocking>,
-    Output<'static>,
+type ConcretePanel = panel::Selected<
+    ExclusiveDevice<SpiDmaBus<'static, Async>, Output<'static>, Delay>,
     Input<'static>,
     Output<'static>,
     Output<'static>,
-    Output<'static>,
     Delay,
 >;
+type ConcreteScreen = epaper::Screen<ConcretePanel, board::PanelPower>;
+
+/// How images and screens are drawn, as the config sets it up.
+fn layout(config: &config::Config) -> render::Layout {
+    let panel = Size::new(ConcretePanel::WIDTH, ConcretePanel::HEIGHT);
+    render::Layout::new(panel, &config.display, &config.status_bar)
+}
+
+static FRAMEBUFFER: StaticCell<<ConcretePanel as Panel>::Framebuffer> = StaticCell::new();
+static SHOWN_FRAMEBUFFER: StaticCell<<ConcretePanel as Panel>::Framebuffer> = StaticCell::new();
 
 #[derive(Debug)]
 enum BootError {
@@ -65,6 +132,21 @@ enum BootError {
     ScreenInit,
 }
 
+impl BootError {
+    /// The diagnostic to show for this error, if the screen can show it at all.
+    fn diagnostic(&self, config: &'static config::Config) -> Option<screens::Diagnostic> {
+        let reason = match self {
+            BootError::WifiConnection => "connection failed",
+            BootError::WifiConnectionTimeout => "connection timed out",
+            BootError::SpiInit | BootError::ScreenInit => return None,
+        };
+        Some(screens::Diagnostic::WifiFailed {
+            ssid: &config.wifi_ssid,
+            reason,
+        })
+    }
+}
+
 impl core::fmt::Display for BootError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
@@ -82,6 +164,22 @@ impl From<embassy_time::TimeoutError> for BootError {
     }
 }
 
+/// A failed boot, together with the screen if it came up far enough to show
+/// the failure on.
+struct BootFailure {
+    error: BootError,
+    screen: Option<ConcreteScreen>,
+}
+
+impl From<BootError> for BootFailure {
+    fn from(error: BootError) -> Self {
+        Self {
+            error,
+            screen: None,
+        }
+    }
+}
+
 struct SpiPins {
     clock_pin: Output<'static>,
     mosi_pin: Output<'static>,
@@ -91,15 +189,24 @@ struct DisplayPins {
     busy: Input<'static>,
     dc: Output<'static>,
     rst: Output<'static>,
-    pwr: Output<'static>,
+    pwr: board::PanelPower,
     cs: Output<'static>,
 }
 
 struct RudoPeripherals {
+    config: &'static config::Config,
     spi: peripherals::SPI2<'static>,
+    spi_dma: peripherals::DMA_CH0<'static>,
+    #[cfg(feature = "status-led")]
     rmt: peripherals::RMT<'static>,
     wifi: peripherals::WIFI<'static>,
+    usb: peripherals::USB_DEVICE<'static>,
+    watchdog: Wdt<TIMG1<'static>>,
+    #[cfg(feature = "battery")]
+    battery: battery::Battery,
+    #[cfg(feature = "status-led")]
     status_led_pin: AnyPin<'static>,
+    button: Input<'static>,
     spi_pins: SpiPins,
     display_pins: DisplayPins,
 }
@@ -107,69 +214,176 @@ struct RudoPeripherals {
 impl RudoPeripherals {
     fn init() -> (TimerGroup<'static, TIMG0<'static>>, peripherals::SW_INTERRUPT<'static>, Self) {
         let peripherals = esp_hal::init(esp_hal::Config::default().with_cpu_clock(CpuClock::max()));
+        rtc::init(peripherals.LPWR);
+        let pins = board::take_pins!(peripherals);
         (
             TimerGroup::new(peripherals.TIMG0),
             peripherals.SW_INTERRUPT,
             Self {
+                config: config::init(peripherals.FLASH),
                 spi: peripherals.SPI2,
+                spi_dma: peripherals.DMA_CH0,
+                #[cfg(feature = "status-led")]
                 rmt: peripherals.RMT,
                 wifi: peripherals.WIFI,
-                status_led_pin: peripherals.GPIO8.degrade(),
+                usb: peripherals.USB_DEVICE,
+                watchdog: TimerGroup::new(peripherals.TIMG1).wdt,
+                #[cfg(feature = "battery")]
+                battery: battery::Battery::new(peripherals.ADC1, pins.battery),
+                #[cfg(feature = "status-led")]
+                status_led_pin: pins.status_led,
+                button: Input::new(pins.button, InputConfig::default().with_pull(Pull::Up)),
                 spi_pins: SpiPins {
-                    clock_pin: Output::new(peripherals.GPIO19, Level::Low, OutputConfig::default()),
-                    mosi_pin: Output::new(peripherals.GPIO20, Level::Low, OutputConfig::default()),
+                    clock_pin: Output::new(pins.sck, Level::Low, OutputConfig::default()),
+                    mosi_pin: Output::new(pins.mosi, Level::Low, OutputConfig::default()),
                 },
                 display_pins: DisplayPins {
-                    cs: Output::new(peripherals.GPIO18, Level::Low, OutputConfig::default()),
-                    busy: Input::new(
-                        peripherals.GPIO23,
-                        InputConfig::default().with_pull(Pull::Up),
+                    cs: Output::new(pins.cs, Level::Low, OutputConfig::default()),
+                    busy: Input::new(pins.busy, InputConfig::default().with_pull(Pull::Up)),
+                    dc: Output::new(pins.dc, Level::Low, OutputConfig::default()),
+                    rst: Output::new(pins.rst, Level::High, OutputConfig::default()),
+                    pwr: board::PanelPower(
+                        pins.panel_power
+                            .map(|pin| Output::new(pin, Level::Low, OutputConfig::default())),
                     ),
-                    dc: Output::new(peripherals.GPIO21, Level::Low, OutputConfig::default()),
-                    rst: Output::new(peripherals.GPIO22, Level::High, OutputConfig::default()),
-                    pwr: Output::new(peripherals.GPIO10, Level::Low, OutputConfig::default()),
                 },
             },
         )
     }
 
-    async fn boot(self, spawner: &Spawner) -> Result<Rudo, BootError> {
-        // Setup the status LED for indication.
-        let rmt = Rmt::new(self.rmt, esp_hal::time::Rate::from_mhz(80)).unwrap();
-        let led = esp_hal_smartled2::Ws2812SmartLeds::<
-            { esp_hal_smartled2::buffer_size::<smart_leds::RGB8>(1) },
-            Blocking,
-        >::new(rmt.channel0, self.status_led_pin)
-        .unwrap();
-        spawner.spawn(status_led_runner(led).unwrap());
+    async fn boot(self, spawner: &Spawner) -> Result<Rudo, BootFailure> {
+        #[cfg(feature = "status-led")]
+        Self::start_status_led(spawner, self.rmt, self.status_led_pin);
         STATUS_LED.signal(status::Status::Booting);
+        console::start(spawner, self.usb, self.config);
+        spawner.spawn(setup_button(self.button).unwrap());
 
-        let rng = Rng::new();
-        let seed = (rng.random() as u64) << 32 | rng.random() as u64;
+        // The screen comes up first so that a Wi-Fi failure can be shown on it.
+        let screen = Self::init_screen(
+            self.spi,
+            self.spi_dma,
+            self.spi_pins,
+            self.display_pins,
+            &self.config.display,
+        )
+        .await?;
 
         info!("Connecting to wifi");
-        let stack = wifi::connect(
-            spawner,
-            self.wifi,
-            seed,
-            (WIFI_SSID, WIFI_PWD),
-        )
-        .with_timeout(Duration::from_secs(20))
-        .await?
-        .map_err(|_| BootError::WifiConnection)?;
+        let stack = match Self::connect_wifi(spawner, self.wifi, self.config).await {
+            Ok(stack) => stack,
+            Err(error) => {
+                return Err(BootFailure {
+                    error,
+                    screen: Some(screen),
+                });
+            }
+        };
         info!("Wifi successfully connected");
+        clock::start(spawner, stack);
 
+        Ok(Rudo {
+            screen,
+            stack,
+            watchdog: self.watchdog,
+            #[cfg(feature = "battery")]
+            battery: self.battery,
+        })
+    }
+
+    /// Open the setup access point and show how to join it. Restarts once
+    /// settings are saved, and sleeps a while if nobody uses it.
+    async fn setup(self, spawner: &Spawner) -> ! {
+        #[cfg(feature = "status-led")]
+        Self::start_status_led(spawner, self.rmt, self.status_led_pin);
+        STATUS_LED.signal(status::Status::Setup);
+        console::start(spawner, self.usb, self.config);
+
+        let name = setup::network_name(esp_hal::efuse::Efuse::mac_address());
+        let ssid = Some(self.config.wifi_ssid.as_str()).filter(|ssid| !ssid.is_empty());
+        let screen = Self::init_screen(
+            self.spi,
+            self.spi_dma,
+            self.spi_pins,
+            self.display_pins,
+            &self.config.display,
+        )
+        .await;
+        match screen {
+            Ok(mut screen) => {
+                screen.clear();
+                // Safe to unwrap: drawing into a framebuffer cannot fail.
+                screens::setup(
+                    &mut layout(self.config)
+                        .oriented()
+                        .apply(&mut screen.display().color_converted()),
+                    &device_info(Some(setup::ADDRESS)),
+                    &name,
+                    &setup::join_code(&name),
+                    setup::ADDRESS,
+                    ssid,
+                )
+                .unwrap();
+                if let Err(e) = screen.update().await {
+                    error!("Could not show the setup screen: {e}");
+                }
+                onscreen::mark_shown(None);
+            }
+            Err(e) => error!("Cannot show the setup screen: {e}"),
+        }
+
+        if setup::run(spawner, self.wifi, seed(), &name, self.config).await {
+            info!("Restarting with the new settings.");
+            safemode::reset();
+            // Let the browser receive the confirmation.
+            Timer::after_secs(1).await;
+            esp_hal::system::software_reset()
+        }
+        info!("Nobody set the device up, sleeping.");
+        STATUS_LED.signal(status::Status::Sleeping);
+        Timer::after_millis(100).await;
+        rtc::sleep_deep(SETUP_RETRY_SLEEP.into())
+    }
+
+    #[cfg(feature = "status-led")]
+    fn start_status_led(spawner: &Spawner, rmt: peripherals::RMT<'static>, pin: AnyPin<'static>) {
+        let rmt = Rmt::new(rmt, esp_hal::time::Rate::from_mhz(80)).unwrap();
+        let led = esp_hal_smartled2::Ws2812SmartLeds::<
+            { esp_hal_smartled2::buffer_size::<smart_leds::RGB8>(1) },
+            Blocking,
+        >::new(rmt.channel0, pin)
+        .unwrap();
+        spawner.spawn(status_led_runner(led).unwrap());
+    }
+
+    async fn init_screen(
+        spi: peripherals::SPI2<'static>,
+        dma: peripherals::DMA_CH0<'static>,
+        spi_pins: SpiPins,
+        display_pins: DisplayPins,
+        settings: &render::DisplaySettings,
+    ) -> Result<ConcreteScreen, BootError> {
         info!("Initializing SPI");
+        let (rx_buffer, rx_descriptors, tx_buffer, tx_descriptors) =
+            esp_hal::dma_buffers!(32, SPI_DMA_BUFFER);
+        let dma_rx = DmaRxBuf::new(rx_descriptors, rx_buffer)
+            .inspect_err(|e| debug!("SPI DMA buffer error: {e:?}"))
+            .map_err(|_| BootError::SpiInit)?;
+        let dma_tx = DmaTxBuf::new(tx_descriptors, tx_buffer)
+            .inspect_err(|e| debug!("SPI DMA buffer error: {e:?}"))
+            .map_err(|_| BootError::SpiInit)?;
         let spi = Spi::new(
-            self.spi,
+            spi,
             esp_hal::spi::master::Config::default()
                 .with_frequency(esp_hal::time::Rate::from_mhz(8))
                 .with_mode(esp_hal::spi::Mode::_0),
         )
         .inspect_err(|e| debug!("SPI init error: {e:?}"))
         .map_err(|_| BootError::SpiInit)?
-        .with_sck(self.spi_pins.clock_pin)
-        .with_mosi(self.spi_pins.mosi_pin);
+        .with_sck(spi_pins.clock_pin)
+        .with_mosi(spi_pins.mosi_pin)
+        .with_dma(dma)
+        .with_buffers(dma_rx, dma_tx)
+        .into_async();
         info!("SPI initialized");
 
         info!("Initialize e-paper screen");
@@ -179,26 +393,180 @@ impl RudoPeripherals {
             rst: rst_pin,
             pwr: pwr_pin,
             cs: cs_pin,
-        } = self.display_pins;
-        let screen = epaper::Screen::init(spi, cs_pin, busy_pin, dc_pin, rst_pin, pwr_pin, Delay)
-            .inspect_err(|e| debug!("Screen init error: {e:?}"))
+        } = display_pins;
+        let spi_device = ExclusiveDevice::new(spi, cs_pin, Delay)
+            .inspect_err(|e| debug!("SPI device error: {e:?}"))
             .map_err(|_| BootError::ScreenInit)?;
+        let buffers = epaper::Framebuffers {
+            draw: FRAMEBUFFER.init_with(Default::default),
+            shown: SHOWN_FRAMEBUFFER.init_with(Default::default),
+        };
+        let screen = epaper::Screen::init(
+            spi_device,
+            busy_pin,
+            dc_pin,
+            rst_pin,
+            pwr_pin,
+            Delay,
+            buffers,
+            settings.full_refresh_interval,
+        )
+        .await
+        .inspect_err(|e| debug!("Screen init error: {e:?}"))
+        .map_err(|_| BootError::ScreenInit)?;
         info!("e-paper screen initialized.");
+        Ok(screen)
+    }
 
-        Ok(Rudo {
-            screen,
-            stack,
-        })
+    async fn connect_wifi(
+        spawner: &Spawner,
+        wifi: peripherals::WIFI<'static>,
+        config: &config::Config,
+    ) -> Result<Stack<'static>, BootError> {
+        let credentials = (config.wifi_ssid.as_str(), config.wifi_password.as_str());
+        wifi::connect(spawner, wifi, seed(), credentials)
+            .with_timeout(overrides::policy().wifi_timeout())
+            .await?
+            .map_err(|_| BootError::WifiConnection)
     }
 }
 
 struct Rudo {
     screen: ConcreteScreen,
     stack: Stack<'static>,
+    watchdog: Wdt<TIMG1<'static>>,
+    #[cfg(feature = "battery")]
+    battery: battery::Battery,
+}
+
+/// What the fetch task needs besides the HTTP client.
+struct Fetcher {
+    config: &'static config::Config,
+    stack: Stack<'static>,
+    #[cfg(feature = "battery")]
+    battery: battery::Battery,
+}
+
+impl Fetcher {
+    /// Battery voltage in millivolts, on boards that measure it.
+    #[cfg(feature = "battery")]
+    fn battery_mv(&mut self) -> Option<u32> {
+        self.battery.millivolts()
+    }
+
+    #[cfg(not(feature = "battery"))]
+    fn battery_mv(&mut self) -> Option<u32> {
+        None
+    }
+
+    fn ip(&self) -> Option<Ipv4Addr> {
+        self.stack
+            .config_v4()
+            .map(|config| config.address.address())
+    }
+
+    /// What to show after a failed request to `url`.
+    fn request_failure(&self, url: &str, error: &http::Error) -> screens::Diagnostic {
+        if !self.stack.is_link_up() {
+            return screens::Diagnostic::WifiFailed {
+                ssid: &self.config.wifi_ssid,
+                reason: "connection lost",
+            };
+        }
+        let url = heapless::String::try_from(url).unwrap_or_default();
+        match error {
+            http::Error::Image => screens::Diagnostic::ImageDecode { url },
+            http::Error::Layout => screens::Diagnostic::LayoutDecode { url },
+            _ => screens::Diagnostic::ServerUnreachable {
+                url,
+                status: error.status_code(),
+            },
+        }
+    }
+}
+
+/// Seed for the network stack.
+fn seed() -> u64 {
+    let rng = Rng::new();
+    (rng.random() as u64) << 32 | rng.random() as u64
+}
+
+/// This device, as diagnostic screens identify it.
+fn device_info(ip: Option<Ipv4Addr>) -> screens::DeviceInfo {
+    screens::DeviceInfo {
+        mac: esp_hal::efuse::Efuse::mac_address(),
+        ip,
+    }
+}
+
+/// Replace the panel content with `diagnostic`, unless it already shows it.
+async fn show_diagnostic(
+    screen: &mut ConcreteScreen,
+    layout: &render::Layout,
+    diagnostic: &screens::Diagnostic,
+    ip: Option<Ipv4Addr>,
+) {
+    if !onscreen::is_new(diagnostic) {
+        return;
+    }
+    info!("Showing diagnostic: {diagnostic}");
+    screen.clear();
+    // Safe to unwrap: drawing into a framebuffer cannot fail.
+    diagnostic
+        .draw(
+            &mut layout
+                .oriented()
+                .apply(&mut screen.display().color_converted()),
+            &device_info(ip),
+        )
+        .unwrap();
+    let result = screen.update().await;
+    refreshed(screen, &result);
+    match result {
+        Ok(()) => onscreen::mark_shown(Some(diagnostic)),
+        Err(e) => error!("Could not show diagnostic: {e}"),
+    }
 }
 
-static IMG_BUF: ConstStaticCell<[u8; 56 << 10]> = ConstStaticCell::new([0; 56 << 10]);
+/// Keep track of what the panel shows after an update with `result`.
+fn refreshed(screen: &ConcreteScreen, result: &Result<(), epaper::Error>) {
+    if result.is_err() {
+        onscreen::set_frame_hash(None);
+        return;
+    }
+    let shot = screen.screenshot();
+    let hash = shot.hash();
+    debug!("Framebuffer hash: {hash:08x}");
+    onscreen::set_frame_hash(Some(hash));
+}
+
+/// Print the framebuffer's screenshot as hex between `BEGIN SCREENSHOT` and
+/// `END SCREENSHOT` lines, to be turned back into an image with `xxd -r -p`.
+fn dump_screenshot(screen: &ConcreteScreen) {
+    let shot = screen.screenshot();
+    esp_println::println!(
+        "BEGIN SCREENSHOT {} {:08x}",
+        shot.content_type(),
+        shot.hash()
+    );
+    let mut line = heapless::String::<64>::new();
+    shot.encode(|piece| {
+        for byte in piece {
+            // Safe to unwrap: the line is printed before it is full.
+            write!(line, "{byte:02x}").unwrap();
+            if line.len() == line.capacity() {
+                esp_println::println!("{line}");
+                line.clear();
+            }
+        }
+    });
+    if !line.is_empty() {
+        esp_println::println!("{line}");
+    }
+    esp_println::println!("END SCREENSHOT");
+}
 
+#[cfg(feature = "status-led")]
 #[embassy_executor::task]
 async fn status_led_runner(
     led: esp_hal_smartled2::Ws2812SmartLeds<'static, { esp_hal_smartled2::buffer_size::<smart_leds::RGB8>(1) }, Blocking>,
@@ -209,62 +577,442 @@ async fn status_led_runner(
     }
 }
 
+/// Restart into the Wi-Fi setup once the button is held down for
+/// [`SETUP_BUTTON_HOLD`].
+#[embassy_executor::task]
+async fn setup_button(mut button: Input<'static>) {
+    loop {
+        button.wait_for_low().await;
+        if button
+            .wait_for_high()
+            .with_timeout(SETUP_BUTTON_HOLD)
+            .await
+            .is_err()
+        {
+            info!("Button held, restarting into Wi-Fi setup.");
+            setup::request();
+            Timer::after_millis(100).await;
+            esp_hal::system::software_reset()
+        }
+    }
+}
+
+/// Notice about a crash in a previous run, drawn until the server has been told.
+fn crash_notice() -> heapless::String<128> {
+    let mut notice = heapless::String::new();
+    if let Some(crash) = crashlog::pending() {
+        let _ = write!(
+            notice,
+            "Recovered from crash at {}:{}: {}",
+            crash.file(),
+            crash.line(),
+            crash.message()
+        );
+    }
+    notice
+}
+
+/// Upload what went wrong in previous runs, now that the server is reachable.
+async fn send_reports(client: &mut http::Client<'_>, watchdog: &mut watchdog::Watch) {
+    let report = watchdog::pending_report();
+    let crash = crashlog::pending();
+    if report.is_none() && crash.is_none() {
+        return;
+    }
+    let buf = pipeline::take_slot().await;
+    let mut message = heapless::String::<512>::new();
+    if let Some(report) = report {
+        watchdog.enter(watchdog::Phase::Report);
+        let _ = write!(message, "{report}");
+        match client.send_log(buf, &message).await {
+            Ok(()) => watchdog::clear_report(),
+            Err(e) => error!("Failed to report {report}: {e}"),
+        }
+    }
+    if let Some(crash) = crash {
+        watchdog.enter(watchdog::Phase::Report);
+        message.clear();
+        let _ = write!(message, "{crash}");
+        match client.send_log(buf, &message).await {
+            Ok(()) => crashlog::clear(),
+            Err(e) => error!("Failed to report crash: {e}"),
+        }
+    }
+    pipeline::release(buf);
+}
+
+/// Log and upload what is wrong with the elements of the layout document at
+/// `range` in `slot`. The rest of the slot takes the response.
+async fn report_layout_errors(
+    client: &mut http::Client<'_>,
+    slot: &mut [u8],
+    range: Range<usize>,
+    watchdog: &mut watchdog::Watch,
+) {
+    let mut message = heapless::String::<512>::new();
+    // Safe to unwrap: the client parsed the document.
+    for (index, error) in layout::parse(&slot[range.clone()]).unwrap().errors() {
+        warn!("Layout element {index} left out: {error}");
+        if message.is_empty() {
+            let _ = write!(message, "Layout elements left out:");
+        }
+        let _ = write!(message, " {index} ({error})");
+    }
+    if message.is_empty() {
+        return;
+    }
+    watchdog.enter(watchdog::Phase::Report);
+    if let Err(e) = client.send_log(&mut slot[range.end..], &message).await {
+        error!("Failed to report layout errors: {e}");
+    }
+}
+
+/// Wait for the display task to refresh the panel and upload what it shows.
+async fn send_screenshot(client: &mut http::Client<'_>, watchdog: &mut watchdog::Watch) {
+    watchdog.enter(watchdog::Phase::Idle);
+    let capture = pipeline::capture().await;
+    if let Some(len) = capture.len {
+        info!("Uploading a screenshot of {len} bytes.");
+        watchdog.enter(watchdog::Phase::Report);
+        if let Err(e) = client
+            .send_screenshot(capture.slot, len, capture.content_type)
+            .await
+        {
+            error!("Failed to send screenshot: {e}");
+        }
+    }
+    pipeline::release(capture.slot);
+}
+
+/// Fetches what to show next and queues it for [`display_jobs`], so that the
+/// next download can start while the panel is still refreshing.
 #[embassy_executor::task]
-async fn update_screen(mut rudo: Rudo) -> ! {
-    use embedded_graphics::Drawable;
+async fn fetch_jobs(mut fetcher: Fetcher) -> ! {
     STATUS_LED.signal(status::Status::Working);
 
-    let mut client = http::Client::new(rudo.stack);
-    let buf = IMG_BUF.take();
-    let mut retry_secs = MIN_RETRY_SECS;
+    let mut client = http::Client::new(fetcher.stack, fetcher.config);
+    let mut retry = overrides::policy().min_retry();
+    let mut watchdog = watchdog::Watch::new(watchdog::Lane::Fetch);
+
+    let mut telemetry = http::Telemetry {
+        width: ConcretePanel::WIDTH,
+        height: ConcretePanel::HEIGHT,
+        frame_hash: None,
+    };
 
     info!("Ready.");
     loop {
+        let battery_mv = fetcher.battery_mv();
+        if let Some(millivolts) = battery_mv
+            && millivolts < battery::LOW_BATTERY_MV
+        {
+            warn!("Battery low: {millivolts} mV");
+            pipeline::submit(pipeline::Job::Diagnostic {
+                diagnostic: screens::Diagnostic::LowBattery {
+                    millivolts,
+                    percent: battery::percent(millivolts),
+                },
+                ip: fetcher.ip(),
+            })
+            .await;
+            STATUS_LED.signal(status::Status::Sleeping);
+            watchdog.sleep(LOW_BATTERY_SLEEP).await;
+            continue;
+        }
+
+        // Waiting for a slot means waiting for the display task, which has
+        // its own budget.
+        watchdog.enter(watchdog::Phase::Idle);
+        let slot = pipeline::take_slot().await;
+
         STATUS_LED.signal(status::Status::Working);
         info!("Fetching data for screen.");
-        let (image_url, sleep_dur) = match client.fetch_api_display(buf).await {
-            Ok(resp) => (resp.image_url, Duration::from_secs(resp.refresh_rate)),
+        watchdog.enter(watchdog::Phase::FetchApi);
+        telemetry.frame_hash = onscreen::frame_hash();
+        let (image_url, layout_url, render_mode, dither, special_function, sleep_dur) =
+            match client.fetch_api_display(slot, &telemetry).await {
+                Ok(resp) => {
+                    if let Some(utc) = client.server_time() {
+                        clock::observe_http_date(utc);
+                    }
+                    if let Some(offset) = resp.utc_offset {
+                        clock::set_utc_offset(offset);
+                    }
+                    overrides::set(&resp.policy);
+                    (
+                        resp.image_url,
+                        resp.layout_url,
+                        resp.render_mode,
+                        resp.dither,
+                        resp.special_function,
+                        Duration::from_secs(resp.refresh_rate),
+                    )
+                }
+                Err(e) => {
+                    error!("Failed to fetch from /api/display: {e:?}");
+                    pipeline::release(slot);
+                    STATUS_LED.signal(status::Status::Failure);
+                    pipeline::submit(pipeline::Job::Diagnostic {
+                        diagnostic: fetcher
+                            .request_failure(&fetcher.config.url(http::DISPLAY_PATH), &e),
+                        ip: fetcher.ip(),
+                    })
+                    .await;
+                    watchdog.sleep_until_woken(retry, &console::REFRESH).await;
+                    retry = (retry * 2).min(overrides::policy().max_retry());
+                    continue;
+                }
+                overrides::set(&resp.policy);
+                (
+                    resp.image_url,
+                    resp.layout_url,
+                    resp.render_mode,
+                    resp.dither,
+                    resp.special_function,
+                    Duration::from_secs(resp.refresh_rate),
+                )
+            }
             Err(e) => {
                 error!("Failed to fetch from /api/display: {e:?}");
+                pipeline::release(slot);
                 STATUS_LED.signal(status::Status::Failure);
-                Timer::after_secs(retry_secs).await;
-                retry_secs = (retry_secs * 2).min(MAX_RETRY_SECS);
+                pipeline::submit(pipeline::Job::Diagnostic {
+                    diagnostic: fetcher
+                        .request_failure(&fetcher.config.url(http::DISPLAY_PATH), &e),
+                    ip: fetcher.ip(),
+                })
+                .await;
+                watchdog.sleep_until_woken(retry, &console::REFRESH).await;
+                retry = (retry * 2).min(overrides::policy().max_retry());
                 continue;
             }
         };
-        info!("Got response. Continue to fetch image from: {}", image_url);
-        match client.fetch_image(buf, &image_url).await {
-            Ok(img) => {
-                rudo.screen.clear();
-                embedded_graphics::image::Image::new(&img, Point::zero())
-                    .draw(&mut rudo.screen.display().color_converted())
-                    .unwrap();
-                if let Err(e) = rudo.screen.update() {
-                    error!("Display update failed: {e:?}");
-                    STATUS_LED.signal(status::Status::Failure);
-                    Timer::after_secs(retry_secs).await;
-                    retry_secs = (retry_secs * 2).min(MAX_RETRY_SECS);
-                    continue;
+        watchdog.enter(watchdog::Phase::FetchImage);
+        let url = layout_url.as_ref().unwrap_or(&image_url);
+        let fetched = match layout_url {
+            Some(_) => {
+                info!("Got response. Continue to fetch layout from: {}", url);
+                client
+                    .fetch_layout(slot, url)
+                    .await
+                    .map(pipeline::Payload::Layout)
+            }
+            None => {
+                info!("Got response. Continue to fetch image from: {}", url);
+                client
+                    .fetch_image(slot, url)
+                    .await
+                    .map(pipeline::Payload::Image)
+            }
+        };
+        match fetched {
+            Ok(payload) => {
+                if let pipeline::Payload::Layout(range) = &payload {
+                    report_layout_errors(&mut client, slot, range.clone(), &mut watchdog).await;
+                }
+                let screenshot = special_function == api::SpecialFunction::Screenshot;
+                pipeline::submit(pipeline::Job::Image {
+                    slot,
+                    payload,
+                    render_mode,
+                    dither,
+                    notice: crash_notice(),
+                    deep_clean: special_function == api::SpecialFunction::DeepClean,
+                    screenshot,
+                    status: overlay::DeviceStatus {
+                        battery: battery_mv.map(battery::percent),
+                        rssi: wifi::rssi(),
+                        updated: clock::now(),
+                        stale: false,
+                    },
+                    refresh_rate: sleep_dur,
+                })
+                .await;
+                if screenshot {
+                    send_screenshot(&mut client, &mut watchdog).await;
                 }
             }
             Err(e) => {
                 error!("Failed to fetch and display image: {e:?}");
+                pipeline::release(slot);
                 STATUS_LED.signal(status::Status::Failure);
-                Timer::after_secs(retry_secs).await;
-                retry_secs = (retry_secs * 2).min(MAX_RETRY_SECS);
+                pipeline::submit(pipeline::Job::Diagnostic {
+                    diagnostic: fetcher.request_failure(url, &e),
+                    ip: fetcher.ip(),
+                })
+                .await;
+                watchdog.sleep_until_woken(retry, &console::REFRESH).await;
+                retry = (retry * 2).min(overrides::policy().max_retry());
                 continue;
             }
         }
-        retry_secs = MIN_RETRY_SECS;
+        retry = overrides::policy().min_retry();
+        send_reports(&mut client, &mut watchdog).await;
         info!("Going to sleep for: {} seconds", sleep_dur.as_secs());
         STATUS_LED.signal(status::Status::Sleeping);
-        Timer::after(sleep_dur).await;
+        watchdog
+            .sleep_until_woken(sleep_dur, &console::REFRESH)
+            .await;
+    }
+}
+
+/// The image the panel shows, for marking it stale when the next one is late.
+struct ShownImage {
+    status: overlay::DeviceStatus,
+    dark: bool,
+    stale_at: Instant,
+}
+
+/// Draws what [`fetch_jobs`] queues and refreshes the panel with it.
+#[embassy_executor::task]
+async fn display_jobs(mut screen: ConcreteScreen, layout: render::Layout) -> ! {
+    let mut watchdog = watchdog::Watch::new(watchdog::Lane::Display);
+    let mut shown: Option<ShownImage> = None;
+    loop {
+        watchdog.enter(watchdog::Phase::Idle);
+        let job = match shown.as_mut().filter(|image| !image.status.stale) {
+            Some(image) => match pipeline::next().with_deadline(image.stale_at).await {
+                Ok(job) => job,
+                Err(_) => {
+                    info!("Next image is late, marking the shown one stale.");
+                    image.status.stale = true;
+                    layout.draw_status_bar(
+                        &mut screen.display().color_converted(),
+                        &image.status,
+                        image.dark,
+                    );
+                    watchdog.enter(watchdog::Phase::Refresh);
+                    let result = screen.update().await;
+                    refreshed(&screen, &result);
+                    if let Err(e) = result {
+                        error!("Could not mark the image stale: {e}");
+                    }
+                    continue;
+                }
+            },
+            None => pipeline::next().await,
+        };
+        // Printing the screenshot leaves the panel as it is.
+        if !matches!(job, pipeline::Job::PrintScreenshot) {
+            shown = None;
+        }
+        match job {
+            pipeline::Job::PrintScreenshot => {
+                watchdog.enter(watchdog::Phase::Report);
+                dump_screenshot(&screen);
+            }
+            pipeline::Job::Diagnostic { diagnostic, ip } => {
+                watchdog.enter(watchdog::Phase::Refresh);
+                show_diagnostic(&mut screen, &layout, &diagnostic, ip).await;
+            }
+            pipeline::Job::TestPattern { ip } => {
+                watchdog.enter(watchdog::Phase::Refresh);
+                screen.clear();
+                // Safe to unwrap: drawing into a framebuffer cannot fail.
+                screens::test_pattern(
+                    &mut layout
+                        .oriented()
+                        .apply(&mut screen.display().color_converted()),
+                    &device_info(ip),
+                )
+                .unwrap();
+                let result = screen.update().await;
+                onscreen::mark_shown(None);
+                refreshed(&screen, &result);
+                if let Err(e) = result {
+                    error!("Could not show the test pattern: {e}");
+                }
+            }
+            pipeline::Job::Image {
+                slot,
+                payload,
+                render_mode,
+                dither,
+                notice,
+                deep_clean,
+                screenshot,
+                status: bar_status,
+                refresh_rate,
+            } => {
+                if deep_clean || maintenance::due(DEEP_CLEAN_REFRESHES, DEEP_CLEAN_INTERVAL) {
+                    watchdog.enter(watchdog::Phase::Clean);
+                    match screen.deep_clean().await {
+                        Ok(()) => maintenance::record_clean(),
+                        Err(e) => error!("Deep clean failed: {e}"),
+                    }
+                }
+                watchdog.enter(watchdog::Phase::Render);
+                let dark = match payload {
+                    pipeline::Payload::Image(image) => {
+                        // Safe to unwrap: the fetch task checked the image header.
+                        let img = Qoi::new(&slot[image]).unwrap();
+                        layout.render(&mut screen, &img, render_mode, dither, &notice, &bar_status)
+                    }
+                    pipeline::Payload::Layout(range) => {
+                        // Safe to unwrap: the fetch task parsed the document.
+                        let document = layout::parse(&slot[range]).unwrap();
+                        layout.render_document(&mut screen, &document, dither, &notice, &bar_status)
+                    }
+                };
+                // The framebuffer has the image now; the slot can take the
+                // next one, or the screenshot.
+                let kept = match screenshot {
+                    true => Some(slot),
+                    false => {
+                        pipeline::release(slot);
+                        None
+                    }
+                };
+                watchdog.enter(watchdog::Phase::Refresh);
+                let result = screen.update().await;
+                onscreen::mark_shown(None);
+                refreshed(&screen, &result);
+                if let Some(slot) = kept {
+                    let shot = screen.screenshot();
+                    let len = result.is_ok().then(|| shot.encoded_len());
+                    let capture = pipeline::Capture {
+                        slot,
+                        len,
+                        content_type: shot.content_type(),
+                    };
+                    pipeline::submit_capture(capture).await;
+                    if len.is_some() {
+                        watchdog.enter(watchdog::Phase::Report);
+                        // No upload outlasts the HTTP timeout.
+                        let timeout = overrides::policy().http_timeout();
+                        if pipeline::stream_screenshot(&shot)
+                            .with_timeout(timeout)
+                            .await
+                            .is_err()
+                        {
+                            warn!("Screenshot upload stopped taking the image, giving up on it.");
+                        }
+                    }
+                }
+                match result {
+                    Ok(()) => maintenance::record_refresh(),
+                    Err(e) => {
+                        error!("Display update failed: {e:?}");
+                        STATUS_LED.signal(status::Status::Failure);
+                    }
+                }
+                // The stale marker is drawn over what is in the framebuffer,
+                // which only works while that is what the panel shows.
+                if layout.status_bar.is_some() && screen.shows_framebuffer() {
+                    shown = Some(ShownImage {
+                        status: bar_status,
+                        dark,
+                        stale_at: Instant::now() + refresh_rate + STALE_AFTER,
+                    });
+                }
+            }
+        }
     }
 }
 
 #[esp_rtos::main]
 async fn main(spawner: Spawner) {
-    esp_println::logger::init_logger_from_env();
+    logbuf::init();
 
     esp_alloc::heap_allocator!(size: 96 << 10);
 
@@ -272,22 +1020,87 @@ async fn main(spawner: Spawner) {
     let sw_int = SoftwareInterruptControl::new(sw_interrupt);
     esp_rtos::start(timg0.timer0, sw_int.software_interrupt0);
     info!("RTOS is initialized");
+    clock::init();
+    watchdog::init();
+    crashlog::init();
+    maintenance::init();
+
+    let config = rudo.config;
+    overrides::init(config.policy);
+    if config.wifi_ssid.is_empty() || setup::take_request() {
+        info!("Starting Wi-Fi setup...");
+        rudo.setup(&spawner).await;
+    }
 
     info!("Booting...");
     match rudo.boot(&spawner).await {
-        Ok(rudo) => {
+        Ok(Rudo {
+            screen,
+            stack,
+            watchdog: wdt,
+            #[cfg(feature = "battery")]
+            battery,
+        }) => {
             info!("Boot finished.");
-            spawner.spawn(update_screen(rudo).unwrap());
-            // update_screen runs forever; main has nothing else to do.
+            safemode::record_success();
+            watchdog::start(wdt);
+            pipeline::init();
+            console::online(stack);
+            spawner.spawn(display_jobs(screen, layout(config)).unwrap());
+            spawner.spawn(
+                fetch_jobs(Fetcher {
+                    config,
+                    stack,
+                    #[cfg(feature = "battery")]
+                    battery,
+                })
+                .unwrap(),
+            );
+            // The tasks run forever; main has nothing else to do.
             core::future::pending::<()>().await;
         }
-        Err(e) => {
+        Err(BootFailure {
+            error: e,
+            mut screen,
+        }) => {
             STATUS_LED.signal(status::Status::BootFailure);
             error!("Boot failed: {e}");
-            error!("Can't continue");
-            info!("Reboot triggered.");
-            Timer::after_secs(3).await;
-            rom::software_reset();
+            let sleep = match safemode::record_failure(overrides::policy().boot_retry()) {
+                safemode::Action::Retry(delay) => {
+                    if let (Some(screen), Some(diagnostic)) =
+                        (screen.as_mut(), e.diagnostic(config))
+                    {
+                        show_diagnostic(screen, &layout(config), &diagnostic, None).await;
+                    }
+                    info!("Retrying boot in {} seconds.", delay.as_secs());
+                    delay
+                }
+                safemode::Action::SafeMode { attempts, sleep } => {
+                    error!("Boot failed {attempts} times in a row, entering safe mode.");
+                    if let Some(mut screen) = screen {
+                        screen.clear();
+                        // Safe to unwrap: drawing into a framebuffer cannot fail.
+                        screens::boot_failure(
+                            &mut layout(config)
+                                .oriented()
+                                .apply(&mut screen.display().color_converted()),
+                            &device_info(None),
+                            &e,
+                            attempts,
+                            sleep,
+                        )
+                        .unwrap();
+                        if let Err(e) = screen.update().await {
+                            error!("Could not show the boot failure: {e}");
+                        }
+                        onscreen::mark_shown(None);
+                    }
+                    sleep
+                }
+            };
+            // Give the status LED a moment to change before powering down.
+            Timer::after_millis(100).await;
+            rtc::sleep_deep(sleep.into());
         }
     }
 }
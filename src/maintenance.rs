@@ -0,0 +1,78 @@
+use embassy_time::Duration;
+use log::debug;
+
+use crate::rtc;
+
+const PERSISTED_MAGIC: u32 = 0x434c_4e31; // "CLN1"
+
+/// `[magic, refreshes since the last deep clean, RTC time of the last deep
+/// clean (low, high word), checksum]`.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PERSISTED: [u32; 5] = [0; 5];
+
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    refreshes: u32,
+    /// [`rtc::micros`] when the panel was last deep cleaned.
+    cleaned_at: u64,
+}
+
+fn load() -> Option<Record> {
+    // SAFETY: only accessed during boot and from the display task.
+    let words = unsafe { (&raw const PERSISTED).read_volatile() };
+    if words[0] != PERSISTED_MAGIC || words[4] != rtc::checksum(&words[..4]) {
+        return None;
+    }
+    Some(Record {
+        refreshes: words[1],
+        cleaned_at: (words[3] as u64) << 32 | words[2] as u64,
+    })
+}
+
+fn store(record: Record) {
+    let mut words = [
+        PERSISTED_MAGIC,
+        record.refreshes,
+        record.cleaned_at as u32,
+        (record.cleaned_at >> 32) as u32,
+        0,
+    ];
+    words[4] = rtc::checksum(&words[..4]);
+    // SAFETY: see `load`.
+    unsafe { (&raw mut PERSISTED).write_volatile(words) };
+}
+
+/// Start counting from now if nothing survived from before, e.g. after a
+/// power loss. Call once at boot.
+pub fn init() {
+    if load().is_none() {
+        record_clean();
+    }
+}
+
+/// Whether the panel is due a deep clean: after `refreshes` refreshes, or
+/// once `interval` has passed since the last one.
+pub fn due(refreshes: u32, interval: Duration) -> bool {
+    let Some(record) = load() else {
+        return false;
+    };
+    let elapsed = rtc::micros().saturating_sub(record.cleaned_at);
+    record.refreshes >= refreshes || elapsed >= interval.as_micros()
+}
+
+/// Count a refresh of the panel towards the next deep clean.
+pub fn record_refresh() {
+    if let Some(mut record) = load() {
+        record.refreshes = record.refreshes.saturating_add(1);
+        store(record);
+    }
+}
+
+/// Start counting towards the next deep clean from now.
+pub fn record_clean() {
+    debug!("Next deep clean counted from now.");
+    store(Record {
+        refreshes: 0,
+        cleaned_at: rtc::micros(),
+    });
+}
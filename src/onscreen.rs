@@ -0,0 +1,61 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use log::debug;
+
+use crate::rtc;
+use crate::screens::Diagnostic;
+
+const PERSISTED_MAGIC: u32 = 0x4449_4147; // "DIAG"
+
+/// `[magic, fingerprint of what is on the panel, checksum]`.
+///
+/// Kept in RTC memory because boot failures restart the device, and the panel
+/// keeps showing the last page through that.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut SHOWN: [u32; 3] = [0; 3];
+
+/// Hash of the framebuffer the panel shows, zero while unknown.
+static FRAME_HASH: AtomicU32 = AtomicU32::new(0);
+
+fn load() -> u32 {
+    // SAFETY: only accessed from one task at a time: `main` during boot, then
+    // the update task.
+    let words = unsafe { (&raw const SHOWN).read_volatile() };
+    if words[0] != PERSISTED_MAGIC || words[2] != rtc::checksum(&words[..2]) {
+        return 0;
+    }
+    words[1]
+}
+
+fn store(fingerprint: u32) {
+    let mut words = [PERSISTED_MAGIC, fingerprint, 0];
+    words[2] = rtc::checksum(&words[..2]);
+    // SAFETY: see `load`.
+    unsafe { (&raw mut SHOWN).write_volatile(words) };
+}
+
+/// Whether `diagnostic` differs from what the panel currently shows.
+pub fn is_new(diagnostic: &Diagnostic) -> bool {
+    let new = diagnostic.fingerprint() != load();
+    if !new {
+        debug!("Panel already shows: {diagnostic}");
+    }
+    new
+}
+
+/// Remember what the panel shows now; `None` for regular content.
+pub fn mark_shown(diagnostic: Option<&Diagnostic>) {
+    store(diagnostic.map_or(0, Diagnostic::fingerprint));
+}
+
+/// Remember the [`Screenshot::hash`](crate::screenshot::Screenshot::hash) of
+/// what the panel shows now; `None` if that is unknown.
+pub fn set_frame_hash(hash: Option<u32>) {
+    FRAME_HASH.store(hash.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Hash of what the panel shows, for telemetry.
+pub fn frame_hash() -> Option<u32> {
+    let hash = FRAME_HASH.load(Ordering::Relaxed);
+    (hash != 0).then_some(hash)
+}
@@ -0,0 +1,447 @@
+use core::fmt::Write as _;
+
+use embedded_graphics::{
+    Pixel,
+    mono_font::{
+        MonoFont, MonoTextStyle, MonoTextStyleBuilder,
+        ascii::{FONT_6X10, FONT_9X15},
+    },
+    pixelcolor::{BinaryColor, Gray8, GrayColor},
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+
+use crate::datetime::DateTime;
+use crate::epaper::{INK, PAPER};
+use crate::settings::{SWITCHES, SettingError, Value, choose, name_of};
+
+const NOTICE_HEIGHT: u32 = 14;
+
+/// Draw a one-line notice in a strip along the bottom edge of `target`.
+pub fn draw_notice<D>(target: &mut D, text: &str) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let area = target.bounding_box();
+    let strip = Rectangle::new(
+        Point::new(
+            area.top_left.x,
+            area.top_left.y + area.size.height.saturating_sub(NOTICE_HEIGHT) as i32,
+        ),
+        Size::new(area.size.width, NOTICE_HEIGHT),
+    );
+    strip
+        .into_styled(PrimitiveStyle::with_fill(INK))
+        .draw(target)?;
+    Text::with_baseline(
+        text,
+        strip.top_left + Point::new(4, 2),
+        MonoTextStyle::new(&FONT_6X10, PAPER),
+        Baseline::Top,
+    )
+    .draw(target)?;
+    Ok(())
+}
+
+/// Where the status bar goes: a strip along an edge, or a badge in a corner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Top,
+    Bottom,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BarSize {
+    #[default]
+    Small,
+    Large,
+}
+
+impl BarSize {
+    fn font(self) -> &'static MonoFont<'static> {
+        match self {
+            BarSize::Small => &FONT_6X10,
+            BarSize::Large => &FONT_9X15,
+        }
+    }
+
+    fn height(self) -> u32 {
+        match self {
+            BarSize::Small => 14,
+            BarSize::Large => 20,
+        }
+    }
+}
+
+/// Space around and between the items of the status bar.
+const BAR_PADDING: u32 = 4;
+
+/// Device state drawn over the fetched image, so that the server does not
+/// have to know about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusBar {
+    pub position: Position,
+    pub size: BarSize,
+    /// Draw the bar light on dark when the image under it is mostly dark.
+    pub invert_on_dark: bool,
+}
+
+/// Names of the status bar settings, as config keys after
+/// `overlay.status_bar.`.
+pub const SETTINGS: [&str; 3] = ["position", "size", "invert_on_dark"];
+
+const POSITIONS: [(&str, Option<Position>); 7] = [
+    ("none", None),
+    ("top", Some(Position::Top)),
+    ("bottom", Some(Position::Bottom)),
+    ("top-left", Some(Position::TopLeft)),
+    ("top-right", Some(Position::TopRight)),
+    ("bottom-left", Some(Position::BottomLeft)),
+    ("bottom-right", Some(Position::BottomRight)),
+];
+const SIZES: [(&str, BarSize); 2] = [("small", BarSize::Small), ("large", BarSize::Large)];
+
+/// The status bar as the config sets it up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BarSettings {
+    /// `None` shows fetched images untouched.
+    pub position: Option<Position>,
+    pub size: BarSize,
+    pub invert_on_dark: bool,
+}
+
+impl BarSettings {
+    /// The setting `name`, named as `set` takes it.
+    pub fn get(&self, name: &str) -> Result<Value, SettingError> {
+        Ok(Value::Name(match name {
+            "position" => name_of(&POSITIONS, self.position),
+            "size" => name_of(&SIZES, self.size),
+            "invert_on_dark" => name_of(&SWITCHES, self.invert_on_dark),
+            _ => return Err(SettingError::UnknownName),
+        }))
+    }
+
+    /// Change the setting `name` to `value`.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), SettingError> {
+        match name {
+            "position" => {
+                self.position = choose(
+                    &POSITIONS,
+                    value,
+                    "none, top, bottom, top-left, top-right, bottom-left or bottom-right",
+                )?
+            }
+            "size" => self.size = choose(&SIZES, value, "small or large")?,
+            "invert_on_dark" => self.invert_on_dark = choose(&SWITCHES, value, "true or false")?,
+            _ => return Err(SettingError::UnknownName),
+        }
+        Ok(())
+    }
+
+    /// The bar to draw, if any.
+    pub fn bar(&self) -> Option<StatusBar> {
+        self.position.map(|position| StatusBar {
+            position,
+            size: self.size,
+            invert_on_dark: self.invert_on_dark,
+        })
+    }
+}
+
+/// What the status bar shows.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeviceStatus {
+    /// Battery charge in percent.
+    pub battery: Option<u8>,
+    /// Signal strength in dBm.
+    pub rssi: Option<i32>,
+    /// Local time the image was fetched.
+    pub updated: Option<DateTime>,
+    /// The next image is overdue.
+    pub stale: bool,
+}
+
+enum Item {
+    Battery(u8),
+    Signal(u8),
+    Text(heapless::String<8>),
+}
+
+impl Item {
+    fn width(&self, size: BarSize) -> u32 {
+        let height = size.height();
+        match self {
+            // Body plus the nub.
+            Item::Battery(_) => height + 2,
+            Item::Signal(_) => 4 * signal_bar_width(height) + 3,
+            Item::Text(text) => {
+                let font = size.font();
+                text.len() as u32 * (font.character_size.width + font.character_spacing)
+            }
+        }
+    }
+
+    fn draw<D>(
+        &self,
+        target: &mut D,
+        size: BarSize,
+        at: Point,
+        colors: (BinaryColor, BinaryColor),
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let (fg, bg) = colors;
+        let height = size.height();
+        // Icons leave a margin of three pixels above and below.
+        let icon_height = height - 6;
+        match self {
+            Item::Battery(percent) => {
+                let body = Rectangle::new(at + Point::new(0, 3), Size::new(height, icon_height));
+                body.into_styled(PrimitiveStyle::with_stroke(fg, 1))
+                    .draw(target)?;
+                let nub_height = icon_height / 2;
+                Rectangle::new(
+                    at + Point::new(height as i32, (3 + (icon_height - nub_height) / 2) as i32),
+                    Size::new(2, nub_height),
+                )
+                .into_styled(PrimitiveStyle::with_fill(fg))
+                .draw(target)?;
+                let level = (height - 4) * *percent as u32 / 100;
+                Rectangle::new(
+                    body.top_left + Point::new(2, 2),
+                    Size::new(level, icon_height - 4),
+                )
+                .into_styled(PrimitiveStyle::with_fill(fg))
+                .draw(target)
+            }
+            Item::Signal(bars) => {
+                let width = signal_bar_width(height);
+                for bar in 0..4 {
+                    let bar_height = icon_height * (bar + 1) / 4;
+                    let style = match bar < *bars as u32 {
+                        true => PrimitiveStyle::with_fill(fg),
+                        false => PrimitiveStyle::with_stroke(fg, 1),
+                    };
+                    Rectangle::new(
+                        at + Point::new(
+                            (bar * (width + 1)) as i32,
+                            (3 + icon_height - bar_height) as i32,
+                        ),
+                        Size::new(width, bar_height),
+                    )
+                    .into_styled(style)
+                    .draw(target)?;
+                }
+                Ok(())
+            }
+            Item::Text(text) => {
+                let style = MonoTextStyleBuilder::new()
+                    .font(size.font())
+                    .text_color(fg)
+                    .background_color(bg)
+                    .build();
+                Text::with_baseline(
+                    text,
+                    at + Point::new(0, height as i32 / 2),
+                    style,
+                    Baseline::Middle,
+                )
+                .draw(target)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn signal_bar_width(height: u32) -> u32 {
+    (height / 5).max(2)
+}
+
+/// Bars out of four for a signal strength in dBm.
+fn signal_bars(rssi: i32) -> u8 {
+    match rssi {
+        -55.. => 4,
+        -65.. => 3,
+        -75.. => 2,
+        -85.. => 1,
+        _ => 0,
+    }
+}
+
+impl StatusBar {
+    fn items(status: &DeviceStatus) -> heapless::Vec<Item, 5> {
+        let mut items = heapless::Vec::new();
+        // Safe to unwrap: there are at most five items.
+        if let Some(percent) = status.battery {
+            let mut text = heapless::String::new();
+            let _ = write!(text, "{percent}%");
+            items.push(Item::Battery(percent)).ok().unwrap();
+            items.push(Item::Text(text)).ok().unwrap();
+        }
+        if let Some(rssi) = status.rssi {
+            items.push(Item::Signal(signal_bars(rssi))).ok().unwrap();
+        }
+        if let Some(updated) = status.updated {
+            let mut text = heapless::String::new();
+            let _ = write!(text, "{:02}:{:02}", updated.hour, updated.minute);
+            items.push(Item::Text(text)).ok().unwrap();
+        }
+        if status.stale {
+            items
+                .push(Item::Text("STALE".try_into().unwrap()))
+                .ok()
+                .unwrap();
+        }
+        items
+    }
+
+    /// The area the bar covers on a canvas of `bounds`.
+    pub fn area(&self, bounds: Rectangle, status: &DeviceStatus) -> Rectangle {
+        let height = self.size.height().min(bounds.size.height);
+        let items = Self::items(status);
+        let badge = items
+            .iter()
+            .map(|item| item.width(self.size) + BAR_PADDING)
+            .sum::<u32>()
+            + BAR_PADDING;
+        let width = match self.position {
+            Position::Top | Position::Bottom => bounds.size.width,
+            _ => badge.min(bounds.size.width),
+        };
+        let right = (bounds.size.width - width) as i32;
+        let bottom = (bounds.size.height - height) as i32;
+        let corner = match self.position {
+            Position::Top | Position::TopLeft => Point::zero(),
+            Position::TopRight => Point::new(right, 0),
+            Position::Bottom | Position::BottomLeft => Point::new(0, bottom),
+            Position::BottomRight => Point::new(right, bottom),
+        };
+        Rectangle::new(bounds.top_left + corner, Size::new(width, height))
+    }
+
+    /// Draw the bar onto `target`. `dark` tells whether the image under it is
+    /// mostly dark, see [`Darkness`].
+    pub fn draw<D>(&self, target: &mut D, status: &DeviceStatus, dark: bool) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let colors = match self.invert_on_dark && dark {
+            true => (PAPER, INK),
+            false => (INK, PAPER),
+        };
+        let area = self.area(target.bounding_box(), status);
+        area.into_styled(PrimitiveStyle::with_fill(colors.1))
+            .draw(target)?;
+        let mut clipped = target.clipped(&area);
+        let mut at = area.top_left + Point::new(BAR_PADDING as i32, 0);
+        for item in Self::items(status) {
+            item.draw(&mut clipped, self.size, at, colors)?;
+            at.x += (item.width(self.size) + BAR_PADDING) as i32;
+        }
+        Ok(())
+    }
+}
+
+/// Measures how dark what is drawn into it is inside an area. Pixels that are
+/// never drawn count as paper.
+pub struct Darkness {
+    area: Rectangle,
+    luma: u32,
+    pixels: u32,
+}
+
+impl Darkness {
+    pub fn new(area: Rectangle) -> Self {
+        Self {
+            area,
+            luma: 0,
+            pixels: 0,
+        }
+    }
+
+    /// Whether the area is darker than mid gray on average.
+    pub fn is_dark(&self) -> bool {
+        let total = self.area.size.width * self.area.size.height;
+        let undrawn = total.saturating_sub(self.pixels);
+        total > 0 && (self.luma + undrawn * 255) / total < 128
+    }
+}
+
+impl Dimensions for Darkness {
+    fn bounding_box(&self) -> Rectangle {
+        self.area
+    }
+}
+
+impl DrawTarget for Darkness {
+    type Color = Gray8;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if self.area.contains(point) {
+                self.luma += color.luma() as u32;
+                self.pixels += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_status_bar_settings() {
+        let mut settings = BarSettings::default();
+        assert_eq!(settings.bar(), None);
+        settings.set("size", "large").unwrap();
+        settings.set("invert_on_dark", "true").unwrap();
+        assert_eq!(settings.bar(), None);
+
+        settings.set("position", " Bottom-Right ").unwrap();
+        assert_eq!(
+            settings.bar(),
+            Some(StatusBar {
+                position: Position::BottomRight,
+                size: BarSize::Large,
+                invert_on_dark: true,
+            })
+        );
+        for name in SETTINGS {
+            let value = settings.get(name).unwrap();
+            let mut copy = BarSettings::default();
+            copy.set(name, &value.to_string()).unwrap();
+            assert_eq!(copy.get(name), Ok(value));
+        }
+
+        assert_eq!(
+            settings.set("position", "left"),
+            Err(SettingError::Invalid(
+                "none, top, bottom, top-left, top-right, bottom-left or bottom-right"
+            ))
+        );
+        assert_eq!(
+            settings.set("invert_on_dark", "1"),
+            Err(SettingError::Invalid("true or false"))
+        );
+        assert_eq!(
+            settings.set("colour", "red"),
+            Err(SettingError::UnknownName)
+        );
+        assert_eq!(settings.get("colour"), Err(SettingError::UnknownName));
+
+        settings.set("position", "none").unwrap();
+        assert_eq!(settings.bar(), None);
+    }
+}
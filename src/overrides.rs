@@ -0,0 +1,70 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use log::info;
+
+use crate::api::PolicyOverrides;
+use crate::policy::{self, Policy};
+use crate::rtc;
+
+/// The configured policy, before the server's overrides.
+static CONFIGURED: Mutex<CriticalSectionRawMutex, Cell<Option<Policy>>> =
+    Mutex::new(Cell::new(None));
+/// The policy in use.
+static CURRENT: Mutex<CriticalSectionRawMutex, Cell<Option<Policy>>> = Mutex::new(Cell::new(None));
+
+const PERSISTED_MAGIC: u32 = 0x504f_4c31; // "POL1"
+
+/// `[magic, overrides in the order of `policy::NAMES` with 0 for none, checksum]`.
+/// Kept for the boot-time values, which apply before the server is reached.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PERSISTED: [u32; 7] = [0; 7];
+
+fn load() -> PolicyOverrides {
+    // SAFETY: only accessed during boot and from the fetch task.
+    let words = unsafe { (&raw const PERSISTED).read_volatile() };
+    let [magic, overrides @ .., checksum] = words;
+    if magic != PERSISTED_MAGIC || checksum != rtc::checksum(&words[..6]) {
+        return PolicyOverrides::default();
+    }
+    policy::from_words(overrides)
+}
+
+fn store(overrides: &PolicyOverrides) {
+    let mut words = [PERSISTED_MAGIC, 0, 0, 0, 0, 0, 0];
+    words[1..6].copy_from_slice(&policy::to_words(overrides));
+    words[6] = rtc::checksum(&words[..6]);
+    // SAFETY: see `load`.
+    unsafe { (&raw mut PERSISTED).write_volatile(words) };
+}
+
+/// Start from `configured`, with the server's overrides from before the last
+/// reset or deep sleep. Call once at boot.
+pub fn init(configured: Policy) {
+    let policy = configured.with(&load());
+    if policy != configured {
+        info!("Using the server's policy: {policy:?}");
+    }
+    CONFIGURED.lock(|cell| cell.set(Some(configured)));
+    CURRENT.lock(|cell| cell.set(Some(policy)));
+}
+
+/// The policy in use: the configured one with the server's overrides.
+pub fn policy() -> Policy {
+    CURRENT.lock(Cell::get).unwrap_or_default()
+}
+
+/// Use what the server sets instead of the configured values, from now on and
+/// after a reset. Values it leaves out go back to the configured ones.
+pub fn set(overrides: &PolicyOverrides) {
+    // As stored, so that what is compared is what was stored last time.
+    let overrides = &policy::from_words(policy::to_words(overrides));
+    if *overrides == load() {
+        return;
+    }
+    store(overrides);
+    let configured = CONFIGURED.lock(Cell::get).unwrap_or_default();
+    let policy = configured.with(overrides);
+    info!("Server changed the policy: {policy:?}");
+    CURRENT.lock(|cell| cell.set(Some(policy)));
+}
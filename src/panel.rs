@@ -0,0 +1,249 @@
+use embedded_graphics::draw_target::DrawTarget;
+use embedded_graphics::pixelcolor::{BinaryColor, PixelColor};
+use embedded_graphics::prelude::OriginDimensions;
+use embedded_graphics::primitives::Rectangle;
+use embedded_hal::{
+    delay::DelayNs,
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use epd_waveshare::prelude::WaveshareDisplay;
+
+use crate::epaper::Error;
+
+/// The panel this firmware is built for, picked with one of the `panel-*`
+/// cargo features.
+#[cfg(feature = "panel-7in5-v2")]
+pub type Selected<SPI, BUSY, DC, RST, DELAY> = crate::uc8179::Uc8179<SPI, BUSY, DC, RST, DELAY>;
+#[cfg(feature = "panel-7in5-b-v2")]
+pub type Selected<SPI, BUSY, DC, RST, DELAY> =
+    crate::uc8179::Uc8179<SPI, BUSY, DC, RST, DELAY, crate::uc8179::Red>;
+#[cfg(feature = "panel-7in5-v1")]
+pub type Selected<SPI, BUSY, DC, RST, DELAY> =
+    epd_waveshare::epd7in5::Epd7in5<SPI, BUSY, DC, RST, DELAY>;
+#[cfg(feature = "panel-4in2")]
+pub type Selected<SPI, BUSY, DC, RST, DELAY> =
+    epd_waveshare::epd4in2::Epd4in2<SPI, BUSY, DC, RST, DELAY>;
+#[cfg(feature = "panel-2in13-v2")]
+pub type Selected<SPI, BUSY, DC, RST, DELAY> =
+    epd_waveshare::epd2in13_v2::Epd2in13<SPI, BUSY, DC, RST, DELAY>;
+
+#[cfg(not(any(
+    feature = "panel-7in5-v2",
+    feature = "panel-7in5-b-v2",
+    feature = "panel-7in5-v1",
+    feature = "panel-4in2",
+    feature = "panel-2in13-v2",
+)))]
+compile_error!("select the e-paper panel with exactly one of the `panel-*` features");
+
+/// Colours a panel can show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorDepth {
+    BlackWhite,
+    /// Black, white and two grays, see [`Panel::update_gray4`].
+    Gray4,
+    /// Black, white and red, see [`Panel::update_tricolor`].
+    TriColor,
+}
+
+impl core::fmt::Display for ColorDepth {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ColorDepth::BlackWhite => write!(f, "black/white"),
+            ColorDepth::Gray4 => write!(f, "4 gray levels"),
+            ColorDepth::TriColor => write!(f, "black/white/red"),
+        }
+    }
+}
+
+/// Raw access to a framebuffer, in the layout the controller expects.
+pub trait Frame {
+    fn bytes(&self) -> &[u8];
+    fn bytes_mut(&mut self) -> &mut [u8];
+}
+
+macro_rules! waveshare_frame {
+    ($($module:ident :: $framebuffer:ident),* $(,)?) => {
+        $(
+            impl Frame for epd_waveshare::$module::$framebuffer {
+                fn bytes(&self) -> &[u8] {
+                    self.buffer()
+                }
+
+                fn bytes_mut(&mut self) -> &mut [u8] {
+                    self.get_mut_buffer()
+                }
+            }
+        )*
+    };
+}
+
+waveshare_frame!(
+    epd7in5_v2::Display7in5,
+    epd7in5::Display7in5,
+    epd4in2::Display4in2,
+    epd2in13_v2::Display2in13,
+);
+
+/// An e-paper panel together with its controller.
+///
+/// The panel owns the BUSY, DC and RST pins; the SPI device and the delay are
+/// lent to it for each operation so that the caller can control power around
+/// them. Whether these are the blocking or the async `embedded-hal` traits is
+/// up to the driver: async drivers let other tasks run during a refresh,
+/// blocking ones hold up the executor until it is done.
+pub trait Panel: Sized {
+    type Spi;
+    type Busy;
+    type Dc: OutputPin;
+    type Rst: OutputPin;
+    type Delay;
+
+    type Color: PixelColor + From<BinaryColor>;
+    /// In-memory image in the layout the controller expects.
+    type Framebuffer: DrawTarget<Color = Self::Color, Error = core::convert::Infallible>
+        + OriginDimensions
+        + Frame
+        + Default
+        + 'static;
+
+    const NAME: &'static str;
+    const WIDTH: u32;
+    const HEIGHT: u32;
+    const COLOR_DEPTH: ColorDepth;
+    /// Whether [`Panel::update_partial`] refreshes just the given area.
+    const PARTIAL_REFRESH: bool = false;
+
+    /// Reset the controller and leave it ready for an update.
+    async fn init(
+        spi: &mut Self::Spi,
+        busy: Self::Busy,
+        dc: Self::Dc,
+        rst: Self::Rst,
+        delay: &mut Self::Delay,
+    ) -> Result<Self, Error>;
+
+    /// Bring the controller out of deep sleep and wait until it is idle.
+    async fn wake(&mut self, spi: &mut Self::Spi, delay: &mut Self::Delay) -> Result<(), Error>;
+
+    /// Send `frame` and refresh the whole panel with it.
+    async fn update(
+        &mut self,
+        spi: &mut Self::Spi,
+        frame: &Self::Framebuffer,
+        delay: &mut Self::Delay,
+    ) -> Result<(), Error>;
+
+    /// Refresh only `area` of the panel, quickly and without flashing.
+    /// `previous` is the frame the panel shows now.
+    ///
+    /// Panels that cannot do this refresh everything.
+    async fn update_partial(
+        &mut self,
+        spi: &mut Self::Spi,
+        frame: &Self::Framebuffer,
+        previous: &Self::Framebuffer,
+        area: Rectangle,
+        delay: &mut Self::Delay,
+    ) -> Result<(), Error> {
+        let _ = (previous, area);
+        self.update(spi, frame, delay).await
+    }
+
+    /// Refresh the whole panel with a four-level image given as the two bit
+    /// planes of a [`GrayFrame`](crate::epaper::GrayFrame). Only for panels
+    /// with [`ColorDepth::Gray4`].
+    async fn update_gray4(
+        &mut self,
+        spi: &mut Self::Spi,
+        planes: [&[u8]; 2],
+        delay: &mut Self::Delay,
+    ) -> Result<(), Error> {
+        let _ = (spi, planes, delay);
+        Err(Error::Unsupported)
+    }
+
+    /// Refresh the whole panel with a black, white and red image given as the
+    /// two planes of a [`TriColorFrame`](crate::epaper::TriColorFrame). Only
+    /// for panels with [`ColorDepth::TriColor`].
+    async fn update_tricolor(
+        &mut self,
+        spi: &mut Self::Spi,
+        planes: [&[u8]; 2],
+        delay: &mut Self::Delay,
+    ) -> Result<(), Error> {
+        let _ = (spi, planes, delay);
+        Err(Error::Unsupported)
+    }
+
+    /// Put the controller into deep sleep; the panel keeps its image.
+    async fn sleep(&mut self, spi: &mut Self::Spi, delay: &mut Self::Delay) -> Result<(), Error>;
+}
+
+/// Implements [`Panel`] for a driver from `epd-waveshare`. These only do full
+/// refreshes, and block while waiting for the panel.
+macro_rules! waveshare_panel {
+    ($name:literal, $module:ident :: $driver:ident, $framebuffer:ident) => {
+        impl<SPI, BUSY, DC, RST, DELAY> Panel
+            for epd_waveshare::$module::$driver<SPI, BUSY, DC, RST, DELAY>
+        where
+            SPI: SpiDevice,
+            BUSY: InputPin,
+            DC: OutputPin,
+            RST: OutputPin,
+            DELAY: DelayNs,
+        {
+            type Spi = SPI;
+            type Busy = BUSY;
+            type Dc = DC;
+            type Rst = RST;
+            type Delay = DELAY;
+
+            type Color = epd_waveshare::color::Color;
+            type Framebuffer = epd_waveshare::$module::$framebuffer;
+
+            const NAME: &'static str = $name;
+            const WIDTH: u32 = epd_waveshare::$module::WIDTH;
+            const HEIGHT: u32 = epd_waveshare::$module::HEIGHT;
+            const COLOR_DEPTH: ColorDepth = ColorDepth::BlackWhite;
+
+            async fn init(
+                spi: &mut SPI,
+                busy: BUSY,
+                dc: DC,
+                rst: RST,
+                delay: &mut DELAY,
+            ) -> Result<Self, Error> {
+                <Self as WaveshareDisplay<SPI, BUSY, DC, RST, DELAY>>::new(
+                    spi, busy, dc, rst, delay, None,
+                )
+                .map_err(|_| Error::InitScreen)
+            }
+
+            async fn wake(&mut self, spi: &mut SPI, delay: &mut DELAY) -> Result<(), Error> {
+                self.wake_up(spi, delay).map_err(|_| Error::WakeUp)?;
+                self.wait_until_idle(spi, delay)
+                    .map_err(|_| Error::BecomingReady)
+            }
+
+            async fn update(
+                &mut self,
+                spi: &mut SPI,
+                frame: &Self::Framebuffer,
+                delay: &mut DELAY,
+            ) -> Result<(), Error> {
+                self.update_and_display_frame(spi, frame.buffer(), delay)
+                    .map_err(|_| Error::UpdateScreen)
+            }
+
+            async fn sleep(&mut self, spi: &mut SPI, delay: &mut DELAY) -> Result<(), Error> {
+                WaveshareDisplay::sleep(self, spi, delay).map_err(|_| Error::Sleep)
+            }
+        }
+    };
+}
+
+waveshare_panel!("Waveshare 7.5\" V1", epd7in5::Epd7in5, Display7in5);
+waveshare_panel!("Waveshare 4.2\"", epd4in2::Epd4in2, Display4in2);
+waveshare_panel!("Waveshare 2.13\" V2", epd2in13_v2::Epd2in13, Display2in13);
@@ -0,0 +1,147 @@
+use core::net::Ipv4Addr;
+use core::ops::Range;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::pipe::Pipe;
+use embassy_time::Duration;
+use static_cell::ConstStaticCell;
+
+use crate::screenshot::Screenshot;
+use crate::{api, dither, overlay, screens};
+
+/// Largest response, JSON or image, a slot holds.
+pub const SLOT_SIZE: usize = 56 << 10;
+
+/// Buffer a response is downloaded into. Whoever holds it owns it: the fetch
+/// task while downloading, the display task while decoding.
+pub type Slot = &'static mut [u8; SLOT_SIZE];
+
+/// Something for the display task to show.
+pub enum Job {
+    Image {
+        slot: Slot,
+        /// What to draw, in `slot`.
+        payload: Payload,
+        render_mode: api::RenderMode,
+        dither: dither::Method,
+        /// Drawn over the image, unless empty.
+        notice: heapless::String<128>,
+        /// Run the ghosting clean cycle before showing the image.
+        deep_clean: bool,
+        /// Hand the slot back through [`capture`], then stream a screenshot
+        /// of the refreshed panel to the upload.
+        screenshot: bool,
+        /// For the status bar.
+        status: overlay::DeviceStatus,
+        /// When the next image is due.
+        refresh_rate: Duration,
+    },
+    Diagnostic {
+        diagnostic: screens::Diagnostic,
+        ip: Option<Ipv4Addr>,
+    },
+    /// Draw [`screens::test_pattern`], asked for on the serial console.
+    TestPattern { ip: Option<Ipv4Addr> },
+    /// Print the framebuffer to the serial console, asked for there.
+    PrintScreenshot,
+}
+
+/// What a job's slot holds, and where.
+pub enum Payload {
+    /// A QOI image.
+    Image(Range<usize>),
+    /// A layout document, see [`crate::layout`].
+    Layout(Range<usize>),
+}
+
+/// A screenshot for the fetch task to upload.
+pub struct Capture {
+    /// For the response to the upload.
+    pub slot: Slot,
+    /// Length of the encoded image [`stream_screenshot`] sends; `None` if
+    /// there is nothing to upload because the refresh failed.
+    pub len: Option<usize>,
+    /// MIME type of the encoded image.
+    pub content_type: &'static str,
+}
+
+static SLOT_A: ConstStaticCell<[u8; SLOT_SIZE]> = ConstStaticCell::new([0; SLOT_SIZE]);
+static SLOT_B: ConstStaticCell<[u8; SLOT_SIZE]> = ConstStaticCell::new([0; SLOT_SIZE]);
+
+/// Slots nobody uses. With two of them, the next image can be downloaded while
+/// the previous one is still being drawn.
+static FREE: Channel<CriticalSectionRawMutex, Slot, 2> = Channel::new();
+/// Jobs waiting for the display task. Holding just one keeps the fetch task
+/// from running ahead by more than an image.
+static READY: Channel<CriticalSectionRawMutex, Job, 1> = Channel::new();
+/// Screenshots on their way back to the fetch task.
+static CAPTURES: Channel<CriticalSectionRawMutex, Capture, 1> = Channel::new();
+/// The encoded screenshot, from the framebuffer straight into the upload, as
+/// frames are larger than a slot.
+static SCREENSHOT: Pipe<CriticalSectionRawMutex, 1024> = Pipe::new();
+
+/// Put both slots up for use. Call once, before the tasks start.
+pub fn init() {
+    for slot in [SLOT_A.take(), SLOT_B.take()] {
+        // Safe to unwrap: the channel has room for both slots.
+        FREE.try_send(slot).ok().unwrap();
+    }
+}
+
+/// Wait until a slot is free and take it.
+pub async fn take_slot() -> Slot {
+    FREE.receive().await
+}
+
+/// Hand a slot back once done with it.
+pub fn release(slot: Slot) {
+    // Safe to unwrap: there are only as many slots as the channel holds.
+    FREE.try_send(slot).ok().unwrap();
+}
+
+/// Queue `job` for the display task, waiting while it is busy with the last one.
+pub async fn submit(job: Job) {
+    READY.send(job).await;
+}
+
+/// Wait for the next job to show.
+pub async fn next() -> Job {
+    READY.receive().await
+}
+
+/// Return the slot of a job that asked for a screenshot.
+pub async fn submit_capture(capture: Capture) {
+    // Whatever an upload that failed halfway left behind.
+    SCREENSHOT.clear();
+    CAPTURES.send(capture).await;
+}
+
+/// Feed `shot` to the upload of the [`Capture`] just submitted, as fast as
+/// [`read_screenshot`] takes it.
+pub async fn stream_screenshot(shot: &Screenshot<'_>) {
+    let mut piece = [0; 256];
+    let mut offset = 0;
+    loop {
+        let len = shot.read(offset, &mut piece);
+        if len == 0 {
+            return;
+        }
+        let mut rest = &piece[..len];
+        while !rest.is_empty() {
+            let written = SCREENSHOT.write(rest).await;
+            rest = &rest[written..];
+        }
+        offset += len;
+    }
+}
+
+/// Read the next bytes of the screenshot being streamed into `buf`.
+pub async fn read_screenshot(buf: &mut [u8]) -> usize {
+    SCREENSHOT.read(buf).await
+}
+
+/// Wait for the screenshot of the job just submitted.
+pub async fn capture() -> Capture {
+    CAPTURES.receive().await
+}
@@ -0,0 +1,269 @@
+use embedded_graphics::{Pixel, prelude::*, primitives::Rectangle};
+use log::warn;
+
+/// How the panel is mounted, as the clockwise rotation of the content.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    #[default]
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Orientation {
+    /// The size content has to be to fill a panel of `panel` size.
+    const fn canvas(self, panel: Size) -> Size {
+        match self {
+            Orientation::Rotate0 | Orientation::Rotate180 => panel,
+            Orientation::Rotate90 | Orientation::Rotate270 => Size::new(panel.height, panel.width),
+        }
+    }
+}
+
+/// Maps content coordinates to panel coordinates: scale, then move, then
+/// rotate.
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    orientation: Orientation,
+    panel: Size,
+    scale: u32,
+    offset: Point,
+    /// Size of the content before scaling.
+    content: Size,
+}
+
+impl Placement {
+    /// Rotate content that fills the panel.
+    pub const fn oriented(panel: Size, orientation: Orientation) -> Self {
+        Self {
+            orientation,
+            panel,
+            scale: 1,
+            offset: Point::new(0, 0),
+            content: orientation.canvas(panel),
+        }
+    }
+
+    /// Place an image of `image` size in the middle of the panel, scaled up by
+    /// the largest whole factor that fits if `scale` is set.
+    pub fn fit(panel: Size, orientation: Orientation, image: Size, scale: bool) -> Self {
+        let canvas = orientation.canvas(panel);
+        if image != canvas {
+            warn!(
+                "Image is {}x{}, the panel {}x{}.",
+                image.width, image.height, canvas.width, canvas.height
+            );
+        }
+        let factor = match scale && image.width > 0 && image.height > 0 {
+            true => (canvas.width / image.width)
+                .min(canvas.height / image.height)
+                .max(1),
+            false => 1,
+        };
+        let scaled = image * factor;
+        if scaled.width > canvas.width || scaled.height > canvas.height {
+            warn!("Image does not fit the panel and will be clipped.");
+        }
+        // Oversized images keep their top left corner in place.
+        let offset = Point::new(
+            (canvas.width.saturating_sub(scaled.width) / 2) as i32,
+            (canvas.height.saturating_sub(scaled.height) / 2) as i32,
+        );
+        Self {
+            orientation,
+            panel,
+            scale: factor,
+            offset,
+            content: image,
+        }
+    }
+
+    /// Size of the content area before rotation.
+    pub const fn canvas(&self) -> Size {
+        self.orientation.canvas(self.panel)
+    }
+
+    /// The same placement without the rotation, for looking at content the way
+    /// it is laid out rather than the way it lands on the panel.
+    pub const fn upright(self) -> Self {
+        Self {
+            orientation: Orientation::Rotate0,
+            panel: self.canvas(),
+            ..self
+        }
+    }
+
+    fn rotate(&self, point: Point) -> Point {
+        let (w, h) = (self.panel.width as i32, self.panel.height as i32);
+        match self.orientation {
+            Orientation::Rotate0 => point,
+            Orientation::Rotate90 => Point::new(w - 1 - point.y, point.x),
+            Orientation::Rotate180 => Point::new(w - 1 - point.x, h - 1 - point.y),
+            Orientation::Rotate270 => Point::new(point.y, h - 1 - point.x),
+        }
+    }
+
+    /// The panel area covered by `area` of the content.
+    fn area(&self, area: &Rectangle) -> Option<Rectangle> {
+        let area = area.intersection(&Rectangle::new(Point::zero(), self.content));
+        let bottom_right = area.bottom_right()?;
+        let scale = self.scale as i32;
+        let top_left = self.offset + area.top_left * scale;
+        let bottom_right = self.offset + bottom_right * scale + Point::new(scale - 1, scale - 1);
+        Some(Rectangle::with_corners(
+            self.rotate(top_left),
+            self.rotate(bottom_right),
+        ))
+    }
+
+    pub fn apply<D: DrawTarget>(self, target: &mut D) -> Placed<'_, D> {
+        Placed {
+            target,
+            placement: self,
+        }
+    }
+}
+
+/// A draw target that places what is drawn on it according to a
+/// [`Placement`]. Drawing outside of the content is clipped.
+pub struct Placed<'a, D> {
+    target: &'a mut D,
+    placement: Placement,
+}
+
+impl<D: DrawTarget> Dimensions for Placed<'_, D> {
+    fn bounding_box(&self) -> Rectangle {
+        Rectangle::new(Point::zero(), self.placement.content)
+    }
+}
+
+impl<D: DrawTarget> DrawTarget for Placed<'_, D> {
+    type Color = D::Color;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let placement = self.placement;
+        let content = self.bounding_box();
+        let pixels = pixels
+            .into_iter()
+            .filter(|Pixel(point, _)| content.contains(*point));
+        if placement.scale == 1 {
+            let offset = placement.offset;
+            return self.target.draw_iter(
+                pixels.map(|Pixel(point, color)| Pixel(placement.rotate(offset + point), color)),
+            );
+        }
+        for Pixel(point, color) in pixels {
+            if let Some(block) = placement.area(&Rectangle::new(point, Size::new(1, 1))) {
+                self.target.fill_solid(&block, color)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        match self.placement.area(area) {
+            Some(area) => self.target.fill_solid(&area, color),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::{mock_display::MockDisplay, pixelcolor::BinaryColor};
+
+    use super::*;
+
+    /// Draw `pixels` of content, placed by `placement`, onto a mock display.
+    fn draw(placement: Placement, pixels: &[(i32, i32, BinaryColor)]) -> MockDisplay<BinaryColor> {
+        let mut display = MockDisplay::new();
+        let pixels = pixels
+            .iter()
+            .map(|&(x, y, color)| Pixel(Point::new(x, y), color));
+        placement.apply(&mut display).draw_iter(pixels).unwrap();
+        display
+    }
+
+    #[test]
+    fn turns_content_with_the_panel() {
+        // A 4 by 2 panel, with its first two content pixels on and off.
+        let panel = Size::new(4, 2);
+        let pixels = [(0, 0, BinaryColor::On), (1, 0, BinaryColor::Off)];
+        let expected: [(Orientation, &[&str]); 4] = [
+            (Orientation::Rotate0, &["#."]),
+            (Orientation::Rotate90, &["   #", "   ."]),
+            (Orientation::Rotate180, &["    ", "  .#"]),
+            (Orientation::Rotate270, &[".", "#"]),
+        ];
+        for (orientation, pattern) in expected {
+            let placement = Placement::oriented(panel, orientation);
+            draw(placement, &pixels).assert_pattern(pattern);
+        }
+    }
+
+    #[test]
+    fn centers_smaller_images() {
+        let placement = Placement::fit(
+            Size::new(8, 4),
+            Orientation::Rotate0,
+            Size::new(2, 2),
+            false,
+        );
+        let mut display = MockDisplay::new();
+        placement
+            .apply(&mut display)
+            .fill_solid(
+                &Rectangle::new(Point::zero(), Size::new(2, 2)),
+                BinaryColor::On,
+            )
+            .unwrap();
+        display.assert_pattern(&["     ", "   ##", "   ##"]);
+    }
+
+    #[test]
+    fn scales_smaller_images_up() {
+        let placement =
+            Placement::fit(Size::new(8, 4), Orientation::Rotate0, Size::new(2, 2), true);
+        let pixels = [
+            (0, 0, BinaryColor::On),
+            (1, 0, BinaryColor::Off),
+            (0, 1, BinaryColor::Off),
+            (1, 1, BinaryColor::On),
+        ];
+        draw(placement, &pixels).assert_pattern(&["  ##..", "  ##..", "  ..##", "  ..##"]);
+    }
+
+    #[test]
+    fn scales_before_turning() {
+        // Laid out 8 by 4 on a 4 by 8 panel.
+        let placement = Placement::fit(
+            Size::new(4, 8),
+            Orientation::Rotate90,
+            Size::new(2, 2),
+            true,
+        );
+        let pixels = [(0, 0, BinaryColor::On), (1, 1, BinaryColor::Off)];
+        draw(placement, &pixels).assert_pattern(&["    ", "    ", "  ##", "  ##", "..  ", "..  "]);
+    }
+
+    #[test]
+    fn clips_to_the_image() {
+        let placement = Placement::fit(
+            Size::new(8, 4),
+            Orientation::Rotate0,
+            Size::new(2, 2),
+            false,
+        );
+        let pixels = [
+            (-1, 0, BinaryColor::On),
+            (2, 1, BinaryColor::On),
+            (1, 1, BinaryColor::On),
+        ];
+        draw(placement, &pixels).assert_pattern(&["     ", "     ", "    #"]);
+    }
+}
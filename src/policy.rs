@@ -0,0 +1,288 @@
+use core::fmt::Display;
+
+use embassy_time::Duration;
+use log::warn;
+
+use crate::api::PolicyOverrides;
+
+/// Range a tunable is kept in, and its built-in value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds {
+    pub min: u32,
+    pub max: u32,
+    pub default: u32,
+}
+
+/// First wait after a failed update; it doubles with every failure after.
+pub const MIN_RETRY_SECS: Bounds = Bounds {
+    min: 5,
+    max: 60 * 60,
+    default: 15,
+};
+/// Longest wait between failed updates.
+pub const MAX_RETRY_SECS: Bounds = Bounds {
+    min: 30,
+    max: 24 * 60 * 60,
+    default: 300,
+};
+/// How long connecting to Wi-Fi may take at boot.
+pub const WIFI_TIMEOUT_SECS: Bounds = Bounds {
+    min: 5,
+    max: 120,
+    default: 20,
+};
+/// How long the server may take to answer a request. The watchdog budget of
+/// the fetch phases leaves room for the longest.
+pub const HTTP_TIMEOUT_SECS: Bounds = Bounds {
+    min: 5,
+    max: 60,
+    default: 45,
+};
+/// Deep sleep after the first failed boot; later ones sleep longer.
+pub const BOOT_RETRY_SECS: Bounds = Bounds {
+    min: 1,
+    max: 60,
+    default: 3,
+};
+
+/// Names of the tunables, as config keys after `policy.` and as fields of the
+/// server's `policy` object.
+pub const NAMES: [&str; 5] = [
+    "min_retry_secs",
+    "max_retry_secs",
+    "wifi_timeout_secs",
+    "http_timeout_secs",
+    "boot_retry_secs",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    UnknownName,
+    NotANumber,
+    OutOfRange(Bounds),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::UnknownName => write!(f, "unknown policy setting"),
+            Error::NotANumber => write!(f, "not a number of seconds"),
+            Error::OutOfRange(bounds) => {
+                write!(f, "must be {} to {} seconds", bounds.min, bounds.max)
+            }
+        }
+    }
+}
+
+/// `overrides` as words in the order of [`NAMES`], with 0 for none. A zero
+/// from the server is brought up to the minimum either way, and so is one.
+pub fn to_words(overrides: &PolicyOverrides) -> [u32; 5] {
+    [
+        overrides.min_retry_secs,
+        overrides.max_retry_secs,
+        overrides.wifi_timeout_secs,
+        overrides.http_timeout_secs,
+        overrides.boot_retry_secs,
+    ]
+    .map(|secs| secs.map_or(0, |secs| secs.max(1)))
+}
+
+/// The overrides [`to_words`] made `words` of.
+pub fn from_words(words: [u32; 5]) -> PolicyOverrides {
+    let [min_retry, max_retry, wifi_timeout, http_timeout, boot_retry] =
+        words.map(|word| (word != 0).then_some(word));
+    PolicyOverrides {
+        min_retry_secs: min_retry,
+        max_retry_secs: max_retry,
+        wifi_timeout_secs: wifi_timeout,
+        http_timeout_secs: http_timeout,
+        boot_retry_secs: boot_retry,
+    }
+}
+
+/// Timeouts and retry intervals, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub min_retry_secs: u32,
+    pub max_retry_secs: u32,
+    pub wifi_timeout_secs: u32,
+    pub http_timeout_secs: u32,
+    pub boot_retry_secs: u32,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            min_retry_secs: MIN_RETRY_SECS.default,
+            max_retry_secs: MAX_RETRY_SECS.default,
+            wifi_timeout_secs: WIFI_TIMEOUT_SECS.default,
+            http_timeout_secs: HTTP_TIMEOUT_SECS.default,
+            boot_retry_secs: BOOT_RETRY_SECS.default,
+        }
+    }
+}
+
+impl Policy {
+    fn field(&mut self, name: &str) -> Option<(&mut u32, Bounds)> {
+        match name {
+            "min_retry_secs" => Some((&mut self.min_retry_secs, MIN_RETRY_SECS)),
+            "max_retry_secs" => Some((&mut self.max_retry_secs, MAX_RETRY_SECS)),
+            "wifi_timeout_secs" => Some((&mut self.wifi_timeout_secs, WIFI_TIMEOUT_SECS)),
+            "http_timeout_secs" => Some((&mut self.http_timeout_secs, HTTP_TIMEOUT_SECS)),
+            "boot_retry_secs" => Some((&mut self.boot_retry_secs, BOOT_RETRY_SECS)),
+            _ => None,
+        }
+    }
+
+    /// The tunable `name`, in seconds.
+    pub fn get(&self, name: &str) -> Result<u32, Error> {
+        let mut copy = *self;
+        let (secs, _) = copy.field(name).ok_or(Error::UnknownName)?;
+        Ok(*secs)
+    }
+
+    /// Change the tunable `name` to `value` seconds, refusing values out of
+    /// its bounds.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        let (secs, bounds) = self.field(name).ok_or(Error::UnknownName)?;
+        let value = value.trim().parse().map_err(|_| Error::NotANumber)?;
+        if !(bounds.min..=bounds.max).contains(&value) {
+            return Err(Error::OutOfRange(bounds));
+        }
+        *secs = value;
+        Ok(())
+    }
+
+    /// This policy with what the server sets instead, each value brought
+    /// within its bounds.
+    pub fn with(mut self, overrides: &PolicyOverrides) -> Self {
+        let values = [
+            overrides.min_retry_secs,
+            overrides.max_retry_secs,
+            overrides.wifi_timeout_secs,
+            overrides.http_timeout_secs,
+            overrides.boot_retry_secs,
+        ];
+        for (name, value) in NAMES.into_iter().zip(values) {
+            let Some(value) = value else {
+                continue;
+            };
+            // Safe to unwrap: every name has a field.
+            let (secs, bounds) = self.field(name).unwrap();
+            *secs = value.clamp(bounds.min, bounds.max);
+            if *secs != value {
+                warn!("Server's {name} of {value} is out of bounds, using {secs}");
+            }
+        }
+        self
+    }
+
+    pub fn min_retry(&self) -> Duration {
+        Duration::from_secs(self.min_retry_secs.into())
+    }
+
+    /// Never shorter than [`Policy::min_retry`].
+    pub fn max_retry(&self) -> Duration {
+        Duration::from_secs(self.max_retry_secs.max(self.min_retry_secs).into())
+    }
+
+    pub fn wifi_timeout(&self) -> Duration {
+        Duration::from_secs(self.wifi_timeout_secs.into())
+    }
+
+    pub fn http_timeout(&self) -> Duration {
+        Duration::from_secs(self.http_timeout_secs.into())
+    }
+
+    pub fn boot_retry(&self) -> Duration {
+        Duration::from_secs(self.boot_retry_secs.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_stored_values_out_of_bounds() {
+        let mut policy = Policy::default();
+        assert_eq!(
+            policy.set("min_retry_secs", "4"),
+            Err(Error::OutOfRange(MIN_RETRY_SECS))
+        );
+        assert_eq!(
+            policy.set("http_timeout_secs", "61"),
+            Err(Error::OutOfRange(HTTP_TIMEOUT_SECS))
+        );
+        assert_eq!(
+            policy.set("wifi_timeout_secs", "-5"),
+            Err(Error::NotANumber)
+        );
+        assert_eq!(policy.set("retry_secs", "60"), Err(Error::UnknownName));
+        assert_eq!(policy, Policy::default());
+
+        policy.set("min_retry_secs", " 5 ").unwrap();
+        policy.set("http_timeout_secs", "60").unwrap();
+        assert_eq!(policy.get("min_retry_secs").unwrap(), 5);
+        assert_eq!(policy.get("http_timeout_secs").unwrap(), 60);
+    }
+
+    #[test]
+    fn clamps_the_servers_values() {
+        let configured = Policy {
+            wifi_timeout_secs: 30,
+            ..Policy::default()
+        };
+        let policy = configured.with(&PolicyOverrides {
+            min_retry_secs: Some(0),
+            max_retry_secs: Some(u32::MAX),
+            http_timeout_secs: Some(20),
+            ..PolicyOverrides::default()
+        });
+        assert_eq!(policy.min_retry_secs, MIN_RETRY_SECS.min);
+        assert_eq!(policy.max_retry_secs, MAX_RETRY_SECS.max);
+        assert_eq!(policy.http_timeout_secs, 20);
+        // Left out, so configured.
+        assert_eq!(policy.wifi_timeout_secs, 30);
+        assert_eq!(policy.boot_retry_secs, BOOT_RETRY_SECS.default);
+
+        assert_eq!(configured.with(&PolicyOverrides::default()), configured);
+    }
+
+    #[test]
+    fn keeps_a_zero_from_the_server_as_the_minimum() {
+        let overrides = PolicyOverrides {
+            min_retry_secs: Some(0),
+            boot_retry_secs: Some(0),
+            http_timeout_secs: Some(20),
+            ..PolicyOverrides::default()
+        };
+        let kept = from_words(to_words(&overrides));
+        assert_eq!(kept.min_retry_secs, Some(1));
+        assert_eq!(kept.boot_retry_secs, Some(1));
+        assert_eq!(kept.http_timeout_secs, Some(20));
+        assert_eq!(kept.max_retry_secs, None);
+        // What is kept compares equal to the same overrides sent again.
+        assert_eq!(from_words(to_words(&kept)), kept);
+        assert_eq!(
+            Policy::default().with(&kept),
+            Policy::default().with(&overrides)
+        );
+    }
+
+    #[test]
+    fn max_retry_is_never_below_min_retry() {
+        let mut policy = Policy::default();
+        policy.set("min_retry_secs", "600").unwrap();
+        policy.set("max_retry_secs", "30").unwrap();
+        assert_eq!(policy.min_retry(), Duration::from_secs(600));
+        assert_eq!(policy.max_retry(), Duration::from_secs(600));
+
+        let policy = Policy::default().with(&PolicyOverrides {
+            min_retry_secs: Some(3600),
+            max_retry_secs: Some(60),
+            ..PolicyOverrides::default()
+        });
+        assert_eq!(policy.max_retry(), policy.min_retry());
+    }
+}
@@ -0,0 +1,364 @@
+use core::fmt::{Display, Write as _};
+use core::net::Ipv4Addr;
+
+use alloc::string::String;
+use embassy_net::{
+    Stack,
+    tcp::{self, TcpSocket},
+};
+use embassy_time::Duration;
+use embedded_io_async::Write as _;
+use esp_radio::wifi::WifiController;
+use log::{debug, info, warn};
+
+use crate::config::{self, Config};
+use crate::request::{self, Request};
+use crate::wifi;
+
+const PORT: u16 = 80;
+/// How long a client may take to send its request or read the response.
+const TIMEOUT: Duration = Duration::from_secs(10);
+const SOCKET_BUFFER: usize = 1536;
+
+#[derive(Debug)]
+pub enum Error {
+    Connection,
+    BadRequest,
+}
+
+impl From<tcp::Error> for Error {
+    fn from(e: tcp::Error) -> Self {
+        debug!("Discarding TCP error details: {e:?}");
+        Self::Connection
+    }
+}
+
+impl From<tcp::AcceptError> for Error {
+    fn from(e: tcp::AcceptError) -> Self {
+        debug!("Discarding TCP accept error details: {e:?}");
+        Self::Connection
+    }
+}
+
+impl From<request::BadRequest> for Error {
+    fn from(_: request::BadRequest) -> Self {
+        Self::BadRequest
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Connection => write!(f, "connection failed"),
+            Error::BadRequest => write!(f, "malformed request"),
+        }
+    }
+}
+
+/// Serve the setup page at `address` until settings have been saved. Scans
+/// with `controller` to offer the networks in range.
+pub async fn serve(
+    stack: Stack<'_>,
+    address: Ipv4Addr,
+    controller: &mut WifiController<'static>,
+    config: &Config,
+) {
+    let mut rx_buf = [0; SOCKET_BUFFER];
+    let mut tx_buf = [0; SOCKET_BUFFER];
+    let mut request = [0; request::MAX_LEN];
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buf, &mut tx_buf);
+        socket.set_timeout(Some(TIMEOUT));
+        let saved = match handle(&mut socket, &mut request, address, controller, config).await {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!("Setup page request failed: {e}");
+                false
+            }
+        };
+        socket.close();
+        if let Err(e) = socket.flush().await {
+            debug!("Setup page connection not closed cleanly: {e:?}");
+        }
+        if saved {
+            return;
+        }
+    }
+}
+
+/// Answer one request, returning whether it saved the settings.
+async fn handle(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut [u8; request::MAX_LEN],
+    address: Ipv4Addr,
+    controller: &mut WifiController<'static>,
+    config: &Config,
+) -> Result<bool, Error> {
+    socket.accept(PORT).await?;
+    let mut len = 0;
+    while Request::parse(&buf[..len])?.is_none() {
+        let read = socket.read(&mut buf[len..]).await?;
+        if read == 0 {
+            return Err(Error::BadRequest);
+        }
+        len += read;
+    }
+    // Safe to unwrap: the loop ends on a complete request.
+    let request = Request::parse(&buf[..len])?.unwrap();
+    debug!("Setup page: {} {}", request.method, request.path);
+
+    // Whatever else a client asks for, such as an operating system checking
+    // for internet access, leads to the setup page.
+    let mut home = heapless::String::<16>::new();
+    // Safe to unwrap: any address fits.
+    write!(home, "{address}").unwrap();
+    let elsewhere = request.host.is_some_and(|host| host != home.as_str());
+    match (request.method, request.path) {
+        _ if elsewhere => {}
+        ("GET", "/") => {
+            let networks = wifi::scan(controller).await;
+            let form = Form::from_config(config);
+            let page = form_page(&form, &networks, None);
+            respond(socket, "200 OK", "", &page).await?;
+            return Ok(false);
+        }
+        ("POST", "/") => {
+            let mut form = Form::parse(request.body);
+            let error = match form.check(config).and_then(|()| form.save()) {
+                Ok(()) => {
+                    info!("Settings saved through the setup page");
+                    respond(socket, "200 OK", "", SAVED_PAGE).await?;
+                    return Ok(true);
+                }
+                Err(e) => e,
+            };
+            form.password.clear();
+            form.token.clear();
+            let networks = wifi::scan(controller).await;
+            let page = form_page(&form, &networks, Some(error));
+            respond(socket, "200 OK", "", &page).await?;
+            return Ok(false);
+        }
+        _ => {}
+    }
+    let mut location = heapless::String::<64>::new();
+    // Safe to unwrap: the header is short.
+    write!(location, "Location: http://{address}/\r\n").unwrap();
+    respond(socket, "302 Found", &location, "").await?;
+    Ok(false)
+}
+
+async fn respond(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    headers: &str,
+    body: &str,
+) -> Result<(), Error> {
+    let mut head = heapless::String::<256>::new();
+    // Safe to unwrap: the caller's headers are short.
+    write!(
+        head,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-store\r\n\
+         Connection: close\r\n\
+         {headers}\r\n",
+        body.len()
+    )
+    .unwrap();
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await?;
+    Ok(())
+}
+
+/// What the setup page asks for.
+struct Form {
+    ssid: heapless::String<32>,
+    password: heapless::String<64>,
+    server: heapless::String<96>,
+    token: heapless::String<64>,
+}
+
+impl Form {
+    /// The current settings, without the secrets.
+    fn from_config(config: &Config) -> Self {
+        Self {
+            ssid: config.wifi_ssid.clone(),
+            password: heapless::String::new(),
+            server: config.server.clone(),
+            token: heapless::String::new(),
+        }
+    }
+
+    /// Read a submitted form. Values that are too long or not UTF-8 are left
+    /// empty, for [`Form::check`] to ask for again.
+    fn parse(body: &[u8]) -> Self {
+        let mut form = Self {
+            ssid: heapless::String::new(),
+            password: heapless::String::new(),
+            server: heapless::String::new(),
+            token: heapless::String::new(),
+        };
+        let body = core::str::from_utf8(body).unwrap_or_default();
+        for field in body.split('&') {
+            let (name, value) = field.split_once('=').unwrap_or((field, ""));
+            match name {
+                "ssid" => form.ssid = decode(value).unwrap_or_default(),
+                "password" => form.password = decode(value).unwrap_or_default(),
+                "server" => form.server = decode(value).unwrap_or_default(),
+                "token" => form.token = decode(value).unwrap_or_default(),
+                _ => {}
+            }
+        }
+        form
+    }
+
+    /// Refuse settings that cannot work. An empty token keeps the current
+    /// one for the current server, and an empty password the current one for
+    /// the current network.
+    fn check(&mut self, config: &Config) -> Result<(), &'static str> {
+        if self.ssid.is_empty() {
+            return Err("Choose a network.");
+        }
+        if self.password.is_empty() && self.ssid == config.wifi_ssid {
+            self.password = config.wifi_password.clone();
+        }
+        if !self.password.is_empty() && self.password.len() < 8 {
+            return Err("A Wi-Fi password has at least 8 characters.");
+        }
+        let server = self.server.trim_end_matches('/');
+        let host = server
+            .strip_prefix("https://")
+            .or_else(|| server.strip_prefix("http://"));
+        if host.is_none_or(str::is_empty) {
+            return Err("Enter a server address starting with http:// or https://.");
+        }
+        // The token is only ever sent to the server it belongs to.
+        if self.token.is_empty() && server == config.server.as_str() {
+            self.token = config.device_id.clone();
+        }
+        if self.token.is_empty() {
+            return Err("Enter the device token.");
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), &'static str> {
+        let settings = [
+            ("wifi.ssid", self.ssid.as_str()),
+            ("wifi.password", self.password.as_str()),
+            ("trmnl.address", self.server.as_str()),
+            ("trmnl.device_id", self.token.as_str()),
+        ];
+        for (key, value) in settings {
+            config::save(key, value).map_err(|e| {
+                warn!("Cannot save {key}: {e}");
+                "The settings could not be saved."
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Decode a form value, `None` if it does not fit or is not UTF-8.
+fn decode<const N: usize>(value: &str) -> Option<heapless::String<N>> {
+    let mut bytes = heapless::Vec::<u8, N>::new();
+    let mut rest = value.as_bytes();
+    while let [byte, tail @ ..] = rest {
+        let (byte, tail) = match (byte, tail) {
+            (b'+', _) => (b' ', tail),
+            (b'%', [high, low, tail @ ..]) => {
+                let hex = [*high, *low];
+                let hex = core::str::from_utf8(&hex).ok()?;
+                (u8::from_str_radix(hex, 16).ok()?, tail)
+            }
+            _ => (*byte, tail),
+        };
+        bytes.push(byte).ok()?;
+        rest = tail;
+    }
+    heapless::String::from_utf8(bytes).ok()
+}
+
+/// `text` with the characters HTML gives meaning to escaped.
+struct Escaped<'a>(&'a str);
+
+impl Display for Escaped<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&#39;")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+const HEAD: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+<meta name=\"viewport\" content=\"width=device-width,initial-scale=1\">\
+<title>Set up Wi-Fi</title><style>\
+body{font-family:sans-serif;max-width:28em;margin:auto;padding:1em}\
+label,input,button{display:block;width:100%;box-sizing:border-box}\
+input{margin:.2em 0 1em;padding:.5em}button{padding:.7em}\
+.error{color:#b00}small{color:#555}</style></head><body>";
+
+const SAVED_PAGE: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+<meta name=\"viewport\" content=\"width=device-width,initial-scale=1\">\
+<title>Saved</title></head><body style=\"font-family:sans-serif\">\
+<h1>Saved</h1><p>The device restarts and connects to the network.</p>\
+</body></html>";
+
+fn form_page(form: &Form, networks: &[wifi::Network], error: Option<&str>) -> String {
+    let mut page = String::from(HEAD);
+    // Writing to a `String` only fails when out of memory, which aborts.
+    let _ = write!(page, "<h1>Set up Wi-Fi</h1>");
+    if let Some(error) = error {
+        let _ = write!(page, "<p class=\"error\">{}</p>", Escaped(error));
+    }
+    let _ = write!(
+        page,
+        "<form method=\"post\" action=\"/\">\
+         <label for=\"ssid\">Network</label>\
+         <input id=\"ssid\" name=\"ssid\" list=\"networks\" maxlength=\"32\" required \
+         value=\"{}\"><datalist id=\"networks\">",
+        Escaped(&form.ssid)
+    );
+    for network in networks {
+        let _ = write!(
+            page,
+            "<option value=\"{ssid}\">{ssid} ({} dBm{})</option>",
+            network.rssi,
+            if network.open { ", open" } else { "" },
+            ssid = Escaped(&network.ssid),
+        );
+    }
+    let _ = write!(
+        page,
+        "</datalist>\
+         <label for=\"password\">Password</label>\
+         <input id=\"password\" name=\"password\" type=\"password\" maxlength=\"64\">\
+         <small>Leave empty for an open network, or to keep the current password.</small>\
+         <label for=\"server\">Server</label>\
+         <input id=\"server\" name=\"server\" type=\"url\" maxlength=\"96\" required \
+         placeholder=\"https://usetrmnl.com\" value=\"{}\">\
+         <label for=\"token\">Device token</label>\
+         <input id=\"token\" name=\"token\" maxlength=\"64\">\
+         <small>Leave empty to keep the current token for the same server.</small>\
+         <p><button type=\"submit\">Save and restart</button></p></form>",
+        Escaped(&form.server)
+    );
+    if networks.is_empty() {
+        let _ = write!(
+            page,
+            "<p><small>No networks found; type the name.</small></p>"
+        );
+    }
+    page.push_str("</body></html>");
+    page
+}
@@ -0,0 +1,917 @@
+use core::fmt::Display;
+
+use embedded_graphics::{prelude::*, primitives::Rectangle};
+
+/// Largest version encoded. Version 10 has 57×57 modules and holds up to 271
+/// bytes at level L.
+pub const MAX_VERSION: u8 = 10;
+/// Modules of light border readers need around the code.
+pub const QUIET_ZONE: u32 = 4;
+
+const MAX_SIZE: usize = 17 + 4 * MAX_VERSION as usize;
+/// Codewords of version 10, the most of any version encoded.
+const MAX_CODEWORDS: usize = 346;
+/// Longest block, data and error correction, of any version and level
+/// encoded: version 9 at level L.
+const MAX_BLOCK_LEN: usize = 146;
+const MAX_BLOCKS: usize = 8;
+const MAX_ECC_LEN: usize = 30;
+
+/// Error correction codewords per block, by level and version.
+const ECC_PER_BLOCK: [[u8; MAX_VERSION as usize]; 4] = [
+    [7, 10, 15, 20, 26, 18, 20, 24, 30, 18],
+    [10, 16, 26, 18, 24, 16, 18, 22, 22, 26],
+    [13, 22, 18, 26, 18, 24, 18, 22, 20, 24],
+    [17, 28, 22, 16, 22, 28, 26, 26, 24, 28],
+];
+
+/// Blocks the codewords are split into, by level and version.
+const BLOCKS: [[u8; MAX_VERSION as usize]; 4] = [
+    [1, 1, 1, 1, 1, 2, 2, 2, 2, 4],
+    [1, 1, 1, 2, 2, 4, 4, 4, 5, 5],
+    [1, 1, 2, 2, 4, 4, 6, 6, 8, 8],
+    [1, 1, 2, 4, 4, 4, 5, 6, 8, 8],
+];
+
+/// How much of a code can be damaged and still be read, about 7, 15, 25 and
+/// 30 percent. Higher levels need a larger code for the same data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcLevel {
+    L,
+    M,
+    Q,
+    H,
+}
+
+impl EcLevel {
+    /// The level's two bits in the format information.
+    fn format_bits(self) -> u32 {
+        match self {
+            EcLevel::L => 0b01,
+            EcLevel::M => 0b00,
+            EcLevel::Q => 0b11,
+            EcLevel::H => 0b10,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The data does not fit a code of [`MAX_VERSION`] at the level asked for.
+    TooLong,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::TooLong => write!(f, "too much data for a QR code"),
+        }
+    }
+}
+
+/// Square grid of modules, a bit each.
+#[derive(Clone)]
+struct Matrix {
+    size: usize,
+    rows: [u64; MAX_SIZE],
+}
+
+impl Matrix {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            rows: [0; MAX_SIZE],
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.rows[y] >> x & 1 == 1
+    }
+
+    fn set(&mut self, x: usize, y: usize, on: bool) {
+        if on {
+            self.rows[y] |= 1 << x;
+        } else {
+            self.rows[y] &= !(1 << x);
+        }
+    }
+}
+
+/// A QR code holding bytes, in byte mode.
+#[derive(Clone)]
+pub struct QrCode {
+    version: u8,
+    modules: Matrix,
+}
+
+impl QrCode {
+    /// Encode `data` in the smallest version that holds it at `level`.
+    pub fn encode(data: &[u8], level: EcLevel) -> Result<Self, Error> {
+        let version = (1..=MAX_VERSION)
+            .find(|&version| {
+                4 + count_bits(version) + data.len() * 8 <= data_codewords(version, level) * 8
+            })
+            .ok_or(Error::TooLong)?;
+
+        let capacity = data_codewords(version, level);
+        let mut codewords = [0; MAX_CODEWORDS];
+        let mut bits = Bits {
+            buf: &mut codewords[..capacity],
+            len: 0,
+        };
+        bits.push(0b0100, 4);
+        bits.push(data.len() as u32, count_bits(version));
+        for &byte in data {
+            bits.push(byte as u32, 8);
+        }
+        let terminator = (capacity * 8 - bits.len).min(4);
+        bits.push(0, terminator);
+        let used = bits.len.div_ceil(8);
+        for (codeword, pad) in codewords[used..capacity]
+            .iter_mut()
+            .zip([0xec, 0x11].into_iter().cycle())
+        {
+            *codeword = pad;
+        }
+        let mut interleaved = [0; MAX_CODEWORDS];
+        let len = add_ecc_and_interleave(&codewords[..capacity], version, level, &mut interleaved);
+
+        let mut builder = Builder::new(version);
+        builder.draw_function_patterns(level);
+        builder.draw_codewords(&interleaved[..len]);
+        builder.apply_best_mask(level);
+        Ok(Self {
+            version,
+            modules: builder.modules,
+        })
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Modules along each side, without the quiet zone.
+    pub fn size(&self) -> u32 {
+        self.modules.size as u32
+    }
+
+    /// Whether the module in column `x` and row `y` is dark.
+    pub fn is_dark(&self, x: u32, y: u32) -> bool {
+        self.modules.get(x as usize, y as usize)
+    }
+
+    /// Width and height in pixels, quiet zone included, with modules of
+    /// `module_size` pixels.
+    pub fn pixel_size(&self, module_size: u32) -> u32 {
+        (self.size() + 2 * QUIET_ZONE) * module_size
+    }
+
+    /// Draw the code with its quiet zone, the top left corner at `top_left`.
+    pub fn draw<D>(
+        &self,
+        target: &mut D,
+        top_left: Point,
+        module_size: u32,
+        dark: D::Color,
+        light: D::Color,
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+    {
+        let side = self.pixel_size(module_size);
+        target.fill_solid(&Rectangle::new(top_left, Size::new_equal(side)), light)?;
+        let origin = top_left + Point::new_equal((QUIET_ZONE * module_size) as i32);
+        let size = self.size();
+        for y in 0..size {
+            // Draw each run of dark modules in one go.
+            let mut x = 0;
+            while x < size {
+                let start = x;
+                while x < size && self.is_dark(x, y) {
+                    x += 1;
+                }
+                if x > start {
+                    let at = Point::new((start * module_size) as i32, (y * module_size) as i32);
+                    let run = Size::new((x - start) * module_size, module_size);
+                    target.fill_solid(&Rectangle::new(origin + at, run), dark)?;
+                }
+                x += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bits of the character count in byte mode.
+fn count_bits(version: u8) -> usize {
+    if version < 10 { 8 } else { 16 }
+}
+
+fn size(version: u8) -> usize {
+    17 + 4 * version as usize
+}
+
+/// Codewords of a version, data and error correction.
+fn codewords(version: u8) -> usize {
+    let version = version as usize;
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules / 8
+}
+
+fn data_codewords(version: u8, level: EcLevel) -> usize {
+    let index = version as usize - 1;
+    codewords(version)
+        - ECC_PER_BLOCK[level as usize][index] as usize * BLOCKS[level as usize][index] as usize
+}
+
+/// Centres of the alignment patterns along either axis.
+fn alignment_positions(version: u8) -> heapless::Vec<usize, 3> {
+    let mut positions = heapless::Vec::new();
+    if version == 1 {
+        return positions;
+    }
+    let count = version as usize / 7 + 2;
+    let step = (version as usize * 8 + count * 3 + 5) / (count * 4 - 4) * 2;
+    // Safe to unwrap: up to version 13 there are at most 3 positions.
+    positions.push(6).unwrap();
+    for i in (0..count - 1).rev() {
+        positions.push(size(version) - 7 - i * step).unwrap();
+    }
+    positions
+}
+
+/// Appends bits to a buffer, most significant first.
+struct Bits<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Bits<'_> {
+    fn push(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            if value >> i & 1 == 1 {
+                self.buf[self.len / 8] |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+/// Split `data` into blocks, add the error correction to each and interleave
+/// them into `out`. Returns the number of codewords written.
+fn add_ecc_and_interleave(data: &[u8], version: u8, level: EcLevel, out: &mut [u8]) -> usize {
+    let index = version as usize - 1;
+    let blocks = BLOCKS[level as usize][index] as usize;
+    let ecc_len = ECC_PER_BLOCK[level as usize][index] as usize;
+    let total = codewords(version);
+    let short_blocks = blocks - total % blocks;
+    let short_len = total / blocks;
+    let divisor = rs_divisor(ecc_len);
+
+    // Short blocks have a gap where long ones have their last data codeword,
+    // so the error correction lines up.
+    let mut split = [[0; MAX_BLOCK_LEN + 1]; MAX_BLOCKS];
+    let mut rest = data;
+    for (i, block) in split[..blocks].iter_mut().enumerate() {
+        let data_len = short_len - ecc_len + usize::from(i >= short_blocks);
+        let (chunk, tail) = rest.split_at(data_len);
+        rest = tail;
+        block[..data_len].copy_from_slice(chunk);
+        rs_remainder(
+            chunk,
+            &divisor[..ecc_len],
+            &mut block[short_len + 1 - ecc_len..short_len + 1],
+        );
+    }
+
+    let mut len = 0;
+    for j in 0..=short_len {
+        for (i, block) in split[..blocks].iter().enumerate() {
+            if j != short_len - ecc_len || i >= short_blocks {
+                out[len] = block[j];
+                len += 1;
+            }
+        }
+    }
+    len
+}
+
+/// Multiply in GF(2⁸) with the QR code polynomial.
+fn gf_mul(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11d);
+        z ^= (y as u32 >> i & 1) * x as u32;
+    }
+    z as u8
+}
+
+/// Generator polynomial of `degree`, highest coefficient first, without the
+/// leading 1.
+fn rs_divisor(degree: usize) -> [u8; MAX_ECC_LEN] {
+    let mut divisor = [0; MAX_ECC_LEN];
+    divisor[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_mul(divisor[j], root);
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+        root = gf_mul(root, 2);
+    }
+    divisor
+}
+
+/// Error correction codewords for `data` into `out`.
+fn rs_remainder(data: &[u8], divisor: &[u8], out: &mut [u8]) {
+    out.fill(0);
+    for &byte in data {
+        let factor = byte ^ out[0];
+        out.rotate_left(1);
+        let last = out.len() - 1;
+        out[last] = 0;
+        for (codeword, &coefficient) in out.iter_mut().zip(divisor) {
+            *codeword ^= gf_mul(coefficient, factor);
+        }
+    }
+}
+
+/// Lays out the modules of a code.
+struct Builder {
+    version: u8,
+    modules: Matrix,
+    /// Modules of patterns and format information, not data.
+    function: Matrix,
+}
+
+impl Builder {
+    fn new(version: u8) -> Self {
+        Self {
+            version,
+            modules: Matrix::new(size(version)),
+            function: Matrix::new(size(version)),
+        }
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules.set(x, y, dark);
+        self.function.set(x, y, true);
+    }
+
+    fn draw_function_patterns(&mut self, level: EcLevel) {
+        let size = self.modules.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        // Finders, with their light separators.
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4..=4_i32 {
+                for dx in -4..=4_i32 {
+                    let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+                    if (0..size as i32).contains(&x) && (0..size as i32).contains(&y) {
+                        let ring = dx.abs().max(dy.abs());
+                        self.set_function(x as usize, y as usize, ring != 2 && ring != 4);
+                    }
+                }
+            }
+        }
+
+        let positions = alignment_positions(self.version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &cy) in positions.iter().enumerate() {
+            for (j, &cx) in positions.iter().enumerate() {
+                // These would overlap the finders.
+                if (i, j) == (0, 0) || (i, j) == (0, last) || (i, j) == (last, 0) {
+                    continue;
+                }
+                for dy in -2..=2_i32 {
+                    for dx in -2..=2_i32 {
+                        let ring = dx.abs().max(dy.abs());
+                        let (x, y) = ((cx as i32 + dx) as usize, (cy as i32 + dy) as usize);
+                        self.set_function(x, y, ring != 1);
+                    }
+                }
+            }
+        }
+
+        // Reserve the format information; the mask is picked later.
+        self.draw_format(level, 0);
+
+        if self.version >= 7 {
+            let mut remainder = self.version as u32;
+            for _ in 0..12 {
+                remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+            }
+            let bits = (self.version as u32) << 12 | remainder;
+            for i in 0..18 {
+                let dark = bits >> i & 1 == 1;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set_function(a, b, dark);
+                self.set_function(b, a, dark);
+            }
+        }
+    }
+
+    /// Both copies of the level and mask, and the dark module beside them.
+    fn draw_format(&mut self, level: EcLevel, mask: u8) {
+        let data = level.format_bits() << 3 | mask as u32;
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: usize| bits >> i & 1 == 1;
+
+        let size = self.modules.size;
+        for i in 0..=5 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Place the codewords in the zigzag of two module wide columns, right to
+    /// left, alternately upwards and downwards.
+    fn draw_codewords(&mut self, data: &[u8]) {
+        let size = self.modules.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        while right >= 1 {
+            // The vertical timing pattern is skipped as a whole.
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..size {
+                let y = if upward {
+                    size - 1 - vertical
+                } else {
+                    vertical
+                };
+                for x in [right, right - 1] {
+                    if !self.function.get(x, y) && i < data.len() * 8 {
+                        self.modules.set(x, y, data[i / 8] >> (7 - i % 8) & 1 == 1);
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    /// Flip the data modules the mask selects. Applying it twice undoes it.
+    fn apply_mask(&mut self, mask: u8) {
+        let size = self.modules.size;
+        for y in 0..size {
+            for x in 0..size {
+                let flip = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if flip && !self.function.get(x, y) {
+                    self.modules.set(x, y, !self.modules.get(x, y));
+                }
+            }
+        }
+    }
+
+    /// Apply the mask that leaves the fewest patterns readers trip over.
+    fn apply_best_mask(&mut self, level: EcLevel) {
+        let mut best = (u32::MAX, 0);
+        for mask in 0..8 {
+            self.apply_mask(mask);
+            self.draw_format(level, mask);
+            best = best.min((self.penalty(), mask));
+            self.apply_mask(mask);
+        }
+        self.apply_mask(best.1);
+        self.draw_format(level, best.1);
+    }
+
+    fn penalty(&self) -> u32 {
+        let size = self.modules.size;
+        let modules = &self.modules;
+        let mut penalty = 0;
+        for transposed in [false, true] {
+            let get = |a: usize, b: usize| {
+                if transposed {
+                    modules.get(b, a)
+                } else {
+                    modules.get(a, b)
+                }
+            };
+            for b in 0..size {
+                // Runs of five or more modules of the same colour.
+                let mut run = 1;
+                for a in 1..=size {
+                    if a < size && get(a, b) == get(a - 1, b) {
+                        run += 1;
+                        continue;
+                    }
+                    if run >= 5 {
+                        penalty += run - 2;
+                    }
+                    run = 1;
+                }
+                // Look-alikes of the finder pattern, with four light modules
+                // on either side; beyond the edge counts as light.
+                for start in -4..size as i32 - 6 {
+                    let dark = |offset: i32| {
+                        let a = start + offset;
+                        (0..size as i32).contains(&a) && get(a as usize, b)
+                    };
+                    let core = (0..7).all(|offset| dark(offset) == (offset != 1 && offset != 5));
+                    if core
+                        && ((-4..0).all(|offset| !dark(offset))
+                            || (7..11).all(|offset| !dark(offset)))
+                    {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+
+        let mut dark = 0;
+        for y in 0..size {
+            for x in 0..size {
+                let color = modules.get(x, y);
+                dark += u32::from(color);
+                if x + 1 < size
+                    && y + 1 < size
+                    && color == modules.get(x + 1, y)
+                    && color == modules.get(x, y + 1)
+                    && color == modules.get(x + 1, y + 1)
+                {
+                    penalty += 3;
+                }
+            }
+        }
+        // Every 5% the dark share is away from half.
+        let total = (size * size) as u32;
+        let k = (dark * 20)
+            .abs_diff(total * 10)
+            .div_ceil(total)
+            .saturating_sub(1);
+        penalty + k * 10
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::mock_display::MockDisplay;
+    use embedded_graphics::pixelcolor::BinaryColor;
+
+    use super::*;
+
+    /// Reads a code back the way a scanner would, from its modules alone.
+    struct Decoder<'a> {
+        size: usize,
+        dark: &'a dyn Fn(usize, usize) -> bool,
+    }
+
+    /// Format information for `data`, by polynomial division.
+    fn format_word(data: u32) -> u32 {
+        let mut value = data << 10;
+        for i in (10..15).rev() {
+            if value >> i & 1 == 1 {
+                value ^= 0x537 << (i - 10);
+            }
+        }
+        (data << 10 | value) ^ 0x5412
+    }
+
+    const ALIGNMENTS: [&[usize]; 10] = [
+        &[],
+        &[6, 18],
+        &[6, 22],
+        &[6, 26],
+        &[6, 30],
+        &[6, 34],
+        &[6, 22, 38],
+        &[6, 24, 42],
+        &[6, 26, 46],
+        &[6, 28, 50],
+    ];
+
+    impl Decoder<'_> {
+        fn version(&self) -> u8 {
+            ((self.size - 17) / 4) as u8
+        }
+
+        fn is_function(&self, x: usize, y: usize) -> bool {
+            let far = self.size - 8;
+            let alignments = ALIGNMENTS[self.version() as usize - 1];
+            let near_alignment = alignments.iter().any(|&ay| {
+                alignments.iter().any(|&ax| {
+                    let on_finder = (ax == 6 && (ay == 6 || ay == self.size - 7))
+                        || (ax == self.size - 7 && ay == 6);
+                    !on_finder && x.abs_diff(ax) <= 2 && y.abs_diff(ay) <= 2
+                })
+            });
+            let version_block = self.version() >= 7
+                && ((x < 6 && y >= self.size - 11 && y < self.size - 8)
+                    || (y < 6 && x >= self.size - 11 && x < self.size - 8));
+            (y <= 8 && (x <= 8 || x >= far))
+                || (x <= 8 && y >= far)
+                || x == 6
+                || y == 6
+                || near_alignment
+                || version_block
+        }
+
+        fn format(&self) -> (u32, u8) {
+            let mut bits = 0;
+            let positions = (0..6)
+                .map(|i| (8, i))
+                .chain([(8, 7), (8, 8), (7, 8)])
+                .chain((9..15).map(|i| (14 - i, 8)));
+            for (i, (x, y)) in positions.enumerate() {
+                bits |= u32::from((self.dark)(x, y)) << i;
+            }
+            let data = (0..32)
+                .find(|&data| format_word(data) == bits)
+                .expect("format information does not decode");
+            (data >> 3, (data & 7) as u8)
+        }
+
+        fn masked(mask: u8, x: usize, y: usize) -> bool {
+            let (i, j) = (y, x);
+            match mask {
+                0 => (i + j) % 2 == 0,
+                1 => i % 2 == 0,
+                2 => j % 3 == 0,
+                3 => (i + j) % 3 == 0,
+                4 => (i / 2 + j / 3) % 2 == 0,
+                5 => (i * j) % 2 + (i * j) % 3 == 0,
+                6 => ((i * j) % 2 + (i * j) % 3) % 2 == 0,
+                _ => ((i + j) % 2 + (i * j) % 3) % 2 == 0,
+            }
+        }
+
+        fn codewords(&self, mask: u8) -> Vec<u8> {
+            let mut bits = Vec::new();
+            let mut column = self.size as i32 - 1;
+            let mut upward = true;
+            while column > 0 {
+                if column == 6 {
+                    column -= 1;
+                }
+                let rows: Vec<usize> = if upward {
+                    (0..self.size).rev().collect()
+                } else {
+                    (0..self.size).collect()
+                };
+                for y in rows {
+                    for x in [column as usize, column as usize - 1] {
+                        if !self.is_function(x, y) {
+                            bits.push((self.dark)(x, y) ^ Self::masked(mask, x, y));
+                        }
+                    }
+                }
+                upward = !upward;
+                column -= 2;
+            }
+            bits.chunks_exact(8)
+                .map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | u8::from(bit)))
+                .collect()
+        }
+
+        fn decode(&self) -> Vec<u8> {
+            assert!((21..=57).contains(&self.size) && (self.size - 17).is_multiple_of(4));
+            let version = self.version();
+            for (cx, cy) in [(3, 3), (self.size - 4, 3), (3, self.size - 4)] {
+                assert!((self.dark)(cx, cy) && !(self.dark)(cx + 2, cy) && (self.dark)(cx + 3, cy));
+            }
+            if version >= 7 {
+                let mut bits = 0;
+                for i in 0..18 {
+                    bits |= u32::from((self.dark)(self.size - 11 + i % 3, i / 3)) << i;
+                }
+                assert_eq!(bits >> 12, version as u32);
+            }
+            let (level_bits, mask) = self.format();
+            let level = [EcLevel::M, EcLevel::L, EcLevel::H, EcLevel::Q][level_bits as usize];
+
+            let index = version as usize - 1;
+            let blocks = BLOCKS[level as usize][index] as usize;
+            let ecc_len = ECC_PER_BLOCK[level as usize][index] as usize;
+            let codewords = self.codewords(mask);
+            let total = codewords.len();
+            let short_len = total / blocks;
+            let short_blocks = blocks - total % blocks;
+
+            let mut split = vec![Vec::new(); blocks];
+            let mut next = codewords.iter();
+            for j in 0..=short_len {
+                for (i, block) in split.iter_mut().enumerate() {
+                    if j != short_len - ecc_len || i >= short_blocks {
+                        block.push(*next.next().unwrap());
+                    }
+                }
+            }
+
+            let mut data = Vec::new();
+            for block in &split {
+                assert!(syndromes_zero(block, ecc_len), "block does not check out");
+                data.extend_from_slice(&block[..block.len() - ecc_len]);
+            }
+
+            let mut reader = BitReader { data: &data, at: 0 };
+            assert_eq!(reader.read(4), 0b0100, "not byte mode");
+            let len = reader.read(if version < 10 { 8 } else { 16 });
+            (0..len).map(|_| reader.read(8) as u8).collect()
+        }
+    }
+
+    /// Whether `block` is a Reed-Solomon code word, with roots α⁰ to αⁿ⁻¹.
+    fn syndromes_zero(block: &[u8], ecc_len: usize) -> bool {
+        let mut exp = [0u8; 255];
+        let mut value = 1u16;
+        for e in &mut exp {
+            *e = value as u8;
+            value <<= 1;
+            if value & 0x100 != 0 {
+                value ^= 0x11d;
+            }
+        }
+        (0..ecc_len).all(|i| {
+            block.iter().fold(0u8, |acc, &codeword| {
+                let scaled = if acc == 0 {
+                    0
+                } else {
+                    let log = exp.iter().position(|&e| e == acc).unwrap();
+                    exp[(log + i) % 255]
+                };
+                scaled ^ codeword
+            }) == 0
+        })
+    }
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        at: usize,
+    }
+
+    impl BitReader<'_> {
+        fn read(&mut self, count: usize) -> u32 {
+            let mut value = 0;
+            for _ in 0..count {
+                let bit = self.data[self.at / 8] >> (7 - self.at % 8) & 1;
+                value = value << 1 | bit as u32;
+                self.at += 1;
+            }
+            value
+        }
+    }
+
+    fn decode(code: &QrCode) -> Vec<u8> {
+        let dark = |x: usize, y: usize| code.is_dark(x as u32, y as u32);
+        Decoder {
+            size: code.size() as usize,
+            dark: &dark,
+        }
+        .decode()
+    }
+
+    const LEVELS: [EcLevel; 4] = [EcLevel::L, EcLevel::M, EcLevel::Q, EcLevel::H];
+
+    #[test]
+    fn round_trips() {
+        let inputs: [&[u8]; 5] = [
+            b"",
+            b"A",
+            b"https://trmnl.app/setup?mac=A0:B1:C2:D3:E4:F5",
+            b"WIFI:T:WPA;S:atrmnl-1A2B;P:correct horse battery;;",
+            "Größe ° — Łódź".as_bytes(),
+        ];
+        for level in LEVELS {
+            for input in inputs {
+                let code = QrCode::encode(input, level).unwrap();
+                assert_eq!(decode(&code), input, "{level:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn every_version_round_trips() {
+        for level in LEVELS {
+            for version in 1..=MAX_VERSION {
+                let capacity =
+                    data_codewords(version, level) - (4 + count_bits(version)).div_ceil(8);
+                let data: Vec<u8> = (0..capacity)
+                    .map(|i| (i * 37 + version as usize) as u8)
+                    .collect();
+                let code = QrCode::encode(&data, level).unwrap();
+                assert_eq!(code.version(), version, "{level:?}");
+                assert_eq!(code.size(), 17 + 4 * version as u32);
+                assert_eq!(decode(&code), data, "{level:?} version {version}");
+            }
+        }
+    }
+
+    #[test]
+    fn picks_the_smallest_version() {
+        assert_eq!(
+            QrCode::encode(&[b'x'; 17], EcLevel::L).unwrap().version(),
+            1
+        );
+        assert_eq!(
+            QrCode::encode(&[b'x'; 18], EcLevel::L).unwrap().version(),
+            2
+        );
+        assert_eq!(QrCode::encode(&[b'x'; 7], EcLevel::H).unwrap().version(), 1);
+        assert_eq!(QrCode::encode(&[b'x'; 8], EcLevel::H).unwrap().version(), 2);
+    }
+
+    #[test]
+    fn rejects_too_much_data() {
+        for (level, capacity) in LEVELS.into_iter().zip([271, 213, 151, 119]) {
+            let code = QrCode::encode(&vec![0; capacity], level).unwrap();
+            assert_eq!(code.version(), MAX_VERSION);
+            assert!(matches!(
+                QrCode::encode(&vec![0; capacity + 1], level),
+                Err(Error::TooLong)
+            ));
+        }
+    }
+
+    #[test]
+    fn drawn_code_decodes() {
+        let data = b"atrmnl";
+        let code = QrCode::encode(data, EcLevel::M).unwrap();
+        let module_size = 2;
+        let offset = Point::new(3, 1);
+        let mut display = MockDisplay::<BinaryColor>::new();
+        display.set_allow_overdraw(true);
+        code.draw(
+            &mut display,
+            offset,
+            module_size,
+            BinaryColor::On,
+            BinaryColor::Off,
+        )
+        .unwrap();
+
+        let side = code.pixel_size(module_size) as i32;
+        assert_eq!(
+            display.affected_area(),
+            Rectangle::new(offset, Size::new_equal(side as u32))
+        );
+        // The quiet zone is light all round.
+        let quiet = (QUIET_ZONE * module_size) as i32;
+        for i in 0..side {
+            for (x, y) in [(i, 0), (i, side - 1), (0, i), (side - 1, i)] {
+                assert_eq!(
+                    display.get_pixel(offset + Point::new(x, y)),
+                    Some(BinaryColor::Off)
+                );
+            }
+        }
+
+        let dark = |x: usize, y: usize| {
+            let at = offset
+                + Point::new_equal(quiet)
+                + Point::new(x as i32, y as i32) * module_size as i32;
+            (0..module_size as i32).all(|dy| {
+                (0..module_size as i32)
+                    .all(|dx| display.get_pixel(at + Point::new(dx, dy)) == Some(BinaryColor::On))
+            })
+        };
+        let decoder = Decoder {
+            size: code.size() as usize,
+            dark: &dark,
+        };
+        assert_eq!(decoder.decode(), data);
+    }
+}
@@ -0,0 +1,212 @@
+use embedded_graphics::prelude::{Point, Size};
+use embedded_graphics::primitives::Rectangle;
+
+/// Changed rows at most this far apart are refreshed as one area.
+const MERGE_GAP_ROWS: usize = 16;
+/// Further areas are merged into the last one.
+pub const MAX_AREAS: usize = 4;
+/// Changes covering more than this share of the panel get a full refresh.
+const MAX_PARTIAL_PERCENT: u32 = 30;
+
+/// How to bring the panel from one frame to the next.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Refresh {
+    Unchanged,
+    Full,
+    /// Refresh only these areas. Their x coordinates and widths are multiples
+    /// of eight so that they cover whole framebuffer bytes.
+    Partial(heapless::Vec<Rectangle, MAX_AREAS>),
+}
+
+/// Decide how to refresh a 1 bit per pixel panel of `size` showing `old` to
+/// show `new`. Rows are padded to whole bytes.
+///
+/// `partials` is the number of partial refreshes since the last full one; once
+/// it reaches `full_every` the next change gets a full refresh to clear
+/// ghosting.
+pub fn plan(old: &[u8], new: &[u8], size: Size, partials: u32, full_every: u32) -> Refresh {
+    let spans = dirty_spans(old, new, size);
+    if spans.is_empty() {
+        return Refresh::Unchanged;
+    }
+    if partials >= full_every {
+        return Refresh::Full;
+    }
+    let area: u32 = spans.iter().map(|span| span.area()).sum();
+    if area * 100 > size.width * size.height * MAX_PARTIAL_PERCENT {
+        return Refresh::Full;
+    }
+    Refresh::Partial(spans.iter().map(|span| span.to_rectangle(size)).collect())
+}
+
+/// Rows `top..=bottom`, bytes `left..=right` within each row.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    top: usize,
+    bottom: usize,
+    left: usize,
+    right: usize,
+}
+
+impl Span {
+    fn merge(&mut self, other: Span) {
+        self.top = self.top.min(other.top);
+        self.bottom = self.bottom.max(other.bottom);
+        self.left = self.left.min(other.left);
+        self.right = self.right.max(other.right);
+    }
+
+    fn area(&self) -> u32 {
+        ((self.bottom - self.top + 1) * (self.right - self.left + 1) * 8) as u32
+    }
+
+    fn to_rectangle(self, size: Size) -> Rectangle {
+        let x = self.left as u32 * 8;
+        let width = ((self.right - self.left + 1) as u32 * 8).min(size.width - x);
+        Rectangle::new(
+            Point::new(x as i32, self.top as i32),
+            Size::new(width, (self.bottom - self.top + 1) as u32),
+        )
+    }
+}
+
+fn dirty_spans(old: &[u8], new: &[u8], size: Size) -> heapless::Vec<Span, MAX_AREAS> {
+    let stride = size.width.div_ceil(8) as usize;
+    let mut spans = heapless::Vec::<Span, MAX_AREAS>::new();
+    let mut current: Option<Span> = None;
+    let rows = old.chunks_exact(stride).zip(new.chunks_exact(stride));
+    for (row, (old, new)) in rows.take(size.height as usize).enumerate() {
+        let Some(left) = old.iter().zip(new).position(|(a, b)| a != b) else {
+            continue;
+        };
+        // Safe to unwrap: a differing byte was found above.
+        let right = old.iter().zip(new).rposition(|(a, b)| a != b).unwrap();
+        let span = Span {
+            top: row,
+            bottom: row,
+            left,
+            right,
+        };
+        match &mut current {
+            Some(current) if row - current.bottom <= MERGE_GAP_ROWS => current.merge(span),
+            _ => {
+                if let Some(done) = current.replace(span) {
+                    push(&mut spans, done);
+                }
+            }
+        }
+    }
+    if let Some(done) = current {
+        push(&mut spans, done);
+    }
+    spans
+}
+
+fn push(spans: &mut heapless::Vec<Span, MAX_AREAS>, span: Span) {
+    if let Err(span) = spans.push(span) {
+        // Safe to unwrap: the push only fails when the vector is full.
+        spans.last_mut().unwrap().merge(span);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 64 by 128 pixels, 8 bytes a row.
+    const SIZE: Size = Size::new(64, 128);
+
+    /// Frames that differ in whole bytes, by row and byte within the row.
+    fn changed(bytes: &[(usize, usize)]) -> ([u8; 1024], [u8; 1024]) {
+        let old = [0; 1024];
+        let mut new = old;
+        for &(row, byte) in bytes {
+            new[row * 8 + byte] = 0xFF;
+        }
+        (old, new)
+    }
+
+    fn partial(areas: &[(i32, i32, u32, u32)]) -> Refresh {
+        Refresh::Partial(
+            areas
+                .iter()
+                .map(|&(x, y, width, height)| {
+                    Rectangle::new(Point::new(x, y), Size::new(width, height))
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn leaves_an_unchanged_frame_alone() {
+        let (old, _) = changed(&[]);
+        assert_eq!(plan(&old, &old, SIZE, 0, 10), Refresh::Unchanged);
+        assert_eq!(plan(&old, &old, SIZE, 10, 10), Refresh::Unchanged);
+    }
+
+    #[test]
+    fn refreshes_the_byte_that_changed() {
+        let (old, mut new) = changed(&[]);
+        new[5 * 8 + 2] = 0x01;
+        assert_eq!(plan(&old, &new, SIZE, 0, 10), partial(&[(16, 5, 8, 1)]));
+    }
+
+    #[test]
+    fn merges_nearby_rows() {
+        let (old, new) = changed(&[(0, 1), (MERGE_GAP_ROWS, 3)]);
+        assert_eq!(
+            plan(&old, &new, SIZE, 0, 10),
+            partial(&[(8, 0, 24, MERGE_GAP_ROWS as u32 + 1)])
+        );
+
+        let (old, new) = changed(&[(0, 1), (MERGE_GAP_ROWS + 1, 3)]);
+        assert_eq!(
+            plan(&old, &new, SIZE, 0, 10),
+            partial(&[(8, 0, 8, 1), (24, MERGE_GAP_ROWS as i32 + 1, 8, 1)])
+        );
+    }
+
+    #[test]
+    fn merges_further_areas_into_the_last() {
+        let gap = MERGE_GAP_ROWS + 1;
+        let bytes: Vec<_> = (0..MAX_AREAS + 2).map(|area| (area * gap, area)).collect();
+        let (old, new) = changed(&bytes);
+        let gap = gap as i32;
+        assert_eq!(
+            plan(&old, &new, SIZE, 0, 10),
+            partial(&[
+                (0, 0, 8, 1),
+                (8, gap, 8, 1),
+                (16, 2 * gap, 8, 1),
+                (24, 3 * gap, 24, 2 * gap as u32 + 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn aligns_areas_to_bytes() {
+        // 60 pixels wide, 8 bytes a row with the last one half used.
+        let size = Size::new(60, 64);
+        let (old, mut new) = changed(&[]);
+        new[3 * 8] = 0x01;
+        new[3 * 8 + 7] = 0x80;
+        assert_eq!(plan(&old, &new, size, 0, 10), partial(&[(0, 3, 60, 1)]));
+
+        let (old, mut new) = changed(&[]);
+        new[3 * 8 + 4] = 0x10;
+        new[4 * 8 + 5] = 0x08;
+        assert_eq!(plan(&old, &new, size, 0, 10), partial(&[(32, 3, 16, 2)]));
+    }
+
+    #[test]
+    fn refreshes_in_full_when_due_or_mostly_changed() {
+        let (old, new) = changed(&[(0, 0)]);
+        assert_eq!(plan(&old, &new, SIZE, 10, 10), Refresh::Full);
+        assert_eq!(plan(&old, &new, SIZE, 0, 0), Refresh::Full);
+
+        let (old, _) = changed(&[]);
+        let mut new = old;
+        new[..8 * 40].fill(0xFF);
+        assert_eq!(plan(&old, &new, SIZE, 0, 10), Refresh::Full);
+    }
+}
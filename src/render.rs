@@ -0,0 +1,391 @@
+use core::convert::Infallible;
+
+use embedded_graphics::{
+    image::Image,
+    pixelcolor::{BinaryColor, Gray2},
+    prelude::*,
+    primitives::Rectangle,
+};
+use log::warn;
+use tinyqoi::Qoi;
+
+use crate::api::RenderMode;
+use crate::dither;
+use crate::epaper::TriColor;
+use crate::layout;
+use crate::overlay;
+use crate::placement::{Orientation, Placement};
+use crate::settings::{self, SWITCHES, SettingError, choose, name_of};
+
+/// Names of the display settings, as config keys after `display.`.
+pub const SETTINGS: [&str; 3] = ["orientation", "scale_images", "full_refresh_interval"];
+
+const ORIENTATIONS: [(&str, Orientation); 4] = [
+    ("0", Orientation::Rotate0),
+    ("90", Orientation::Rotate90),
+    ("180", Orientation::Rotate180),
+    ("270", Orientation::Rotate270),
+];
+
+/// How the panel is mounted and driven, as the config sets it up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplaySettings {
+    pub orientation: Orientation,
+    /// Scale images smaller than the panel up by the largest whole factor
+    /// that fits.
+    pub scale_images: bool,
+    /// Partial refreshes before a full one clears the ghosting they leave.
+    pub full_refresh_interval: u32,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self {
+            orientation: Orientation::Rotate0,
+            scale_images: false,
+            full_refresh_interval: 10,
+        }
+    }
+}
+
+impl DisplaySettings {
+    /// The setting `name`, as `set` takes it.
+    pub fn get(&self, name: &str) -> Result<settings::Value, SettingError> {
+        match name {
+            "orientation" => Ok(settings::Value::Name(name_of(
+                &ORIENTATIONS,
+                self.orientation,
+            ))),
+            "scale_images" => Ok(settings::Value::Name(name_of(&SWITCHES, self.scale_images))),
+            "full_refresh_interval" => Ok(settings::Value::Count(self.full_refresh_interval)),
+            _ => Err(SettingError::UnknownName),
+        }
+    }
+
+    /// Change the setting `name` to `value`.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), SettingError> {
+        match name {
+            "orientation" => self.orientation = choose(&ORIENTATIONS, value, "0, 90, 180 or 270")?,
+            "scale_images" => self.scale_images = choose(&SWITCHES, value, "true or false")?,
+            "full_refresh_interval" => self.full_refresh_interval = settings::count(value)?,
+            _ => return Err(SettingError::UnknownName),
+        }
+        Ok(())
+    }
+}
+
+/// The framebuffers of a panel, in whichever of its colour depths an image
+/// is drawn in. Each method blanks the framebuffer it returns and switches
+/// the next update to its depth.
+pub trait Canvas {
+    fn mono(&mut self) -> impl DrawTarget<Color = BinaryColor, Error = Infallible>;
+    /// `None` if the panel cannot show gray.
+    fn gray(&mut self) -> Option<impl DrawTarget<Color = Gray2, Error = Infallible>>;
+    /// `None` if the panel only has black.
+    fn tricolor(&mut self) -> Option<impl DrawTarget<Color = TriColor, Error = Infallible>>;
+}
+
+/// How fetched images, and what is drawn over them, are laid out on the panel.
+///
+/// Every `draw_*` method takes the framebuffer as the panel sees it and
+/// applies the orientation itself.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    /// Size of the panel in its native orientation.
+    pub panel: Size,
+    pub orientation: Orientation,
+    /// Scale images that do not match the panel instead of centering them.
+    pub scale_images: bool,
+    pub status_bar: Option<overlay::StatusBar>,
+}
+
+impl Layout {
+    /// The layout `display` and `status_bar` set up on a panel of `panel`
+    /// size in its native orientation.
+    pub fn new(panel: Size, display: &DisplaySettings, status_bar: &overlay::BarSettings) -> Self {
+        Self {
+            panel,
+            orientation: display.orientation,
+            scale_images: display.scale_images,
+            status_bar: status_bar.bar(),
+        }
+    }
+
+    /// Maps drawing onto the panel the way it is mounted.
+    pub const fn oriented(&self) -> Placement {
+        Placement::oriented(self.panel, self.orientation)
+    }
+
+    fn placement(&self, img: &Qoi<'_>) -> Placement {
+        Placement::fit(self.panel, self.orientation, img.size(), self.scale_images)
+    }
+
+    /// Draw the fetched image, the status bar and `notice` into `canvas`.
+    /// Returns whether the image is dark under the status bar.
+    pub fn render(
+        &self,
+        canvas: &mut impl Canvas,
+        img: &Qoi<'_>,
+        mode: RenderMode,
+        dither: dither::Method,
+        notice: &str,
+        status: &overlay::DeviceStatus,
+    ) -> bool {
+        let dark = self.is_dark(img, status);
+        // Tri-colour panels show every image in their three inks.
+        if let Some(mut frame) = canvas.tricolor() {
+            self.draw_tricolor(&mut frame, img, dither, notice, status, dark);
+            return dark;
+        }
+        if mode == RenderMode::Gray4 {
+            match canvas.gray() {
+                Some(mut frame) => {
+                    self.draw_gray(&mut frame, img, notice, status, dark);
+                    return dark;
+                }
+                None => warn!("Panel cannot show gray, rendering in black and white."),
+            }
+        }
+        self.draw_mono(&mut canvas.mono(), img, dither, notice, status, dark);
+        dark
+    }
+
+    /// Like [`Self::render`], for a layout document. Documents are always
+    /// drawn in black and white, or in the inks of a tri-colour panel.
+    pub fn render_document(
+        &self,
+        canvas: &mut impl Canvas,
+        document: &layout::Document<'_>,
+        dither: dither::Method,
+        notice: &str,
+        status: &overlay::DeviceStatus,
+    ) -> bool {
+        let dark = self.is_dark_document(document, status);
+        if let Some(mut frame) = canvas.tricolor() {
+            self.draw_document_tricolor(&mut frame, document, dither, notice, status, dark);
+            return dark;
+        }
+        self.draw_document_mono(&mut canvas.mono(), document, dither, notice, status, dark);
+        dark
+    }
+
+    /// Whether `img` is dark under the status bar, which then gets drawn
+    /// light on dark. Always `false` unless the bar asks for that.
+    fn is_dark(&self, img: &Qoi<'_>, status: &overlay::DeviceStatus) -> bool {
+        let Some(bar) = self.status_bar.filter(|bar| bar.invert_on_dark) else {
+            return false;
+        };
+        let placement = self.placement(img);
+        let canvas = Rectangle::new(Point::zero(), placement.canvas());
+        let mut darkness = overlay::Darkness::new(bar.area(canvas, status));
+        // Decoding the image again is cheaper than reading pixels back out
+        // of the framebuffer in every panel's layout. Safe to unwrap:
+        // measuring darkness cannot fail.
+        Image::new(img, Point::zero())
+            .draw(&mut placement.upright().apply(&mut darkness).color_converted())
+            .unwrap();
+        darkness.is_dark()
+    }
+
+    /// Like [`Self::is_dark`], for a layout document.
+    fn is_dark_document(
+        &self,
+        document: &layout::Document<'_>,
+        status: &overlay::DeviceStatus,
+    ) -> bool {
+        let Some(bar) = self.status_bar.filter(|bar| bar.invert_on_dark) else {
+            return false;
+        };
+        let canvas = Rectangle::new(Point::zero(), self.oriented().canvas());
+        let mut darkness = overlay::Darkness::new(bar.area(canvas, status));
+        // Safe to unwrap: measuring darkness cannot fail.
+        document
+            .draw(
+                &mut darkness.color_converted::<BinaryColor>().color_converted(),
+                dither::Method::None,
+                false,
+            )
+            .unwrap();
+        darkness.is_dark()
+    }
+
+    /// Draw `img` dithered to black and white, with the status bar and
+    /// `notice` on top, into a blank framebuffer.
+    fn draw_mono<D>(
+        &self,
+        target: &mut D,
+        img: &Qoi<'_>,
+        dither: dither::Method,
+        notice: &str,
+        status: &overlay::DeviceStatus,
+        dark: bool,
+    ) where
+        D: DrawTarget<Color = BinaryColor, Error = Infallible>,
+    {
+        // Safe to unwrap: drawing into a framebuffer cannot fail.
+        Image::new(img, Point::zero())
+            .draw(
+                &mut dither::Dither::new(&mut self.placement(img).apply(target), dither)
+                    .color_converted(),
+            )
+            .unwrap();
+        let mut oriented = self.oriented().apply(target);
+        self.draw_overlays(&mut oriented, notice, status, dark);
+    }
+
+    /// Like [`Self::draw_mono`], in four gray levels.
+    fn draw_gray<D>(
+        &self,
+        target: &mut D,
+        img: &Qoi<'_>,
+        notice: &str,
+        status: &overlay::DeviceStatus,
+        dark: bool,
+    ) where
+        D: DrawTarget<Color = Gray2, Error = Infallible>,
+    {
+        // Safe to unwrap: drawing into a framebuffer cannot fail.
+        Image::new(img, Point::zero())
+            .draw(&mut self.placement(img).apply(target).color_converted())
+            .unwrap();
+        let mut oriented = self.oriented().apply(target);
+        self.draw_overlays(&mut oriented.color_converted(), notice, status, dark);
+    }
+
+    /// Like [`Self::draw_mono`], with each colour mapped to the nearest of
+    /// the black, white and red inks.
+    fn draw_tricolor<D>(
+        &self,
+        target: &mut D,
+        img: &Qoi<'_>,
+        dither: dither::Method,
+        notice: &str,
+        status: &overlay::DeviceStatus,
+        dark: bool,
+    ) where
+        D: DrawTarget<Color = TriColor, Error = Infallible>,
+    {
+        // Safe to unwrap: drawing into a framebuffer cannot fail.
+        Image::new(img, Point::zero())
+            .draw(&mut dither::InkDither::new(
+                &mut self.placement(img).apply(target),
+                dither,
+            ))
+            .unwrap();
+        let mut oriented = self.oriented().apply(target);
+        self.draw_overlays(&mut oriented.color_converted(), notice, status, dark);
+    }
+
+    /// Draw `document` in black and white, with the status bar and `notice`
+    /// on top, into a blank framebuffer.
+    fn draw_document_mono<D>(
+        &self,
+        target: &mut D,
+        document: &layout::Document<'_>,
+        dither: dither::Method,
+        notice: &str,
+        status: &overlay::DeviceStatus,
+        dark: bool,
+    ) where
+        D: DrawTarget<Color = BinaryColor, Error = Infallible>,
+    {
+        let mut oriented = self.oriented().apply(target);
+        // Safe to unwrap: drawing into a framebuffer cannot fail.
+        document
+            .draw(&mut oriented.color_converted(), dither, false)
+            .unwrap();
+        self.draw_overlays(&mut oriented, notice, status, dark);
+    }
+
+    /// Like [`Self::draw_document_mono`], with red where the document asks
+    /// for it.
+    fn draw_document_tricolor<D>(
+        &self,
+        target: &mut D,
+        document: &layout::Document<'_>,
+        dither: dither::Method,
+        notice: &str,
+        status: &overlay::DeviceStatus,
+        dark: bool,
+    ) where
+        D: DrawTarget<Color = TriColor, Error = Infallible>,
+    {
+        let mut oriented = self.oriented().apply(target);
+        // Safe to unwrap: drawing into a framebuffer cannot fail.
+        document.draw(&mut oriented, dither, true).unwrap();
+        self.draw_overlays(&mut oriented.color_converted(), notice, status, dark);
+    }
+
+    /// Draw the status bar alone, e.g. over the image already in the
+    /// framebuffer to mark it stale.
+    pub fn draw_status_bar<D>(&self, target: &mut D, status: &overlay::DeviceStatus, dark: bool)
+    where
+        D: DrawTarget<Color = BinaryColor, Error = Infallible>,
+    {
+        if let Some(bar) = self.status_bar {
+            // Safe to unwrap: drawing into a framebuffer cannot fail.
+            bar.draw(&mut self.oriented().apply(target), status, dark)
+                .unwrap();
+        }
+    }
+
+    fn draw_overlays<D>(
+        &self,
+        target: &mut D,
+        notice: &str,
+        status: &overlay::DeviceStatus,
+        dark: bool,
+    ) where
+        D: DrawTarget<Color = BinaryColor, Error = Infallible>,
+    {
+        if let Some(bar) = self.status_bar {
+            // Safe to unwrap: drawing into a framebuffer cannot fail.
+            bar.draw(target, status, dark).unwrap();
+        }
+        if !notice.is_empty() {
+            // Safe to unwrap: as above.
+            overlay::draw_notice(target, notice).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_display_settings() {
+        let mut settings = DisplaySettings::default();
+        settings.set("orientation", "270").unwrap();
+        settings.set("scale_images", "TRUE").unwrap();
+        assert_eq!(settings.orientation, Orientation::Rotate270);
+        assert!(settings.scale_images);
+        for name in SETTINGS {
+            let value = settings.get(name).unwrap();
+            let mut copy = DisplaySettings::default();
+            copy.set(name, &value.to_string()).unwrap();
+            assert_eq!(copy.get(name), Ok(value));
+        }
+        assert_eq!(
+            settings.set("orientation", "45"),
+            Err(SettingError::Invalid("0, 90, 180 or 270"))
+        );
+
+        settings.set("full_refresh_interval", " 25 ").unwrap();
+        assert_eq!(settings.full_refresh_interval, 25);
+        assert_eq!(
+            settings.get("full_refresh_interval"),
+            Ok(settings::Value::Count(25))
+        );
+
+        assert_eq!(
+            settings.set("full_refresh_interval", "-1"),
+            Err(SettingError::Invalid("a whole number"))
+        );
+        assert_eq!(
+            settings.set("rotation", "90"),
+            Err(SettingError::UnknownName)
+        );
+        assert_eq!(settings.full_refresh_interval, 25);
+    }
+}
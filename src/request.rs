@@ -0,0 +1,121 @@
+/// Room for the request line, headers and form; browsers send far less.
+pub const MAX_LEN: usize = 2048;
+
+/// A request that is not HTTP, or that does not fit in [`MAX_LEN`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct BadRequest;
+
+/// The parts of an HTTP request the portal looks at.
+#[derive(Debug)]
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub host: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// `None` until `buf` holds the whole request.
+    pub fn parse(buf: &'a [u8]) -> Result<Option<Self>, BadRequest> {
+        let Some(head_len) = buf.windows(4).position(|window| window == b"\r\n\r\n") else {
+            return match buf.len() {
+                MAX_LEN => Err(BadRequest),
+                _ => Ok(None),
+            };
+        };
+        let head = core::str::from_utf8(&buf[..head_len]).map_err(|_| BadRequest)?;
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+            return Err(BadRequest);
+        };
+        let path = target.split('?').next().unwrap_or_default();
+
+        let mut host = None;
+        let mut content_len: usize = 0;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("host") {
+                host = Some(value);
+            } else if name.eq_ignore_ascii_case("content-length") {
+                content_len = value.parse().map_err(|_| BadRequest)?;
+            }
+        }
+
+        let body_start = head_len + 4;
+        let body_end = body_start
+            .checked_add(content_len)
+            .filter(|end| *end <= MAX_LEN)
+            .ok_or(BadRequest)?;
+        Ok(buf.get(body_start..body_end).map(|body| Self {
+            method,
+            path,
+            host,
+            body,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_the_whole_request() {
+        assert!(Request::parse(b"").unwrap().is_none());
+        assert!(
+            Request::parse(b"GET / HTTP/1.1\r\nHost: x")
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            Request::parse(b"POST / HTTP/1.1\r\nContent-Length: 8\r\n\r\nssid=")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn reads_the_parts() {
+        let request = Request::parse(
+            b"GET /generate_204?x=1 HTTP/1.1\r\nhost: connectivitycheck.gstatic.com\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/generate_204");
+        assert_eq!(request.host, Some("connectivitycheck.gstatic.com"));
+        assert_eq!(request.body, b"");
+
+        let request = Request::parse(b"POST / HTTP/1.1\r\nCONTENT-LENGTH:  6\r\n\r\nssid=x")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.host, None);
+        assert_eq!(request.body, b"ssid=x");
+    }
+
+    #[test]
+    fn refuses_malformed_requests() {
+        assert_eq!(Request::parse(b"GET\r\n\r\n").unwrap_err(), BadRequest);
+        assert_eq!(
+            Request::parse(b"POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n").unwrap_err(),
+            BadRequest
+        );
+        assert_eq!(Request::parse(&[b'a'; MAX_LEN]).unwrap_err(), BadRequest);
+    }
+
+    #[test]
+    fn refuses_bodies_that_cannot_fit() {
+        let too_long = format!("POST / HTTP/1.1\r\nContent-Length: {MAX_LEN}\r\n\r\n");
+        assert_eq!(Request::parse(too_long.as_bytes()).unwrap_err(), BadRequest);
+        // Would wrap around when added to the body's start.
+        let overflowing = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        assert_eq!(
+            Request::parse(overflowing.as_bytes()).unwrap_err(),
+            BadRequest
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use esp_hal::peripherals::LPWR;
+use esp_hal::rtc_cntl::{Rtc, sleep::TimerWakeupSource};
+
+/// The low-power RTC is shared between the clock, the watchdog and deep sleep,
+/// so it lives behind a global rather than being owned by any one of them.
+static RTC: Mutex<CriticalSectionRawMutex, RefCell<Option<Rtc<'static>>>> =
+    Mutex::new(RefCell::new(None));
+
+pub fn init(lpwr: LPWR<'static>) {
+    RTC.lock(|rtc| rtc.replace(Some(Rtc::new(lpwr))));
+}
+
+/// Run `f` with exclusive access to the RTC.
+///
+/// Panics if [`init`] has not been called yet.
+pub fn with<R>(f: impl FnOnce(&mut Rtc<'static>) -> R) -> R {
+    RTC.lock(|rtc| f(rtc.borrow_mut().as_mut().expect("rtc is not initialized")))
+}
+
+/// Microseconds counted by the RTC slow clock.
+///
+/// Unlike `embassy_time::Instant` this keeps counting through deep sleep and
+/// software or watchdog resets; only a power-on reset starts it from zero.
+pub fn micros() -> u64 {
+    with(|rtc| rtc.time_since_boot().as_micros())
+}
+
+/// Checksum for records kept in RTC memory.
+///
+/// Persistent RAM comes up with random content after a power loss, so every
+/// record stored there carries one of these next to its data.
+pub fn checksum(words: &[u32]) -> u32 {
+    words.iter().fold(0x811c_9dc5, |acc: u32, word| {
+        (acc ^ word).wrapping_mul(0x0100_0193).rotate_left(5)
+    })
+}
+
+/// Power down everything but the RTC domain for `duration`. The device
+/// restarts from scratch afterwards; state meant to survive must be kept in
+/// persistent RTC memory.
+pub fn sleep_deep(duration: core::time::Duration) -> ! {
+    let timer = TimerWakeupSource::new(duration);
+    with(|rtc| rtc.sleep_deep(&[&timer]))
+}
@@ -0,0 +1,76 @@
+use embassy_time::Duration;
+use log::info;
+
+use crate::rtc;
+
+/// Consecutive failed boots before the device gives up and enters safe mode.
+const MAX_BOOT_ATTEMPTS: u32 = 5;
+/// Deep sleep between boot attempts as multiples of the first, indexed by the
+/// number of failures so far.
+const RETRY_DELAY_FACTORS: [u32; MAX_BOOT_ATTEMPTS as usize - 1] = [1, 5, 20, 100];
+/// Deep sleep in safe mode before the device tries again.
+const SAFE_MODE_SLEEP: Duration = Duration::from_secs(6 * 60 * 60);
+
+const PERSISTED_MAGIC: u32 = 0x424f_4f54; // "BOOT"
+
+/// `[magic, failed boot attempts, checksum]`.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PERSISTED: [u32; 3] = [0; 3];
+
+fn load() -> u32 {
+    // SAFETY: only accessed from `main` during boot.
+    let words = unsafe { (&raw const PERSISTED).read_volatile() };
+    if words[0] != PERSISTED_MAGIC || words[2] != rtc::checksum(&words[..2]) {
+        return 0;
+    }
+    words[1]
+}
+
+fn store(attempts: u32) {
+    let mut words = [PERSISTED_MAGIC, attempts, 0];
+    words[2] = rtc::checksum(&words[..2]);
+    // SAFETY: see `load`.
+    unsafe { (&raw mut PERSISTED).write_volatile(words) };
+}
+
+/// What to do after a failed boot.
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    /// Sleep for a while, then try again.
+    Retry(Duration),
+    /// Show what went wrong and sleep for a long time.
+    SafeMode { attempts: u32, sleep: Duration },
+}
+
+/// Count a failed boot and decide how long to back off, starting from
+/// `first_delay`.
+pub fn record_failure(first_delay: Duration) -> Action {
+    let attempts = load().saturating_add(1);
+    store(attempts);
+    match RETRY_DELAY_FACTORS.get(attempts as usize - 1) {
+        Some(&factor) => Action::Retry(first_delay * factor),
+        None => Action::SafeMode {
+            attempts,
+            sleep: SAFE_MODE_SLEEP,
+        },
+    }
+}
+
+/// Failed boots since the last successful one.
+pub fn failed_attempts() -> u32 {
+    load()
+}
+
+/// Start counting failures from zero, e.g. after new settings.
+pub fn reset() {
+    store(0);
+}
+
+/// Reset the failure count after a successful boot.
+pub fn record_success() {
+    let attempts = load();
+    if attempts > 0 {
+        info!("Booted after {attempts} failed attempts");
+        store(0);
+    }
+}
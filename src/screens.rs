@@ -0,0 +1,340 @@
+use core::fmt::{Display, Write as _};
+use core::net::Ipv4Addr;
+
+use embassy_time::Duration;
+use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::Alignment,
+};
+
+use crate::epaper::{INK, PAPER};
+use crate::font::{self, FontStyle};
+use crate::qr::{EcLevel, QrCode};
+
+const MARGIN: i32 = 24;
+const LINE_SPACING: i32 = 4;
+
+/// Writes lines of text top to bottom onto a full-screen page.
+struct Page<'a, D> {
+    target: &'a mut D,
+    cursor: Point,
+}
+
+impl<'a, D> Page<'a, D>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    fn new(target: &'a mut D) -> Self {
+        let origin = target.bounding_box().top_left;
+        Self {
+            target,
+            cursor: origin + Point::new(MARGIN, MARGIN),
+        }
+    }
+
+    fn title(&mut self, text: &str) -> Result<(), D::Error> {
+        self.paragraph(text, FontStyle::new(font::LARGE, INK))?;
+        self.cursor.y += font::LARGE.line_height() as i32;
+        Ok(())
+    }
+
+    fn text(&mut self, args: core::fmt::Arguments<'_>) -> Result<(), D::Error> {
+        let mut text = heapless::String::<256>::new();
+        // Overlong text is cut; it would not fit the page anyway.
+        let _ = text.write_fmt(args);
+        self.paragraph(&text, FontStyle::new(font::SMALL, INK))
+    }
+
+    /// Draw `text` wrapped between the margins below the last paragraph.
+    fn paragraph(&mut self, text: &str, style: FontStyle<'_, BinaryColor>) -> Result<(), D::Error> {
+        let area = self.target.bounding_box();
+        let width = (area.size.width as i32 - 2 * MARGIN).max(0) as u32;
+        let height = (area.top_left.y + area.size.height as i32 - self.cursor.y).max(0) as u32;
+        let used = style.draw_wrapped(
+            text,
+            Rectangle::new(self.cursor, Size::new(width, height)),
+            Alignment::Left,
+            self.target,
+        )?;
+        self.cursor.y += used as i32 + LINE_SPACING;
+        Ok(())
+    }
+
+    /// Draw a QR code of `data` below the last paragraph, with modules as
+    /// large as fit above the footer. Left out if it does not fit at all.
+    fn qr(&mut self, data: &str) -> Result<(), D::Error> {
+        let Ok(code) = QrCode::encode(data.as_bytes(), EcLevel::M) else {
+            return Ok(());
+        };
+        let area = self.target.bounding_box();
+        let width = area.top_left.x + area.size.width as i32 - self.cursor.x;
+        let height = self.footer_top() - LINE_SPACING - self.cursor.y;
+        let fits = (1..=MAX_MODULE_SIZE)
+            .rev()
+            .find(|&size| code.pixel_size(size) as i32 <= width.min(height));
+        let Some(module_size) = fits else {
+            return Ok(());
+        };
+        code.draw(self.target, self.cursor, module_size, INK, PAPER)?;
+        self.cursor.y += code.pixel_size(module_size) as i32 + LINE_SPACING;
+        Ok(())
+    }
+
+    /// Where the text of the footer starts.
+    fn footer_top(&self) -> i32 {
+        let area = self.target.bounding_box();
+        let height = font::SMALL.line_height() as i32;
+        area.top_left.y + area.size.height as i32 - MARGIN - height
+    }
+
+    /// Identify the device along the bottom edge of the page.
+    fn footer(&mut self, device: &DeviceInfo) -> Result<(), D::Error> {
+        let area = self.target.bounding_box();
+        let y = self.footer_top();
+        Line::new(
+            Point::new(area.top_left.x + MARGIN, y - LINE_SPACING * 2),
+            Point::new(
+                area.top_left.x + area.size.width as i32 - MARGIN,
+                y - LINE_SPACING * 2,
+            ),
+        )
+        .into_styled(PrimitiveStyle::with_stroke(INK, 1))
+        .draw(self.target)?;
+        self.cursor = Point::new(area.top_left.x + MARGIN, y);
+        self.text(format_args!("{device}"))
+    }
+}
+
+/// Largest QR code module drawn, in pixels.
+const MAX_MODULE_SIZE: u32 = 4;
+
+/// What identifies the device on a diagnostic screen.
+pub struct DeviceInfo {
+    pub mac: [u8; 6],
+    pub ip: Option<Ipv4Addr>,
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d, e, g] = self.mac;
+        write!(
+            f,
+            "Device {a:02X}:{b:02X}:{c:02X}:{d:02X}:{e:02X}:{g:02X}   Firmware {}   IP ",
+            crate::FIRMWARE_VERSION
+        )?;
+        match self.ip {
+            Some(ip) => write!(f, "{ip}"),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+/// An error condition worth telling whoever looks at the panel about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    WifiFailed {
+        ssid: &'static str,
+        reason: &'static str,
+    },
+    ServerUnreachable {
+        url: heapless::String<128>,
+        status: Option<u16>,
+    },
+    ImageDecode {
+        url: heapless::String<128>,
+    },
+    LayoutDecode {
+        url: heapless::String<128>,
+    },
+    LowBattery {
+        millivolts: u32,
+        percent: u8,
+    },
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Diagnostic::WifiFailed { ssid, reason } => {
+                write!(f, "wifi {ssid} failed: {reason}")
+            }
+            Diagnostic::ServerUnreachable { url, status } => {
+                write!(f, "server unreachable: {url}")?;
+                match status {
+                    Some(status) => write!(f, " (status {status})"),
+                    None => Ok(()),
+                }
+            }
+            Diagnostic::ImageDecode { url } => write!(f, "could not decode image: {url}"),
+            Diagnostic::LayoutDecode { url } => write!(f, "could not parse layout: {url}"),
+            Diagnostic::LowBattery { millivolts, .. } => write!(f, "low battery: {millivolts} mV"),
+        }
+    }
+}
+
+impl Diagnostic {
+    /// Draw the full-screen page for this diagnostic.
+    pub fn draw<D>(&self, target: &mut D, device: &DeviceInfo) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut page = Page::new(target);
+        match self {
+            Diagnostic::WifiFailed { ssid, reason } => {
+                page.title("Cannot connect to Wi-Fi")?;
+                page.text(format_args!("Network: {ssid}"))?;
+                page.text(format_args!("Reason: {reason}"))?;
+                page.text(format_args!(
+                    "Check that the network is in range and the password is correct."
+                ))?;
+            }
+            Diagnostic::ServerUnreachable { url, status } => {
+                page.title("Cannot reach the server")?;
+                page.text(format_args!("URL: {url}"))?;
+                match status {
+                    Some(status) => page.text(format_args!("HTTP status: {status}"))?,
+                    None => page.text(format_args!("No response"))?,
+                }
+                page.text(format_args!("The device keeps retrying in the background."))?;
+                page.qr(url)?;
+            }
+            Diagnostic::ImageDecode { url } => {
+                page.title("Cannot show the image")?;
+                page.text(format_args!("URL: {url}"))?;
+                page.text(format_args!(
+                    "The server sent something that is not a valid QOI image."
+                ))?;
+                page.qr(url)?;
+            }
+            Diagnostic::LayoutDecode { url } => {
+                page.title("Cannot show the layout")?;
+                page.text(format_args!("URL: {url}"))?;
+                page.text(format_args!(
+                    "The server sent invalid JSON, or more than {} elements.",
+                    crate::layout::MAX_ELEMENTS
+                ))?;
+                page.qr(url)?;
+            }
+            Diagnostic::LowBattery {
+                millivolts,
+                percent,
+            } => {
+                page.title("Battery low")?;
+                page.text(format_args!("Battery at {millivolts} mV ({percent}%)."))?;
+                page.text(format_args!("Please charge the device."))?;
+            }
+        }
+        page.footer(device)
+    }
+
+    /// Identifies the state shown, ignoring details that change on their own
+    /// such as the exact battery voltage.
+    pub fn fingerprint(&self) -> u32 {
+        let mut hasher = Fingerprint(0x811c_9dc5);
+        let _ = match self {
+            Diagnostic::LowBattery { .. } => write!(hasher, "low battery"),
+            _ => write!(hasher, "{self}"),
+        };
+        hasher.0
+    }
+}
+
+/// FNV-1a over the formatted text.
+struct Fingerprint(u32);
+
+impl core::fmt::Write for Fingerprint {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &byte in s.as_bytes() {
+            self.0 = (self.0 ^ byte as u32).wrapping_mul(0x0100_0193);
+        }
+        Ok(())
+    }
+}
+
+/// Shown when the device gave up booting and sleeps in safe mode.
+pub fn boot_failure<D>(
+    target: &mut D,
+    device: &DeviceInfo,
+    error: &dyn Display,
+    attempts: u32,
+    retry_in: Duration,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let mut page = Page::new(target);
+    page.title("Device failed to start")?;
+    page.text(format_args!("Error: {error}"))?;
+    page.text(format_args!("Failed attempts: {attempts}"))?;
+    page.text(format_args!(
+        "Sleeping for {} minutes before trying again.",
+        retry_in.as_secs() / 60
+    ))?;
+    page.footer(device)
+}
+
+/// Shown while the device offers its own network for setting it up.
+pub fn setup<D>(
+    target: &mut D,
+    device: &DeviceInfo,
+    network: &str,
+    join_code: &str,
+    address: Ipv4Addr,
+    ssid: Option<&str>,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let mut page = Page::new(target);
+    page.title("Set up Wi-Fi")?;
+    match ssid {
+        Some(ssid) => page.text(format_args!("The device cannot connect to {ssid}."))?,
+        None => page.text(format_args!("No Wi-Fi network is set up yet."))?,
+    }
+    page.text(format_args!(
+        "Join the network {network}, or scan the code below, then open http://{address}/ \
+         to choose a network and server."
+    ))?;
+    page.qr(join_code)?;
+    page.footer(device)
+}
+
+/// Side of a square in the test pattern's checkerboard, in pixels.
+const CHECKER: u32 = 8;
+
+/// Shown by `screen test` on the serial console: a frame along the edges and
+/// a block of ink next to a checkerboard, to check that every part of the
+/// panel draws.
+pub fn test_pattern<D>(target: &mut D, device: &DeviceInfo) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let area = target.bounding_box();
+    area.into_styled(PrimitiveStyle::with_stroke(INK, 1))
+        .draw(target)?;
+
+    let side = (area.size.width / 4).min(area.size.height / 3) / CHECKER * CHECKER;
+    let origin = area.center() - Point::new(side as i32, side as i32 / 2);
+    target.fill_solid(&Rectangle::new(origin, Size::new(side, side)), INK)?;
+    let squares = side / CHECKER;
+    for row in 0..squares {
+        for column in (row % 2..squares).step_by(2) {
+            let offset = Point::new(
+                ((squares + column) * CHECKER) as i32,
+                (row * CHECKER) as i32,
+            );
+            let square = Rectangle::new(origin + offset, Size::new(CHECKER, CHECKER));
+            target.fill_solid(&square, INK)?;
+        }
+    }
+
+    let mut page = Page::new(target);
+    page.title("Screen test")?;
+    page.text(format_args!(
+        "{} x {} pixels",
+        area.size.width, area.size.height
+    ))?;
+    page.footer(device)
+}
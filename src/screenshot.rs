@@ -0,0 +1,261 @@
+use core::fmt::Write as _;
+
+use embedded_graphics::prelude::Size;
+
+/// A framebuffer as sent to the panel, encoded as a netpbm image: PBM for
+/// black and white frames, PGM with four levels for grayscale ones and PPM
+/// for black, white and red ones.
+///
+/// The encoding depends only on what the pixels look like, not on how the
+/// panel stores them, so the same frame has the same [`Screenshot::hash`] on
+/// every panel and in the simulator.
+pub struct Screenshot<'a> {
+    frame: Frame<'a>,
+    size: Size,
+}
+
+enum Frame<'a> {
+    /// One bit per pixel, rows padded to whole bytes. Pixels whose bit
+    /// equals `paper` are blank.
+    Mono { bytes: &'a [u8], paper: bool },
+    /// The two bit planes of a [`GrayFrame`](crate::epaper::GrayFrame).
+    Gray { planes: [&'a [u8]; 2] },
+    /// The two bit planes of a [`TriColorFrame`](crate::epaper::TriColorFrame).
+    TriColor { planes: [&'a [u8]; 2] },
+}
+
+/// The kind of netpbm image a [`Screenshot`] encodes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One bit per pixel, black as 1.
+    Pbm,
+    /// One byte per pixel from 0, black, to 3, white.
+    Pgm,
+    /// Three bytes per pixel, each channel 0 or 1.
+    Ppm,
+}
+
+/// Largest run of bytes handed to the sink at once.
+const CHUNK: usize = 64;
+
+impl<'a> Screenshot<'a> {
+    pub fn mono(bytes: &'a [u8], paper: bool, size: Size) -> Self {
+        Self {
+            frame: Frame::Mono { bytes, paper },
+            size,
+        }
+    }
+
+    pub fn gray(planes: [&'a [u8]; 2], size: Size) -> Self {
+        Self {
+            frame: Frame::Gray { planes },
+            size,
+        }
+    }
+
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    pub fn tricolor(planes: [&'a [u8]; 2], size: Size) -> Self {
+        Self {
+            frame: Frame::TriColor { planes },
+            size,
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        match self.frame {
+            Frame::Mono { .. } => Format::Pbm,
+            Frame::Gray { .. } => Format::Pgm,
+            Frame::TriColor { .. } => Format::Ppm,
+        }
+    }
+
+    /// MIME type of the encoded image.
+    pub fn content_type(&self) -> &'static str {
+        match self.format() {
+            Format::Pbm => "image/x-portable-bitmap",
+            Format::Pgm => "image/x-portable-graymap",
+            Format::Ppm => "image/x-portable-pixmap",
+        }
+    }
+
+    fn header(&self) -> heapless::String<24> {
+        let mut header = heapless::String::new();
+        // Safe to unwrap: two u32 and the magic fit.
+        let Size { width, height } = self.size;
+        match self.format() {
+            Format::Pbm => write!(header, "P4\n{width} {height}\n"),
+            Format::Pgm => write!(header, "P5\n{width} {height}\n3\n"),
+            Format::Ppm => write!(header, "P6\n{width} {height}\n1\n"),
+        }
+        .unwrap();
+        header
+    }
+
+    fn stride(&self) -> usize {
+        self.size.width.div_ceil(8) as usize
+    }
+
+    /// Length of the encoded image in bytes.
+    pub fn encoded_len(&self) -> usize {
+        let pixels = match self.format() {
+            Format::Pbm => self.stride(),
+            Format::Pgm => self.size.width as usize,
+            Format::Ppm => self.size.width as usize * 3,
+        } * self.size.height as usize;
+        self.header().len() + pixels
+    }
+
+    /// Feed the encoded image to `sink`, a piece at a time.
+    pub fn encode(&self, mut sink: impl FnMut(&[u8])) {
+        sink(self.header().as_bytes());
+        self.encode_pixels(sink);
+    }
+
+    /// Like [`Screenshot::encode`], without the header.
+    pub fn encode_pixels(&self, mut sink: impl FnMut(&[u8])) {
+        let mut chunk = [0; CHUNK];
+        let mut offset = 0;
+        loop {
+            let len = self.read_pixels(offset, &mut chunk);
+            if len == 0 {
+                break;
+            }
+            sink(&chunk[..len]);
+            offset += len;
+        }
+    }
+
+    /// Copy the encoded image from `offset` on into `buf`, so that it can be
+    /// sent a piece at a time without holding all of it. Returns how many
+    /// bytes were copied, 0 once past the end.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let header = self.header();
+        let header = header.as_bytes();
+        let Some(rest) = offset.checked_sub(header.len()) else {
+            let len = (header.len() - offset).min(buf.len());
+            buf[..len].copy_from_slice(&header[offset..offset + len]);
+            return len + self.read_pixels(0, &mut buf[len..]);
+        };
+        self.read_pixels(rest, buf)
+    }
+
+    /// Like [`Screenshot::read`], for the pixels after the header.
+    fn read_pixels(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let end = self.encoded_len() - self.header().len();
+        let len = end.saturating_sub(offset).min(buf.len());
+        for (index, dst) in (offset..).zip(&mut buf[..len]) {
+            *dst = self.pixel_byte(index);
+        }
+        len
+    }
+
+    /// Byte `index` of the encoded pixels.
+    fn pixel_byte(&self, index: usize) -> u8 {
+        match self.frame {
+            // PBM has black as 1.
+            Frame::Mono { bytes, paper } => match paper {
+                true => !bytes[index],
+                false => bytes[index],
+            },
+            Frame::Gray { planes } => {
+                // Level 3 is white, as in `GrayFrame`.
+                let [first, second] = self.bits(planes, index);
+                first as u8 * 2 + second as u8
+            }
+            Frame::TriColor { planes } => {
+                let rgb = match self.bits(planes, index / 3) {
+                    [_, true] => [1, 0, 0],
+                    [true, false] => [1, 1, 1],
+                    [false, false] => [0, 0, 0],
+                };
+                rgb[index % 3]
+            }
+        }
+    }
+
+    /// The bits of pixel `index`, counted row by row, in both planes of a two
+    /// plane frame.
+    fn bits(&self, planes: [&[u8]; 2], index: usize) -> [bool; 2] {
+        let width = self.size.width as usize;
+        let (y, x) = (index / width, index % width);
+        let byte = y * self.stride() + x / 8;
+        planes.map(|plane| plane[byte] & (0x80 >> (x % 8)) != 0)
+    }
+
+    /// FNV-1a over the encoded image.
+    pub fn hash(&self) -> u32 {
+        let mut hash = 0x811c_9dc5_u32;
+        self.encode(|piece| {
+            for &byte in piece {
+                hash = (hash ^ byte as u32).wrapping_mul(0x0100_0193);
+            }
+        });
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ten pixels wide, two rows, with the pixels of the first row in every
+    /// combination of the two planes.
+    const PLANES: [&[u8]; 2] = [
+        &[0b1010_0000, 0b0100_0000, 0, 0],
+        &[0b1100_0000, 0b1000_0000, 0xff, 0xff],
+    ];
+    const SIZE: Size = Size::new(10, 2);
+
+    fn encoded(shot: &Screenshot<'_>) -> Vec<u8> {
+        let mut image = Vec::new();
+        shot.encode(|piece| image.extend_from_slice(piece));
+        image
+    }
+
+    #[test]
+    fn encodes_netpbm() {
+        let mono = Screenshot::mono(&[0xf0, 0x00, 0x0f, 0xff], true, SIZE);
+        assert_eq!(encoded(&mono), b"P4\n10 2\n\x0f\xff\xf0\x00");
+
+        let gray = Screenshot::gray(PLANES, SIZE);
+        let mut expected = b"P5\n10 2\n3\n".to_vec();
+        expected.extend([3, 1, 2, 0, 0, 0, 0, 0, 1, 2]);
+        expected.extend([1; 10]);
+        assert_eq!(encoded(&gray), expected);
+
+        let tricolor = Screenshot::tricolor(PLANES, SIZE);
+        let image = encoded(&tricolor);
+        assert_eq!(image.len(), tricolor.encoded_len());
+        assert_eq!(
+            &image[..22],
+            b"P6\n10 2\n1\n\x01\x00\x00\x01\x00\x00\x01\x01\x01\x00\x00\x00"
+        );
+    }
+
+    #[test]
+    fn reads_in_pieces() {
+        let shots = [
+            Screenshot::mono(&[0xf0, 0x00, 0x0f, 0xff], false, SIZE),
+            Screenshot::gray(PLANES, SIZE),
+            Screenshot::tricolor(PLANES, SIZE),
+        ];
+        for shot in &shots {
+            for piece_len in [1, 7, 64] {
+                let mut image = Vec::new();
+                let mut piece = vec![0; piece_len];
+                loop {
+                    let len = shot.read(image.len(), &mut piece);
+                    if len == 0 {
+                        break;
+                    }
+                    image.extend_from_slice(&piece[..len]);
+                }
+                assert_eq!(image, encoded(shot));
+            }
+            assert_eq!(shot.read(shot.encoded_len() + 5, &mut [0; 8]), 0);
+        }
+    }
+}
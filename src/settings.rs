@@ -0,0 +1,67 @@
+use core::fmt;
+
+/// Values of on/off settings.
+pub const SWITCHES: [(&str, bool); 2] = [("false", false), ("true", true)];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SettingError {
+    UnknownName,
+    /// Not one of the values the setting takes, which are listed.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SettingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingError::UnknownName => write!(f, "unknown setting"),
+            SettingError::Invalid(values) => write!(f, "must be {values}"),
+        }
+    }
+}
+
+/// A setting as read back from the settings it is part of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    /// One of the names from the setting's table.
+    Name(&'static str),
+    Count(u32),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Name(name) => f.write_str(name),
+            Value::Count(count) => write!(f, "{count}"),
+        }
+    }
+}
+
+/// The entry of `choices` named `value`, ignoring case. `values` lists the
+/// names for the error.
+pub fn choose<T: Copy>(
+    choices: &[(&str, T)],
+    value: &str,
+    values: &'static str,
+) -> Result<T, SettingError> {
+    choices
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(value.trim()))
+        .map(|&(_, choice)| choice)
+        .ok_or(SettingError::Invalid(values))
+}
+
+pub fn name_of<T: PartialEq>(choices: &[(&'static str, T)], value: T) -> &'static str {
+    // Safe to unwrap: every value has a name.
+    choices
+        .iter()
+        .find(|(_, choice)| *choice == value)
+        .unwrap()
+        .0
+}
+
+pub fn count(value: &str) -> Result<u32, SettingError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| SettingError::Invalid("a whole number"))
+}
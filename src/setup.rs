@@ -0,0 +1,104 @@
+use core::fmt::Write as _;
+use core::net::Ipv4Addr;
+
+use embassy_executor::Spawner;
+use embassy_net::Stack;
+use embassy_time::{Duration, WithTimeout};
+use esp_hal::peripherals::WIFI;
+use log::{error, info};
+
+use crate::config::Config;
+use crate::{dhcpd, dnsd, portal, rtc, wifi};
+
+/// Where the device is on its own network, and the setup page with it.
+pub const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+/// How long the access point stays up without settings being saved.
+pub const TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+const PERSISTED_MAGIC: u32 = 0x5345_5455; // "SETU"
+
+/// `[magic, setup requested, checksum]`.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PERSISTED: [u32; 3] = [0; 3];
+
+fn store(requested: bool) {
+    let mut words = [PERSISTED_MAGIC, requested as u32, 0];
+    words[2] = rtc::checksum(&words[..2]);
+    // SAFETY: only accessed from `main` during boot.
+    unsafe { (&raw mut PERSISTED).write_volatile(words) };
+}
+
+/// Start the setup on the next boot, which comes from a reset rather than a
+/// power loss.
+pub fn request() {
+    store(true);
+}
+
+/// Whether setup was requested before the last reset. Clears the request.
+pub fn take_request() -> bool {
+    // SAFETY: see `store`.
+    let words = unsafe { (&raw const PERSISTED).read_volatile() };
+    let requested =
+        words[0] == PERSISTED_MAGIC && words[2] == rtc::checksum(&words[..2]) && words[1] != 0;
+    if requested {
+        store(false);
+    }
+    requested
+}
+
+/// Name of the access point, told apart from other devices by the end of the
+/// MAC address.
+pub fn network_name(mac: [u8; 6]) -> heapless::String<16> {
+    let mut name = heapless::String::new();
+    // Safe to unwrap: the name is 10 characters.
+    write!(name, "TRMNL-{:02X}{:02X}", mac[4], mac[5]).unwrap();
+    name
+}
+
+/// What a phone camera reads from a QR code to join the open network `name`.
+pub fn join_code(name: &str) -> heapless::String<48> {
+    let mut code = heapless::String::new();
+    // Safe to unwrap: names from `network_name` fit.
+    write!(code, "WIFI:T:nopass;S:{name};;").unwrap();
+    code
+}
+
+/// Open the access point `name` with the setup page until settings are saved
+/// or [`TIMEOUT`] passes. Returns whether settings were saved.
+pub async fn run(
+    spawner: &Spawner,
+    wifi: WIFI<'static>,
+    seed: u64,
+    name: &str,
+    config: &Config,
+) -> bool {
+    let (stack, mut controller) =
+        match wifi::start_access_point(spawner, wifi, seed, name, ADDRESS).await {
+            Ok(started) => started,
+            Err(e) => {
+                error!("Cannot start the access point: {e:?}");
+                return false;
+            }
+        };
+    spawner.spawn(dhcp_server(stack).unwrap());
+    spawner.spawn(dns_server(stack).unwrap());
+    info!("Setup page at http://{ADDRESS}/");
+    portal::serve(stack, ADDRESS, &mut controller, config)
+        .with_timeout(TIMEOUT)
+        .await
+        .is_ok()
+}
+
+#[embassy_executor::task]
+async fn dhcp_server(stack: Stack<'static>) {
+    if let Err(error) = dhcpd::serve(stack, ADDRESS).await {
+        error!("Cannot serve DHCP: {error:?}");
+    }
+}
+
+#[embassy_executor::task]
+async fn dns_server(stack: Stack<'static>) {
+    if let Err(error) = dnsd::serve(stack, ADDRESS).await {
+        error!("Cannot serve DNS: {error:?}");
+    }
+}
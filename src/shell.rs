@@ -0,0 +1,586 @@
+use core::fmt::{Display, Write};
+use core::net::Ipv4Addr;
+
+use crate::datetime::DateTime;
+
+/// Longest command line taken; the rest of a longer line is dropped.
+pub const MAX_LINE: usize = 160;
+/// Longest setting value shown.
+pub const MAX_VALUE: usize = 96;
+/// Log lines `log tail` shows without a count.
+const DEFAULT_TAIL: usize = 20;
+
+const HELP: &str = "\
+status                     device, network and power state
+refresh                    fetch and show the next image now
+reboot                     restart the device
+config get <key>           show a setting
+config set <key> <value>   store a setting, used from the next boot on
+config unset <key>         go back to the built-in value of a setting
+wifi scan                  list the networks in range
+log tail [lines]           show the latest log lines
+screen test                draw a test pattern on the panel
+screenshot                 print the framebuffer as hex, for xxd -r -p
+ota                        show the firmware image and how to update it
+factory-reset confirm      forget every stored setting and restart
+";
+
+/// A parsed command line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command<'a> {
+    Help,
+    Status,
+    Refresh,
+    Reboot,
+    ConfigGet(&'a str),
+    ConfigSet { key: &'a str, value: &'a str },
+    ConfigUnset(&'a str),
+    WifiScan,
+    LogTail(usize),
+    ScreenTest,
+    Screenshot,
+    Ota,
+    FactoryReset,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error<'a> {
+    Unknown(&'a str),
+    /// The command exists but was given the wrong arguments.
+    Usage(&'static str),
+}
+
+impl Display for Error<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Unknown(command) => write!(f, "unknown command {command:?}, try \"help\""),
+            Error::Usage(usage) => write!(f, "usage: {usage}"),
+        }
+    }
+}
+
+impl<'a> Command<'a> {
+    /// Parse `line`; `None` if it is blank.
+    pub fn parse(line: &'a str) -> Result<Option<Self>, Error<'a>> {
+        let line = line.trim();
+        let (word, rest) = split_word(line);
+        let (argument, tail) = split_word(rest);
+        let command = match word {
+            "" => return Ok(None),
+            "help" | "?" => Command::Help,
+            "status" => Command::Status,
+            "refresh" => Command::Refresh,
+            "reboot" => Command::Reboot,
+            "config" => match (argument, split_word(tail)) {
+                ("get", (key, "")) if !key.is_empty() => Command::ConfigGet(key),
+                ("get", _) => return Err(Error::Usage("config get <key>")),
+                ("set", (key, value)) if !key.is_empty() && !value.is_empty() => {
+                    Command::ConfigSet {
+                        key,
+                        value: unquote(value),
+                    }
+                }
+                ("set", _) => return Err(Error::Usage("config set <key> <value>")),
+                ("unset", (key, "")) if !key.is_empty() => Command::ConfigUnset(key),
+                ("unset", _) => return Err(Error::Usage("config unset <key>")),
+                _ => return Err(Error::Usage("config get|set|unset <key> [value]")),
+            },
+            "wifi" => match (argument, tail) {
+                ("scan", "") => Command::WifiScan,
+                _ => return Err(Error::Usage("wifi scan")),
+            },
+            "log" => match (argument, split_word(tail)) {
+                ("tail", ("", _)) => Command::LogTail(DEFAULT_TAIL),
+                ("tail", (lines, "")) => match lines.parse() {
+                    Ok(lines) if lines > 0 => Command::LogTail(lines),
+                    _ => return Err(Error::Usage("log tail [lines]")),
+                },
+                _ => return Err(Error::Usage("log tail [lines]")),
+            },
+            "screen" => match (argument, tail) {
+                ("test", "") => Command::ScreenTest,
+                _ => return Err(Error::Usage("screen test")),
+            },
+            "screenshot" => match argument {
+                "" => Command::Screenshot,
+                _ => return Err(Error::Usage("screenshot")),
+            },
+            "ota" => match argument {
+                "" => Command::Ota,
+                _ => return Err(Error::Usage("ota")),
+            },
+            "factory-reset" => match (argument, tail) {
+                ("confirm", "") => Command::FactoryReset,
+                _ => return Err(Error::Usage("factory-reset confirm")),
+            },
+            _ => return Err(Error::Unknown(word)),
+        };
+        Ok(Some(command))
+    }
+}
+
+/// The first word of `text` and what follows it, without the space between.
+fn split_word(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (text, ""),
+    }
+}
+
+/// `value` without the double quotes that keep its spaces, or make it empty.
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// What `status` shows.
+pub struct Status {
+    pub firmware: &'static str,
+    pub uptime_secs: u64,
+    pub mac: [u8; 6],
+    pub ssid: heapless::String<32>,
+    /// `None` until the device is on the network.
+    pub ip: Option<Ipv4Addr>,
+    pub rssi: Option<i32>,
+    pub battery_mv: Option<u32>,
+    pub time: Option<DateTime>,
+    pub failed_boots: u32,
+    pub frame_hash: Option<u32>,
+}
+
+/// A setting as `config get` shows it.
+pub struct Setting {
+    /// What the device uses now.
+    pub running: heapless::String<MAX_VALUE>,
+    /// What the store has, for the next boot.
+    pub stored: Option<heapless::String<MAX_VALUE>>,
+}
+
+/// What the commands act on.
+pub trait Device {
+    type Error: Display;
+
+    fn status(&mut self) -> Status;
+    /// Cut the wait for the next image short.
+    fn refresh(&mut self) -> Result<(), Self::Error>;
+    /// Restart, after giving the output a moment to go out.
+    async fn reboot(&mut self);
+    fn config_get(&mut self, key: &str) -> Result<Setting, Self::Error>;
+    fn config_set(&mut self, key: &str, value: &str) -> Result<(), Self::Error>;
+    fn config_unset(&mut self, key: &str) -> Result<(), Self::Error>;
+    /// Call `found` with the name, signal strength in dBm and openness of each
+    /// network in range.
+    async fn wifi_scan(&mut self, found: &mut dyn FnMut(&str, i8, bool))
+    -> Result<(), Self::Error>;
+    /// Call `line` with each of the latest `lines` log lines, oldest first.
+    fn log_tail(&mut self, lines: usize, line: &mut dyn FnMut(&str));
+    async fn screen_test(&mut self) -> Result<(), Self::Error>;
+    /// Print the framebuffer as a hex dump of its screenshot.
+    async fn screenshot(&mut self) -> Result<(), Self::Error>;
+    /// Write what there is to know about firmware updates.
+    fn ota(&mut self, out: &mut dyn Write) -> core::fmt::Result;
+    /// Forget the stored settings.
+    fn factory_reset(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Run the command on `line` against `device`, writing what it has to say to
+/// `out`.
+pub async fn execute<D: Device>(
+    line: &str,
+    device: &mut D,
+    out: &mut impl Write,
+) -> core::fmt::Result {
+    let command = match Command::parse(line) {
+        Ok(Some(command)) => command,
+        Ok(None) => return Ok(()),
+        Err(e) => return writeln!(out, "{e}"),
+    };
+    match command {
+        Command::Help => out.write_str(HELP),
+        Command::Status => write_status(&device.status(), out),
+        Command::Refresh => match device.refresh() {
+            Ok(()) => writeln!(out, "refreshing"),
+            Err(e) => writeln!(out, "cannot refresh: {e}"),
+        },
+        Command::Reboot => {
+            writeln!(out, "rebooting")?;
+            device.reboot().await;
+            Ok(())
+        }
+        Command::ConfigGet(key) => match device.config_get(key) {
+            Ok(setting) => write_setting(key, &setting, out),
+            Err(e) => writeln!(out, "cannot get {key}: {e}"),
+        },
+        Command::ConfigSet { key, value } => match device.config_set(key, value) {
+            Ok(()) => writeln!(out, "{key} stored, reboot to use it"),
+            Err(e) => writeln!(out, "cannot set {key}: {e}"),
+        },
+        Command::ConfigUnset(key) => match device.config_unset(key) {
+            Ok(()) => writeln!(out, "{key} unset, reboot to use the built-in value"),
+            Err(e) => writeln!(out, "cannot unset {key}: {e}"),
+        },
+        Command::WifiScan => {
+            let mut result = Ok(());
+            let mut networks = 0;
+            let scanned = device
+                .wifi_scan(&mut |ssid, rssi, open| {
+                    networks += 1;
+                    let security = if open { "open" } else { "secured" };
+                    result = result.and(writeln!(out, "{rssi:>4} dBm  {security:<7}  {ssid}"));
+                })
+                .await;
+            result?;
+            match scanned {
+                Ok(()) => writeln!(out, "{networks} networks"),
+                Err(e) => writeln!(out, "cannot scan: {e}"),
+            }
+        }
+        Command::LogTail(lines) => {
+            let mut result = Ok(());
+            device.log_tail(lines, &mut |line| {
+                result = result.and(writeln!(out, "{line}"))
+            });
+            result
+        }
+        Command::ScreenTest => match device.screen_test().await {
+            Ok(()) => writeln!(out, "drawing the test pattern"),
+            Err(e) => writeln!(out, "cannot show the test pattern: {e}"),
+        },
+        Command::Screenshot => match device.screenshot().await {
+            Ok(()) => writeln!(out, "printing the screenshot"),
+            Err(e) => writeln!(out, "cannot print a screenshot: {e}"),
+        },
+        Command::Ota => device.ota(out),
+        Command::FactoryReset => match device.factory_reset() {
+            Ok(()) => {
+                writeln!(out, "settings erased, rebooting")?;
+                device.reboot().await;
+                Ok(())
+            }
+            Err(e) => writeln!(out, "cannot reset: {e}"),
+        },
+    }
+}
+
+fn write_status(status: &Status, out: &mut impl Write) -> core::fmt::Result {
+    let [a, b, c, d, e, f] = status.mac;
+    writeln!(out, "firmware   {}", status.firmware)?;
+    writeln!(out, "uptime     {} s", status.uptime_secs)?;
+    writeln!(
+        out,
+        "mac        {a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{f:02x}"
+    )?;
+    writeln!(out, "wifi       {}", status.ssid)?;
+    match status.ip {
+        Some(ip) => writeln!(out, "ip         {ip}")?,
+        None => writeln!(out, "ip         offline")?,
+    }
+    if let Some(rssi) = status.rssi {
+        writeln!(out, "signal     {rssi} dBm")?;
+    }
+    if let Some(millivolts) = status.battery_mv {
+        writeln!(out, "battery    {millivolts} mV")?;
+    }
+    match status.time {
+        Some(time) => writeln!(out, "time       {time}")?,
+        None => writeln!(out, "time       not synchronized")?,
+    }
+    writeln!(out, "boot fails {}", status.failed_boots)?;
+    match status.frame_hash {
+        Some(hash) => writeln!(out, "frame      {hash:08x}"),
+        None => writeln!(out, "frame      unknown"),
+    }
+}
+
+fn write_setting<'a>(key: &str, setting: &'a Setting, out: &mut impl Write) -> core::fmt::Result {
+    let secret = key.contains("password");
+    let shown = |value: &'a str| match secret && !value.is_empty() {
+        true => "********",
+        false => value,
+    };
+    writeln!(out, "{key} = {:?}", shown(&setting.running))?;
+    match &setting.stored {
+        Some(stored) if *stored != setting.running => {
+            writeln!(out, "stored: {:?}, used after a reboot", shown(stored))
+        }
+        Some(_) => writeln!(out, "stored"),
+        None => writeln!(out, "built-in"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("  "), Ok(None));
+        assert_eq!(Command::parse("status"), Ok(Some(Command::Status)));
+        assert_eq!(Command::parse(" reboot \r"), Ok(Some(Command::Reboot)));
+        assert_eq!(
+            Command::parse("config get wifi.ssid"),
+            Ok(Some(Command::ConfigGet("wifi.ssid")))
+        );
+        assert_eq!(
+            Command::parse("config set wifi.ssid  My Home  "),
+            Ok(Some(Command::ConfigSet {
+                key: "wifi.ssid",
+                value: "My Home"
+            }))
+        );
+        assert_eq!(
+            Command::parse("config set wifi.password \"\""),
+            Ok(Some(Command::ConfigSet {
+                key: "wifi.password",
+                value: ""
+            }))
+        );
+        assert_eq!(
+            Command::parse("config unset trmnl.address"),
+            Ok(Some(Command::ConfigUnset("trmnl.address")))
+        );
+        assert_eq!(Command::parse("wifi scan"), Ok(Some(Command::WifiScan)));
+        assert_eq!(Command::parse("log tail"), Ok(Some(Command::LogTail(20))));
+        assert_eq!(Command::parse("log tail 5"), Ok(Some(Command::LogTail(5))));
+        assert_eq!(Command::parse("screen test"), Ok(Some(Command::ScreenTest)));
+        assert_eq!(Command::parse("screenshot"), Ok(Some(Command::Screenshot)));
+        assert_eq!(Command::parse("ota"), Ok(Some(Command::Ota)));
+        assert_eq!(
+            Command::parse("factory-reset confirm"),
+            Ok(Some(Command::FactoryReset))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        assert_eq!(Command::parse("reset"), Err(Error::Unknown("reset")));
+        assert_eq!(
+            Command::parse("config set wifi.ssid"),
+            Err(Error::Usage("config set <key> <value>"))
+        );
+        assert_eq!(
+            Command::parse("config get a b"),
+            Err(Error::Usage("config get <key>"))
+        );
+        assert_eq!(
+            Command::parse("config"),
+            Err(Error::Usage("config get|set|unset <key> [value]"))
+        );
+        assert_eq!(
+            Command::parse("log tail 0"),
+            Err(Error::Usage("log tail [lines]"))
+        );
+        assert_eq!(
+            Command::parse("log tail many"),
+            Err(Error::Usage("log tail [lines]"))
+        );
+        assert_eq!(Command::parse("wifi"), Err(Error::Usage("wifi scan")));
+        assert_eq!(
+            Command::parse("screenshot now"),
+            Err(Error::Usage("screenshot"))
+        );
+        assert_eq!(
+            Command::parse("ota http://example.com/firmware.bin"),
+            Err(Error::Usage("ota"))
+        );
+        assert_eq!(
+            Command::parse("factory-reset"),
+            Err(Error::Usage("factory-reset confirm"))
+        );
+    }
+
+    /// Records what the shell asks of it.
+    #[derive(Default)]
+    struct MockDevice {
+        calls: Vec<String>,
+        stored: Vec<(String, String)>,
+        fail: bool,
+    }
+
+    impl Device for MockDevice {
+        type Error = &'static str;
+
+        fn status(&mut self) -> Status {
+            Status {
+                firmware: "1.2.3",
+                uptime_secs: 42,
+                mac: [0xde, 0xad, 0xbe, 0xef, 0x00, 0x01],
+                ssid: "Home".try_into().unwrap(),
+                ip: Some(Ipv4Addr::new(10, 0, 0, 7)),
+                rssi: Some(-61),
+                battery_mv: None,
+                time: Some(DateTime::from_unix(1_700_000_000)),
+                failed_boots: 0,
+                frame_hash: Some(0xabcd),
+            }
+        }
+
+        fn refresh(&mut self) -> Result<(), Self::Error> {
+            self.calls.push("refresh".into());
+            Ok(())
+        }
+
+        async fn reboot(&mut self) {
+            self.calls.push("reboot".into());
+        }
+
+        fn config_get(&mut self, key: &str) -> Result<Setting, Self::Error> {
+            if key == "nope" {
+                return Err("unknown config key");
+            }
+            let stored = self.stored.iter().find(|(k, _)| k == key);
+            Ok(Setting {
+                running: "secret1234".try_into().unwrap(),
+                stored: stored.map(|(_, value)| value.as_str().try_into().unwrap()),
+            })
+        }
+
+        fn config_set(&mut self, key: &str, value: &str) -> Result<(), Self::Error> {
+            self.stored.push((key.into(), value.into()));
+            Ok(())
+        }
+
+        fn config_unset(&mut self, key: &str) -> Result<(), Self::Error> {
+            self.stored.retain(|(k, _)| k != key);
+            Ok(())
+        }
+
+        async fn wifi_scan(
+            &mut self,
+            found: &mut dyn FnMut(&str, i8, bool),
+        ) -> Result<(), Self::Error> {
+            found("Home", -40, false);
+            found("Cafe", -75, true);
+            if self.fail { Err("busy") } else { Ok(()) }
+        }
+
+        fn log_tail(&mut self, lines: usize, line: &mut dyn FnMut(&str)) {
+            for i in 0..lines.min(3) {
+                line(&format!("line {i}"));
+            }
+        }
+
+        async fn screen_test(&mut self) -> Result<(), Self::Error> {
+            if self.fail {
+                return Err("the panel is not ready");
+            }
+            self.calls.push("screen test".into());
+            Ok(())
+        }
+
+        async fn screenshot(&mut self) -> Result<(), Self::Error> {
+            if self.fail {
+                return Err("not online");
+            }
+            self.calls.push("screenshot".into());
+            Ok(())
+        }
+
+        fn ota(&mut self, out: &mut dyn Write) -> core::fmt::Result {
+            writeln!(out, "no OTA")
+        }
+
+        fn factory_reset(&mut self) -> Result<(), Self::Error> {
+            self.calls.push("factory reset".into());
+            self.stored.clear();
+            Ok(())
+        }
+    }
+
+    /// Runs a future the mock device never makes wait.
+    fn run(line: &str, device: &mut MockDevice) -> String {
+        let mut out = String::new();
+        let mut context = Context::from_waker(Waker::noop());
+        match pin!(execute(line, device, &mut out)).poll(&mut context) {
+            Poll::Ready(result) => result.unwrap(),
+            Poll::Pending => panic!("the mock device never waits"),
+        }
+        out
+    }
+
+    #[test]
+    fn dispatches_to_the_device() {
+        let mut device = MockDevice::default();
+        assert!(run("help", &mut device).contains("factory-reset confirm"));
+        assert_eq!(run("", &mut device), "");
+        assert_eq!(
+            run("frobnicate", &mut device),
+            "unknown command \"frobnicate\", try \"help\"\n"
+        );
+        assert_eq!(run("refresh", &mut device), "refreshing\n");
+        assert_eq!(run("reboot", &mut device), "rebooting\n");
+        assert_eq!(
+            run("screen test", &mut device),
+            "drawing the test pattern\n"
+        );
+        assert_eq!(run("screenshot", &mut device), "printing the screenshot\n");
+        assert_eq!(run("ota", &mut device), "no OTA\n");
+        assert_eq!(
+            device.calls,
+            ["refresh", "reboot", "screen test", "screenshot"]
+        );
+
+        let status = run("status", &mut device);
+        assert!(status.contains("mac        de:ad:be:ef:00:01\n"));
+        assert!(status.contains("ip         10.0.0.7\n"));
+        assert!(status.contains("time       2023-11-14 22:13:20\n"));
+        assert!(!status.contains("battery"));
+
+        assert_eq!(
+            run("wifi scan", &mut device),
+            " -40 dBm  secured  Home\n -75 dBm  open     Cafe\n2 networks\n"
+        );
+        assert_eq!(run("log tail 2", &mut device), "line 0\nline 1\n");
+
+        device.fail = true;
+        assert_eq!(
+            run("screen test", &mut device),
+            "cannot show the test pattern: the panel is not ready\n"
+        );
+        assert_eq!(
+            run("screenshot", &mut device),
+            "cannot print a screenshot: not online\n"
+        );
+        assert!(run("wifi scan", &mut device).ends_with("cannot scan: busy\n"));
+    }
+
+    #[test]
+    fn shows_settings() {
+        let mut device = MockDevice::default();
+        assert_eq!(
+            run("config get wifi.ssid", &mut device),
+            "wifi.ssid = \"secret1234\"\nbuilt-in\n"
+        );
+        assert_eq!(
+            run("config set wifi.ssid Office", &mut device),
+            "wifi.ssid stored, reboot to use it\n"
+        );
+        assert_eq!(
+            run("config get wifi.ssid", &mut device),
+            "wifi.ssid = \"secret1234\"\nstored: \"Office\", used after a reboot\n"
+        );
+        run("config set wifi.password hunter22", &mut device);
+        assert_eq!(
+            run("config get wifi.password", &mut device),
+            "wifi.password = \"********\"\nstored: \"********\", used after a reboot\n"
+        );
+        run("config unset wifi.ssid", &mut device);
+        assert!(run("config get wifi.ssid", &mut device).ends_with("built-in\n"));
+        assert_eq!(
+            run("config get nope", &mut device),
+            "cannot get nope: unknown config key\n"
+        );
+
+        assert_eq!(
+            run("factory-reset confirm", &mut device),
+            "settings erased, rebooting\n"
+        );
+        assert!(device.stored.is_empty());
+        assert_eq!(device.calls, ["factory reset", "reboot"]);
+    }
+}
@@ -0,0 +1,160 @@
+use core::fmt::Display;
+
+use embassy_net::{
+    IpEndpoint, Stack,
+    dns::{self, DnsQueryType},
+    udp::{self, PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Instant, WithTimeout};
+use log::debug;
+
+const NTP_PORT: u16 = 123;
+const PACKET_LEN: usize = 48;
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Seconds between the NTP epoch (1900) and the unix epoch (1970).
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+
+#[derive(Debug)]
+pub enum Error {
+    Dns,
+    Socket,
+    Timeout,
+    InvalidResponse,
+    Unsynchronized,
+}
+
+impl From<dns::Error> for Error {
+    fn from(e: dns::Error) -> Self {
+        debug!("Discarding DNS error details: {e:?}");
+        Self::Dns
+    }
+}
+
+impl From<udp::BindError> for Error {
+    fn from(e: udp::BindError) -> Self {
+        debug!("Discarding UDP bind error details: {e:?}");
+        Self::Socket
+    }
+}
+
+impl From<udp::SendError> for Error {
+    fn from(e: udp::SendError) -> Self {
+        debug!("Discarding UDP send error details: {e:?}");
+        Self::Socket
+    }
+}
+
+impl From<udp::RecvError> for Error {
+    fn from(e: udp::RecvError) -> Self {
+        debug!("Discarding UDP receive error details: {e:?}");
+        Self::Socket
+    }
+}
+
+impl From<embassy_time::TimeoutError> for Error {
+    fn from(_: embassy_time::TimeoutError) -> Self {
+        Self::Timeout
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Dns => write!(f, "could not resolve ntp server"),
+            Error::Socket => write!(f, "udp socket error"),
+            Error::Timeout => write!(f, "ntp server took too long to respond"),
+            Error::InvalidResponse => write!(f, "invalid ntp response"),
+            Error::Unsynchronized => write!(f, "ntp server is not synchronized"),
+        }
+    }
+}
+
+/// Ask `server` for the current time.
+///
+/// Returns the UTC time in microseconds since the unix epoch at the moment the
+/// reply was received, corrected for half of the network round trip.
+pub async fn query(stack: Stack<'_>, server: &str, nonce: u64) -> Result<u64, Error> {
+    let addrs = stack.dns_query(server, DnsQueryType::A).await?;
+    let addr = *addrs.first().ok_or(Error::Dns)?;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buf = [0; PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buf = [0; PACKET_LEN];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    socket.bind(0)?;
+
+    let sent_at = Instant::now();
+    socket
+        .send_to(&request(nonce), IpEndpoint::new(addr, NTP_PORT))
+        .await?;
+    let mut packet = [0; PACKET_LEN];
+    let (len, _) = socket
+        .recv_from(&mut packet)
+        .with_timeout(TIMEOUT)
+        .await??;
+    let round_trip = Instant::now() - sent_at;
+
+    let reply = Reply::parse(&packet[..len], nonce)?;
+    Ok(reply.utc_at_receipt(round_trip.as_micros()))
+}
+
+/// A client request. The nonce goes into the transmit timestamp, which the
+/// server echoes back as the originate timestamp.
+fn request(nonce: u64) -> [u8; PACKET_LEN] {
+    let mut packet = [0; PACKET_LEN];
+    // LI = 0 (no warning), VN = 4, Mode = 3 (client).
+    packet[0] = (4 << 3) | 3;
+    packet[40..48].copy_from_slice(&nonce.to_be_bytes());
+    packet
+}
+
+struct Reply {
+    /// When the server received the request, in unix microseconds.
+    receive_us: u64,
+    /// When the server sent the reply, in unix microseconds.
+    transmit_us: u64,
+}
+
+impl Reply {
+    fn parse(packet: &[u8], nonce: u64) -> Result<Self, Error> {
+        let packet: &[u8; PACKET_LEN] = packet.try_into().map_err(|_| Error::InvalidResponse)?;
+        let leap_indicator = packet[0] >> 6;
+        let mode = packet[0] & 0b111;
+        let stratum = packet[1];
+        if mode != 4 || read_u64(packet, 24) != nonce {
+            return Err(Error::InvalidResponse);
+        }
+        // Stratum 0 is a kiss-o'-death packet, LI = 3 an unsynchronized server.
+        if leap_indicator == 3 || stratum == 0 || stratum > 15 {
+            return Err(Error::Unsynchronized);
+        }
+        Ok(Self {
+            receive_us: timestamp_to_unix_us(read_u64(packet, 32)),
+            transmit_us: timestamp_to_unix_us(read_u64(packet, 40)),
+        })
+    }
+
+    fn utc_at_receipt(&self, round_trip_us: u64) -> u64 {
+        let server_hold = self.transmit_us.saturating_sub(self.receive_us);
+        let network_us = round_trip_us.saturating_sub(server_hold);
+        self.transmit_us + network_us / 2
+    }
+}
+
+fn read_u64(packet: &[u8; PACKET_LEN], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&packet[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Converts a 32.32 fixed point NTP timestamp into unix microseconds.
+fn timestamp_to_unix_us(timestamp: u64) -> u64 {
+    let mut secs = timestamp >> 32;
+    // Era 0 ends in 2036; anything that would lie before 1968 belongs to era 1.
+    if secs < 0x8000_0000 {
+        secs += 1 << 32;
+    }
+    let frac_us = ((timestamp & 0xffff_ffff) * 1_000_000) >> 32;
+    (secs - NTP_UNIX_OFFSET_SECS) * 1_000_000 + frac_us
+}
@@ -1,3 +1,4 @@
+#[cfg(feature = "status-led")]
 use smart_leds::{brightness, colors, SmartLedsWrite, RGB8};
 
 #[derive(Debug, Clone, Copy)]
@@ -7,8 +8,11 @@ pub enum Status {
     Working,
     Sleeping,
     Failure,
+    /// Waiting to be set up through its own access point.
+    Setup,
 }
 
+#[cfg(feature = "status-led")]
 impl Status {
     fn as_color(&self) -> RGB8 {
         match self {
@@ -17,15 +21,18 @@ impl Status {
             Self::Working => colors::GREEN,
             Self::Failure => colors::RED,
             Self::BootFailure => colors::CRIMSON,
+            Self::Setup => colors::PURPLE,
         }
     }
 }
 
+#[cfg(feature = "status-led")]
 pub struct Led<LED: SmartLedsWrite> {
     brightness: u8,
     writer: LED,
 }
 
+#[cfg(feature = "status-led")]
 impl<LED> Led<LED>
 where
     LED: SmartLedsWrite<Color = RGB8>,
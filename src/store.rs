@@ -0,0 +1,640 @@
+use core::fmt::Display;
+
+use embedded_storage::nor_flash::NorFlash;
+use log::{debug, info, warn};
+
+/// Layout of the records; sectors of another version are ignored.
+const FORMAT_VERSION: u16 = 1;
+const SECTOR_MAGIC: u32 = 0x4746_4341; // "ACFG"
+/// Magic, version, sequence number and checksum.
+const SECTOR_HEADER: u32 = 16;
+/// Key length, flags, value length and checksum.
+const RECORD_HEADER: usize = 8;
+/// Flash is written in words; records start on one.
+const ALIGN: usize = 4;
+/// A record without a value, hiding earlier ones of its key.
+const FLAG_REMOVED: u8 = 1;
+/// What erased flash reads as, in the key length of the next record.
+const ERASED: u8 = 0xff;
+
+pub const MAX_KEY: usize = 32;
+pub const MAX_VALUE: usize = 256;
+const MAX_RECORD: usize = RECORD_HEADER + MAX_KEY + MAX_VALUE;
+
+#[derive(Debug)]
+pub enum Error {
+    Flash,
+    /// The key or value is longer than [`MAX_KEY`] or [`MAX_VALUE`].
+    TooLong,
+    /// The live records do not fit a sector.
+    Full,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Flash => write!(f, "flash access failed"),
+            Error::TooLong => write!(f, "key or value too long"),
+            Error::Full => write!(f, "config store is full"),
+        }
+    }
+}
+
+fn flash_error(e: impl core::fmt::Debug) -> Error {
+    debug!("Discarding flash error details: {e:?}");
+    Error::Flash
+}
+
+/// CRC-32 (IEEE), bit by bit; records are short and rarely written.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// The sector records are appended to.
+#[derive(Debug, Clone, Copy)]
+struct Active {
+    sector: u32,
+    sequence: u32,
+    /// Offset of the next record in the sector.
+    end: u32,
+}
+
+/// A record read back from flash.
+struct Record<'a> {
+    key: &'a [u8],
+    value: &'a [u8],
+    removed: bool,
+    /// Bytes the record takes up, padding included.
+    len: u32,
+}
+
+fn padded(len: usize) -> usize {
+    len.next_multiple_of(ALIGN)
+}
+
+/// Key-value pairs appended as checksummed records to the sectors of a flash
+/// region.
+///
+/// Records go into one sector at a time. When it is full, the latest record
+/// of each key is copied into the next sector, which then takes over with a
+/// higher sequence number. Going round all sectors in turn spreads the erases
+/// evenly, and a sector only counts once its header is written after its
+/// records, so losing power half way leaves the previous sector in charge.
+pub struct Store<F> {
+    flash: F,
+    /// Where the region starts in `flash`.
+    offset: u32,
+    sector_size: u32,
+    sectors: u32,
+    active: Option<Active>,
+}
+
+impl<F: NorFlash> Store<F> {
+    /// Open the store in the `len` bytes of `flash` from `offset`, both whole
+    /// erase sectors.
+    pub fn open(flash: F, offset: u32, len: u32) -> Result<Self, Error> {
+        let sector_size = F::ERASE_SIZE as u32;
+        let mut store = Self {
+            flash,
+            offset,
+            sector_size,
+            sectors: len / sector_size,
+            active: None,
+        };
+        for sector in 0..store.sectors {
+            let Some(sequence) = store.read_sector_header(sector)? else {
+                continue;
+            };
+            if store
+                .active
+                .is_some_and(|active| active.sequence >= sequence)
+            {
+                continue;
+            }
+            store.active = Some(Active {
+                sector,
+                sequence,
+                end: SECTOR_HEADER,
+            });
+        }
+        if let Some(mut active) = store.active {
+            active.end = store.scan_end(active.sector)?;
+            info!(
+                "Config store in sector {} of {}, {} bytes used",
+                active.sector, store.sectors, active.end
+            );
+            store.active = Some(active);
+        }
+        Ok(store)
+    }
+
+    fn address(&self, sector: u32, offset: u32) -> u32 {
+        self.offset + sector * self.sector_size + offset
+    }
+
+    /// The sequence number of `sector`, if it holds records of this format.
+    fn read_sector_header(&mut self, sector: u32) -> Result<Option<u32>, Error> {
+        let mut header = [0; SECTOR_HEADER as usize];
+        self.flash
+            .read(self.address(sector, 0), &mut header)
+            .map_err(flash_error)?;
+        let word =
+            |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        let valid = word(0) == SECTOR_MAGIC
+            && u16::from_le_bytes([header[4], header[5]]) == FORMAT_VERSION
+            && word(12) == crc32(0, &header[..12]);
+        Ok(valid.then(|| word(8)))
+    }
+
+    /// Read the record at `offset` of `sector` into `buf`. `None` at the end
+    /// of the records.
+    fn read_record<'b>(
+        &mut self,
+        sector: u32,
+        offset: u32,
+        buf: &'b mut [u8; MAX_RECORD],
+    ) -> Result<Option<Record<'b>>, Error> {
+        if offset + RECORD_HEADER as u32 > self.sector_size {
+            return Ok(None);
+        }
+        let address = self.address(sector, offset);
+        self.flash
+            .read(address, &mut buf[..RECORD_HEADER])
+            .map_err(flash_error)?;
+        let key_len = buf[0] as usize;
+        let value_len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        if buf[0] == ERASED || key_len > MAX_KEY || value_len > MAX_VALUE {
+            return Ok(None);
+        }
+        let len = padded(RECORD_HEADER + key_len + value_len);
+        if offset + len as u32 > self.sector_size {
+            return Ok(None);
+        }
+        self.flash
+            .read(address + RECORD_HEADER as u32, &mut buf[RECORD_HEADER..len])
+            .map_err(flash_error)?;
+        let checksum = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let body = &buf[RECORD_HEADER..RECORD_HEADER + key_len + value_len];
+        if checksum != crc32(crc32(0, &buf[..4]), body) {
+            return Ok(None);
+        }
+        let (key, value) = body.split_at(key_len);
+        Ok(Some(Record {
+            key,
+            value,
+            removed: buf[1] & FLAG_REMOVED != 0,
+            len: len as u32,
+        }))
+    }
+
+    /// Where the next record in `sector` goes. A record that does not check
+    /// out, e.g. after losing power while writing it, ends the sector; the
+    /// next write moves on to a fresh one.
+    fn scan_end(&mut self, sector: u32) -> Result<u32, Error> {
+        let mut buf = [0; MAX_RECORD];
+        let mut offset = SECTOR_HEADER;
+        while let Some(record) = self.read_record(sector, offset, &mut buf)? {
+            offset += record.len;
+        }
+        if offset + RECORD_HEADER as u32 <= self.sector_size {
+            let mut next = [0; RECORD_HEADER];
+            self.flash
+                .read(self.address(sector, offset), &mut next)
+                .map_err(flash_error)?;
+            if next[0] != ERASED {
+                warn!("Config store sector {sector} has a broken record at {offset}");
+                return Ok(self.sector_size);
+            }
+        }
+        Ok(offset)
+    }
+
+    /// Read the value of `key` into `buf`.
+    pub fn get<'b>(&mut self, key: &str, buf: &'b mut [u8]) -> Result<Option<&'b [u8]>, Error> {
+        let Some(active) = self.active else {
+            return Ok(None);
+        };
+        let mut record_buf = [0; MAX_RECORD];
+        let mut found = None;
+        let mut offset = SECTOR_HEADER;
+        while offset < active.end {
+            let Some(record) = self.read_record(active.sector, offset, &mut record_buf)? else {
+                break;
+            };
+            if record.key == key.as_bytes() {
+                found = match record.removed {
+                    true => None,
+                    false => {
+                        let len = record.value.len().min(buf.len());
+                        buf[..len].copy_from_slice(&record.value[..len]);
+                        Some(len)
+                    }
+                };
+            }
+            offset += record.len;
+        }
+        Ok(found.map(|len| &buf[..len]))
+    }
+
+    /// Store `value` under `key`, replacing what was there.
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), Error> {
+        self.append(key, value, 0)
+    }
+
+    /// Forget `key`.
+    pub fn remove(&mut self, key: &str) -> Result<(), Error> {
+        self.append(key, &[], FLAG_REMOVED)
+    }
+
+    /// Erase every record.
+    pub fn clear(&mut self) -> Result<(), Error> {
+        let start = self.address(0, 0);
+        self.flash
+            .erase(start, start + self.sectors * self.sector_size)
+            .map_err(flash_error)?;
+        self.active = None;
+        Ok(())
+    }
+
+    fn append(&mut self, key: &str, value: &[u8], flags: u8) -> Result<(), Error> {
+        if key.len() > MAX_KEY || value.len() > MAX_VALUE {
+            return Err(Error::TooLong);
+        }
+        let mut record = [0xff; MAX_RECORD];
+        let len = encode_record(&mut record, key.as_bytes(), value, flags);
+        match self.active {
+            Some(mut active) if active.end + len as u32 <= self.sector_size => {
+                let written = self
+                    .flash
+                    .write(self.address(active.sector, active.end), &record[..len]);
+                // Part of the record may have been written; the next one goes
+                // into a fresh sector rather than over it.
+                active.end = match written {
+                    Ok(()) => active.end + len as u32,
+                    Err(_) => self.sector_size,
+                };
+                self.active = Some(active);
+                written.map_err(flash_error)
+            }
+            _ => self.compact(key, &record[..len]),
+        }
+    }
+
+    /// Move the live records and `record`, which replaces those of `key`,
+    /// into the next sector.
+    fn compact(&mut self, key: &str, record: &[u8]) -> Result<(), Error> {
+        if self.sectors < 2 {
+            return Err(Error::Full);
+        }
+        let (sector, sequence) = match self.active {
+            Some(active) => (
+                (active.sector + 1) % self.sectors,
+                active.sequence.wrapping_add(1),
+            ),
+            None => (0, 1),
+        };
+        let start = self.address(sector, 0);
+        self.flash
+            .erase(start, start + self.sector_size)
+            .map_err(flash_error)?;
+
+        let mut end = SECTOR_HEADER;
+        if let Some(active) = self.active {
+            let mut buf = [0; MAX_RECORD];
+            let mut offset = SECTOR_HEADER;
+            while offset < active.end {
+                let Some(live) = self.read_record(active.sector, offset, &mut buf)? else {
+                    break;
+                };
+                let len = live.len;
+                let keep = !live.removed
+                    && live.key != key.as_bytes()
+                    && !self.replaced(active, live.key, offset + len)?;
+                if keep {
+                    if end + len > self.sector_size - record.len() as u32 {
+                        return Err(Error::Full);
+                    }
+                    self.flash
+                        .write(self.address(sector, end), &buf[..len as usize])
+                        .map_err(flash_error)?;
+                    end += len;
+                }
+                offset += len;
+            }
+        }
+        if end + record.len() as u32 > self.sector_size {
+            return Err(Error::Full);
+        }
+        self.flash
+            .write(self.address(sector, end), record)
+            .map_err(flash_error)?;
+        end += record.len() as u32;
+
+        let mut header = [0; SECTOR_HEADER as usize];
+        header[..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        header[8..12].copy_from_slice(&sequence.to_le_bytes());
+        let checksum = crc32(0, &header[..12]);
+        header[12..].copy_from_slice(&checksum.to_le_bytes());
+        self.flash.write(start, &header).map_err(flash_error)?;
+        debug!("Config store moved to sector {sector}, {end} bytes used");
+        self.active = Some(Active {
+            sector,
+            sequence,
+            end,
+        });
+        Ok(())
+    }
+
+    /// Whether a record of `key` follows `offset` in the active sector.
+    fn replaced(&mut self, active: Active, key: &[u8], mut offset: u32) -> Result<bool, Error> {
+        let mut buf = [0; MAX_RECORD];
+        while offset < active.end {
+            let Some(record) = self.read_record(active.sector, offset, &mut buf)? else {
+                break;
+            };
+            if record.key == key {
+                return Ok(true);
+            }
+            offset += record.len;
+        }
+        Ok(false)
+    }
+}
+
+/// Lay out a record in `buf`, returning its padded length.
+fn encode_record(buf: &mut [u8; MAX_RECORD], key: &[u8], value: &[u8], flags: u8) -> usize {
+    let body_len = key.len() + value.len();
+    buf[0] = key.len() as u8;
+    buf[1] = flags;
+    buf[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+    buf[RECORD_HEADER..RECORD_HEADER + key.len()].copy_from_slice(key);
+    buf[RECORD_HEADER + key.len()..RECORD_HEADER + body_len].copy_from_slice(value);
+    let checksum = crc32(
+        crc32(0, &buf[..4]),
+        &buf[RECORD_HEADER..RECORD_HEADER + body_len],
+    );
+    buf[4..8].copy_from_slice(&checksum.to_le_bytes());
+    padded(RECORD_HEADER + body_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    use super::*;
+
+    const SECTOR: usize = 4096;
+    /// The store's region, between a sector on either side that it must not
+    /// touch.
+    const OFFSET: u32 = SECTOR as u32;
+    const LEN: u32 = 4 * SECTOR as u32;
+
+    /// NOR flash in memory: erasing sets bits, writing only clears them.
+    struct RamFlash {
+        bytes: Vec<u8>,
+        erases: Vec<u32>,
+        /// Bytes that can still be written before the power goes out.
+        power: Option<usize>,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self {
+                bytes: vec![0xff; 6 * SECTOR],
+                erases: vec![0; 6],
+                power: None,
+            }
+        }
+
+        /// Where `data` was last written in the region.
+        fn find(&self, data: &[u8]) -> usize {
+            self.bytes
+                .windows(data.len())
+                .rposition(|window| window == data)
+                .unwrap()
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 4;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            assert!(
+                offset.is_multiple_of(4) && bytes.len().is_multiple_of(4),
+                "unaligned read"
+            );
+            bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.bytes.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            let (from, to) = (from as usize, to as usize);
+            assert!(
+                from.is_multiple_of(SECTOR) && to.is_multiple_of(SECTOR),
+                "unaligned erase"
+            );
+            for sector in from / SECTOR..to / SECTOR {
+                self.erases[sector] += 1;
+            }
+            self.bytes[from..to].fill(0xff);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            assert!(
+                offset.is_multiple_of(4) && bytes.len().is_multiple_of(4),
+                "unaligned write"
+            );
+            for (i, &byte) in bytes.iter().enumerate() {
+                if let Some(power) = &mut self.power {
+                    if *power == 0 {
+                        return Err(NorFlashErrorKind::Other);
+                    }
+                    *power -= 1;
+                }
+                let cell = &mut self.bytes[offset + i];
+                assert_eq!(*cell & byte, byte, "write over unerased flash");
+                *cell &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn open(flash: &mut RamFlash) -> Store<&mut RamFlash> {
+        Store::open(flash, OFFSET, LEN).unwrap()
+    }
+
+    fn get(store: &mut Store<&mut RamFlash>, key: &str) -> Option<String> {
+        let mut buf = [0; MAX_VALUE];
+        let value = store.get(key, &mut buf).unwrap()?;
+        Some(String::from_utf8(value.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn keeps_the_latest_value() {
+        let mut flash = RamFlash::new();
+        let mut store = open(&mut flash);
+        assert_eq!(get(&mut store, "wifi.ssid"), None);
+        store.set("wifi.ssid", b"home").unwrap();
+        store.set("trmnl.address", b"https://example.com").unwrap();
+        store.set("wifi.ssid", b"office").unwrap();
+        store.set("trmnl.device_id", b"abc").unwrap();
+        store.remove("trmnl.device_id").unwrap();
+        assert_eq!(get(&mut store, "wifi.ssid").as_deref(), Some("office"));
+        assert_eq!(get(&mut store, "trmnl.device_id"), None);
+
+        let mut store = open(&mut flash);
+        assert_eq!(get(&mut store, "wifi.ssid").as_deref(), Some("office"));
+        assert_eq!(
+            get(&mut store, "trmnl.address").as_deref(),
+            Some("https://example.com")
+        );
+        assert_eq!(get(&mut store, "trmnl.device_id"), None);
+        assert!(matches!(
+            store.set("wifi.ssid", &[b'x'; MAX_VALUE + 1]),
+            Err(Error::TooLong)
+        ));
+    }
+
+    #[test]
+    fn compacts_into_the_next_sector() {
+        let mut flash = RamFlash::new();
+        let mut store = open(&mut flash);
+        store.set("wifi.ssid", b"home").unwrap();
+        store.remove("trmnl.device_id").unwrap();
+        for i in 0..2000 {
+            store
+                .set("wifi.password", format!("secret{i}").as_bytes())
+                .unwrap();
+        }
+        assert_eq!(
+            get(&mut store, "wifi.password").as_deref(),
+            Some("secret1999")
+        );
+
+        let mut store = open(&mut flash);
+        assert_eq!(get(&mut store, "wifi.ssid").as_deref(), Some("home"));
+        assert_eq!(
+            get(&mut store, "wifi.password").as_deref(),
+            Some("secret1999")
+        );
+        // Round and round the region, evenly, and nowhere else.
+        let erases = &flash.erases[1..5];
+        assert!(erases.iter().all(|&n| n >= 3), "{erases:?}");
+        assert!(erases.iter().max().unwrap() - erases.iter().min().unwrap() <= 1);
+        assert_eq!([flash.erases[0], flash.erases[5]], [0, 0]);
+        assert!(flash.bytes[..SECTOR].iter().all(|&byte| byte == 0xff));
+        assert!(flash.bytes[5 * SECTOR..].iter().all(|&byte| byte == 0xff));
+    }
+
+    #[test]
+    fn refuses_records_that_do_not_fit() {
+        let mut flash = RamFlash::new();
+        let mut store = open(&mut flash);
+        let value = [b'v'; MAX_VALUE];
+        let mut stored = 0;
+        let full = loop {
+            match store.set(&format!("key{stored}"), &value) {
+                Ok(()) => stored += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(full, Error::Full));
+        assert!(stored > 10);
+        // What was stored is still there.
+        let mut store = open(&mut flash);
+        assert_eq!(
+            get(&mut store, "key0").map(|value| value.len()),
+            Some(MAX_VALUE)
+        );
+    }
+
+    #[test]
+    fn ignores_a_corrupted_record() {
+        let mut flash = RamFlash::new();
+        let mut store = open(&mut flash);
+        store.set("wifi.ssid", b"home").unwrap();
+        store.set("wifi.ssid", b"office").unwrap();
+        store.set("trmnl.device_id", b"abc").unwrap();
+        let at = flash.find(b"office");
+        flash.bytes[at] &= !1;
+
+        // The records from the broken one on are lost.
+        let mut store = open(&mut flash);
+        assert_eq!(get(&mut store, "wifi.ssid").as_deref(), Some("home"));
+        assert_eq!(get(&mut store, "trmnl.device_id"), None);
+        // Nothing is written after it; the next record moves on to a fresh
+        // sector.
+        store.set("trmnl.device_id", b"def").unwrap();
+        let mut store = open(&mut flash);
+        assert_eq!(get(&mut store, "wifi.ssid").as_deref(), Some("home"));
+        assert_eq!(get(&mut store, "trmnl.device_id").as_deref(), Some("def"));
+        assert_eq!(flash.erases[1..3], [1, 1]);
+    }
+
+    #[test]
+    fn survives_losing_power_while_writing() {
+        let mut flash = RamFlash::new();
+        let mut store = open(&mut flash);
+        store.set("wifi.ssid", b"home").unwrap();
+        for i in 0..150 {
+            store
+                .set("wifi.password", format!("secret{i}").as_bytes())
+                .unwrap();
+        }
+
+        // Cut the power all through writing records, compactions included.
+        for power in (0..2 * SECTOR).step_by(3) {
+            let mut torn = RamFlash {
+                bytes: flash.bytes.clone(),
+                erases: flash.erases.clone(),
+                power: Some(power),
+            };
+            let mut store = open(&mut torn);
+            while store.set("wifi.ssid", b"office").is_ok() {}
+            torn.power = None;
+
+            let mut store = open(&mut torn);
+            let ssid = get(&mut store, "wifi.ssid");
+            assert!(
+                matches!(ssid.as_deref(), Some("home" | "office")),
+                "{power}: {ssid:?}"
+            );
+            assert_eq!(
+                get(&mut store, "wifi.password").as_deref(),
+                Some("secret149"),
+                "{power}"
+            );
+            store.set("wifi.ssid", b"again").unwrap();
+            let mut store = open(&mut torn);
+            assert_eq!(
+                get(&mut store, "wifi.ssid").as_deref(),
+                Some("again"),
+                "{power}"
+            );
+        }
+    }
+}
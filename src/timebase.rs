@@ -0,0 +1,162 @@
+use log::debug;
+
+/// Drift is only estimated over intervals at least this long; shorter ones are
+/// dominated by network jitter.
+const MIN_DRIFT_INTERVAL_US: u64 = 10 * 60 * 1_000_000;
+/// The RC slow clock is specified to within a few percent; anything beyond
+/// this is a bad sample rather than drift.
+const MAX_DRIFT_PPM: i64 = 100_000;
+
+/// Maps the RTC counter onto UTC.
+///
+/// The RTC slow clock runs off an RC oscillator that is fast or slow by up to a
+/// few percent. Every sync from a trusted source measures how far the counter
+/// ran away since the previous one, and later readings are corrected by that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timebase {
+    /// UTC at the last sync, in microseconds since the unix epoch.
+    pub utc_us: u64,
+    /// RTC counter at the last sync.
+    pub local_us: u64,
+    /// How much faster than real time the RTC counter runs, in parts per million.
+    pub drift_ppm: i32,
+}
+
+impl Timebase {
+    pub const fn new(utc_us: u64, local_us: u64) -> Self {
+        Self {
+            utc_us,
+            local_us,
+            drift_ppm: 0,
+        }
+    }
+
+    /// UTC in microseconds for the RTC counter value `local_us`.
+    ///
+    /// Returns `None` if the counter went backwards, which means it was reset
+    /// by a power loss and this timebase no longer applies.
+    pub fn utc_at(&self, local_us: u64) -> Option<u64> {
+        let elapsed = local_us.checked_sub(self.local_us)? as i128;
+        let corrected = elapsed * 1_000_000 / (1_000_000 + self.drift_ppm as i128);
+        Some(self.utc_us.saturating_add(corrected as u64))
+    }
+
+    /// Re-anchor at a new reference reading, updating the drift estimate if
+    /// `estimate_drift` is set and enough time has passed.
+    pub fn resync(&mut self, utc_us: u64, local_us: u64, estimate_drift: bool) {
+        let Some(local_elapsed) = local_us.checked_sub(self.local_us) else {
+            *self = Self::new(utc_us, local_us);
+            return;
+        };
+        if estimate_drift && local_elapsed >= MIN_DRIFT_INTERVAL_US {
+            let real_elapsed = utc_us as i64 - self.utc_us as i64;
+            if real_elapsed > 0 {
+                let measured = (local_elapsed as i64 - real_elapsed) * 1_000_000 / real_elapsed;
+                if measured.abs() <= MAX_DRIFT_PPM {
+                    // Average with the previous estimate to damp the jitter of
+                    // single samples.
+                    self.drift_ppm = if self.drift_ppm == 0 {
+                        measured as i32
+                    } else {
+                        ((self.drift_ppm as i64 + measured) / 2) as i32
+                    };
+                } else {
+                    debug!("Ignoring implausible clock drift of {measured} ppm");
+                }
+            }
+        }
+        self.utc_us = utc_us;
+        self.local_us = local_us;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000;
+    const HOUR: u64 = 60 * 60 * SECOND;
+    /// Some time in 2025, in microseconds since the unix epoch.
+    const EPOCH: u64 = 1_750_000_000 * SECOND;
+
+    /// An RTC counter running `ppm` parts per million fast, for `real_us`.
+    fn counted(real_us: u64, ppm: i64) -> u64 {
+        (real_us as i128 * (1_000_000 + ppm as i128) / 1_000_000) as u64
+    }
+
+    #[test]
+    fn follows_the_counter_without_drift() {
+        let timebase = Timebase::new(EPOCH, 5 * SECOND);
+        assert_eq!(timebase.utc_at(5 * SECOND), Some(EPOCH));
+        assert_eq!(timebase.utc_at(5 * SECOND + HOUR), Some(EPOCH + HOUR));
+    }
+
+    #[test]
+    fn corrects_a_fast_clock_across_sleeps() {
+        // Two percent fast, as an RC oscillator may be.
+        let ppm = 20_000;
+        let mut timebase = Timebase::new(EPOCH, 0);
+        // Uncorrected, an hour's sleep is 72 s too long.
+        assert_eq!(
+            timebase.utc_at(counted(HOUR, ppm)),
+            Some(EPOCH + HOUR + 72 * SECOND)
+        );
+
+        timebase.resync(EPOCH + HOUR, counted(HOUR, ppm), true);
+        assert_eq!(timebase.drift_ppm, 20_000);
+
+        // Six hours of deep sleep later, without a sync in between.
+        let local = counted(7 * HOUR, ppm);
+        assert_eq!(timebase.utc_at(local), Some(EPOCH + 7 * HOUR));
+    }
+
+    #[test]
+    fn corrects_a_slow_clock() {
+        let ppm = -35_000;
+        let mut timebase = Timebase::new(EPOCH, 0);
+        timebase.resync(EPOCH + 2 * HOUR, counted(2 * HOUR, ppm), true);
+        assert_eq!(timebase.drift_ppm, -35_000);
+        let utc = timebase.utc_at(counted(26 * HOUR, ppm)).unwrap();
+        assert!(utc.abs_diff(EPOCH + 26 * HOUR) < SECOND / 1000);
+    }
+
+    #[test]
+    fn averages_successive_estimates() {
+        let mut timebase = Timebase::new(EPOCH, 0);
+        timebase.resync(EPOCH + HOUR, counted(HOUR, 10_000), true);
+        let local = counted(HOUR, 10_000) + counted(HOUR, 30_000);
+        timebase.resync(EPOCH + 2 * HOUR, local, true);
+        assert_eq!(timebase.drift_ppm, 20_000);
+    }
+
+    #[test]
+    fn leaves_the_estimate_alone_without_a_good_sample() {
+        let mut timebase = Timebase::new(EPOCH, 0);
+        // Not asked to, as for HTTP dates.
+        timebase.resync(EPOCH + HOUR, counted(HOUR, 20_000), false);
+        assert_eq!(timebase.drift_ppm, 0);
+        // Too short an interval.
+        let local = timebase.local_us + counted(5 * 60 * SECOND, 20_000);
+        timebase.resync(EPOCH + HOUR + 5 * 60 * SECOND, local, true);
+        assert_eq!(timebase.drift_ppm, 0);
+        // Too far off to be drift.
+        let local = timebase.local_us + 2 * HOUR;
+        timebase.resync(timebase.utc_us + HOUR, local, true);
+        assert_eq!(timebase.drift_ppm, 0);
+        // Either way the reading is the new anchor.
+        assert_eq!(
+            timebase.utc_at(local),
+            Some(EPOCH + 2 * HOUR + 5 * 60 * SECOND)
+        );
+    }
+
+    #[test]
+    fn starts_over_when_the_counter_was_reset() {
+        let mut timebase = Timebase::new(EPOCH, HOUR);
+        timebase.resync(EPOCH + HOUR, counted(HOUR, 20_000) + HOUR, true);
+        assert_eq!(timebase.utc_at(HOUR / 2), None);
+
+        timebase.resync(EPOCH + 3 * HOUR, SECOND, true);
+        assert_eq!(timebase, Timebase::new(EPOCH + 3 * HOUR, SECOND));
+    }
+}
@@ -0,0 +1,391 @@
+use core::marker::PhantomData;
+
+use embassy_time::{Duration, Instant, WithTimeout};
+use embedded_graphics::prelude::{Point, Size};
+use embedded_graphics::primitives::Rectangle;
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::{delay::DelayNs, digital::Wait, spi::SpiDevice};
+use epd_waveshare::epd7in5_v2::Display7in5;
+
+use crate::epaper::Error;
+use crate::panel::{ColorDepth, Frame, Panel};
+
+const WIDTH: u32 = 800;
+const HEIGHT: u32 = 480;
+const STRIDE: usize = WIDTH as usize / 8;
+
+/// Longest the controller may stay busy, e.g. during a full refresh.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for BUSY before asking for the status again.
+const BUSY_POLL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+enum Command {
+    PanelSetting = 0x00,
+    PowerSetting = 0x01,
+    PowerOff = 0x02,
+    PowerOn = 0x04,
+    BoosterSoftStart = 0x06,
+    DeepSleep = 0x07,
+    DataStartTransmission1 = 0x10,
+    DisplayRefresh = 0x12,
+    DataStartTransmission2 = 0x13,
+    DualSpi = 0x15,
+    VcomAndDataInterval = 0x50,
+    TconSetting = 0x60,
+    Resolution = 0x61,
+    GetStatus = 0x71,
+    PartialWindow = 0x90,
+    PartialIn = 0x91,
+    PartialOut = 0x92,
+    CascadeSetting = 0xE0,
+    ForceTemperature = 0xE5,
+}
+
+/// One of the 800×480 panels driven by a UC8179.
+pub trait Variant {
+    const NAME: &'static str;
+    const COLOR_DEPTH: ColorDepth;
+    const PARTIAL_REFRESH: bool;
+    /// Which inks the controller drives, with the LUT from OTP.
+    const PANEL_SETTING: u8;
+    /// Border and data polarity.
+    const DATA_INTERVAL: u8;
+}
+
+/// The black and white Waveshare 7.5" V2.
+pub struct BlackWhite;
+
+impl Variant for BlackWhite {
+    const NAME: &'static str = "Waveshare 7.5\" V2";
+    const COLOR_DEPTH: ColorDepth = ColorDepth::Gray4;
+    const PARTIAL_REFRESH: bool = true;
+    const PANEL_SETTING: u8 = 0x1F;
+    const DATA_INTERVAL: u8 = 0x10;
+}
+
+/// The black, white and red Waveshare 7.5" (B) V2. Its first data
+/// transmission is black and white with white as 1, the second marks red.
+pub struct Red;
+
+impl Variant for Red {
+    const NAME: &'static str = "Waveshare 7.5\" (B) V2";
+    const COLOR_DEPTH: ColorDepth = ColorDepth::TriColor;
+    const PARTIAL_REFRESH: bool = false;
+    const PANEL_SETTING: u8 = 0x0F;
+    const DATA_INTERVAL: u8 = 0x11;
+}
+
+/// The UC8179 controller of the Waveshare 7.5" V2 panel and its red (B)
+/// sibling.
+///
+/// `epd-waveshare` only does full black and white refreshes on these panels;
+/// this driver adds partial refresh and four gray levels, and the red ink.
+/// It is async throughout, waiting for BUSY on its interrupt rather than
+/// polling it.
+pub struct Uc8179<SPI, BUSY, DC, RST, DELAY, V = BlackWhite> {
+    busy: BUSY,
+    dc: DC,
+    rst: RST,
+    _bus: PhantomData<(SPI, DELAY, V)>,
+}
+
+impl<SPI, BUSY, DC, RST, DELAY, V> Uc8179<SPI, BUSY, DC, RST, DELAY, V>
+where
+    SPI: SpiDevice,
+    BUSY: Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+    V: Variant,
+{
+    async fn command(&mut self, spi: &mut SPI, command: Command) -> Result<(), Error> {
+        self.dc.set_low().map_err(|_| Error::Bus)?;
+        spi.write(&[command as u8]).await.map_err(|_| Error::Bus)
+    }
+
+    async fn data(&mut self, spi: &mut SPI, data: &[u8]) -> Result<(), Error> {
+        self.dc.set_high().map_err(|_| Error::Bus)?;
+        spi.write(data).await.map_err(|_| Error::Bus)
+    }
+
+    async fn command_with_data(
+        &mut self,
+        spi: &mut SPI,
+        command: Command,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.command(spi, command).await?;
+        self.data(spi, data).await
+    }
+
+    async fn reset(&mut self, delay: &mut DELAY) -> Result<(), Error> {
+        self.rst.set_high().map_err(|_| Error::Bus)?;
+        delay.delay_ms(20).await;
+        self.rst.set_low().map_err(|_| Error::Bus)?;
+        delay.delay_ms(2).await;
+        self.rst.set_high().map_err(|_| Error::Bus)?;
+        delay.delay_ms(20).await;
+        Ok(())
+    }
+
+    /// BUSY is low while the controller works. It only updates the pin after
+    /// being asked for its status, so the status is asked for again whenever
+    /// BUSY stays low for a while.
+    async fn wait_until_idle(&mut self, spi: &mut SPI) -> Result<(), Error> {
+        let deadline = Instant::now() + BUSY_TIMEOUT;
+        loop {
+            self.command(spi, Command::GetStatus).await?;
+            match self.busy.wait_for_high().with_timeout(BUSY_POLL).await {
+                Ok(result) => return result.map_err(|_| Error::Bus),
+                Err(_) if Instant::now() < deadline => continue,
+                Err(_) => return Err(Error::BecomingReady),
+            }
+        }
+    }
+
+    async fn power_up(&mut self, spi: &mut SPI, delay: &mut DELAY) -> Result<(), Error> {
+        self.reset(delay).await?;
+        // VGH=20V, VGL=-20V, VDH=15V, VDL=-15V
+        self.command_with_data(spi, Command::PowerSetting, &[0x07, 0x07, 0x3F, 0x3F])
+            .await?;
+        self.command(spi, Command::PowerOn).await?;
+        delay.delay_ms(100).await;
+        self.wait_until_idle(spi).await?;
+        self.command_with_data(spi, Command::PanelSetting, &[V::PANEL_SETTING])
+            .await?;
+        self.command_with_data(
+            spi,
+            Command::Resolution,
+            &[
+                (WIDTH >> 8) as u8,
+                WIDTH as u8,
+                (HEIGHT >> 8) as u8,
+                HEIGHT as u8,
+            ],
+        )
+        .await?;
+        self.command_with_data(spi, Command::DualSpi, &[0x00])
+            .await?;
+        self.command_with_data(spi, Command::VcomAndDataInterval, &[V::DATA_INTERVAL, 0x07])
+            .await?;
+        self.command_with_data(spi, Command::TconSetting, &[0x22])
+            .await
+    }
+
+    async fn refresh(&mut self, spi: &mut SPI, delay: &mut DELAY) -> Result<(), Error> {
+        self.command(spi, Command::DisplayRefresh).await?;
+        delay.delay_ms(100).await;
+        self.wait_until_idle(spi).await
+    }
+
+    /// Send the bytes of `frame` inside `area`, inverted as partial mode and
+    /// the red panel's black and white transmission expect them.
+    async fn write_window(
+        &mut self,
+        spi: &mut SPI,
+        command: Command,
+        frame: &[u8],
+        area: &Rectangle,
+    ) -> Result<(), Error> {
+        self.command(spi, command).await?;
+        let left = area.top_left.x as usize / 8;
+        let right = left + area.size.width.div_ceil(8) as usize;
+        let top = area.top_left.y as usize;
+        let mut row = [0; STRIDE];
+        for y in top..top + area.size.height as usize {
+            let src = &frame[y * STRIDE + left..y * STRIDE + right];
+            for (dst, src) in row.iter_mut().zip(src) {
+                *dst = !src;
+            }
+            self.data(spi, &row[..src.len()]).await?;
+        }
+        Ok(())
+    }
+
+    /// Send `byte` for every byte of a frame.
+    async fn fill(&mut self, spi: &mut SPI, command: Command, byte: u8) -> Result<(), Error> {
+        self.command(spi, command).await?;
+        for _ in 0..HEIGHT {
+            self.data(spi, &[byte; STRIDE]).await?;
+        }
+        Ok(())
+    }
+}
+
+impl<SPI, BUSY, DC, RST, DELAY, V> Panel for Uc8179<SPI, BUSY, DC, RST, DELAY, V>
+where
+    SPI: SpiDevice,
+    BUSY: Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+    DELAY: DelayNs,
+    V: Variant,
+{
+    type Spi = SPI;
+    type Busy = BUSY;
+    type Dc = DC;
+    type Rst = RST;
+    type Delay = DELAY;
+
+    type Color = epd_waveshare::color::Color;
+    type Framebuffer = Display7in5;
+
+    const NAME: &'static str = V::NAME;
+    const WIDTH: u32 = WIDTH;
+    const HEIGHT: u32 = HEIGHT;
+    const COLOR_DEPTH: ColorDepth = V::COLOR_DEPTH;
+    const PARTIAL_REFRESH: bool = V::PARTIAL_REFRESH;
+
+    async fn init(
+        spi: &mut SPI,
+        busy: BUSY,
+        dc: DC,
+        rst: RST,
+        delay: &mut DELAY,
+    ) -> Result<Self, Error> {
+        let mut panel = Self {
+            busy,
+            dc,
+            rst,
+            _bus: PhantomData,
+        };
+        panel
+            .power_up(spi, delay)
+            .await
+            .map_err(|_| Error::InitScreen)?;
+        Ok(panel)
+    }
+
+    async fn wake(&mut self, spi: &mut SPI, delay: &mut DELAY) -> Result<(), Error> {
+        self.power_up(spi, delay).await
+    }
+
+    async fn update(
+        &mut self,
+        spi: &mut SPI,
+        frame: &Display7in5,
+        delay: &mut DELAY,
+    ) -> Result<(), Error> {
+        if V::COLOR_DEPTH == ColorDepth::TriColor {
+            let panel = Rectangle::new(Point::zero(), Size::new(WIDTH, HEIGHT));
+            self.write_window(spi, Command::DataStartTransmission1, frame.bytes(), &panel)
+                .await
+                .map_err(|_| Error::UpdateScreen)?;
+            self.fill(spi, Command::DataStartTransmission2, 0x00)
+                .await
+                .map_err(|_| Error::UpdateScreen)?;
+        } else {
+            self.command_with_data(spi, Command::DataStartTransmission2, frame.bytes())
+                .await
+                .map_err(|_| Error::UpdateScreen)?;
+        }
+        self.refresh(spi, delay).await
+    }
+
+    async fn update_partial(
+        &mut self,
+        spi: &mut SPI,
+        frame: &Display7in5,
+        previous: &Display7in5,
+        area: Rectangle,
+        delay: &mut DELAY,
+    ) -> Result<(), Error> {
+        // Pretending to be hot makes the controller pick its fast waveform.
+        self.command_with_data(spi, Command::CascadeSetting, &[0x02])
+            .await?;
+        self.command_with_data(spi, Command::ForceTemperature, &[0x6E])
+            .await?;
+        // Floating border, inverted data polarity.
+        self.command_with_data(spi, Command::VcomAndDataInterval, &[0xA9, 0x07])
+            .await?;
+
+        let (x, y) = (area.top_left.x as u32, area.top_left.y as u32);
+        let x_end = x + area.size.width - 1;
+        let y_end = y + area.size.height - 1;
+        self.command(spi, Command::PartialIn).await?;
+        self.command_with_data(
+            spi,
+            Command::PartialWindow,
+            &[
+                (x >> 8) as u8,
+                x as u8,
+                (x_end >> 8) as u8,
+                x_end as u8,
+                (y >> 8) as u8,
+                y as u8,
+                (y_end >> 8) as u8,
+                y_end as u8,
+                // Scan the gates inside and outside of the window.
+                0x01,
+            ],
+        )
+        .await?;
+        // The controller lost its memory of the old frame in deep sleep.
+        self.write_window(
+            spi,
+            Command::DataStartTransmission1,
+            previous.bytes(),
+            &area,
+        )
+        .await?;
+        self.write_window(spi, Command::DataStartTransmission2, frame.bytes(), &area)
+            .await?;
+        self.refresh(spi, delay).await?;
+        self.command(spi, Command::PartialOut).await
+    }
+
+    async fn update_gray4(
+        &mut self,
+        spi: &mut SPI,
+        planes: [&[u8]; 2],
+        delay: &mut DELAY,
+    ) -> Result<(), Error> {
+        if V::COLOR_DEPTH != ColorDepth::Gray4 {
+            return Err(Error::Unsupported);
+        }
+        self.command_with_data(spi, Command::BoosterSoftStart, &[0x27, 0x27, 0x18, 0x17])
+            .await?;
+        // This forced temperature selects the grayscale waveform, which
+        // drives each pixel from the pair of bits in the old and new planes.
+        self.command_with_data(spi, Command::CascadeSetting, &[0x02])
+            .await?;
+        self.command_with_data(spi, Command::ForceTemperature, &[0x5F])
+            .await?;
+        self.command_with_data(spi, Command::DataStartTransmission1, planes[0])
+            .await
+            .map_err(|_| Error::UpdateScreen)?;
+        self.command_with_data(spi, Command::DataStartTransmission2, planes[1])
+            .await
+            .map_err(|_| Error::UpdateScreen)?;
+        self.refresh(spi, delay).await
+    }
+
+    async fn update_tricolor(
+        &mut self,
+        spi: &mut SPI,
+        planes: [&[u8]; 2],
+        delay: &mut DELAY,
+    ) -> Result<(), Error> {
+        if V::COLOR_DEPTH != ColorDepth::TriColor {
+            return Err(Error::Unsupported);
+        }
+        self.command_with_data(spi, Command::DataStartTransmission1, planes[0])
+            .await
+            .map_err(|_| Error::UpdateScreen)?;
+        self.command_with_data(spi, Command::DataStartTransmission2, planes[1])
+            .await
+            .map_err(|_| Error::UpdateScreen)?;
+        self.refresh(spi, delay).await
+    }
+
+    async fn sleep(&mut self, spi: &mut SPI, _delay: &mut DELAY) -> Result<(), Error> {
+        self.command_with_data(spi, Command::VcomAndDataInterval, &[0xF7])
+            .await?;
+        self.command(spi, Command::PowerOff).await?;
+        self.wait_until_idle(spi).await?;
+        self.command_with_data(spi, Command::DeepSleep, &[0xA5])
+            .await
+    }
+}
@@ -0,0 +1,344 @@
+use core::cell::RefCell;
+use core::fmt::Display;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use esp_hal::peripherals::TIMG1;
+use esp_hal::rtc_cntl::{RwdtStage, RwdtStageAction, SocResetReason};
+use esp_hal::timer::timg::{MwdtStage, Wdt};
+use log::{debug, warn};
+
+use crate::rtc;
+
+/// The RTC watchdog only catches what the main watchdog cannot, e.g. a stalled
+/// APB clock, so it is sized above the longest phase budget.
+const BACKSTOP_SECS: u64 = 180;
+/// How often a legitimate sleep feeds the watchdog.
+const SLEEP_FEED_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The steps of an update cycle, each with the longest time it may
+/// legitimately take before the device is considered hung.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Idle,
+    FetchApi,
+    FetchImage,
+    Render,
+    Refresh,
+    Clean,
+    Report,
+    Sleep,
+}
+
+impl Phase {
+    fn budget_secs(self) -> u64 {
+        match self {
+            // Connect, TLS handshake, the request timeout and the body read.
+            Phase::FetchApi | Phase::FetchImage | Phase::Report => {
+                30 + u64::from(crate::policy::HTTP_TIMEOUT_SECS.max)
+            }
+            // Waking the panel plus a full refresh of about five seconds, or
+            // twenty on the tri-colour panel.
+            Phase::Refresh => 45,
+            // Three full refreshes in a row.
+            Phase::Clean => 90,
+            Phase::Render => 15,
+            // Sleeping feeds every `SLEEP_FEED_INTERVAL`.
+            Phase::Idle | Phase::Sleep => 30,
+        }
+    }
+
+    fn from_word(word: u32) -> Self {
+        match word {
+            1 => Phase::FetchApi,
+            2 => Phase::FetchImage,
+            3 => Phase::Render,
+            4 => Phase::Refresh,
+            5 => Phase::Report,
+            6 => Phase::Sleep,
+            7 => Phase::Clean,
+            _ => Phase::Idle,
+        }
+    }
+
+    fn to_word(self) -> u32 {
+        match self {
+            Phase::Idle => 0,
+            Phase::FetchApi => 1,
+            Phase::FetchImage => 2,
+            Phase::Render => 3,
+            Phase::Refresh => 4,
+            Phase::Report => 5,
+            Phase::Sleep => 6,
+            Phase::Clean => 7,
+        }
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Phase::Idle => write!(f, "idle"),
+            Phase::FetchApi => write!(f, "api request"),
+            Phase::FetchImage => write!(f, "image download"),
+            Phase::Render => write!(f, "rendering"),
+            Phase::Refresh => write!(f, "panel refresh"),
+            Phase::Clean => write!(f, "panel deep clean"),
+            Phase::Report => write!(f, "reporting"),
+            Phase::Sleep => write!(f, "sleep"),
+        }
+    }
+}
+
+/// A watchdog reset that has not been reported to the server yet.
+#[derive(Debug, Clone, Copy)]
+pub struct ResetReport {
+    reason: u32,
+    phase: Phase,
+}
+
+impl Display for ResetReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "watchdog reset during {}", self.phase)?;
+        match SocResetReason::from_repr(self.reason as usize) {
+            Some(reason) => write!(f, " ({reason:?})"),
+            None => write!(f, " (reason {})", self.reason),
+        }
+    }
+}
+
+const PERSISTED_MAGIC: u32 = 0x5744_5431; // "WDT1"
+
+/// `[magic, current phase, pending reason, pending phase, checksum]`.
+///
+/// A pending reason of zero means nothing is waiting to be reported.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PERSISTED: [u32; 5] = [0; 5];
+
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    phase: Phase,
+    pending: Option<ResetReport>,
+}
+
+fn load() -> Record {
+    // SAFETY: only accessed during boot and from tasks on the main executor,
+    // which never preempt each other.
+    let words = unsafe { (&raw const PERSISTED).read_volatile() };
+    if words[0] != PERSISTED_MAGIC || words[4] != rtc::checksum(&words[..4]) {
+        return Record {
+            phase: Phase::Idle,
+            pending: None,
+        };
+    }
+    Record {
+        phase: Phase::from_word(words[1]),
+        pending: (words[2] != 0).then(|| ResetReport {
+            reason: words[2],
+            phase: Phase::from_word(words[3]),
+        }),
+    }
+}
+
+fn store(record: Record) {
+    let mut words = [0; 5];
+    words[0] = PERSISTED_MAGIC;
+    words[1] = record.phase.to_word();
+    if let Some(report) = record.pending {
+        words[2] = report.reason;
+        words[3] = report.phase.to_word();
+    }
+    words[4] = rtc::checksum(&words[..4]);
+    // SAFETY: see `load`.
+    unsafe { (&raw mut PERSISTED).write_volatile(words) };
+}
+
+/// Check whether the last reset was caused by a watchdog and remember it for
+/// reporting. Call once at boot, before the supervisor is started.
+pub fn init() {
+    let mut record = load();
+    let reason = esp_hal::system::reset_reason();
+    if let Some(
+        reason @ (SocResetReason::CoreMwdt0
+        | SocResetReason::CoreMwdt1
+        | SocResetReason::CoreRtcWdt
+        | SocResetReason::Cpu0Mwdt0
+        | SocResetReason::Cpu0Mwdt1
+        | SocResetReason::Cpu0RtcWdt
+        | SocResetReason::SysRtcWdt
+        | SocResetReason::SysSuperWdt),
+    ) = reason
+    {
+        let report = ResetReport {
+            reason: reason as u32,
+            phase: record.phase,
+        };
+        warn!("Recovered from a {report}");
+        record.pending = Some(report);
+    }
+    record.phase = Phase::Idle;
+    store(record);
+}
+
+/// The watchdog reset waiting to be reported, if any.
+pub fn pending_report() -> Option<ResetReport> {
+    load().pending
+}
+
+/// Forget the pending report once the server has acknowledged it.
+pub fn clear_report() {
+    let mut record = load();
+    record.pending = None;
+    store(record);
+}
+
+/// The tasks that report progress to the watchdog, each on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    Fetch,
+    Display,
+}
+
+/// Owns the hardware watchdogs and only feeds them while every lane is within
+/// the budget of the phase it is in.
+struct Supervisor {
+    wdt: Wdt<TIMG1<'static>>,
+    /// When each lane overruns its current phase; `None` while it waits for
+    /// the other lane, which cannot hang.
+    deadlines: [Option<(Phase, Instant)>; 2],
+}
+
+impl Supervisor {
+    /// Point the watchdog at the earliest deadline and persist the phase it
+    /// belongs to. A deadline that already passed is left for the watchdog to
+    /// catch.
+    fn arm(&mut self) {
+        let (phase, deadline) = self
+            .deadlines
+            .iter()
+            .flatten()
+            .min_by_key(|(_, deadline)| *deadline)
+            .copied()
+            .unwrap_or((
+                Phase::Idle,
+                Instant::now() + Duration::from_secs(Phase::Idle.budget_secs()),
+            ));
+        let mut record = load();
+        if record.phase != phase {
+            record.phase = phase;
+            store(record);
+        }
+        let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+            return;
+        };
+        self.wdt.set_timeout(
+            MwdtStage::Stage0,
+            esp_hal::time::Duration::from_micros(remaining.as_micros()),
+        );
+        self.wdt.feed();
+        rtc::with(|rtc| rtc.rwdt.feed());
+    }
+}
+
+static SUPERVISOR: Mutex<CriticalSectionRawMutex, RefCell<Option<Supervisor>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Start the hardware watchdogs. Call once, before any [`Watch`] is used.
+pub fn start(mut wdt: Wdt<TIMG1<'static>>) {
+    wdt.set_timeout(
+        MwdtStage::Stage0,
+        esp_hal::time::Duration::from_secs(Phase::Idle.budget_secs()),
+    );
+    wdt.enable();
+    rtc::with(|rtc| {
+        // The timeout goes first so that the watchdog never runs with the
+        // bootloader's. Enabling it resets the stage actions to their
+        // defaults, so the action can only follow.
+        rtc.rwdt.set_timeout(
+            RwdtStage::Stage0,
+            esp_hal::time::Duration::from_secs(BACKSTOP_SECS),
+        );
+        rtc.rwdt.enable();
+        // Keep the RTC domain, and with it the clock and persisted
+        // records, alive through the reset.
+        rtc.rwdt
+            .set_stage_action(RwdtStage::Stage0, RwdtStageAction::ResetCore);
+    });
+    SUPERVISOR.lock(|supervisor| {
+        let mut supervisor = supervisor.borrow_mut();
+        supervisor
+            .insert(Supervisor {
+                wdt,
+                deadlines: [None; 2],
+            })
+            .arm();
+    });
+}
+
+/// Reports the progress of one lane through its phases.
+pub struct Watch {
+    lane: Lane,
+}
+
+impl Watch {
+    pub fn new(lane: Lane) -> Self {
+        Self { lane }
+    }
+
+    /// Record progress into `phase` and give it its full budget. In
+    /// [`Phase::Idle`] the lane waits on the other one and has no budget.
+    pub fn enter(&mut self, phase: Phase) {
+        debug!("Watchdog: {:?} entering {phase}", self.lane);
+        let deadline = (phase != Phase::Idle).then(|| {
+            (
+                phase,
+                Instant::now() + Duration::from_secs(phase.budget_secs()),
+            )
+        });
+        SUPERVISOR.lock(|supervisor| {
+            let mut supervisor = supervisor.borrow_mut();
+            let supervisor = supervisor.as_mut().expect("watchdog is not started");
+            supervisor.deadlines[self.lane as usize] = deadline;
+            supervisor.arm();
+        });
+    }
+
+    /// Sleep for `duration`, which counts as progress for as long as it lasts.
+    pub async fn sleep(&mut self, duration: Duration) {
+        self.sleep_unless(duration, None).await
+    }
+
+    /// Like [`Watch::sleep`], but wake up early once `wake` is signalled.
+    pub async fn sleep_until_woken(
+        &mut self,
+        duration: Duration,
+        wake: &Signal<CriticalSectionRawMutex, ()>,
+    ) {
+        self.sleep_unless(duration, Some(wake)).await
+    }
+
+    async fn sleep_unless(
+        &mut self,
+        duration: Duration,
+        wake: Option<&Signal<CriticalSectionRawMutex, ()>>,
+    ) {
+        let mut remaining = duration;
+        loop {
+            self.enter(Phase::Sleep);
+            if remaining == Duration::MIN {
+                return;
+            }
+            let step = remaining.min(SLEEP_FEED_INTERVAL);
+            match wake {
+                Some(wake) => {
+                    if wake.wait().with_timeout(step).await.is_ok() {
+                        return;
+                    }
+                }
+                None => Timer::after(step).await,
+            }
+            remaining -= step;
+        }
+    }
+}
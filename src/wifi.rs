@@ -1,7 +1,14 @@
+use core::cmp::Reverse;
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicI32, Ordering};
+
 use alloc::string::String;
 use embassy_executor::Spawner;
-use embassy_net::{Runner, Stack, StackResources};
-use embassy_time::{Duration, Timer};
+use embassy_futures::select::{Either, select};
+use embassy_net::{Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer, WithTimeout};
 use esp_hal::peripherals::WIFI;
 use esp_radio::wifi::{
     self, ConnectedStationInfo, ControllerConfig, WifiController, WifiError,
@@ -10,6 +17,30 @@ use log::{debug, error, info};
 use static_cell::StaticCell;
 
 static STACK_RESOURCES: StaticCell<StackResources<2>> = StaticCell::new();
+/// The access point serves DHCP, DNS and HTTP, besides the stack's own DNS
+/// client.
+static AP_STACK_RESOURCES: StaticCell<StackResources<4>> = StaticCell::new();
+
+/// Most networks [`scan`] reports.
+pub const MAX_NETWORKS: usize = 16;
+/// How long [`scan_connected`] waits for the connection task.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Asks the connection task, which owns the controller, for a scan.
+static SCAN_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static SCAN_RESULT: Signal<CriticalSectionRawMutex, heapless::Vec<Network, MAX_NETWORKS>> =
+    Signal::new();
+
+/// How often the signal strength is sampled while connected.
+const RSSI_INTERVAL: Duration = Duration::from_secs(60);
+/// Signal strength of the access point in dBm, zero while unknown.
+static RSSI: AtomicI32 = AtomicI32::new(0);
+
+/// Last sampled signal strength of the access point, in dBm.
+pub fn rssi() -> Option<i32> {
+    let rssi = RSSI.load(Ordering::Relaxed);
+    (rssi != 0).then_some(rssi)
+}
 
 pub async fn connect(
     spawner: &Spawner,
@@ -53,6 +84,89 @@ pub async fn connect(
     Ok(stack)
 }
 
+/// Start an open access point called `ssid`, with the device at `address` on
+/// a /24 network. The station side stays up, unconnected, for [`scan`].
+pub async fn start_access_point(
+    spawner: &Spawner,
+    wifi: WIFI<'static>,
+    seed: u64,
+    ssid: &str,
+    address: Ipv4Addr,
+) -> Result<(Stack<'static>, WifiController<'static>), Error> {
+    let (mut controller, interfaces) = wifi::new(wifi, ControllerConfig::default())?;
+
+    let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(address, 24),
+        gateway: Some(address),
+        dns_servers: Default::default(),
+    });
+    let stack_resources: &'static mut _ = AP_STACK_RESOURCES.init(StackResources::new());
+    let (stack, runner) = embassy_net::new(interfaces.access_point, config, stack_resources, seed);
+
+    controller.set_config(&wifi::Config::AccessPointStation(
+        wifi::sta::StationConfig::default(),
+        wifi::ap::AccessPointConfig::default().with_ssid(ssid.into()),
+    ))?;
+    spawner.spawn(net_task(runner).unwrap());
+    info!("Access point {ssid} is up at {address}");
+    Ok((stack, controller))
+}
+
+/// A network found by [`scan`].
+pub struct Network {
+    pub ssid: heapless::String<32>,
+    /// Signal strength in dBm.
+    pub rssi: i8,
+    /// Whether joining needs no password.
+    pub open: bool,
+}
+
+/// Look for networks in range, strongest first, each name once.
+pub async fn scan(
+    controller: &mut WifiController<'static>,
+) -> heapless::Vec<Network, MAX_NETWORKS> {
+    let mut networks = heapless::Vec::<Network, MAX_NETWORKS>::new();
+    let found = match controller
+        .scan_async(&wifi::scan::ScanConfig::default())
+        .await
+    {
+        Ok(found) => found,
+        Err(e) => {
+            error!("Wifi scan failed: {e:?}");
+            return networks;
+        }
+    };
+    for network in found {
+        let Ok(ssid) = heapless::String::try_from(network.ssid.as_str()) else {
+            continue;
+        };
+        if ssid.is_empty() || networks.iter().any(|known| known.ssid == ssid) {
+            continue;
+        }
+        let open = matches!(network.auth_method, None | Some(wifi::AuthMethod::None));
+        if networks
+            .push(Network {
+                ssid,
+                rssi: network.signal_strength,
+                open,
+            })
+            .is_err()
+        {
+            break;
+        }
+    }
+    networks.sort_unstable_by_key(|network| Reverse(network.rssi));
+    networks
+}
+
+/// Like [`scan`], while connected as a station. `None` if there is no
+/// connection to scan from, or the scan took too long.
+pub async fn scan_connected() -> Option<heapless::Vec<Network, MAX_NETWORKS>> {
+    SCAN_RESULT.reset();
+    SCAN_REQUEST.signal(());
+    SCAN_RESULT.wait().with_timeout(SCAN_TIMEOUT).await.ok()
+}
+
 #[embassy_executor::task]
 async fn connection(mut controller: WifiController<'static>, ssid: String, password: String) {
     if let Err(error) = connection_fallible(&mut controller, ssid, password).await {
@@ -75,16 +189,24 @@ async fn connection_fallible(
 
     loop {
         if controller.is_connected() {
-            // Wait until we're no longer connected
-            let mut subscriber = controller.subscribe()?;
-            loop {
-                match subscriber.next_event().await {
-                    esp_radio::wifi::event::MessageResult::Message(
+            // Wait until we're no longer connected, sampling the signal
+            // strength in the meantime.
+            while controller.is_connected() {
+                RSSI.store(controller.rssi().unwrap_or(0), Ordering::Relaxed);
+                let mut subscriber = controller.subscribe()?;
+                let event = subscriber.next_event().with_timeout(RSSI_INTERVAL);
+                match select(event, SCAN_REQUEST.wait()).await {
+                    Either::First(Ok(esp_radio::wifi::event::MessageResult::Message(
                         esp_radio::wifi::event::EventInfo::StationDisconnected { .. },
-                    ) => break,
-                    _ => {}
+                    ))) => break,
+                    Either::First(_) => {}
+                    Either::Second(()) => {
+                        drop(subscriber);
+                        SCAN_RESULT.signal(scan(controller).await);
+                    }
                 }
             }
+            RSSI.store(0, Ordering::Relaxed);
             debug!("Disconnected, reconnecting...");
         }
 